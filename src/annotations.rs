@@ -0,0 +1,35 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+use crate::canonical;
+
+// Adds `annotations` to the top-level `annotations` of a staged manifest or index, replacing
+// existing values with the same key.
+pub(crate) fn add(manifest: &Path, annotations: &BTreeMap<String, String>) -> Result<()> {
+    if annotations.is_empty() {
+        return Ok(());
+    }
+
+    let mut value: Value = serde_json::from_slice(&fs::read(manifest)?)?;
+    let existing = value
+        .as_object_mut()
+        .context("Manifest is not a JSON object")?
+        .entry("annotations")
+        .or_insert_with(|| Value::Object(Map::new()));
+
+    match existing.as_object_mut() {
+        Some(existing) => {
+            for (key, annotation) in annotations {
+                existing.insert(key.clone(), Value::from(annotation.as_str()));
+            }
+        }
+        None => bail!("Manifest annotations are not a JSON object"),
+    }
+
+    fs::write(manifest, canonical::to_vec(&value)?)?;
+
+    Ok(())
+}
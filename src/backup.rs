@@ -0,0 +1,376 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{bail, Context, Result};
+use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncReadExt;
+
+use crate::capabilities::{self, Capability};
+use crate::filter::ImageFilter;
+use crate::r2configs;
+use crate::v2;
+use crate::v2::catalog::{self, CATALOG_KEY};
+use crate::v2::chunks::{self, CHUNKS_PREFIX};
+use crate::v2::lister::Lister;
+
+const INDEX_ENTRY: &str = "backup-index.json";
+const LEVEL: i32 = 3;
+
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+    pub filter: ImageFilter,
+    // The index of a previous backup: only objects that changed since are archived.
+    pub since: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupSummary {
+    pub repositories: usize,
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct BackupIndex {
+    created: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base: Option<String>,
+    repositories: BTreeSet<String>,
+    objects: BTreeMap<String, BackupObject>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct BackupObject {
+    size: u64,
+    sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e_tag: Option<String>,
+    // The earlier archive of the chain holding the object, when it is not in this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archive: Option<String>,
+}
+
+// Streams every object of the selected repositories (and the chunks their recipes use) into a
+// zstd-compressed tar of bucket keys. The index, listing each object with its digest and content
+// type, ends the archive and is also written next to it as `<out>.index.json`.
+//
+// With `since`, objects whose ETag is unchanged from the previous backup are left out of the
+// archive, the index still lists them with the archive of the chain that holds them.
+pub async fn backup(out: &Path, options: BackupOptions) -> Result<BackupSummary> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    capabilities::require(&client, &env_vars.r2_bucket, "backup", &[Capability::Read, Capability::List]).await?;
+
+    let repositories: BTreeSet<String> = read_catalog(&client, &env_vars.r2_bucket).await?.into_iter().filter(|name| options.filter.matches(name)).collect();
+    let keys = select_keys(&client, &env_vars.r2_bucket, &repositories).await?;
+    log::info!("Backing up {} objects of {} repositories to {}", keys.len(), repositories.len(), out.display());
+
+    let file = File::create(out).context(format!("Failed to create {}", out.display()))?;
+    let mut builder = tar::Builder::new(zstd::Encoder::new(file, LEVEL)?);
+
+    let base = match &options.since {
+        Some(since) => Some((archive_name(since)?, read_index_file(since)?)),
+        None => None,
+    };
+
+    let mut index = BackupIndex {
+        created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        base: base.as_ref().map(|(name, _)| name.clone()),
+        repositories: repositories.clone(),
+        objects: BTreeMap::new(),
+    };
+    let mut archived = 0;
+    for (key, e_tag) in &keys {
+        let previous = base.as_ref().and_then(|(name, base)| Some((name, base.objects.get(key)?)));
+        if let Some((name, previous)) = previous.filter(|(_, previous)| previous.e_tag == *e_tag) {
+            let archive = previous.archive.clone().unwrap_or_else(|| name.clone());
+            index.objects.insert(key.clone(), BackupObject { archive: Some(archive), ..previous.clone() });
+            continue;
+        }
+
+        let (mut staged, mut object) = download(&client, &env_vars.r2_bucket, key).await?;
+        staged.seek(SeekFrom::Start(0))?;
+        append(&mut builder, key, object.size, &mut staged)?;
+        object.e_tag = e_tag.clone();
+        index.objects.insert(key.clone(), object);
+        archived += 1;
+    }
+
+    let index_data = serde_json::to_vec_pretty(&index)?;
+    append(&mut builder, INDEX_ENTRY, index_data.len() as u64, index_data.as_slice())?;
+    builder.into_inner()?.finish()?.sync_all()?;
+    fs::write(index_path(out), &index_data).context(format!("Failed to write the index of {}", out.display()))?;
+
+    let summary = BackupSummary {
+        repositories: repositories.len(),
+        objects: archived,
+        bytes: index.objects.values().filter(|object| object.archive.is_none()).map(|object| object.size).sum(),
+    };
+    match &index.base {
+        Some(base) => log::info!(
+            "Backed up {} changed objects ({} bytes) to {}, {} unchanged objects are in {} and earlier archives",
+            summary.objects,
+            summary.bytes,
+            out.display(),
+            index.objects.len() - summary.objects,
+            base
+        ),
+        None => log::info!("Backed up {} objects ({} bytes) to {}", summary.objects, summary.bytes, out.display()),
+    }
+
+    Ok(summary)
+}
+
+// Uploads the objects of a backup that are missing from the bucket, verifying each against the
+// index, and merges the restored repositories into the catalog. A differential backup is restored
+// together with the earlier archives of its chain, which must be in the same directory. Restoring
+// again after an interruption skips what is already there.
+pub async fn restore(archive: &Path, options: BackupOptions) -> Result<BackupSummary> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    capabilities::require(&client, &env_vars.r2_bucket, "restore", &[Capability::Write]).await?;
+
+    let index = read_index(archive)?;
+    let repositories: BTreeSet<String> = index.repositories.iter().filter(|name| options.filter.matches(name)).cloned().collect();
+
+    let mut chain: BTreeMap<PathBuf, BTreeMap<&str, &BackupObject>> = BTreeMap::new();
+    for (key, object) in &index.objects {
+        if !key.starts_with(CHUNKS_PREFIX) && !owner(key, &index.repositories).is_some_and(|owner| repositories.contains(owner)) {
+            continue;
+        }
+
+        let path = match &object.archive {
+            Some(name) => archive.with_file_name(name),
+            None => archive.to_owned(),
+        };
+        chain.entry(path).or_default().insert(key, object);
+    }
+
+    let mut restored = Vec::new();
+    let mut bytes = 0;
+    for (path, objects) in &chain {
+        bytes += restore_archive(&client, &env_vars.r2_bucket, path, objects, &mut restored).await?;
+    }
+
+    for repository in &repositories {
+        catalog::update_catalog(&env_vars, repository).await?;
+    }
+    let bloom = v2::bloom::load(&client, &env_vars).await?;
+    v2::bloom::record(&env_vars, &bloom, &restored).await?;
+
+    log::info!("Restored {} objects ({} bytes) of {} repositories from {}", restored.len(), bytes, repositories.len(), archive.display());
+
+    Ok(BackupSummary {
+        repositories: repositories.len(),
+        objects: restored.len(),
+        bytes,
+    })
+}
+
+async fn restore_archive(client: &S3Client, r2_bucket: &str, archive: &Path, objects: &BTreeMap<&str, &BackupObject>, restored: &mut Vec<String>) -> Result<u64> {
+    let mut bytes = 0;
+    let mut remaining = objects.len();
+    let mut entries = tar::Archive::new(zstd::Decoder::new(File::open(archive).context(format!("Failed to open {}", archive.display()))?)?);
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        let key = entry.path()?.to_string_lossy().into_owned();
+        let Some(object) = objects.get(key.as_str()) else {
+            continue;
+        };
+        remaining -= 1;
+
+        if chunks::exists(client, r2_bucket, &key).await? {
+            continue;
+        }
+
+        let mut staged = NamedTempFile::new()?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = entry.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            staged.write_all(&buffer[..read])?;
+        }
+        if format!("{:x}", hasher.finalize()) != object.sha256 {
+            bail!("{} in {} does not match its digest, the archive is corrupt", key, archive.display());
+        }
+
+        let req = PutObjectRequest {
+            bucket: r2_bucket.to_owned(),
+            key: key.clone(),
+            body: Some(v2::memory::stream_file(staged.path(), object.size, 8 * 1024 * 1024).await?),
+            content_type: object.content_type.clone(),
+            ..Default::default()
+        };
+        client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+
+        bytes += object.size;
+        restored.push(key);
+    }
+
+    if remaining > 0 {
+        bail!("{} is missing {} objects listed in the backup index", archive.display(), remaining);
+    }
+
+    Ok(bytes)
+}
+
+async fn read_catalog(client: &S3Client, r2_bucket: &str) -> Result<Vec<String>> {
+    let data = match get(client, r2_bucket, CATALOG_KEY).await? {
+        Some(data) => data,
+        None => return Ok(Vec::new()),
+    };
+    let catalog: Value = serde_json::from_slice(&data).context("Malformed catalog")?;
+
+    Ok(catalog["repositories"].as_array().into_iter().flatten().filter_map(|name| name.as_str().map(str::to_owned)).collect())
+}
+
+// Every object below `v2/` that belongs to one of `repositories`, plus the shared chunks their
+// recipes reference. The catalog and blob index are rebuilt on restore instead.
+async fn select_keys(client: &S3Client, r2_bucket: &str, repositories: &BTreeSet<String>) -> Result<Vec<(String, Option<String>)>> {
+    let all: BTreeSet<String> = read_catalog(client, r2_bucket).await?.into_iter().collect();
+    let objects = Lister::new(client, r2_bucket).concurrency(8).list("v2/").await?;
+
+    let mut keys = Vec::new();
+    let mut chunk_keys = BTreeSet::new();
+    for object in objects {
+        let Some(repository) = owner(&object.key, &all).filter(|owner| repositories.contains(*owner)) else {
+            continue;
+        };
+
+        if let Some(blob_name) = object.key.strip_prefix(&format!("v2/{}/recipes/", repository)) {
+            if let Some(recipe) = chunks::read_recipe(client, r2_bucket, repository, blob_name).await? {
+                chunk_keys.extend(recipe.chunks.into_iter().map(|chunk| format!("{}{}", CHUNKS_PREFIX, chunk.hash)));
+            }
+        }
+        keys.push((object.key, object.e_tag));
+    }
+    // Chunks are named by their content, so an unchanged key is an unchanged chunk.
+    keys.extend(chunk_keys.into_iter().map(|key| (key, None)));
+
+    Ok(keys)
+}
+
+// The repository a key belongs to: the longest name `<name>` with the key below `v2/<name>/` or
+// `v2/_receipts/<name>/`, so that nested repositories are told apart from their parents.
+fn owner<'a>(key: &str, repositories: &'a BTreeSet<String>) -> Option<&'a str> {
+    let rest = key.strip_prefix("v2/")?;
+    let rest = rest.strip_prefix("_receipts/").unwrap_or(rest);
+
+    repositories
+        .iter()
+        .filter(|name| rest.strip_prefix(name.as_str()).is_some_and(|rest| rest.starts_with('/')))
+        .max_by_key(|name| name.len())
+        .map(String::as_str)
+}
+
+async fn download(client: &S3Client, r2_bucket: &str, key: &str) -> Result<(NamedTempFile, BackupObject)> {
+    let req = GetObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let output = client.get_object(req).await.context(format!("Failed to download {}", key))?;
+    let mut body = output.body.context(format!("{} has no body", key))?.into_async_read();
+
+    let mut staged = NamedTempFile::new()?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = body.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        staged.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+
+    let object = BackupObject {
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+        content_type: output.content_type,
+        e_tag: None,
+        archive: None,
+    };
+
+    Ok((staged, object))
+}
+
+async fn get(client: &S3Client, r2_bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    let req = GetObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let output = match client.get_object(req).await {
+        Ok(output) => output,
+        Err(e) if v2::is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to download {}", key)),
+    };
+
+    let mut data = Vec::new();
+    output.body.context(format!("{} has no body", key))?.into_async_read().read_to_end(&mut data).await?;
+
+    Ok(Some(data))
+}
+
+fn append<W: Write, R: Read>(builder: &mut tar::Builder<W>, path: &str, size: u64, data: R) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    builder.append_data(&mut header, path, data).context(format!("Failed to archive {}", path))
+}
+
+fn index_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".index.json");
+    PathBuf::from(path)
+}
+
+// The archive an index written by `backup` belongs to.
+fn archive_name(index: &Path) -> Result<String> {
+    let name = index.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    match name.strip_suffix(".index.json") {
+        Some(archive) if !archive.is_empty() => Ok(archive.to_owned()),
+        _ => bail!("{} is not a backup index (expected <archive>.index.json)", index.display()),
+    }
+}
+
+fn read_index_file(path: &Path) -> Result<BackupIndex> {
+    let data = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&data).context(format!("Malformed index {}", path.display()))
+}
+
+// The index next to the archive, or the one at its end when the archive was moved on its own.
+fn read_index(archive: &Path) -> Result<BackupIndex> {
+    let sidecar = index_path(archive);
+    if sidecar.exists() {
+        return read_index_file(&sidecar);
+    }
+
+    let mut entries = tar::Archive::new(zstd::Decoder::new(File::open(archive).context(format!("Failed to open {}", archive.display()))?)?);
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(INDEX_ENTRY) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            return serde_json::from_slice(&data).context(format!("Malformed index in {}", archive.display()));
+        }
+    }
+
+    bail!("{} has no index, it is incomplete", archive.display())
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+
+use crate::{run_with_options, PushOptions};
+
+#[derive(Clone, Debug)]
+pub struct BatchJob {
+    pub image: String,
+    pub tag: String,
+    pub options: PushOptions,
+}
+
+// One image per line as `image:tag [source]`; blank lines and `#` comments are ignored.
+pub fn parse_batch_file<P: AsRef<Path>>(path: P, options: &PushOptions) -> Result<Vec<BatchJob>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path).context(format!("Failed to read batch file {}", path.display()))?;
+
+    let mut jobs = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let reference = fields.next().unwrap();
+        let (image, tag) = split_reference(reference)
+            .context(format!("{}:{}: expected image:tag, got {}", path.display(), number + 1, reference))?;
+
+        let mut options = options.clone();
+        if let Some(source) = fields.next() {
+            options.source = Some(source.to_owned());
+        }
+
+        jobs.push(BatchJob { image, tag, options });
+    }
+
+    Ok(jobs)
+}
+
+pub(crate) fn split_reference(reference: &str) -> Option<(String, String)> {
+    let (image, tag) = reference.rsplit_once(':')?;
+    if image.is_empty() || tag.is_empty() || tag.contains('/') {
+        return None;
+    }
+
+    Some((image.to_owned(), tag.to_owned()))
+}
+
+pub async fn run_batch(jobs: Vec<BatchJob>) -> Result<()> {
+    let total = jobs.len();
+    let mut failed = Vec::new();
+
+    for job in jobs {
+        let reference = format!("{}:{}", job.image, job.tag);
+        if let Err(e) = run_with_options(job.image, job.tag, job.options).await {
+            log::error!("Failed to push {}: {:#}", reference, e);
+            failed.push(reference);
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("Failed to push {} of {} images: {}", failed.len(), total, failed.join(", "));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use anyhow::{anyhow, bail, Result};
+
+use crate::config::{self, PricingConfig};
+
+static MAX_OPS: OnceLock<u64> = OnceLock::new();
+static USED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OpClass {
+    // PUT, COPY, POST and LIST.
+    A,
+    // GET and HEAD.
+    B,
+    // Free, but they count against the budget.
+    Delete,
+}
+
+// The operations a command is about to issue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpEstimate {
+    pub class_a: u64,
+    pub class_b: u64,
+    pub deletes: u64,
+}
+
+impl OpEstimate {
+    pub fn total(&self) -> u64 {
+        self.class_a + self.class_b + self.deletes
+    }
+
+    // In US dollars, before the free tier.
+    pub fn cost(&self, pricing: &PricingConfig) -> f64 {
+        (self.class_a as f64 * pricing.class_a_per_million + self.class_b as f64 * pricing.class_b_per_million) / 1_000_000.0
+    }
+}
+
+impl fmt::Display for OpEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} class A, {} class B and {} delete operations", self.class_a, self.class_b, self.deletes)
+    }
+}
+
+// Caps the number of bucket operations the process issues. Once `max_ops` are used up, every
+// further operation fails, and commands that estimate their operations up front refuse to start
+// when the estimate does not fit in what is left.
+pub fn set_operation_budget(max_ops: u64) -> Result<()> {
+    MAX_OPS.set(max_ops).map_err(|_| anyhow!("The operation budget is already configured"))
+}
+
+// The bucket operations issued so far.
+pub fn operations_used() -> u64 {
+    USED.load(Ordering::SeqCst)
+}
+
+pub(crate) fn charge(class: OpClass) -> Result<()> {
+    let used = USED.fetch_add(1, Ordering::SeqCst) + 1;
+    match MAX_OPS.get() {
+        Some(max_ops) if used > *max_ops => bail!("Operation budget of {} exhausted ({:?} operation refused)", max_ops, class),
+        _ => Ok(()),
+    }
+}
+
+// Reports what `command` is projected to issue and refuses to go on when it would overrun the
+// budget.
+pub(crate) fn check_estimate(command: &str, estimate: OpEstimate) -> Result<()> {
+    let pricing = config::load_config_file().map(|config| config.pricing).unwrap_or_default();
+    log::warn!("{} will issue up to {} (about ${:.4})", command, estimate, estimate.cost(&pricing));
+
+    if let Some(max_ops) = MAX_OPS.get() {
+        let left = max_ops.saturating_sub(operations_used());
+        if estimate.total() > left {
+            bail!("{} needs up to {} operations, but only {} of the budget of {} are left", command, estimate.total(), left, max_ops);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::env::consts;
+
+// The crate features a build can have, as `--version --build-info` reports them.
+const FEATURES: [(&str, bool); 3] = [("cli", cfg!(feature = "cli")), ("tui", cfg!(feature = "tui")), ("vendored-tls", cfg!(feature = "vendored-tls"))];
+
+// The version, enabled features, target and linkage of this build, one `<key>: <value>` per line,
+// for bug reports and for checking that a static build is what was shipped.
+pub fn build_info() -> String {
+    let features: Vec<&str> = FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect();
+    let linkage = match cfg!(target_feature = "crt-static") {
+        true => "static",
+        false => "dynamic",
+    };
+    let tls = match cfg!(feature = "vendored-tls") {
+        true => "native-tls with vendored OpenSSL, rustls",
+        false => "native-tls, rustls",
+    };
+
+    [
+        format!("version: {}", env!("CARGO_PKG_VERSION")),
+        format!("features: {}", if features.is_empty() { "none".to_owned() } else { features.join(", ") }),
+        format!("target: {}-{}", consts::ARCH, consts::OS),
+        format!("profile: {}", if cfg!(debug_assertions) { "debug" } else { "release" }),
+        format!("linkage: {}", linkage),
+        format!("tls: {}", tls),
+    ]
+    .join("\n")
+}
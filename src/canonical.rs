@@ -0,0 +1,214 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde_json::{Number, Value};
+use sha2::{Digest, Sha256};
+
+use crate::hash_utils;
+
+// How staged manifests are encoded. Manifests the uploader modifies (annotations, recompression)
+// are always written as canonical JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ManifestEncoding {
+    // Manifests nothing was requested for keep their original bytes and digests.
+    #[default]
+    Preserve,
+    // Every manifest is re-serialized as canonical JSON, so digests do not depend on how the
+    // source registry or build tool formatted them.
+    Canonical,
+}
+
+// RFC 8785 (JCS) serialization: no whitespace, object members sorted by their UTF-16 code units,
+// minimal string escapes and numbers formatted like ECMAScript.
+pub(crate) fn to_vec(value: &Value) -> Result<Vec<u8>> {
+    let mut out = String::new();
+    write_value(&mut out, value)?;
+
+    Ok(out.into_bytes())
+}
+
+fn write_value(out: &mut String, value: &Value) -> Result<()> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+        Value::Number(number) => out.push_str(&format_number(number)),
+        // serde_json escapes exactly the characters JCS requires, with the same short forms.
+        Value::String(string) => out.push_str(&serde_json::to_string(string)?),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, value)?;
+            }
+            out.push(']');
+        }
+        Value::Object(members) => {
+            let mut members: Vec<(&String, &Value)> = members.iter().collect();
+            members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+
+            out.push('{');
+            for (i, (key, value)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_value(out, value)?;
+            }
+            out.push('}');
+        }
+    }
+
+    Ok(())
+}
+
+// JCS numbers are IEEE 754 doubles printed as ECMAScript's Number.prototype.toString does:
+// integers without a fraction, exponents only below 1e-6 and from 1e21 on.
+fn format_number(number: &Number) -> String {
+    let value = number.as_f64().unwrap_or_default();
+    if value == 0.0 {
+        return "0".to_owned();
+    }
+
+    let magnitude = value.abs();
+    if (1e-6..1e21).contains(&magnitude) {
+        return match value.fract() == 0.0 {
+            true => format!("{:.0}", value),
+            false => format!("{}", value),
+        };
+    }
+
+    let formatted = format!("{:e}", value);
+    match formatted.split_once('e') {
+        Some((mantissa, exponent)) if !exponent.starts_with('-') => format!("{}e+{}", mantissa, exponent),
+        _ => formatted,
+    }
+}
+
+// Rewrites every staged manifest of the image in `dir` as canonical JSON.
+pub(crate) fn canonicalize(dir: &Path) -> Result<()> {
+    rewrite_manifests(dir, |_| Ok(true))
+}
+
+// Applies `update` to the top manifest staged in `dir` and to every child manifest of an index.
+// Manifests it reports as changed are written back as canonical JSON, child manifests under their
+// new digests, which the index descriptors are updated to.
+pub(crate) fn rewrite_manifests<F>(dir: &Path, mut update: F) -> Result<()>
+where
+    F: FnMut(&mut Value) -> Result<bool>,
+{
+    let top_path = dir.join("manifest.json");
+    let mut top: Value = read_json(&top_path)?;
+    let mut changed = update(&mut top)?;
+
+    for descriptor in top["manifests"].as_array_mut().into_iter().flatten() {
+        let Some(hex) = descriptor_hex(descriptor) else {
+            continue;
+        };
+        let child_path = dir.join(format!("{}.manifest.json", hex));
+        if !child_path.exists() {
+            continue;
+        }
+
+        let mut child = read_json(&child_path)?;
+        if !update(&mut child)? {
+            continue;
+        }
+        let data = to_vec(&child)?;
+        fs::remove_file(&child_path)?;
+        let new_path = dir.join("child.manifest.json.tmp");
+        fs::write(&new_path, &data)?;
+        let digest = hash_utils::compute_sha256(&new_path)?;
+        fs::rename(&new_path, dir.join(format!("{}.manifest.json", digest.trim_start_matches("sha256:"))))?;
+
+        descriptor["digest"] = Value::from(digest);
+        descriptor["size"] = Value::from(data.len());
+        changed = true;
+    }
+
+    if changed {
+        fs::write(&top_path, to_vec(&top)?)?;
+    }
+
+    Ok(())
+}
+
+// Applies `update` to the config of every staged manifest. Configs it reports as changed are
+// stored as canonical JSON under their new digests and the manifests are rewritten to match.
+pub(crate) fn rewrite_configs<F>(dir: &Path, mut update: F) -> Result<()>
+where
+    F: FnMut(&mut Value) -> Result<bool>,
+{
+    let mut replaced = BTreeSet::new();
+    rewrite_manifests(dir, |manifest| {
+        let Some(hex) = descriptor_hex(&manifest["config"]) else {
+            return Ok(false);
+        };
+        let mut config = read_json(&dir.join(&hex))?;
+        if !update(&mut config)? {
+            return Ok(false);
+        }
+
+        let data = to_vec(&config)?;
+        let new_hex = format!("{:x}", Sha256::digest(&data));
+        fs::write(dir.join(&new_hex), &data)?;
+        if new_hex != hex {
+            replaced.insert(hex);
+        }
+
+        manifest["config"]["digest"] = Value::from(format!("sha256:{}", new_hex));
+        manifest["config"]["size"] = Value::from(data.len());
+
+        Ok(true)
+    })?;
+
+    // Only removed once every manifest is rewritten, the children of an index may share a config.
+    for hex in replaced {
+        fs::remove_file(dir.join(hex))?;
+    }
+
+    Ok(())
+}
+
+fn descriptor_hex(descriptor: &Value) -> Option<String> {
+    descriptor["digest"].as_str().and_then(|digest| digest.split_once(':')).map(|(_, hex)| hex.to_owned())
+}
+
+pub(crate) fn read_json(path: &Path) -> Result<Value> {
+    let data = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&data).context(format!("Failed to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn canonical(value: Value) -> String {
+        String::from_utf8(to_vec(&value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn objects_are_sorted_without_whitespace() {
+        assert_eq!(canonical(json!({"b": [1, {"d": null, "c": true}], "a": "x"})), r#"{"a":"x","b":[1,{"c":true,"d":null}]}"#);
+        // U+1F600 sorts before U+FB01 by its UTF-16 surrogates, after it by code point.
+        assert_eq!(canonical(json!({"\u{fb01}": 1, "\u{1f600}": 2})), "{\"\u{1f600}\":2,\"\u{fb01}\":1}");
+        assert_eq!(canonical(json!("tab\there \"é\"")), "\"tab\\there \\\"é\\\"\"");
+    }
+
+    #[test]
+    fn numbers_are_formatted_like_ecmascript() {
+        let number = |text: &str| canonical(serde_json::from_str(text).unwrap());
+        assert_eq!(number("0"), "0");
+        assert_eq!(number("-0.0"), "0");
+        assert_eq!(number("1.0"), "1");
+        assert_eq!(number("-42"), "-42");
+        assert_eq!(number("0.5"), "0.5");
+        assert_eq!(number("1e20"), "100000000000000000000");
+        assert_eq!(number("1e21"), "1e+21");
+        assert_eq!(number("1.5e-7"), "1.5e-7");
+    }
+}
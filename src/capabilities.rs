@@ -0,0 +1,148 @@
+use std::fmt;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use rusoto_core::RusotoError;
+use rusoto_s3::{DeleteObjectRequest, GetObjectError, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+
+use crate::r2configs;
+use crate::v2;
+
+const PROBE_PREFIX: &str = "v2/_probe/";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Read,
+    List,
+    Write,
+    Delete,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Read => "read",
+            Capability::List => "list",
+            Capability::Write => "write",
+            Capability::Delete => "delete",
+        })
+    }
+}
+
+pub async fn probe_capabilities() -> Result<Vec<(Capability, bool)>> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+
+    let mut capabilities = Vec::new();
+    for capability in [Capability::Read, Capability::List, Capability::Write, Capability::Delete] {
+        capabilities.push((capability, check(&client, &env_vars.r2_bucket, capability).await?));
+    }
+
+    Ok(capabilities)
+}
+
+pub(crate) async fn require(client: &S3Client, bucket: &str, command: &str, capabilities: &[Capability]) -> Result<()> {
+    let mut missing = Vec::new();
+    for &capability in capabilities {
+        if !check(client, bucket, capability).await? {
+            missing.push(capability.to_string());
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "The R2 credentials are not allowed to {} objects in bucket {}, which {} needs. \
+             Use an API token with Object Read & Write permission for this bucket",
+            missing.join(" or "),
+            bucket,
+            command,
+        );
+    }
+
+    Ok(())
+}
+
+// Probes against keys that do not exist, so that read and delete checks never touch real objects:
+// a denied request answers 403 while an allowed one answers 404 (or 204 for deletes).
+async fn check(client: &S3Client, bucket: &str, capability: Capability) -> Result<bool> {
+    let key = probe_key();
+
+    let result = match capability {
+        Capability::Read => {
+            let req = GetObjectRequest {
+                bucket: bucket.to_owned(),
+                key,
+                ..Default::default()
+            };
+
+            match client.get_object(req).await {
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => Ok(()),
+                Err(e) if v2::is_not_found(&e) => Ok(()),
+                result => result.map(|_| ()).map_err(classify),
+            }
+        }
+        Capability::List => {
+            let req = ListObjectsV2Request {
+                bucket: bucket.to_owned(),
+                prefix: Some(PROBE_PREFIX.to_owned()),
+                max_keys: Some(1),
+                ..Default::default()
+            };
+
+            client.list_objects_v2(req).await.map(|_| ()).map_err(classify)
+        }
+        Capability::Write => {
+            let req = PutObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.clone(),
+                body: Some(Vec::new().into()),
+                ..Default::default()
+            };
+
+            let result = client.put_object(req).await.map(|_| ()).map_err(classify);
+            if result.is_ok() {
+                let req = DeleteObjectRequest {
+                    bucket: bucket.to_owned(),
+                    key,
+                    ..Default::default()
+                };
+                if client.delete_object(req).await.is_err() {
+                    log::warn!("Could not remove the write probe object under {}", PROBE_PREFIX);
+                }
+            }
+            result
+        }
+        Capability::Delete => {
+            let req = DeleteObjectRequest {
+                bucket: bucket.to_owned(),
+                key,
+                ..Default::default()
+            };
+
+            client.delete_object(req).await.map(|_| ()).map_err(classify)
+        }
+    };
+
+    match result {
+        Ok(()) => Ok(true),
+        Err(ProbeError::Denied) => Ok(false),
+        Err(ProbeError::Other(e)) => Err(e).context(format!("Failed to probe {} access to bucket {}", capability, bucket)),
+    }
+}
+
+enum ProbeError {
+    Denied,
+    Other(anyhow::Error),
+}
+
+fn classify<E: std::error::Error + Send + Sync + 'static>(e: RusotoError<E>) -> ProbeError {
+    match &e {
+        RusotoError::Unknown(response) if response.status.as_u16() == 403 => ProbeError::Denied,
+        _ => ProbeError::Other(e.into()),
+    }
+}
+
+fn probe_key() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    format!("{}{}-{}", PROBE_PREFIX, process::id(), nanos)
+}
@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::converter::SourceLocation;
+use crate::destination;
+use crate::filter::ImageFilter;
+use crate::jobs::RetryPolicy;
+use crate::v2::memory::parse_size;
+
+const DEFAULT_CONFIG_FILE: &str = "oci-r2-uploader.toml";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub public_url: Option<String>,
+    #[serde(default)]
+    pub filter: ImageFilter,
+    #[serde(default)]
+    pub notify: Vec<NotifierConfig>,
+    #[serde(default)]
+    pub serve: ServeSettings,
+    #[serde(default)]
+    pub replication: Vec<ReplicaConfig>,
+    #[serde(default)]
+    pub quota: Vec<QuotaConfig>,
+    #[serde(default)]
+    pub image_policy: ImagePolicyConfig,
+    #[serde(default)]
+    pub redact: RedactionConfig,
+    #[serde(default)]
+    pub sources: Vec<SourceLocation>,
+    #[serde(default)]
+    pub dest: BTreeMap<String, DestinationConfig>,
+    #[serde(default)]
+    pub pricing: PricingConfig,
+    #[serde(default)]
+    pub convert_retry: ConvertRetryConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationConfig {
+    pub bucket: Option<String>,
+    pub account_id: Option<String>,
+    pub access_key_id_env: Option<String>,
+    pub secret_access_key_env: Option<String>,
+    pub prefix: Option<String>,
+    pub public_url: Option<String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    pub cache_control: CacheControl,
+    pub policy: Option<PathBuf>,
+    #[serde(default)]
+    pub require_signing: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheControl {
+    pub blobs: Option<String>,
+    pub manifests: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImagePolicyConfig {
+    #[serde(default)]
+    pub forbid_root: bool,
+    #[serde(default)]
+    pub required_labels: Vec<String>,
+    #[serde(default)]
+    pub forbidden_base_digests: Vec<String>,
+    #[serde(default)]
+    pub approved_bases: Vec<ApprovedBase>,
+    #[serde(default)]
+    pub unapproved_base: PolicyAction,
+}
+
+impl ImagePolicyConfig {
+    pub fn is_empty(&self) -> bool {
+        !self.forbid_root && self.required_labels.is_empty() && self.forbidden_base_digests.is_empty() && self.approved_bases.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovedBase {
+    pub name: String,
+    pub layers: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    #[default]
+    Reject,
+    Warn,
+}
+
+// Labels and annotations to scrub before an image is uploaded. `remove` holds glob patterns of
+// keys, `rewrite` literal replacements applied to the values that are kept.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedactionConfig {
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRule>,
+}
+
+// R2 prices in US dollars, for cost estimates. The defaults are the standard storage class list
+// prices; override them when the prices change.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PricingConfig {
+    pub storage_gb_month: f64,
+    pub class_a_per_million: f64,
+    pub class_b_per_million: f64,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        PricingConfig {
+            storage_gb_month: 0.015,
+            class_a_per_million: 4.50,
+            class_b_per_million: 0.36,
+        }
+    }
+}
+
+impl PricingConfig {
+    pub fn storage_cost(&self, bytes: u64) -> f64 {
+        bytes as f64 / 1_000_000_000.0 * self.storage_gb_month
+    }
+}
+
+// Overrides of the default conversion retry policy.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConvertRetryConfig {
+    pub max_attempts: Option<u32>,
+    pub backoff_secs: Option<u64>,
+}
+
+impl ConvertRetryConfig {
+    pub(crate) fn apply(&self, policy: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(policy.max_attempts),
+            backoff: self.backoff_secs.map(Duration::from_secs).unwrap_or(policy.backoff),
+        }
+    }
+}
+
+impl RedactionConfig {
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.rewrite.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub find: String,
+    pub replace: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServeSettings {
+    pub auth: Option<AuthConfig>,
+    pub cache: Option<BlobCacheConfig>,
+    // Accepts writes from clients. Read-only by default.
+    #[serde(default)]
+    pub push: bool,
+    pub events: Option<PullEventsConfig>,
+    // Keeps per-repository pull counts in the bucket, shown by `list_repositories` with `with_pulls`.
+    #[serde(default)]
+    pub count_pulls: bool,
+    pub scrub: Option<ScrubConfig>,
+    // Addresses or CIDR networks of the reverse proxies in front of serve, e.g. Cloudflare's ranges.
+    // Only their `CF-Connecting-IP` and `X-Forwarded-For` headers are believed.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+// Verifies a rotating sample of the bucket's blobs and manifests against their digests in the
+// background, reporting corruption to the log, the webhook and `/metrics`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrubConfig {
+    #[serde(default = "default_scrub_interval")]
+    pub interval_secs: u64,
+    // Objects verified per round.
+    #[serde(default = "default_scrub_sample")]
+    pub sample: usize,
+    pub webhook: Option<String>,
+}
+
+// Where serve mode reports manifest pulls: a webhook receiving each event as JSON, a file of JSON
+// lines, or both.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PullEventsConfig {
+    pub webhook: Option<String>,
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlobCacheConfig {
+    pub dir: PathBuf,
+    // Defaults to 10 GiB.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_size: Option<u64>,
+    // Cached blobs are fetched from the bucket again once they are this old. Unlimited by default.
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub secret: String,
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    #[serde(default = "default_issuer")]
+    pub service: String,
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl_secs: u64,
+    #[serde(default)]
+    pub anonymous: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    // Bearer token Prometheus presents on `/metrics`. Without it `/metrics` is refused.
+    pub metrics_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    // Repositories the user may also push to, when `serve.push` is enabled.
+    #[serde(default)]
+    pub push: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicaConfig {
+    pub name: String,
+    pub bucket: String,
+    pub account_id: Option<String>,
+    pub access_key_id_env: Option<String>,
+    pub secret_access_key_env: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub namespace: String,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_bytes: Option<u64>,
+    pub max_objects: Option<u64>,
+}
+
+impl QuotaConfig {
+    pub fn applies_to(&self, image: &str) -> bool {
+        let namespace = self.namespace.trim_end_matches('/');
+        image == namespace || image.strip_prefix(namespace).is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum NotifierConfig {
+    Slack {
+        url: String,
+        #[serde(flatten)]
+        message: MessageConfig,
+    },
+    Discord {
+        url: String,
+        #[serde(flatten)]
+        message: MessageConfig,
+    },
+    Email {
+        smtp_host: String,
+        smtp_port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+        #[serde(flatten)]
+        message: MessageConfig,
+    },
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MessageConfig {
+    #[serde(default)]
+    pub on: Vec<NotifyOn>,
+    pub success_template: Option<String>,
+    pub failure_template: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyOn {
+    Success,
+    Failure,
+}
+
+// Sizes are given either in bytes or as strings such as "50G".
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_size(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+fn default_issuer() -> String {
+    "oci-r2-uploader".to_owned()
+}
+
+fn default_scrub_interval() -> u64 {
+    3600
+}
+
+fn default_scrub_sample() -> usize {
+    100
+}
+
+fn default_token_ttl() -> u64 {
+    300
+}
+
+pub(crate) fn config_path() -> Option<PathBuf> {
+    match env::var("OCI_R2_CONFIG") {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => {
+            let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+            path.exists().then_some(path)
+        }
+    }
+}
+
+pub fn load_config() -> Result<Config> {
+    let mut config = load_config_file()?;
+
+    // The selected destination's public URL replaces the top-level one.
+    let public_url = destination::resolve(&config)?.and_then(|(_, destination)| destination.public_url.clone());
+    if public_url.is_some() {
+        config.public_url = public_url;
+    }
+
+    Ok(config)
+}
+
+pub(crate) fn load_config_file() -> Result<Config> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+
+    let data = read_config_file(&path)?;
+    toml::from_str(&data).context(format!("Failed to parse config file {}", path.display()))
+}
+
+pub(crate) fn read_config_file(path: &Path) -> Result<String> {
+    let data = fs::read_to_string(path).context(format!("Failed to read config file {}", path.display()))?;
+    expand_env(&data).context(format!("Failed to expand config file {}", path.display()))
+}
+
+// Replaces `${VAR}` and `${VAR:-default}` with the environment, before the file is parsed. `$${`
+// stands for a literal `${`, and comment lines are left alone.
+fn expand_env(data: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(data.len());
+
+    for (number, line) in data.split_inclusive('\n').enumerate() {
+        if line.trim_start().starts_with('#') {
+            expanded.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                expanded.push_str(&rest[..start - 1]);
+                expanded.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            expanded.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("Unterminated ${{ on line {}", number + 1),
+            };
+
+            let expression = &rest[start + 2..end];
+            let (name, default) = match expression.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expression, None),
+            };
+            match (env::var(name), default) {
+                (Ok(value), _) => expanded.push_str(&value),
+                (Err(_), Some(default)) => expanded.push_str(default),
+                (Err(_), None) => bail!("{} is not set (line {}), set it or use ${{{}:-default}}", name, number + 1, name),
+            }
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_env_substitutes_variables_and_defaults() {
+        env::set_var("OCI_R2_TEST_EXPAND_BUCKET", "images");
+        env::remove_var("OCI_R2_TEST_EXPAND_UNSET");
+
+        assert_eq!(expand_env("bucket = \"${OCI_R2_TEST_EXPAND_BUCKET}\"\n").unwrap(), "bucket = \"images\"\n");
+        assert_eq!(expand_env("tag = \"${OCI_R2_TEST_EXPAND_UNSET:-latest}\"").unwrap(), "tag = \"latest\"");
+        assert_eq!(expand_env("a = \"${OCI_R2_TEST_EXPAND_BUCKET:-x}-$${literal}\"").unwrap(), "a = \"images-${literal}\"");
+        assert_eq!(expand_env("  # ${OCI_R2_TEST_EXPAND_UNSET}\nb = 1\n").unwrap(), "  # ${OCI_R2_TEST_EXPAND_UNSET}\nb = 1\n");
+    }
+
+    #[test]
+    fn expand_env_rejects_unset_and_unterminated_variables() {
+        env::remove_var("OCI_R2_TEST_EXPAND_MISSING");
+
+        assert!(expand_env("a = 1\nb = \"${OCI_R2_TEST_EXPAND_MISSING}\"").unwrap_err().to_string().contains("line 2"));
+        assert!(expand_env("a = \"${OCI_R2_TEST_EXPAND_MISSING\"").unwrap_err().to_string().contains("Unterminated"));
+    }
+}
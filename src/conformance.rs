@@ -0,0 +1,233 @@
+use std::fmt;
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderName, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, IF_NONE_MATCH, RANGE};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+
+use crate::batch::split_reference;
+use crate::prewarm::MANIFEST_ACCEPT;
+use crate::smoke::Puller;
+
+const MISSING_DIGEST: &str = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+// One behaviour of the OCI distribution spec pull workflow. Clients fail to pull when a required
+// behaviour is missing; the others are optimisations or newer parts of the spec.
+#[derive(Clone, Debug)]
+pub struct ConformanceCheck {
+    pub name: &'static str,
+    pub required: bool,
+    pub error: Option<String>,
+}
+
+impl ConformanceCheck {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl fmt::Display for ConformanceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match (self.passed(), self.required) {
+            (true, _) => "PASS",
+            (false, true) => "FAIL",
+            (false, false) => "WARN",
+        };
+        write!(f, "{} {}", status, self.name)?;
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+// What the checks pull: the manifest behind the tag and one blob it references.
+struct Subject {
+    tag: String,
+    digest: String,
+    media_type: Option<String>,
+    blob: String,
+    blob_size: u64,
+}
+
+// Runs the pull tests of the OCI distribution spec conformance suite that apply to a read-only
+// registry against `registry_url`, using `<image>:<tag>` pushed beforehand. Reports which
+// behaviours the serving setup (the bucket behind a Worker or nginx, or `serve`) gets right.
+pub async fn conformance(reference: &str, registry_url: &str) -> Result<Vec<ConformanceCheck>> {
+    let (image, tag) = split_reference(reference).context(format!("Invalid reference {} (expected <image>:<tag>)", reference))?;
+    let mut puller = Puller::new(registry_url, &image)?;
+
+    let mut checks = vec![check("base endpoint", true, base(&mut puller).await)];
+    let subject = match subject(&mut puller, tag).await {
+        Ok(subject) => subject,
+        Err(e) => {
+            checks.push(check("pull manifest by tag", true, Err(e)));
+            return Ok(checks);
+        }
+    };
+    checks.push(check("pull manifest by tag", true, Ok(())));
+
+    checks.push(check("manifest content type", true, manifest_content_type(&mut puller, &subject).await));
+    checks.push(check("manifest digest header", false, manifest_digest_header(&mut puller, &subject).await));
+    checks.push(check("head manifest", true, head(&mut puller, &format!("manifests/{}", subject.tag), None).await));
+    checks.push(check("pull manifest by digest", true, puller.manifest(&subject.digest, Some(&subject.digest)).await.map(|_| ())));
+    checks.push(check("head blob", true, head(&mut puller, &format!("blobs/{}", subject.blob), Some(subject.blob_size)).await));
+    checks.push(check("pull blob", true, puller.blob(&subject.blob, Some(subject.blob_size)).await.map(|_| ())));
+    checks.push(check("blob range request", false, blob_range(&mut puller, &subject).await));
+    checks.push(check("unknown manifest", true, unknown(&mut puller, "manifests/conformance-missing-tag", "MANIFEST_UNKNOWN").await));
+    checks.push(check("unknown blob", true, unknown(&mut puller, &format!("blobs/{}", MISSING_DIGEST), "BLOB_UNKNOWN").await));
+    checks.push(check("tags list", false, tags_list(&mut puller, &image, &subject).await));
+    checks.push(check("referrers", false, referrers(&mut puller, &subject).await));
+    checks.push(check("conditional manifest request", false, conditional(&mut puller, &subject).await));
+
+    let failed = checks.iter().filter(|check| !check.passed()).count();
+    log::info!("{} of {} conformance checks passed against {}", checks.len() - failed, checks.len(), registry_url);
+
+    Ok(checks)
+}
+
+fn check(name: &'static str, required: bool, result: Result<()>) -> ConformanceCheck {
+    ConformanceCheck { name, required, error: result.err().map(|e| format!("{:#}", e)) }
+}
+
+async fn base(puller: &mut Puller) -> Result<()> {
+    let url = puller.base_url();
+    let response = puller.send(Method::GET, &url, &[]).await?;
+    if response.status() != StatusCode::OK {
+        bail!("{} answered {}", url, response.status());
+    }
+
+    Ok(())
+}
+
+async fn subject(puller: &mut Puller, tag: String) -> Result<Subject> {
+    let (digest, manifest) = puller.manifest(&tag, None).await?;
+    let media_type = manifest["mediaType"].as_str().map(str::to_owned);
+
+    // Indexes reference no blobs themselves, the first platform's config is used.
+    let image_manifest = match manifest["manifests"].as_array().and_then(|children| children.first()) {
+        Some(child) => {
+            let child = child["digest"].as_str().context("Index entry without a digest")?;
+            puller.manifest(child, Some(child)).await?.1
+        }
+        None => manifest,
+    };
+    let blob = image_manifest["config"]["digest"].as_str().context("The manifest has no config")?.to_owned();
+    let blob_size = image_manifest["config"]["size"].as_u64().context("The config descriptor has no size")?;
+
+    Ok(Subject { tag, digest, media_type, blob, blob_size })
+}
+
+async fn manifest_content_type(puller: &mut Puller, subject: &Subject) -> Result<()> {
+    let url = puller.url(&format!("manifests/{}", subject.tag));
+    let response = puller.send(Method::GET, &url, &[(ACCEPT, MANIFEST_ACCEPT)]).await?;
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+
+    match &subject.media_type {
+        Some(media_type) if content_type != media_type => bail!("Content-Type is {:?}, the manifest is a {}", content_type, media_type),
+        None if content_type.is_empty() => bail!("no Content-Type"),
+        _ => Ok(()),
+    }
+}
+
+async fn manifest_digest_header(puller: &mut Puller, subject: &Subject) -> Result<()> {
+    let url = puller.url(&format!("manifests/{}", subject.tag));
+    let response = puller.send(Method::GET, &url, &[(ACCEPT, MANIFEST_ACCEPT)]).await?;
+    match response.headers().get("docker-content-digest").and_then(|value| value.to_str().ok()) {
+        Some(digest) if digest == subject.digest => Ok(()),
+        Some(digest) => bail!("Docker-Content-Digest is {}, the manifest has digest {}", digest, subject.digest),
+        None => bail!("no Docker-Content-Digest header on a pull by tag"),
+    }
+}
+
+async fn head(puller: &mut Puller, path: &str, size: Option<u64>) -> Result<()> {
+    let url = puller.url(path);
+    let response = puller.send(Method::HEAD, &url, &[(ACCEPT, MANIFEST_ACCEPT)]).await?;
+    if response.status() != StatusCode::OK {
+        bail!("HEAD {} answered {}", url, response.status());
+    }
+
+    let length = response.headers().get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()).and_then(|value| value.parse::<u64>().ok());
+    match (length, size) {
+        (None, _) => bail!("HEAD {} has no Content-Length", url),
+        (Some(length), Some(size)) if length != size => bail!("HEAD {} has Content-Length {}, the descriptor says {}", url, length, size),
+        _ => Ok(()),
+    }
+}
+
+async fn blob_range(puller: &mut Puller, subject: &Subject) -> Result<()> {
+    let url = puller.url(&format!("blobs/{}", subject.blob));
+    let response = puller.send(Method::GET, &url, &[(RANGE, "bytes=0-0")]).await?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        bail!("a range request answered {}", response.status());
+    }
+
+    let body = response.bytes().await?;
+    if body.len() != 1 {
+        bail!("a one byte range returned {} bytes", body.len());
+    }
+
+    Ok(())
+}
+
+async fn unknown(puller: &mut Puller, path: &str, code: &str) -> Result<()> {
+    let url = puller.url(path);
+    let response = puller.send(Method::GET, &url, &[(ACCEPT, MANIFEST_ACCEPT)]).await?;
+    if response.status() != StatusCode::NOT_FOUND {
+        bail!("{} answered {}, expected 404", url, response.status());
+    }
+
+    // Clients only rely on the status, but the spec asks for an error body naming the code.
+    let body: Value = response.json().await.unwrap_or_default();
+    let codes: Vec<&str> = body["errors"].as_array().into_iter().flatten().filter_map(|error| error["code"].as_str()).collect();
+    if !codes.contains(&code) {
+        bail!("the 404 has no {} error code", code);
+    }
+
+    Ok(())
+}
+
+async fn tags_list(puller: &mut Puller, image: &str, subject: &Subject) -> Result<()> {
+    let url = puller.url("tags/list");
+    let response = puller.send(Method::GET, &url, &[]).await?;
+    if response.status() != StatusCode::OK {
+        bail!("{} answered {}", url, response.status());
+    }
+
+    let body: Value = response.json().await.context(format!("Malformed tag list from {}", url))?;
+    if body["name"].as_str() != Some(image) {
+        bail!("the tag list does not name {}", image);
+    }
+    if !body["tags"].as_array().into_iter().flatten().any(|tag| tag.as_str() == Some(subject.tag.as_str())) {
+        bail!("the tag list does not include {}", subject.tag);
+    }
+
+    Ok(())
+}
+
+async fn referrers(puller: &mut Puller, subject: &Subject) -> Result<()> {
+    let url = puller.url(&format!("referrers/{}", subject.digest));
+    let response = puller.send(Method::GET, &url, &[]).await?;
+    if response.status() != StatusCode::OK {
+        bail!("{} answered {}", url, response.status());
+    }
+
+    let body: Value = response.json().await.context(format!("Malformed referrers index from {}", url))?;
+    if !body["manifests"].is_array() {
+        bail!("the referrers response is not an image index");
+    }
+
+    Ok(())
+}
+
+async fn conditional(puller: &mut Puller, subject: &Subject) -> Result<()> {
+    let url = puller.url(&format!("manifests/{}", subject.tag));
+    let etag = format!("\"{}\"", subject.digest);
+    let headers: [(HeaderName, &str); 2] = [(ACCEPT, MANIFEST_ACCEPT), (IF_NONE_MATCH, &etag)];
+    let response = puller.send(Method::GET, &url, &headers).await?;
+    if response.status() != StatusCode::NOT_MODIFIED {
+        bail!("If-None-Match with the current digest answered {}", response.status());
+    }
+
+    Ok(())
+}
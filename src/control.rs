@@ -0,0 +1,205 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::sync::watch;
+
+use crate::v2::memory::parse_size;
+
+// The rate limit of nice pushes, unless one is set already. Blob bodies, chunks and patches all
+// pass through `admit`, so at most one `THROTTLE_CHUNK` beyond it is ever in flight.
+pub(crate) const NICE_RATE_LIMIT: u64 = 2 * 1024 * 1024;
+// Throttled bodies are admitted in chunks of this size, well below a second's worth at any
+// useful rate limit.
+pub(crate) const THROTTLE_CHUNK: usize = 64 * 1024;
+
+// Lets a running push be paused, resumed, aborted or throttled from outside. Upload bodies are
+// admitted chunk by chunk as they are sent, so the rate limit shapes the traffic itself and a pause
+// holds uploads in flight.
+#[derive(Clone)]
+pub struct UploadControl(Arc<Inner>);
+
+struct Inner {
+    paused: watch::Sender<bool>,
+    aborted: AtomicBool,
+    // Bytes per second, 0 for unlimited.
+    rate_limit: AtomicU64,
+    // When the bytes admitted so far will have been sent at the rate limit.
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Default for UploadControl {
+    fn default() -> Self {
+        UploadControl(Arc::new(Inner {
+            paused: watch::channel(false).0,
+            aborted: AtomicBool::new(false),
+            rate_limit: AtomicU64::new(0),
+            next_free: Mutex::new(None),
+        }))
+    }
+}
+
+impl fmt::Debug for UploadControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe())
+    }
+}
+
+impl UploadControl {
+    pub fn pause(&self) {
+        self.0.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.0.paused.send_replace(false);
+    }
+
+    pub fn abort(&self) {
+        self.0.aborted.store(true, Ordering::SeqCst);
+        // Wakes up uploads waiting on a pause so they see the abort.
+        self.resume();
+    }
+
+    pub fn set_rate_limit(&self, bytes_per_sec: Option<u64>) {
+        self.0.rate_limit.store(bytes_per_sec.unwrap_or(0), Ordering::SeqCst);
+        *self.0.next_free.lock().unwrap() = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.0.paused.borrow()
+    }
+
+    pub fn rate_limit(&self) -> Option<u64> {
+        Some(self.0.rate_limit.load(Ordering::SeqCst)).filter(|rate| *rate > 0)
+    }
+
+    fn describe(&self) -> String {
+        let state = match (self.0.aborted.load(Ordering::SeqCst), self.is_paused()) {
+            (true, _) => "aborted",
+            (false, true) => "paused",
+            (false, false) => "running",
+        };
+        match self.rate_limit() {
+            Some(rate) => format!("{}, rate limit {} bytes/s", state, rate),
+            None => state.to_owned(),
+        }
+    }
+
+    // Waits while the push is paused and then admits `bytes` at the rate limit, failing once the
+    // push is aborted. Idle time earns no credit, so a pause between blobs is never followed by a
+    // burst above the limit.
+    pub(crate) async fn admit(&self, bytes: u64) -> Result<()> {
+        let mut paused = self.0.paused.subscribe();
+        while *paused.borrow_and_update() && !self.0.aborted.load(Ordering::SeqCst) {
+            let _ = paused.changed().await;
+        }
+        if self.0.aborted.load(Ordering::SeqCst) {
+            bail!("Push aborted");
+        }
+
+        let Some(rate) = self.rate_limit() else {
+            return Ok(());
+        };
+        let wait = {
+            let mut next_free = self.0.next_free.lock().unwrap();
+            let now = Instant::now();
+            let start = next_free.filter(|next_free| *next_free > now).unwrap_or(now);
+            *next_free = Some(start + Duration::from_secs_f64(bytes as f64 / rate as f64));
+            start - now
+        };
+        tokio::time::sleep(wait).await;
+
+        Ok(())
+    }
+
+    // Admits each chunk of `stream` as the HTTP client reads it.
+    pub(crate) fn throttle<S>(&self, stream: S) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        let control = self.clone();
+        stream.then(move |chunk| {
+            let control = control.clone();
+            async move {
+                let chunk = chunk?;
+                control.admit(chunk.len() as u64).await.map_err(io::Error::other)?;
+                Ok(chunk)
+            }
+        })
+    }
+
+    // Runs one control command: `pause`, `resume`, `abort`, `rate <size>` (per second, e.g.
+    // `rate 20M`), `rate off` or `status`. Returns the reply line.
+    pub fn command(&self, line: &str) -> Result<String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("pause"), None, _) => self.pause(),
+            (Some("resume"), None, _) => self.resume(),
+            (Some("abort"), None, _) => self.abort(),
+            (Some("rate"), Some("off"), None) => self.set_rate_limit(None),
+            (Some("rate"), Some(rate), None) => self.set_rate_limit(Some(parse_size(rate.trim_end_matches("/s"))?.max(1))),
+            (Some("status"), None, _) => {}
+            _ => bail!("Unknown command {:?} (expected pause, resume, abort, rate <size>|off or status)", line.trim()),
+        }
+
+        Ok(self.describe())
+    }
+}
+
+// Serves control commands on a Unix-domain socket at `path`, one per line, until the returned task
+// is aborted. Each command is answered with `ok <state>` or `error <message>`.
+#[cfg(unix)]
+pub(crate) fn serve_socket(path: &Path, control: UploadControl) -> Result<tokio::task::JoinHandle<()>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use anyhow::Context;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    // A socket left behind by a killed push would make the bind fail. Anything else at the path is
+    // left alone, a mistyped path must not cost a file.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path).context(format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path).context(format!("Failed to bind control socket {}", path.display()))?;
+    // Whoever can connect can pause or abort the push.
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).context(format!("Failed to restrict control socket {}", path.display()))?;
+    log::info!("Listening for control commands on {}", path.display());
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let control = control.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = match control.command(&line) {
+                        Ok(state) => {
+                            log::info!("Control command {:?}: {}", line.trim(), state);
+                            format!("ok {}\n", state)
+                        }
+                        Err(e) => format!("error {:#}\n", e),
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn serve_socket(_path: &Path, _control: UploadControl) -> Result<tokio::task::JoinHandle<()>> {
+    bail!("Control sockets are only supported on Unix")
+}
@@ -0,0 +1,57 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{anyhow, Context, Result};
+use tempfile::TempDir;
+
+use super::{oci_layout, SourceConverter};
+use crate::policy::TrustPolicy;
+use crate::progress::Progress;
+
+pub(crate) struct Crane {
+    pub(crate) policy: Option<TrustPolicy>,
+    pub(crate) registry_config: Option<PathBuf>,
+}
+
+impl SourceConverter for Crane {
+    fn name(&self) -> &'static str {
+        "crane"
+    }
+
+    fn is_available(&self) -> bool {
+        super::command_exists("crane")
+    }
+
+    fn convert(&self, image: &str, tag: &str, source: Option<&str>, dst: &Path, _progress: &Progress) -> Result<()> {
+        let source = match source {
+            Some(source) => source.to_owned(),
+            None => format!("{}:{}", image, tag),
+        };
+
+        if let Some(policy) = &self.policy {
+            policy.enforce_registry(&source)?;
+        }
+
+        let layout_dir = TempDir::new()?;
+
+        let mut command = Command::new("crane");
+        crate::proxy::apply(&mut command);
+        super::apply_registry_config(&mut command, self.registry_config.as_deref());
+        let output = command
+            .arg("pull")
+            .arg("--format=oci")
+            .arg(&source)
+            .arg(layout_dir.path())
+            .output()
+            .context("Failed to execute crane command")?;
+
+        if !output.status.success() {
+            return Err(anyhow!("crane {}:\n{}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+                .context(format!("Failed to pull image {}", source));
+        }
+
+        oci_layout::stage_layout(layout_dir.path(), None, dst)?;
+        log::info!("Converted {}", source);
+
+        Ok(())
+    }
+}
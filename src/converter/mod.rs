@@ -0,0 +1,203 @@
+mod crane;
+mod oci_layout;
+mod skopeo;
+mod sources;
+mod wasm;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+
+use crate::jobs::RetryPolicy;
+use crate::progress::Progress;
+use crate::policy::TrustPolicy;
+
+pub(crate) use skopeo::{copy_to_layout, sync_to_dir, synced_images, upstream_digest};
+pub use sources::SourceLocation;
+
+pub(crate) trait SourceConverter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn is_available(&self) -> bool;
+
+    // Reports the blobs it copies to `progress` when it can tell.
+    fn convert(&self, image: &str, tag: &str, source: Option<&str>, dst: &Path, progress: &Progress) -> Result<()>;
+
+    // Whether a failed conversion is worth retrying. Converters reading local files fail the same
+    // way every time, those talking to a daemon or registry may not.
+    fn is_flaky(&self) -> bool {
+        true
+    }
+
+    // Checks up front that the source exists, for a clearer error than a failed conversion.
+    fn preflight(&self, _image: &str, _tag: &str, _source: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConverterKind {
+    #[default]
+    Auto,
+    Skopeo,
+    Crane,
+    OciLayout,
+    Wasm,
+}
+
+impl FromStr for ConverterKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ConverterKind::Auto),
+            "skopeo" => Ok(ConverterKind::Skopeo),
+            "crane" => Ok(ConverterKind::Crane),
+            "oci-layout" => Ok(ConverterKind::OciLayout),
+            "wasm" => Ok(ConverterKind::Wasm),
+            _ => bail!("Unknown converter {} (expected auto, skopeo, crane, oci-layout or wasm)", s),
+        }
+    }
+}
+
+pub(crate) fn select(kind: ConverterKind, source: Option<&str>, policy: Option<&Path>, sources: &[SourceLocation], registry_config: Option<&Path>) -> Result<Box<dyn SourceConverter>> {
+    // skopeo evaluates the policy itself, the other converters enforce it before pulling.
+    let trust_policy = policy.map(TrustPolicy::load).transpose()?;
+    let registry_config = registry_config.map(Path::to_owned);
+    let skopeo = || Box::new(skopeo::Skopeo { policy: policy.map(Path::to_owned), sources: sources.to_vec(), registry_config: registry_config.clone() });
+    let crane = || Box::new(crane::Crane { policy: trust_policy.clone(), registry_config: registry_config.clone() });
+    let oci_layout = || Box::new(oci_layout::OciLayout { policy: trust_policy.clone() });
+
+    let converter: Box<dyn SourceConverter> = match kind {
+        ConverterKind::Skopeo => skopeo(),
+        ConverterKind::Crane => crane(),
+        ConverterKind::OciLayout => oci_layout(),
+        ConverterKind::Wasm => Box::new(wasm::Wasm),
+        ConverterKind::Auto => {
+            if source.is_some_and(oci_layout::is_layout) {
+                return Ok(oci_layout());
+            }
+            if source.is_some_and(wasm::is_module) {
+                return Ok(Box::new(wasm::Wasm));
+            }
+
+            let candidates: [Box<dyn SourceConverter>; 2] = [skopeo(), crane()];
+            for converter in candidates {
+                if converter.is_available() {
+                    log::info!("Using {} to convert the image", converter.name());
+                    return Ok(converter);
+                }
+            }
+
+            bail!("No image converter found, install skopeo or crane");
+        }
+    };
+
+    if !converter.is_available() {
+        bail!("{} is not installed", converter.name());
+    }
+
+    Ok(converter)
+}
+
+// Converts the image, retrying with exponential backoff from an emptied `dst` when the converter
+// fails for a reason that may go away. The converters wait for skopeo or crane for minutes, so they
+// run on blocking threads, away from the runtime's workers.
+pub(crate) async fn convert_with_retry(converter: Arc<dyn SourceConverter>, image: &str, tag: &str, source: Option<&str>, dst: &Path, retry: RetryPolicy, progress: &Progress) -> Result<()> {
+    let conversion = Arc::new(Conversion {
+        image: image.to_owned(),
+        tag: tag.to_owned(),
+        source: source.map(str::to_owned),
+        dst: dst.to_owned(),
+        progress: progress.clone(),
+    });
+    blocking(&converter, &conversion, |converter, c| converter.preflight(&c.image, &c.tag, c.source.as_deref())).await?;
+
+    let max_attempts = match converter.is_flaky() {
+        true => retry.max_attempts.max(1),
+        false => 1,
+    };
+
+    let mut attempt = 1;
+    loop {
+        let e = match blocking(&converter, &conversion, |converter, c| converter.convert(&c.image, &c.tag, c.source.as_deref(), &c.dst, &c.progress)).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= max_attempts || is_permanent(&e) => return Err(e),
+            Err(e) => e,
+        };
+
+        let backoff = retry.backoff_after(attempt);
+        log::warn!("Conversion of {}:{} failed (attempt {}/{}), retrying in {:?}: {:#}", image, tag, attempt, max_attempts, backoff, e);
+        for entry in fs::read_dir(dst)? {
+            let path = entry?.path();
+            match path.is_dir() {
+                true => fs::remove_dir_all(&path)?,
+                false => fs::remove_file(&path)?,
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+// What a conversion reads and writes, owned so it can move to a blocking thread.
+struct Conversion {
+    image: String,
+    tag: String,
+    source: Option<String>,
+    dst: PathBuf,
+    progress: Progress,
+}
+
+async fn blocking(converter: &Arc<dyn SourceConverter>, conversion: &Arc<Conversion>, run: fn(&dyn SourceConverter, &Conversion) -> Result<()>) -> Result<()> {
+    let (converter, conversion) = (converter.clone(), conversion.clone());
+    tokio::task::spawn_blocking(move || run(converter.as_ref(), &conversion)).await.context("The converter panicked")?
+}
+
+// Failures that another attempt would only repeat: the image does not exist or the registry
+// refuses the credentials. The converters report them in the tool's own words.
+fn is_permanent(e: &anyhow::Error) -> bool {
+    const PERMANENT: [&str; 8] = [
+        "manifest unknown",
+        "name unknown",
+        "not found",
+        "unauthorized",
+        "authentication required",
+        "access denied",
+        "denied:",
+        "invalid username/password",
+    ];
+
+    let message = format!("{:#}", e).to_lowercase();
+    PERMANENT.iter().any(|pattern| message.contains(pattern))
+}
+
+// Points skopeo and crane at the docker `config.json` in `registry_config` for the credentials of
+// this command only, instead of the user's own.
+pub(crate) fn apply_registry_config(command: &mut Command, registry_config: Option<&Path>) {
+    if let Some(dir) = registry_config {
+        command.env("DOCKER_CONFIG", dir).env("REGISTRY_AUTH_FILE", dir.join("config.json"));
+    }
+}
+
+pub(crate) fn command_exists(cmd: &str) -> bool {
+    Command::new(cmd).output().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_permanent_matches_missing_images_and_refused_credentials() {
+        assert!(is_permanent(&anyhow::anyhow!("reading manifest 1.0: manifest unknown")));
+        assert!(is_permanent(&anyhow::anyhow!("Error: UNAUTHORIZED: authentication required")));
+        assert!(is_permanent(&anyhow::anyhow!("denied: requested access to the resource is denied")));
+        assert!(is_permanent(&anyhow::anyhow!("skopeo failed").context("Invalid username/password")));
+        assert!(!is_permanent(&anyhow::anyhow!("connection reset by peer")));
+        assert!(!is_permanent(&anyhow::anyhow!("received unexpected HTTP status: 503 Service Unavailable")));
+    }
+}
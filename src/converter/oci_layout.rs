@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use super::SourceConverter;
+use crate::policy::TrustPolicy;
+use crate::progress::Progress;
+
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+pub(crate) struct OciLayout {
+    pub(crate) policy: Option<TrustPolicy>,
+}
+
+impl SourceConverter for OciLayout {
+    fn name(&self) -> &'static str {
+        "oci-layout"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn is_flaky(&self) -> bool {
+        false
+    }
+
+    fn convert(&self, _image: &str, tag: &str, source: Option<&str>, dst: &Path, _progress: &Progress) -> Result<()> {
+        let layout_dir = match source {
+            Some(source) => Path::new(source.strip_prefix("oci:").unwrap_or(source)),
+            None => bail!("The oci-layout converter needs the layout directory as source"),
+        };
+
+        if !is_layout(&layout_dir.to_string_lossy()) {
+            bail!("{} is not an OCI image layout", layout_dir.display());
+        }
+
+        if let Some(policy) = &self.policy {
+            policy.enforce_layout(layout_dir)?;
+        }
+
+        stage_layout(layout_dir, Some(tag), dst)?;
+        log::info!("Converted {}", layout_dir.display());
+
+        Ok(())
+    }
+}
+
+pub(crate) fn is_layout(source: &str) -> bool {
+    let source = source.strip_prefix("oci:").unwrap_or(source);
+    Path::new(source).join("oci-layout").is_file()
+}
+
+// Lays out the image referenced by `tag` the way `skopeo copy --all ... dir:` does: the top-level
+// manifest in `manifest.json`, per-platform manifests in `<hex>.manifest.json` and every other
+// blob under its hex digest.
+pub(crate) fn stage_layout(layout_dir: &Path, tag: Option<&str>, dst: &Path) -> Result<()> {
+    let index: Value = read_json(&layout_dir.join("index.json"))?;
+    let descriptor = select_manifest(&index, tag)?;
+
+    let manifest_path = blob_path(layout_dir, descriptor)?;
+    fs::copy(&manifest_path, dst.join("manifest.json"))?;
+
+    let manifest: Value = read_json(&manifest_path)?;
+    if is_index(descriptor, &manifest) {
+        for child in manifest["manifests"].as_array().into_iter().flatten() {
+            let child_path = blob_path(layout_dir, child)?;
+            let child_name = format!("{}.manifest.json", hex(child)?);
+            fs::copy(&child_path, dst.join(child_name))?;
+
+            copy_image_blobs(layout_dir, &read_json(&child_path)?, dst)?;
+        }
+    } else {
+        copy_image_blobs(layout_dir, &manifest, dst)?;
+    }
+
+    Ok(())
+}
+
+fn select_manifest<'a>(index: &'a Value, tag: Option<&str>) -> Result<&'a Value> {
+    let manifests = match index["manifests"].as_array() {
+        Some(manifests) if !manifests.is_empty() => manifests,
+        _ => bail!("OCI layout index.json has no manifests"),
+    };
+
+    if let Some(tag) = tag {
+        let tagged = manifests.iter().find(|m| m["annotations"][REF_NAME_ANNOTATION].as_str() == Some(tag));
+        if let Some(descriptor) = tagged {
+            return Ok(descriptor);
+        }
+    }
+
+    if manifests.len() == 1 {
+        return Ok(&manifests[0]);
+    }
+
+    bail!("OCI layout has {} manifests and none is tagged {}", manifests.len(), tag.unwrap_or("<none>"));
+}
+
+fn copy_image_blobs(layout_dir: &Path, manifest: &Value, dst: &Path) -> Result<()> {
+    let config = std::iter::once(&manifest["config"]).filter(|config| config.is_object());
+    let layers = manifest["layers"].as_array().into_iter().flatten();
+
+    for descriptor in config.chain(layers) {
+        let target = dst.join(hex(descriptor)?);
+        if !target.exists() {
+            fs::copy(blob_path(layout_dir, descriptor)?, target)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_index(descriptor: &Value, manifest: &Value) -> bool {
+    let media_type = descriptor["mediaType"].as_str().or_else(|| manifest["mediaType"].as_str());
+    media_type.is_some_and(|media_type| INDEX_MEDIA_TYPES.contains(&media_type))
+}
+
+fn blob_path(layout_dir: &Path, descriptor: &Value) -> Result<PathBuf> {
+    let digest = descriptor["digest"].as_str().context("Descriptor has no digest")?;
+    let (algorithm, hex) = digest.split_once(':').context(format!("Malformed digest {}", digest))?;
+
+    Ok(layout_dir.join("blobs").join(algorithm).join(hex))
+}
+
+fn hex(descriptor: &Value) -> Result<&str> {
+    let digest = descriptor["digest"].as_str().context("Descriptor has no digest")?;
+    digest.split_once(':').map(|(_, hex)| hex).context(format!("Malformed digest {}", digest))
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let data = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&data).context(format!("Failed to parse {}", path.display()))
+}
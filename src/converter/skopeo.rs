@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+use super::sources::{self, SourceLocation};
+use super::SourceConverter;
+use crate::progress::{Progress, ProgressEvent};
+
+const OUTPUT_TAIL: usize = 20;
+const SUGGESTIONS: usize = 3;
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Image { index: usize, total: usize },
+    Blob(String),
+    Config(String),
+    Manifest,
+}
+
+#[derive(Default)]
+struct Output {
+    tail: VecDeque<String>,
+    blobs: usize,
+}
+
+pub(crate) struct Skopeo {
+    pub(crate) policy: Option<PathBuf>,
+    // Where to look for the image without an explicit source, the local daemon when empty.
+    pub(crate) sources: Vec<SourceLocation>,
+    pub(crate) registry_config: Option<PathBuf>,
+}
+
+impl SourceConverter for Skopeo {
+    fn name(&self) -> &'static str {
+        "skopeo"
+    }
+
+    fn is_available(&self) -> bool {
+        super::command_exists("skopeo")
+    }
+
+    fn convert(&self, image: &str, tag: &str, source: Option<&str>, dst: &Path, progress: &Progress) -> Result<()> {
+        let (source, _export_dir) = match source {
+            Some(source) => (source.to_owned(), None),
+            None if !self.sources.is_empty() => sources::resolve(&self.sources, image, tag)?,
+            None => (format!("docker-daemon:{}:{}", image, tag), None),
+        };
+
+        copy(&source, self.policy.as_deref(), self.registry_config.as_deref(), &format!("dir:{}", dst.display()), progress)
+    }
+
+    // Images from the local daemon are looked up with the docker CLI first, so a typo fails with
+    // the images it probably meant instead of deep inside skopeo. Without the CLI the check is
+    // left to skopeo.
+    fn preflight(&self, image: &str, tag: &str, source: Option<&str>) -> Result<()> {
+        if source.is_none() && !self.sources.is_empty() {
+            return sources::locate(&self.sources, image, tag).map(|_| ());
+        }
+        if source.is_some() || !super::command_exists("docker") {
+            return Ok(());
+        }
+
+        let reference = format!("{}:{}", image, tag);
+        let inspect = Command::new("docker").args(["image", "inspect", "--format", "{{.Id}}", &reference]).output();
+        match inspect {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(_) => {}
+            Err(e) => {
+                log::debug!("Skipping the daemon preflight: {}", e);
+                return Ok(());
+            }
+        }
+
+        let images = match Command::new("docker").args(["images", "--format", "{{.Repository}}:{{.Tag}}"]).output() {
+            Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into_owned(),
+            // The daemon itself is unreachable; skopeo reports that well enough.
+            _ => return Ok(()),
+        };
+
+        let matches = close_matches(&reference, images.lines().filter(|line| !line.contains("<none>")));
+        match matches.is_empty() {
+            true => bail!("Image {} not found in the local Docker daemon", reference),
+            false => bail!("Image {} not found in the local Docker daemon, did you mean {}?", reference, matches.join(", ")),
+        }
+    }
+}
+
+// The local images closest to `reference`: other tags of the same repository first, then by edit
+// distance, ignoring anything too different to be a typo.
+fn close_matches<'a>(reference: &str, images: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let repository = reference.rsplit_once(':').map_or(reference, |(repository, _)| repository);
+    let threshold = (reference.len() / 3).max(3);
+
+    let mut candidates: Vec<(bool, usize, &str)> = images
+        .map(|image| {
+            let same_repository = image.rsplit_once(':').is_some_and(|(candidate, _)| candidate == repository);
+            (!same_repository, edit_distance(reference, image), image)
+        })
+        .filter(|(other_repository, distance, _)| !other_repository || *distance <= threshold)
+        .collect();
+    candidates.sort();
+    candidates.dedup_by_key(|(_, _, image)| *image);
+
+    candidates.into_iter().take(SUGGESTIONS).map(|(_, _, image)| image).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Copies every platform of `source` into an OCI image layout, tagged `tag`. Blobs the layout
+// already holds, from other tags of the same repository, are not pulled again.
+pub(crate) fn copy_to_layout(source: &str, policy: Option<&Path>, registry_config: Option<&Path>, layout_dir: &Path, tag: &str) -> Result<()> {
+    copy(source, policy, registry_config, &format!("oci:{}:{}", layout_dir.display(), tag), &Progress::default())
+}
+
+// The digest of the manifest `reference` (`docker://<registry>/<path>:<tag>`) resolves to upstream,
+// read without pulling the image.
+pub(crate) fn upstream_digest(reference: &str, registry_config: Option<&Path>) -> Result<String> {
+    let mut command = Command::new("skopeo");
+    crate::proxy::apply(&mut command);
+    super::apply_registry_config(&mut command, registry_config);
+    let output = command.args(["inspect", "--raw"]).arg(reference).output().context("Failed to execute skopeo command")?;
+    if !output.status.success() {
+        bail!("Failed to inspect {}: {}", reference, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(format!("sha256:{:x}", Sha256::digest(&output.stdout)))
+}
+
+// Copies every tag of `repository` (`docker://<registry>/<path>`) into `staging` with a single
+// `skopeo sync`, so the registry is authenticated against once for the whole repository.
+pub(crate) fn sync_to_dir(repository: &str, policy: Option<&Path>, registry_config: Option<&Path>, staging: &Path) -> Result<()> {
+    let mut command = Command::new("skopeo");
+    crate::proxy::apply(&mut command);
+    super::apply_registry_config(&mut command, registry_config);
+    if let Some(policy) = policy {
+        command.arg("--policy").arg(policy);
+    }
+
+    let output = command
+        .args(["sync", "--all", "--scoped", "--src", "docker", "--dest", "dir"])
+        .arg(repository.strip_prefix("docker://").unwrap_or(repository))
+        .arg(staging)
+        .output()
+        .context("Failed to execute skopeo command")?;
+
+    if !output.status.success() {
+        return Err(anyhow!("skopeo {}:\n{}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+            .context(format!("Failed to sync {}", repository));
+    }
+
+    Ok(())
+}
+
+// The images in a `skopeo sync --dest dir` staging directory: one `dir:` layout per tag, named
+// `<repository>:<tag>` and nested under the registry and path with `--scoped`. Returns the tag and
+// layout directory of each, in tag order.
+pub(crate) fn synced_images(staging: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut images = Vec::new();
+    let mut pending = vec![staging.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            match name.rsplit_once(':') {
+                Some((_, tag)) if path.join("manifest.json").is_file() => images.push((tag.to_owned(), path)),
+                _ => pending.push(path),
+            }
+        }
+    }
+    images.sort();
+
+    Ok(images)
+}
+
+fn copy(source: &str, policy: Option<&Path>, registry_config: Option<&Path>, destination: &str, progress: &Progress) -> Result<()> {
+    let mut command = Command::new("skopeo");
+    crate::proxy::apply(&mut command);
+    super::apply_registry_config(&mut command, registry_config);
+    if let Some(policy) = policy {
+        command.arg("--policy").arg(policy);
+    }
+
+    let mut child = command
+        .arg("copy")
+        .arg("--all")
+        .arg(source)
+        .arg(destination)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to execute skopeo command")?;
+
+    let output = Arc::new(Mutex::new(Output::default()));
+    let stdout = stream_output(child.stdout.take().unwrap(), output.clone(), progress.clone());
+    let stderr = stream_output(child.stderr.take().unwrap(), output.clone(), progress.clone());
+
+    let status = child.wait().context("Failed to wait for skopeo command")?;
+    let _ = stdout.join();
+    let _ = stderr.join();
+
+    let output = output.lock().unwrap();
+    if !status.success() {
+        let tail = output.tail.iter().cloned().collect::<Vec<_>>().join("\n");
+        return Err(anyhow!("skopeo {}:\n{}", status, tail))
+            .context(format!("Failed to convert image {}", source));
+    }
+
+    log::info!("Converted {} ({} blobs)", source, output.blobs);
+
+    Ok(())
+}
+
+fn stream_output<R: Read + Send + 'static>(stream: R, output: Arc<Mutex<Output>>, progress: Progress) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            log::debug!("skopeo: {}", line);
+
+            let mut output = output.lock().unwrap();
+            match parse_progress(&line) {
+                Some(Step::Image { index, total }) => log::info!("Converting image {}/{}", index, total),
+                Some(Step::Blob(digest)) => {
+                    output.blobs += 1;
+                    log::info!("Converting blob {} ({} copied)", digest, output.blobs);
+                    progress.emit(ProgressEvent::Converted { name: digest });
+                }
+                Some(Step::Config(digest)) => {
+                    log::info!("Converting config {}", digest);
+                    progress.emit(ProgressEvent::Converted { name: digest });
+                }
+                Some(Step::Manifest) => log::info!("Writing converted manifest"),
+                None => {}
+            }
+
+            if output.tail.len() == OUTPUT_TAIL {
+                output.tail.pop_front();
+            }
+            output.tail.push_back(line);
+        }
+    })
+}
+
+fn parse_progress(line: &str) -> Option<Step> {
+    let line = line.trim();
+
+    if let Some(rest) = line.strip_prefix("Copying blob ") {
+        return Some(Step::Blob(first_word(rest)?));
+    }
+    if let Some(rest) = line.strip_prefix("Copying config ") {
+        return Some(Step::Config(first_word(rest)?));
+    }
+    if line.starts_with("Writing manifest") {
+        return Some(Step::Manifest);
+    }
+    if let Some(rest) = line.strip_prefix("Copying image ") {
+        let counter = rest.rsplit_once('(')?.1.trim_end_matches(')');
+        let (index, total) = counter.split_once('/')?;
+        return Some(Step::Image {
+            index: index.trim().parse().ok()?,
+            total: total.trim().parse().ok()?,
+        });
+    }
+
+    None
+}
+
+fn first_word(s: &str) -> Option<String> {
+    s.split_whitespace().next().map(|word| word.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_progress_reads_skopeo_steps() {
+        assert_eq!(parse_progress("Copying blob sha256:abc done"), Some(Step::Blob("sha256:abc".to_owned())));
+        assert_eq!(parse_progress("  Copying config sha256:def done  "), Some(Step::Config("sha256:def".to_owned())));
+        assert_eq!(parse_progress("Writing manifest to image destination"), Some(Step::Manifest));
+        assert_eq!(parse_progress("Copying image sha256:123 (2/3)"), Some(Step::Image { index: 2, total: 3 }));
+        assert_eq!(parse_progress("Copying image sha256:123"), None);
+        assert_eq!(parse_progress("Getting image source signatures"), None);
+    }
+
+    #[test]
+    fn close_matches_prefers_the_same_repository() {
+        let images = ["other:1.0", "apq:1.0", "app:2.0", "zzzzzzzz:9", "app:1.1"];
+        assert_eq!(close_matches("app:1.0", images.into_iter()), vec!["app:1.1", "app:2.0", "apq:1.0"]);
+        assert_eq!(close_matches("app:1.0", ["app:1.0", "app:1.0"].into_iter()), vec!["app:1.0"]);
+        assert!(close_matches("app:1.0", ["unrelated/name:latest"].into_iter()).is_empty());
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("tag", "tag"), 0);
+        assert_eq!(edit_distance("", "tag"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("1.0", "1.0.1"), 2);
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::process::Command;
+use std::str::FromStr;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tempfile::TempDir;
+
+// Where the `skopeo` converter looks for an image when no explicit `source` is given. A chain of
+// locations is tried in order and the first one holding the image is used.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SourceLocation {
+    // `docker-daemon`
+    DockerDaemon,
+    // `containerd` or `containerd:<namespace>`
+    Containerd { namespace: String },
+    // `docker://<registry>/<prefix>`, the image is pulled from `<registry>/<prefix>/<image>`.
+    Registry { prefix: String },
+}
+
+impl FromStr for SourceLocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "docker-daemon" => Ok(SourceLocation::DockerDaemon),
+            "containerd" => Ok(SourceLocation::Containerd { namespace: "default".to_owned() }),
+            _ => match (s.strip_prefix("containerd:"), s.strip_prefix("docker://")) {
+                (Some(namespace), _) if !namespace.is_empty() => Ok(SourceLocation::Containerd { namespace: namespace.to_owned() }),
+                (_, Some(prefix)) => Ok(SourceLocation::Registry { prefix: prefix.trim_end_matches('/').to_owned() }),
+                _ => bail!("Unknown source {} (expected docker-daemon, containerd[:<namespace>] or docker://<registry>)", s),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for SourceLocation {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceLocation::DockerDaemon => f.write_str("docker-daemon"),
+            SourceLocation::Containerd { namespace } => write!(f, "containerd:{}", namespace),
+            SourceLocation::Registry { prefix } => write!(f, "docker://{}", prefix),
+        }
+    }
+}
+
+impl SourceLocation {
+    // Registries are not probed, a missing image surfaces when skopeo pulls it.
+    fn has_image(&self, reference: &str) -> bool {
+        match self {
+            SourceLocation::DockerDaemon => {
+                super::command_exists("docker")
+                    && Command::new("docker").args(["image", "inspect", "--format", "{{.Id}}", reference]).output().is_ok_and(|output| output.status.success())
+            }
+            SourceLocation::Containerd { namespace } => {
+                let listing = Command::new("ctr").args(["--namespace", namespace, "images", "ls", "--quiet"]).output();
+                let qualified = containerd_reference(reference);
+                listing.is_ok_and(|output| output.status.success() && String::from_utf8_lossy(&output.stdout).lines().any(|line| line == qualified))
+            }
+            SourceLocation::Registry { .. } => true,
+        }
+    }
+}
+
+// The first location of `chain` holding `<image>:<tag>`.
+pub(crate) fn locate<'a>(chain: &'a [SourceLocation], image: &str, tag: &str) -> Result<&'a SourceLocation> {
+    let reference = format!("{}:{}", image, tag);
+    match chain.iter().find(|location| location.has_image(&reference)) {
+        Some(location) => Ok(location),
+        None => {
+            let tried: Vec<String> = chain.iter().map(ToString::to_string).collect();
+            bail!("Image {} not found in any source ({})", reference, tried.join(", "))
+        }
+    }
+}
+
+// The skopeo reference to convert `<image>:<tag>` from. Images in containerd are exported to an
+// OCI archive first, which lives in the returned directory until it is dropped.
+pub(crate) fn resolve(chain: &[SourceLocation], image: &str, tag: &str) -> Result<(String, Option<TempDir>)> {
+    let location = locate(chain, image, tag)?;
+    log::info!("Converting {}:{} from {}", image, tag, location);
+
+    match location {
+        SourceLocation::DockerDaemon => Ok((format!("docker-daemon:{}:{}", image, tag), None)),
+        SourceLocation::Registry { prefix } if prefix.is_empty() => Ok((format!("docker://{}:{}", image, tag), None)),
+        SourceLocation::Registry { prefix } => Ok((format!("docker://{}/{}:{}", prefix, image, tag), None)),
+        SourceLocation::Containerd { namespace } => {
+            let export_dir = tempfile::tempdir()?;
+            let archive = export_dir.path().join("image.tar");
+            let output = Command::new("ctr")
+                .args(["--namespace", namespace, "images", "export"])
+                .arg(&archive)
+                .arg(containerd_reference(&format!("{}:{}", image, tag)))
+                .output()
+                .context("Failed to execute ctr command")?;
+            if !output.status.success() {
+                bail!("ctr images export failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+            }
+
+            Ok((format!("oci-archive:{}", archive.display()), Some(export_dir)))
+        }
+    }
+}
+
+// containerd stores fully qualified names, e.g. `docker.io/library/nginx:1.25` for `nginx:1.25`.
+fn containerd_reference(reference: &str) -> String {
+    let (first, rest) = match reference.split_once('/') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (reference, None),
+    };
+
+    match rest {
+        Some(_) if first.contains('.') || first.contains(':') || first == "localhost" => reference.to_owned(),
+        Some(_) => format!("docker.io/{}", reference),
+        None => format!("docker.io/library/{}", reference),
+    }
+}
@@ -0,0 +1,98 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::SourceConverter;
+use crate::canonical;
+use crate::progress::Progress;
+
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v0+json";
+const LAYER_MEDIA_TYPE: &str = "application/wasm";
+const CORE_MODULE_VERSION: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+
+// Wraps a single module in the CNCF wasm OCI artifact layout (wasm config plus one
+// `application/wasm` layer) that wasmtime, spin and containerd wasm shims pull.
+pub(crate) struct Wasm;
+
+impl SourceConverter for Wasm {
+    fn name(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn is_flaky(&self) -> bool {
+        false
+    }
+
+    fn convert(&self, _image: &str, _tag: &str, source: Option<&str>, dst: &Path, _progress: &Progress) -> Result<()> {
+        let module_path = match source {
+            Some(source) => Path::new(source),
+            None => bail!("The wasm converter needs the module file as source"),
+        };
+
+        let module = fs::read(module_path).context(format!("Failed to read {}", module_path.display()))?;
+        if module.len() < 8 || &module[..4] != b"\0asm" {
+            bail!("{} is not a WebAssembly module", module_path.display());
+        }
+        let component = module[4..8] != CORE_MODULE_VERSION;
+
+        let layer_digest = write_blob(dst, &module)?;
+        let config = json!({
+            "created": humantime::format_rfc3339_seconds(source_date()?).to_string(),
+            "architecture": "wasm",
+            "os": if component { "wasip2" } else { "wasip1" },
+            "layerDigests": [layer_digest],
+        });
+        let config_data = canonical::to_vec(&config)?;
+        let config_digest = write_blob(dst, &config_data)?;
+
+        let mut annotations = json!({ "org.opencontainers.image.created": config["created"] });
+        if let Some(title) = module_path.file_name() {
+            annotations["org.opencontainers.image.title"] = json!(title.to_string_lossy());
+        }
+
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": CONFIG_MEDIA_TYPE,
+            "config": { "mediaType": CONFIG_MEDIA_TYPE, "digest": config_digest, "size": config_data.len() },
+            "layers": [{ "mediaType": LAYER_MEDIA_TYPE, "digest": layer_digest, "size": module.len() }],
+            "annotations": annotations,
+        });
+        fs::write(dst.join("manifest.json"), canonical::to_vec(&manifest)?)?;
+
+        log::info!("Packaged {} as a wasm {}", module_path.display(), if component { "component" } else { "module" });
+
+        Ok(())
+    }
+}
+
+pub(crate) fn is_module(source: &str) -> bool {
+    let path = Path::new(source);
+    path.extension().is_some_and(|extension| extension == "wasm") && path.is_file()
+}
+
+// `SOURCE_DATE_EPOCH` as used by reproducible builds, or the Unix epoch, so that packaging the same
+// module twice produces the same digests.
+fn source_date() -> Result<SystemTime> {
+    let seconds = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(seconds) => seconds.trim().parse().context(format!("SOURCE_DATE_EPOCH {} is not a number of seconds", seconds))?,
+        Err(_) => 0,
+    };
+
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn write_blob(dst: &Path, data: &[u8]) -> Result<String> {
+    let hex = format!("{:x}", Sha256::digest(data));
+    fs::write(dst.join(&hex), data)?;
+
+    Ok(format!("sha256:{}", hex))
+}
@@ -0,0 +1,36 @@
+use anyhow::{Context, Result};
+use rusoto_s3::{DeleteObjectRequest, S3};
+
+use crate::budget::{self, OpClass};
+use crate::expiry;
+use crate::r2configs;
+use crate::v2;
+use crate::v2::{catalog, chunks};
+
+// Deletes the tag `<image>:<tag>` and drops it from the tag list and the expiry index. The manifest
+// stays reachable by digest and the layers are left for garbage collection, since other tags may
+// share them. Returns false when the tag did not exist. The name is not validated, so repositories
+// pushed under invalid names before can still be cleaned up.
+pub async fn delete_tag(image: &str, tag: &str) -> Result<bool> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+
+    let key = format!("v2/{}/manifests/{}", image, tag);
+    if !chunks::exists(&client, &env_vars.r2_bucket, &key).await? {
+        return Ok(false);
+    }
+
+    let req = DeleteObjectRequest {
+        bucket: env_vars.r2_bucket.clone(),
+        key: key.clone(),
+        ..Default::default()
+    };
+    budget::charge(OpClass::Delete)?;
+    client.delete_object(req).await.context(format!("Failed to delete {}", key))?;
+
+    catalog::remove_tag(&env_vars, image, tag).await?;
+    expiry::forget_tag(&env_vars, image, tag).await?;
+    log::info!("Deleted {}:{}", image, tag);
+
+    Ok(true)
+}
@@ -0,0 +1,100 @@
+use std::env;
+use std::sync::OnceLock;
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::config::{self, Config, DestinationConfig};
+use crate::r2configs::R2Configs;
+use crate::PushOptions;
+
+static SELECTED: OnceLock<String> = OnceLock::new();
+
+// Makes every following command of the process use the `[dest.<name>]` profile of the config file.
+// Without a call, `OCI_R2_DEST` selects the profile.
+pub fn select_destination(name: &str) -> Result<()> {
+    let config = config::load_config_file()?;
+    if !config.dest.contains_key(name) {
+        bail!("Destination {} is not defined in the config file (known: {})", name, known(&config));
+    }
+
+    SELECTED.set(name.to_owned()).map_err(|_| anyhow!("A destination is already selected"))
+}
+
+pub(crate) fn selected() -> Option<String> {
+    SELECTED.get().cloned().or_else(|| env::var("OCI_R2_DEST").ok().filter(|name| !name.is_empty()))
+}
+
+pub(crate) fn resolve(config: &Config) -> Result<Option<(String, &DestinationConfig)>> {
+    let name = match selected() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    match config.dest.get(&name) {
+        Some(destination) => Ok(Some((name, destination))),
+        None => bail!("Destination {} is not defined in the config file (known: {})", name, known(config)),
+    }
+}
+
+// Overrides the connection settings from the environment with those of the destination.
+pub(crate) fn apply(destination: &DestinationConfig, env_vars: &mut R2Configs) -> Result<()> {
+    let credential = |name: &Option<String>| -> Result<Option<String>> {
+        name.as_ref().map(|name| env::var(name).context(format!("{} is not set", name))).transpose()
+    };
+
+    if let Some(account_id) = &destination.account_id {
+        env_vars.cloudflare_account_id = account_id.clone();
+    }
+    if let Some(bucket) = &destination.bucket {
+        env_vars.r2_bucket = bucket.clone();
+    }
+    if let Some(access_key_id) = credential(&destination.access_key_id_env)? {
+        env_vars.r2_access_key_id = access_key_id;
+    }
+    if let Some(secret_access_key) = credential(&destination.secret_access_key_env)? {
+        env_vars.r2_secret_access_key = secret_access_key;
+    }
+
+    Ok(())
+}
+
+// Fills in what the push options leave open from the destination. Explicit options win, except that
+// a destination requiring signing always gets signed provenance.
+pub(crate) fn apply_defaults(name: &str, destination: &DestinationConfig, options: &mut PushOptions) {
+    for (key, value) in &destination.annotations {
+        options.annotations.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+
+    let cache_control = &mut options.cache_control;
+    if cache_control.blobs.is_none() {
+        cache_control.blobs = destination.cache_control.blobs.clone();
+    }
+    if cache_control.manifests.is_none() {
+        cache_control.manifests = destination.cache_control.manifests.clone();
+    }
+
+    if options.policy.is_none() {
+        options.policy = destination.policy.clone();
+    }
+
+    if destination.require_signing {
+        if !options.provenance {
+            log::info!("Destination {} requires signing, attaching signed provenance", name);
+        }
+        options.provenance = true;
+        options.require_signing = true;
+    }
+}
+
+pub(crate) fn prefixed(destination: &DestinationConfig, image: &str) -> String {
+    match destination.prefix.as_deref().map(|prefix| prefix.trim_matches('/')) {
+        Some(prefix) if !prefix.is_empty() && !image.starts_with(&format!("{}/", prefix)) => format!("{}/{}", prefix, image),
+        _ => image.to_owned(),
+    }
+}
+
+fn known(config: &Config) -> String {
+    match config.dest.is_empty() {
+        true => "none".to_owned(),
+        false => config.dest.keys().cloned().collect::<Vec<_>>().join(", "),
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use anyhow::{anyhow, Context, Result};
+use flate2::write::GzEncoder;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::config;
+use crate::stats::PushStats;
+use crate::PushOptions;
+
+const LOG_LINES: usize = 500;
+const FAILED_REQUESTS: usize = 50;
+const REDACTED: &str = "[redacted]";
+
+// Config keys whose values are credentials or contain them (webhook URLs embed their token).
+const SECRET_KEYS: [&str; 6] = ["secret", "password", "username", "url", "webhook", "metrics_token"];
+// Config keys naming the variable a credential is read from, e.g. `secret_access_key_env`.
+const SECRET_ENV_SUFFIX: &str = "_env";
+const SECRET_ENV_VARS: [&str; 2] = ["R2_ACCESS_KEY_ID", "R2_SECRET_ACCESS_KEY"];
+
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static REQUESTS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static LOGGER: OnceLock<DiagnosticsLogger> = OnceLock::new();
+
+struct DiagnosticsLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for DiagnosticsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LevelFilter::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "{} {:<5} {}: {}",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        push_bounded(&LOG, line, LOG_LINES);
+
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Installs `inner` as the logger while keeping the last log lines, down to debug level, for
+// diagnostics bundles.
+pub fn install_diagnostics_logger(inner: Box<dyn Log>) -> Result<()> {
+    let logger = LOGGER.get_or_init(|| DiagnosticsLogger { inner });
+    log::set_logger(logger).map_err(|_| anyhow!("A logger is already installed"))?;
+    log::set_max_level(LevelFilter::Debug);
+
+    Ok(())
+}
+
+pub(crate) fn record_failed_request(line: String) {
+    push_bounded(&REQUESTS, line, FAILED_REQUESTS);
+}
+
+fn push_bounded(buffer: &Mutex<VecDeque<String>>, line: String, capacity: usize) {
+    let mut buffer = buffer.lock().unwrap();
+    if buffer.len() == capacity {
+        buffer.pop_front();
+    }
+    buffer.push_back(line);
+}
+
+// Writes `oci-r2-diagnostics-<request id>.tar.gz` into `dir` with everything needed to triage a
+// failed push. Credentials are redacted from every file.
+pub(crate) fn write_bundle(dir: &Path, request_id: &str, error: &anyhow::Error, stats: &PushStats, options: &PushOptions) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("oci-r2-diagnostics-{}.tar.gz", request_id));
+
+    let mut files = vec![
+        ("version.txt", version()),
+        ("error.txt", format!("{:#}\n\n{:?}\n", error, error)),
+        ("options.txt", format!("{:#?}\n", options)),
+        ("stats.json", serde_json::to_string_pretty(&stats.to_json())?),
+        ("environment.txt", environment()),
+        ("requests.txt", lines(&REQUESTS, |line| line.contains(request_id))),
+        ("log.txt", lines(&LOG, |_| true)),
+    ];
+    let mut secrets = SECRET_ENV_VARS.iter().filter_map(|name| env::var(name).ok()).collect();
+    if let Some(config) = redacted_config(&mut secrets)? {
+        files.push(("config.toml", config));
+    }
+
+    let file = File::create(&path).context(format!("Failed to create {}", path.display()))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, flate2::Compression::default()));
+    let mtime = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    for (name, content) in files {
+        let content = redact(&content, &secrets);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, name, content.as_bytes())?;
+    }
+    archive.into_inner()?.finish()?;
+
+    Ok(path)
+}
+
+fn version() -> String {
+    format!(
+        "oci-r2-uploader {}\nos: {}\narch: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH
+    )
+}
+
+// Which of the relevant variables are set, never their values.
+fn environment() -> String {
+    let names = [
+        "CLOUDFLARE_ACCOUNT_ID",
+        "R2_BUCKET",
+        "R2_ACCESS_KEY_ID",
+        "R2_SECRET_ACCESS_KEY",
+        "OCI_R2_CONFIG",
+        "HTTPS_PROXY",
+        "HTTP_PROXY",
+        "NO_PROXY",
+    ];
+
+    names
+        .iter()
+        .map(|name| format!("{}: {}\n", name, if env::var_os(name).is_some() { "set" } else { "unset" }))
+        .collect()
+}
+
+fn lines(buffer: &Mutex<VecDeque<String>>, filter: impl Fn(&str) -> bool) -> String {
+    let buffer = buffer.lock().unwrap();
+    buffer.iter().filter(|line| filter(line)).map(|line| format!("{}\n", line)).collect()
+}
+
+// The config file with its secrets replaced. The `${VAR}` references stay as written, while the
+// values they expand to are added to `secrets`.
+fn redacted_config(secrets: &mut Vec<String>) -> Result<Option<String>> {
+    let path = match config::config_path() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    if let Ok(Ok(expanded)) = config::read_config_file(&path).map(|data| data.parse::<toml::Table>()) {
+        collect_secrets(&expanded, secrets);
+    }
+
+    let data = fs::read_to_string(&path).context(format!("Failed to read config file {}", path.display()))?;
+    match data.parse::<toml::Table>() {
+        Ok(mut table) => {
+            redact_table(&mut table);
+            Ok(Some(toml::to_string_pretty(&table)?))
+        }
+        Err(e) => Ok(Some(format!("# {} could not be parsed, contents omitted: {}\n", path.display(), e))),
+    }
+}
+
+fn redact_table(table: &mut toml::Table) {
+    for (key, value) in table.iter_mut() {
+        match value {
+            toml::Value::Table(table) => redact_table(table),
+            toml::Value::Array(items) => {
+                for item in items.iter_mut() {
+                    if let toml::Value::Table(table) = item {
+                        redact_table(table);
+                    }
+                }
+            }
+            _ if SECRET_KEYS.contains(&key.as_str()) => *value = toml::Value::String(REDACTED.to_owned()),
+            _ => {}
+        }
+    }
+}
+
+// The secret values of `table`, and those of the variables its `*_env` keys name.
+fn collect_secrets(table: &toml::Table, secrets: &mut Vec<String>) {
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(table) => collect_secrets(table, secrets),
+            toml::Value::Array(items) => {
+                for item in items {
+                    if let toml::Value::Table(table) = item {
+                        collect_secrets(table, secrets);
+                    }
+                }
+            }
+            toml::Value::String(value) if SECRET_KEYS.contains(&key.as_str()) => secrets.push(value.clone()),
+            toml::Value::String(name) if key.ends_with(SECRET_ENV_SUFFIX) => secrets.extend(env::var(name).ok()),
+            _ => {}
+        }
+    }
+}
+
+// Replaces secrets wherever they appear, in case an error message or a log line quoted one. Longer
+// values go first so that a secret containing another is not left half redacted.
+fn redact(content: &str, secrets: &[String]) -> String {
+    let mut secrets: Vec<&String> = secrets.iter().filter(|secret| !secret.is_empty()).collect();
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+
+    let mut content = content.to_owned();
+    for secret in secrets {
+        content = content.replace(secret.as_str(), REDACTED);
+    }
+
+    content
+}
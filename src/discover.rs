@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::r2configs;
+use crate::v2::{cas, referrers};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Referrer {
+    pub digest: String,
+    pub media_type: String,
+    pub artifact_type: Option<String>,
+    pub size: u64,
+    pub annotations: BTreeMap<String, String>,
+}
+
+// Lists the artifacts attached to `<image>@<digest>` from the referrers index in the bucket, like
+// `oras discover`.
+pub async fn discover(reference: &str, artifact_type: Option<&str>) -> Result<Vec<Referrer>> {
+    let (image, digest) = reference.split_once('@').context(format!("{} is not a digest reference (expected <image>@sha256:<hex>)", reference))?;
+
+    let env_vars = r2configs::parse_r2configs()?;
+    let index: Value = match cas::read_object(&env_vars, &referrers::referrers_key(image, digest)).await? {
+        Some(data) => serde_json::from_slice(&data).context("Malformed referrers index")?,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(index["manifests"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|manifest| Referrer {
+            digest: manifest["digest"].as_str().unwrap_or_default().to_owned(),
+            media_type: manifest["mediaType"].as_str().unwrap_or_default().to_owned(),
+            artifact_type: manifest["artifactType"].as_str().map(|artifact_type| artifact_type.to_owned()),
+            size: manifest["size"].as_u64().unwrap_or_default(),
+            annotations: manifest["annotations"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_owned())))
+                .collect(),
+        })
+        .filter(|referrer| artifact_type.is_none() || referrer.artifact_type.as_deref() == artifact_type)
+        .collect())
+}
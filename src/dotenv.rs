@@ -0,0 +1,110 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+const DOTENV_FILE: &str = ".env";
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static LOAD: Once = Once::new();
+
+// Makes `load_dotenv` a no-op, e.g. for the CLI's `--no-dotenv`. Has no effect once it was loaded.
+pub fn disable_dotenv() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+// Loads `.env` from the working directory, once. Variables that are already set win over the file.
+// Setting variables races with other threads reading them, so the library never calls this itself:
+// programs call it before they start any threads.
+pub fn load_dotenv() {
+    LOAD.call_once(|| {
+        if DISABLED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let path = Path::new(DOTENV_FILE);
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        let mut loaded = 0;
+        for (number, line) in data.lines().enumerate() {
+            match parse_line(line) {
+                Some(Ok((key, value))) if env::var_os(&key).is_none() => {
+                    env::set_var(key, value);
+                    loaded += 1;
+                }
+                Some(Err(message)) => log::warn!("Ignoring {} line {}: {}", DOTENV_FILE, number + 1, message),
+                Some(Ok(_)) | None => {}
+            }
+        }
+        log::debug!("Loaded {} variables from {}", loaded, DOTENV_FILE);
+    });
+}
+
+// `KEY=value`, optionally prefixed with `export` and with the value in single or double quotes.
+// Blank lines and `#` comments yield `None`.
+fn parse_line(line: &str) -> Option<Result<(String, String), &'static str>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+    let (key, value) = match line.split_once('=') {
+        Some((key, value)) => (key.trim(), value.trim()),
+        None => return Some(Err("expected KEY=value")),
+    };
+    if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Some(Err("invalid variable name"));
+    }
+
+    let value = if let Some(quoted) = value.strip_prefix('"') {
+        match quoted.strip_suffix('"') {
+            Some(quoted) => quoted.replace("\\n", "\n").replace("\\\"", "\""),
+            None => return Some(Err("unterminated double quote")),
+        }
+    } else if let Some(quoted) = value.strip_prefix('\'') {
+        match quoted.strip_suffix('\'') {
+            Some(quoted) => quoted.to_owned(),
+            None => return Some(Err("unterminated single quote")),
+        }
+    } else {
+        value.split_once(" #").map_or(value, |(value, _)| value.trim_end()).to_owned()
+    };
+
+    Some(Ok((key.to_owned(), value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> Option<Result<(String, String), &'static str>> {
+        Some(Ok((key.to_owned(), value.to_owned())))
+    }
+
+    #[test]
+    fn parse_line_reads_assignments() {
+        assert_eq!(parse_line("R2_BUCKET=images"), pair("R2_BUCKET", "images"));
+        assert_eq!(parse_line("export  R2_BUCKET = images "), pair("R2_BUCKET", "images"));
+        assert_eq!(parse_line("R2_BUCKET=images # production"), pair("R2_BUCKET", "images"));
+        assert_eq!(parse_line("R2_BUCKET=a#b"), pair("R2_BUCKET", "a#b"));
+        assert_eq!(parse_line(r#"KEY="a # b\n\"c\"""#), pair("KEY", "a # b\n\"c\""));
+        assert_eq!(parse_line(r"KEY='a\nb'"), pair("KEY", r"a\nb"));
+        assert_eq!(parse_line("KEY="), pair("KEY", ""));
+    }
+
+    #[test]
+    fn parse_line_skips_blanks_and_reports_errors() {
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("# KEY=value"), None);
+        assert_eq!(parse_line("KEY"), Some(Err("expected KEY=value")));
+        assert_eq!(parse_line("MY-KEY=value"), Some(Err("invalid variable name")));
+        assert_eq!(parse_line("=value"), Some(Err("invalid variable name")));
+        assert_eq!(parse_line("KEY=\"value"), Some(Err("unterminated double quote")));
+        assert_eq!(parse_line("KEY='value"), Some(Err("unterminated single quote")));
+    }
+}
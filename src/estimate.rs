@@ -0,0 +1,78 @@
+use std::fmt;
+use anyhow::Result;
+
+use crate::budget::OpEstimate;
+use crate::config::{self, PricingConfig};
+use crate::pipeline::{Pipeline, PipelineDeps};
+use crate::plan::Operation;
+use crate::stats::PushStats;
+use crate::PushOptions;
+
+#[derive(Clone, Debug)]
+pub struct PushEstimate {
+    pub image: String,
+    pub tag: String,
+    // Bytes the push adds to the bucket; blobs already stored under the same key add nothing.
+    pub new_bytes: u64,
+    pub operations: OpEstimate,
+    pub pricing: PricingConfig,
+}
+
+impl PushEstimate {
+    pub fn monthly_storage_cost(&self) -> f64 {
+        self.pricing.storage_cost(self.new_bytes)
+    }
+
+    pub fn operations_cost(&self) -> f64 {
+        self.operations.cost(&self.pricing)
+    }
+}
+
+impl fmt::Display for PushEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: +{} bytes stored (+${:.4}/month), {} (${:.4})",
+            self.image,
+            self.tag,
+            self.new_bytes,
+            self.monthly_storage_cost(),
+            self.operations,
+            self.operations_cost()
+        )
+    }
+}
+
+// Converts and stages `<image>:<tag>` like `plan` and prices what pushing it would do with the
+// `[pricing]` from the config: one existence check per object, one write per object that is not
+// skipped, and the storage of everything uploaded or copied.
+pub async fn estimate(image: String, tag: String, mut options: PushOptions) -> Result<PushEstimate> {
+    let config = config::load_config()?;
+    let (image, tag) = crate::resolve_target(image, tag, &mut options, &config)?;
+
+    let deps = PipelineDeps::from_env()?;
+    let staging_dir = tempfile::Builder::new().prefix(".oci-r2-estimate-").tempdir_in(&deps.work_dir)?;
+    let deps = PipelineDeps { work_dir: staging_dir.path().to_owned(), ..deps };
+    let plan = Pipeline::new(&image, &tag, &options, &config, &deps).dry_run(&mut PushStats::default()).await?;
+
+    let mut estimate = PushEstimate {
+        image,
+        tag,
+        new_bytes: 0,
+        operations: OpEstimate { class_b: plan.operations.len() as u64, ..Default::default() },
+        pricing: config.pricing,
+    };
+    for operation in &plan.operations {
+        match operation.operation {
+            Operation::Skip => {}
+            Operation::Update => estimate.operations.class_a += 1,
+            Operation::Upload | Operation::UploadChunked | Operation::UploadDelta | Operation::Copy => {
+                estimate.operations.class_a += 1;
+                estimate.new_bytes += operation.size.unwrap_or_default();
+            }
+        }
+    }
+    log::info!("{}", estimate);
+
+    Ok(estimate)
+}
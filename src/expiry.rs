@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use rusoto_s3::{DeleteObjectRequest, S3};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::annotations;
+use crate::budget::{self, OpClass, OpEstimate};
+use crate::r2configs::{self, R2Configs};
+use crate::v2;
+use crate::v2::{cas, catalog, referrers};
+use crate::v2::lister::Lister;
+
+pub(crate) const EXPIRES_ANNOTATION: &str = "dev.oci-r2.expires";
+const EXPIRY_FILE: &str = "expiry.json";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrunedTag {
+    pub image: String,
+    pub tag: String,
+    pub expired: SystemTime,
+}
+
+// Accepts a duration from now ("14d"), a date ("2024-12-31") or an RFC 3339 timestamp.
+pub fn parse_expiry(s: &str) -> Result<SystemTime> {
+    if let Ok(duration) = humantime::parse_duration(s) {
+        return Ok(SystemTime::now() + duration);
+    }
+
+    let timestamp = if s.len() == 10 { format!("{}T00:00:00Z", s) } else { s.to_owned() };
+    humantime::parse_rfc3339_weak(&timestamp).context(format!("Invalid expiry {} (expected a duration such as 14d, a date or a timestamp)", s))
+}
+
+pub(crate) fn annotate(manifest: &Path, expires: SystemTime) -> Result<()> {
+    let annotation = humantime::format_rfc3339_seconds(expires).to_string();
+
+    annotations::add(manifest, &BTreeMap::from([(EXPIRES_ANNOTATION.to_owned(), annotation)]))
+}
+
+pub(crate) fn read_annotation(manifest: &[u8]) -> Option<SystemTime> {
+    let value: Value = serde_json::from_slice(manifest).ok()?;
+    let expires = value["annotations"][EXPIRES_ANNOTATION].as_str()?;
+
+    parse_expiry(expires).ok()
+}
+
+// `v2/<image>/expiry.json` maps each expiring tag to its manifest object so that pruning does not
+// need to read every manifest in the bucket.
+pub(crate) async fn record(env_vars: &R2Configs, image: &str, tag: &str, expires: SystemTime) -> Result<()> {
+    let key = format!("v2/{}/{}", image, EXPIRY_FILE);
+    let entry = json!({ "expires": humantime::format_rfc3339_seconds(expires).to_string(), "manifest": tag_key(image, tag) });
+
+    cas::update_object(env_vars, &key, "application/json", |current| {
+        let mut index = read_index(current)?;
+        index.insert(tag.to_owned(), entry.clone());
+
+        Ok(Some(serde_json::to_vec(&index)?))
+    })
+    .await
+}
+
+// Deletes the manifests of expired tags and drops the tags from the tag lists. The manifest's own
+// annotation wins over the recorded expiry; layers are left for garbage collection.
+pub async fn prune_expired(dry_run: bool) -> Result<Vec<PrunedTag>> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    let now = SystemTime::now();
+
+    let indexes: Vec<String> = Lister::new(&client, &env_vars.r2_bucket)
+        .concurrency(8)
+        .list_keys("v2/")
+        .await?
+        .into_iter()
+        .filter(|key| key.ends_with(&format!("/{}", EXPIRY_FILE)))
+        .collect();
+
+    let mut indexes_read = Vec::new();
+    for index_key in indexes {
+        let index = read_index(cas::read_object(&env_vars, &index_key).await?.as_deref())?;
+        indexes_read.push((index_key, index));
+    }
+
+    // Every tag's manifest is read; pruning one deletes the manifest and its referrers index and
+    // rewrites the tag list and the expiry index.
+    let tags = indexes_read.iter().map(|(_, index)| index.len() as u64).sum::<u64>();
+    let estimate = match dry_run {
+        true => OpEstimate { class_b: tags, ..Default::default() },
+        false => OpEstimate { class_a: 2 * tags, class_b: 3 * tags, deletes: 2 * tags },
+    };
+    budget::check_estimate("Pruning expired tags", estimate)?;
+
+    let mut pruned = Vec::new();
+    for (index_key, index) in indexes_read {
+        let image = index_key.trim_start_matches("v2/").trim_end_matches(&format!("/{}", EXPIRY_FILE)).to_owned();
+
+        for (tag, entry) in index {
+            // Always the tag's own key: older records named the top manifest's blob, and deleting
+            // that left the tag pullable with its content gone.
+            let manifest_key = tag_key(&image, &tag);
+
+            let manifest = cas::read_object(&env_vars, &manifest_key).await?;
+            let expires = match &manifest {
+                Some(manifest) => read_annotation(manifest).or_else(|| entry["expires"].as_str().and_then(|expires| parse_expiry(expires).ok())),
+                None => Some(now),
+            };
+            let expired = match expires {
+                Some(expires) if expires <= now => expires,
+                _ => continue,
+            };
+
+            if dry_run {
+                log::info!("Would prune {}:{} (expired {})", image, tag, humantime::format_rfc3339_seconds(expired));
+            } else {
+                let req = DeleteObjectRequest {
+                    bucket: env_vars.r2_bucket.clone(),
+                    key: manifest_key.clone(),
+                    ..Default::default()
+                };
+                budget::charge(OpClass::Delete)?;
+                client.delete_object(req).await.context(format!("Failed to delete {}", manifest_key))?;
+                if let Some(manifest) = &manifest {
+                    let digest = format!("sha256:{:x}", Sha256::digest(manifest));
+                    referrers::delete_index(&client, &env_vars.r2_bucket, &image, &digest).await?;
+                }
+                catalog::remove_tag(&env_vars, &image, &tag).await?;
+                forget(&env_vars, &index_key, &tag).await?;
+                log::info!("Pruned {}:{} (expired {})", image, tag, humantime::format_rfc3339_seconds(expired));
+            }
+
+            pruned.push(PrunedTag { image: image.clone(), tag, expired });
+        }
+    }
+
+    Ok(pruned)
+}
+
+fn tag_key(image: &str, tag: &str) -> String {
+    format!("v2/{}/manifests/{}", image, tag)
+}
+
+// Drops a deleted tag from the image's expiry index.
+pub(crate) async fn forget_tag(env_vars: &R2Configs, image: &str, tag: &str) -> Result<()> {
+    forget(env_vars, &format!("v2/{}/{}", image, EXPIRY_FILE), tag).await
+}
+
+async fn forget(env_vars: &R2Configs, index_key: &str, tag: &str) -> Result<()> {
+    cas::update_object(env_vars, index_key, "application/json", |current| {
+        let mut index = read_index(current)?;
+        if index.remove(tag).is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(serde_json::to_vec(&index)?))
+    })
+    .await
+}
+
+fn read_index(current: Option<&[u8]>) -> Result<Map<String, Value>> {
+    match current {
+        Some(data) => serde_json::from_slice(data).context("Malformed expiry index"),
+        None => Ok(Map::new()),
+    }
+}
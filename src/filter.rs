@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+
+use crate::batch::BatchJob;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(try_from = "RawImageFilter")]
+pub struct ImageFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawImageFilter {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+impl TryFrom<RawImageFilter> for ImageFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawImageFilter) -> Result<Self> {
+        ImageFilter::new(&raw.include, &raw.exclude)
+    }
+}
+
+impl ImageFilter {
+    pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Self> {
+        Ok(ImageFilter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub(crate) fn patterns(&self) -> (&[Pattern], &[Pattern]) {
+        (&self.include, &self.exclude)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, image: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|p| p.matches_with(image, MATCH_OPTIONS));
+        included && !self.exclude.iter().any(|p| p.matches_with(image, MATCH_OPTIONS))
+    }
+
+    pub fn apply(&self, jobs: Vec<BatchJob>) -> Vec<BatchJob> {
+        jobs.into_iter()
+            .filter(|job| {
+                let matches = self.matches(&job.image);
+                if !matches {
+                    log::info!("Skipping {}:{}, excluded by the image filter", job.image, job.tag);
+                }
+                matches
+            })
+            .collect()
+    }
+}
+
+fn compile<S: AsRef<str>>(patterns: &[S]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern.as_ref()).context(format!("Invalid image pattern {}", pattern.as_ref())))
+        .collect()
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use crate::conformance::ConformanceCheck;
+use crate::fsck::Drift;
+use crate::validate::{ConfigIssue, Severity};
+
+const TOOL: &str = "oci-r2-uploader";
+
+// One result of a check (index drift from `fsck`, a config issue, a conformance check or an image
+// policy violation), in a form CI systems can ingest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub rule: String,
+    pub severity: Severity,
+    // A config file key, bucket key, image reference or check name.
+    pub location: String,
+    // None when the check passed.
+    pub message: Option<String>,
+}
+
+impl From<&Drift> for Finding {
+    fn from(drift: &Drift) -> Self {
+        Finding {
+            rule: "index-drift".to_owned(),
+            severity: if drift.fixed { Severity::Warning } else { Severity::Error },
+            location: drift.key.clone(),
+            message: Some(drift.to_string()),
+        }
+    }
+}
+
+impl From<&ConfigIssue> for Finding {
+    fn from(issue: &ConfigIssue) -> Self {
+        let message = match &issue.fix {
+            Some(fix) => format!("{} (fix: {})", issue.message, fix),
+            None => issue.message.clone(),
+        };
+        Finding { rule: "config".to_owned(), severity: issue.severity, location: issue.location.clone(), message: Some(message) }
+    }
+}
+
+impl From<&ConformanceCheck> for Finding {
+    fn from(check: &ConformanceCheck) -> Self {
+        Finding {
+            rule: "conformance".to_owned(),
+            severity: if check.required { Severity::Error } else { Severity::Warning },
+            location: check.name.to_owned(),
+            message: check.error.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    // SARIF 2.1.0, for GitHub code scanning. Passed checks are left out.
+    Sarif,
+    // JUnit XML, one test case per finding, for CI test report views.
+    Junit,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sarif" => Ok(ReportFormat::Sarif),
+            "junit" => Ok(ReportFormat::Junit),
+            _ => bail!("Unknown report format {} (expected sarif or junit)", s),
+        }
+    }
+}
+
+pub fn findings_report(findings: &[Finding], format: ReportFormat) -> Result<String> {
+    match format {
+        ReportFormat::Sarif => Ok(serde_json::to_string_pretty(&sarif(findings))?),
+        ReportFormat::Junit => Ok(junit(findings)),
+    }
+}
+
+fn sarif(findings: &[Finding]) -> Value {
+    let rules: BTreeSet<&str> = findings.iter().map(|finding| finding.rule.as_str()).collect();
+    let results: Vec<Value> = findings
+        .iter()
+        .filter_map(|finding| {
+            let message = finding.message.as_ref()?;
+            Some(json!({
+                "ruleId": finding.rule,
+                "level": level(finding.severity),
+                "message": { "text": message },
+                "locations": [{ "physicalLocation": { "artifactLocation": { "uri": artifact(&finding.location) } } }],
+            }))
+        })
+        .collect();
+
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": TOOL,
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules.iter().map(|rule| json!({ "id": rule })).collect::<Vec<_>>(),
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+// Config issues are located at `<file>: <key>`; code scanning wants just the file.
+fn artifact(location: &str) -> &str {
+    location.split_once(": ").map_or(location, |(file, _)| file)
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    }
+}
+
+// Warnings pass, with the message kept as output, so they show up without failing the build.
+fn junit(findings: &[Finding]) -> String {
+    let failures = findings.iter().filter(|finding| finding.message.is_some() && finding.severity == Severity::Error).count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\">\n", TOOL, findings.len(), failures));
+    xml.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n", TOOL, findings.len(), failures));
+    for finding in findings {
+        let case = format!("    <testcase classname=\"{}\" name=\"{}\"", escape(&finding.rule), escape(&finding.location));
+        match (&finding.message, finding.severity) {
+            (None, _) => xml.push_str(&format!("{}/>\n", case)),
+            (Some(message), Severity::Error) => {
+                let message = escape(message);
+                xml.push_str(&format!("{}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n", case, message, message));
+            }
+            (Some(message), Severity::Warning) => {
+                xml.push_str(&format!("{}>\n      <system-out>warning: {}</system-out>\n    </testcase>\n", case, escape(message)));
+            }
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+
+    xml
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::budget::{self, OpEstimate};
+use crate::capabilities::{self, Capability};
+use crate::list::split_key;
+use crate::r2configs::{self, R2Configs};
+use crate::v2;
+use crate::v2::catalog::{self, CATALOG_KEY};
+use crate::v2::lister::Lister;
+use crate::v2::{cas, referrers};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Drift {
+    pub key: String,
+    pub problem: String,
+    pub fixed: bool,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.problem)?;
+        if self.fixed {
+            write!(f, " (fixed)")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Cross-checks the index objects (`v2/_catalog`, `v2/<name>/tags/list` and the referrers indexes)
+// against a listing of the bucket. With `fix`, each index is rewritten to match the objects that
+// actually exist; entries are only ever added for objects that exist and removed for ones that do
+// not, so a fix is safe to run while pushes are going on.
+pub async fn fsck(fix: bool) -> Result<Vec<Drift>> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    let mut required = vec![Capability::Read, Capability::List];
+    if fix {
+        required.push(Capability::Write);
+    }
+    capabilities::require(&client, &env_vars.r2_bucket, "fsck", &required).await?;
+
+    let keys = Lister::new(&client, &env_vars.r2_bucket).concurrency(8).list_keys("v2/").await?;
+
+    let mut repositories: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for key in &keys {
+        if let Some((name, kind, reference)) = split_key(key) {
+            let tags = repositories.entry(name).or_default();
+            if kind == "manifests" && is_tag(reference) {
+                tags.insert(reference);
+            }
+        }
+    }
+
+    let tag_lists: BTreeSet<&str> = keys.iter().filter_map(|key| key.strip_prefix("v2/")?.strip_suffix("/tags/list")).collect();
+    let names: BTreeSet<&str> = repositories.keys().copied().chain(tag_lists.iter().copied()).collect();
+
+    // Each index is read once, and with `fix` possibly read again and rewritten.
+    let indexes = 1 + names.len() as u64 + keys.iter().filter(|key| key.contains("/referrers/")).count() as u64;
+    let estimate = match fix {
+        true => OpEstimate { class_a: indexes, class_b: 2 * indexes, ..Default::default() },
+        false => OpEstimate { class_b: indexes, ..Default::default() },
+    };
+    budget::check_estimate("fsck", estimate)?;
+
+    let mut drift = Vec::new();
+    check_catalog(&env_vars, &repositories, fix, &mut drift).await?;
+
+    let empty = BTreeSet::new();
+    for name in names {
+        check_tags(&env_vars, name, repositories.get(name).unwrap_or(&empty), fix, &mut drift).await?;
+    }
+
+    for key in keys.iter().filter(|key| key.contains("/referrers/")) {
+        check_referrers(&env_vars, key, &keys, fix, &mut drift).await?;
+    }
+
+    match drift.len() {
+        0 => log::info!("No drift between the indexes and the {} objects in the bucket", keys.len()),
+        count => log::warn!("Found {} drifted index entries", count),
+    }
+
+    Ok(drift)
+}
+
+// Pushes name manifests after their content hash, so a 64 digit hex reference is not a tag.
+fn is_tag(reference: &str) -> bool {
+    let content_hash = reference.len() == 64 && reference.bytes().all(|b| b.is_ascii_hexdigit());
+    !content_hash && !reference.contains(':')
+}
+
+async fn check_catalog(env_vars: &R2Configs, repositories: &BTreeMap<&str, BTreeSet<&str>>, fix: bool, drift: &mut Vec<Drift>) -> Result<()> {
+    let current = cas::read_object(env_vars, CATALOG_KEY).await?;
+    let listed = catalog::read_list(current.as_deref(), "repositories")?;
+
+    let dangling: BTreeSet<&String> = listed.iter().filter(|name| !repositories.contains_key(name.as_str())).collect();
+    let missing: BTreeSet<&str> = repositories.keys().copied().filter(|name| !listed.contains(*name)).collect();
+    let mut found = Vec::new();
+    for name in &dangling {
+        found.push(format!("lists {}, which has no objects", name));
+    }
+    for name in &missing {
+        found.push(format!("is missing {}", name));
+    }
+    if found.is_empty() {
+        return Ok(());
+    }
+
+    // Only the drift found here is repaired, so repositories pushed since the listing are kept.
+    if fix {
+        cas::update_object(env_vars, CATALOG_KEY, "application/json", |current| {
+            let mut listed = catalog::read_list(current, "repositories")?;
+            listed.retain(|name| !dangling.contains(name));
+            listed.extend(missing.iter().map(|name| name.to_string()));
+
+            Ok(Some(json!({ "repositories": listed }).to_string().into_bytes()))
+        })
+        .await
+        .context(format!("Failed to repair {}", CATALOG_KEY))?;
+    }
+    drift.extend(found.into_iter().map(|problem| Drift { key: CATALOG_KEY.to_owned(), problem, fixed: fix }));
+
+    Ok(())
+}
+
+async fn check_tags(env_vars: &R2Configs, name: &str, tags: &BTreeSet<&str>, fix: bool, drift: &mut Vec<Drift>) -> Result<()> {
+    let key = catalog::tags_key(name);
+    let current = cas::read_object(env_vars, &key).await?;
+    let listed = catalog::read_list(current.as_deref(), "tags")?;
+
+    let dangling: BTreeSet<&String> = listed.iter().filter(|tag| !tags.contains(tag.as_str())).collect();
+    let missing: BTreeSet<&str> = tags.iter().copied().filter(|tag| !listed.contains(*tag)).collect();
+    if dangling.is_empty() && missing.is_empty() {
+        return Ok(());
+    }
+
+    if fix {
+        cas::update_object(env_vars, &key, "application/json", |current| {
+            let mut listed = catalog::read_list(current, "tags")?;
+            listed.retain(|tag| !dangling.contains(tag));
+            listed.extend(missing.iter().map(|tag| tag.to_string()));
+
+            Ok(Some(json!({ "name": name, "tags": listed }).to_string().into_bytes()))
+        })
+        .await
+        .context(format!("Failed to repair {}", key))?;
+    }
+
+    for tag in dangling {
+        drift.push(Drift { key: key.clone(), problem: format!("lists {}, which has no manifest", tag), fixed: fix });
+    }
+    for tag in missing {
+        drift.push(Drift { key: key.clone(), problem: format!("is missing {}", tag), fixed: fix });
+    }
+
+    Ok(())
+}
+
+async fn check_referrers(env_vars: &R2Configs, key: &str, keys: &BTreeSet<String>, fix: bool, drift: &mut Vec<Drift>) -> Result<()> {
+    let Some((image, _)) = key.strip_prefix("v2/").and_then(|rest| rest.rsplit_once("/referrers/")) else {
+        return Ok(());
+    };
+    let exists = |manifest: &Value| manifest["digest"].as_str().is_some_and(|digest| keys.contains(&format!("v2/{}/manifests/{}", image, digest)));
+
+    let index: Value = match cas::read_object(env_vars, key).await? {
+        Some(data) => serde_json::from_slice(&data).context(format!("Malformed referrers index {}", key))?,
+        None => return Ok(()),
+    };
+    let dangling: Vec<String> = index["manifests"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|manifest| !exists(manifest))
+        .map(|manifest| manifest["digest"].as_str().unwrap_or("an entry without a digest").to_owned())
+        .collect();
+    if dangling.is_empty() {
+        return Ok(());
+    }
+
+    if fix {
+        cas::update_object(env_vars, key, referrers::INDEX_MEDIA_TYPE, |current| {
+            let mut index: Value = match current {
+                Some(data) => serde_json::from_slice(data).context(format!("Malformed referrers index {}", key))?,
+                None => return Ok(None),
+            };
+            if let Some(manifests) = index["manifests"].as_array_mut() {
+                manifests.retain(exists);
+            }
+
+            Ok(Some(serde_json::to_vec(&index)?))
+        })
+        .await
+        .context(format!("Failed to repair {}", key))?;
+    }
+    drift.extend(dangling.into_iter().map(|digest| Drift { key: key.to_owned(), problem: format!("lists {}, which has no manifest", digest), fixed: fix }));
+
+    Ok(())
+}
@@ -2,11 +2,11 @@ use std::fs;
 use std::io::Read;
 use std::path::Path;
 use anyhow::Result;
-use blake3::Hasher;
+use sha2::{Digest, Sha256};
 
-pub fn compute_blake3<P: AsRef<Path>>(path: P) -> Result<String> {
+pub fn compute_sha256<P: AsRef<Path>>(path: P) -> Result<String> {
     let mut file = fs::File::open(path)?;
-    let mut hasher = Hasher::new();
+    let mut hasher = Sha256::new();
     let mut buffer = [0; 4096];
     loop {
         let bytes = file.read(&mut buffer)?;
@@ -17,5 +17,13 @@ pub fn compute_blake3<P: AsRef<Path>>(path: P) -> Result<String> {
         hasher.update(&buffer[..bytes]);
     }
 
-    Ok(hasher.finalize().to_hex().to_string())
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
@@ -0,0 +1,37 @@
+use std::path::Path;
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::canonical;
+
+// Config fields that only describe how the image was built. `history` holds the build commands,
+// the others are Docker's record of the build container.
+const BUILD_FIELDS: [&str; 6] = ["history", "container_config", "container", "docker_version", "author", "comment"];
+
+// Removes the build history and build-only fields from the config of every image manifest staged
+// in `dir`, storing the stripped configs under their new digests. Returns the number of history
+// entries removed.
+pub(crate) fn strip_history(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    canonical::rewrite_configs(dir, |config| {
+        let Some(fields) = config.as_object_mut() else {
+            return Ok(false);
+        };
+
+        removed += fields.get("history").and_then(Value::as_array).map_or(0, Vec::len);
+        let mut changed = false;
+        for field in BUILD_FIELDS {
+            changed |= fields.remove(field).is_some();
+        }
+        // The ID of the image the build container ran from.
+        if let Some(runtime) = fields.get_mut("config").and_then(Value::as_object_mut) {
+            changed |= runtime.remove("Image").is_some();
+        }
+
+        Ok(changed)
+    })?;
+
+    log::info!("Stripped {} build history entries from the image config", removed);
+
+    Ok(removed)
+}
@@ -0,0 +1,172 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::body::HttpBody;
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+
+use crate::batch::BatchJob;
+use crate::converter::ConverterKind;
+use crate::filter::ImageFilter;
+use crate::hash_utils;
+use crate::jobs::{JobQueue, JobQueueConfig, RetryPolicy};
+use crate::PushOptions;
+
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct HookServerConfig {
+    pub addr: SocketAddr,
+    pub token: String,
+    pub options: PushOptions,
+    pub queue: JobQueueConfig,
+    pub filter: ImageFilter,
+}
+
+struct HookState {
+    token: String,
+    options: PushOptions,
+    queue: JobQueue,
+    filter: ImageFilter,
+}
+
+struct HookRequest {
+    job: BatchJob,
+    digest: Option<String>,
+    retry: Option<RetryPolicy>,
+}
+
+pub async fn serve_hooks(config: HookServerConfig) -> Result<()> {
+    if config.token.is_empty() {
+        bail!("A non-empty token is required to serve hooks");
+    }
+
+    let state = Arc::new(HookState {
+        token: config.token,
+        options: config.options,
+        queue: JobQueue::new(config.queue),
+        filter: config.filter,
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone()))) }
+    });
+
+    let server = Server::try_bind(&config.addr)
+        .context(format!("Failed to bind {}", config.addr))?
+        .serve(make_service);
+    log::info!("Listening for push hooks on {}", config.addr);
+
+    server.await.context("Hook server failed")
+}
+
+async fn handle(req: Request<Body>, state: Arc<HookState>) -> Result<Response<Body>, Infallible> {
+    if !is_authorized(&req, &state.token) {
+        return Ok(respond(StatusCode::UNAUTHORIZED, json!({ "error": "invalid token" })));
+    }
+
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/hooks/push") => push(req, &state).await,
+        (&Method::GET, "/hooks/jobs") => {
+            let jobs: Vec<Value> = state.queue.jobs().iter().map(|job| job.to_json()).collect();
+            respond(StatusCode::OK, json!({ "jobs": jobs }))
+        }
+        (&Method::GET, path) if path.starts_with("/hooks/jobs/") => {
+            let job = path["/hooks/jobs/".len()..].parse().ok().and_then(|id| state.queue.job(id));
+            match job {
+                Some(job) => respond(StatusCode::OK, job.to_json()),
+                None => respond(StatusCode::NOT_FOUND, json!({ "error": "unknown job" })),
+            }
+        }
+        (_, "/hooks/push") | (_, "/hooks/jobs") => respond(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "method not allowed" })),
+        _ => respond(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+
+    Ok(response)
+}
+
+async fn push(req: Request<Body>, state: &HookState) -> Response<Body> {
+    let request = match read_request(req, &state.options).await {
+        Ok(request) => request,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, json!({ "error": format!("{:#}", e) })),
+    };
+
+    let (image, tag) = (request.job.image.clone(), request.job.tag.clone());
+    if !state.filter.matches(&image) {
+        log::info!("Ignoring push hook for {}:{}, excluded by the image filter", image, tag);
+        return respond(StatusCode::OK, json!({ "image": image, "tag": tag, "status": "filtered" }));
+    }
+
+    let (id, deduplicated) = state.queue.enqueue(request.job, request.digest, request.retry);
+
+    respond(StatusCode::ACCEPTED, json!({ "id": id, "image": image, "tag": tag, "deduplicated": deduplicated }))
+}
+
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) => hash_utils::constant_time_eq(provided.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+async fn read_request(req: Request<Body>, options: &PushOptions) -> Result<HookRequest> {
+    // Chunked bodies announce no length, so the limit is enforced while reading.
+    let mut stream = req.into_body();
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.data().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) as u64 > MAX_BODY_SIZE {
+            bail!("Request body must be at most {} bytes", MAX_BODY_SIZE);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let payload: Value = serde_json::from_slice(&body).context("Request body is not valid JSON")?;
+
+    let image = payload["image"].as_str().context("image is required")?;
+    let tag = payload["tag"].as_str().context("tag is required")?;
+    if image.is_empty() || tag.is_empty() {
+        bail!("image and tag must not be empty");
+    }
+
+    let mut options = options.clone();
+    if let Some(source) = payload["source"].as_str() {
+        // Other transports (`dir:`, `oci:`, `docker-archive:`) and the layout converters read
+        // local paths, which would let hook callers push files from this host.
+        if source.strip_prefix("docker://").is_none_or(|reference| reference.is_empty() || reference.contains("://")) {
+            bail!("source must be a registry reference of the form docker://<registry>/<image>:<tag>");
+        }
+        if matches!(options.converter, ConverterKind::OciLayout | ConverterKind::Wasm) {
+            bail!("source cannot be set, the {:?} converter reads local files", options.converter);
+        }
+        options.source = Some(source.to_owned());
+    }
+
+    let retry = payload["max_attempts"].as_u64().map(|max_attempts| RetryPolicy {
+        max_attempts: max_attempts.clamp(1, 10) as u32,
+        backoff: payload["backoff_secs"].as_u64().map_or(RetryPolicy::default().backoff, Duration::from_secs),
+    });
+
+    Ok(HookRequest {
+        job: BatchJob { image: image.to_owned(), tag: tag.to_owned(), options },
+        digest: payload["digest"].as_str().map(|digest| digest.to_owned()),
+        retry,
+    })
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use tempfile::TempDir;
+
+use crate::config::{self, ImagePolicyConfig, PolicyAction};
+use crate::converter;
+use crate::findings::Finding;
+use crate::validate::Severity;
+use crate::PushOptions;
+
+const BASE_DIGEST_ANNOTATION: &str = "org.opencontainers.image.base.digest";
+
+// Evaluates the image policy against a staged `dir:` layout before anything is uploaded.
+pub(crate) fn check(policy: &ImagePolicyConfig, dir: &Path) -> Result<()> {
+    let violations = violations(policy, dir)?;
+    if !violations.is_empty() {
+        bail!("Image policy violations:\n{}", violations.join("\n"));
+    }
+
+    Ok(())
+}
+
+// Converts `<image>:<tag>` like a push would and evaluates the image policy against it without
+// uploading anything. Returns one finding per violation, or a single passed one.
+pub async fn check_image_policy(image: &str, tag: &str, options: &PushOptions) -> Result<Vec<Finding>> {
+    let config = config::load_config()?;
+    let dir = TempDir::new()?;
+    let converter = converter::select(options.converter, options.source.as_deref(), options.policy.as_deref(), &options.sources, options.registry_config.as_deref())?;
+    converter::convert_with_retry(converter.into(), image, tag, options.source.as_deref(), dir.path(), crate::pipeline::convert_retry(options, &config), &options.progress).await?;
+
+    let location = format!("{}:{}", image, tag);
+    let violations = violations(&config.image_policy, dir.path())?;
+    if violations.is_empty() {
+        return Ok(vec![Finding { rule: "image-policy".to_owned(), severity: Severity::Error, location, message: None }]);
+    }
+
+    Ok(violations
+        .into_iter()
+        .map(|violation| Finding { rule: "image-policy".to_owned(), severity: Severity::Error, location: location.clone(), message: Some(violation) })
+        .collect())
+}
+
+// Every platform of a multi-platform image is checked; attestation manifests, and other manifests
+// without an image config, are skipped.
+fn violations(policy: &ImagePolicyConfig, dir: &Path) -> Result<Vec<String>> {
+    if policy.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let top = read_json(&dir.join("manifest.json"))?;
+    let manifests = match top["manifests"].as_array() {
+        Some(children) => children
+            .iter()
+            .filter(|child| !is_attestation(child))
+            .map(|child| read_json(&dir.join(format!("{}.manifest.json", hex(child)?))))
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![top.clone()],
+    };
+
+    let mut violations = Vec::new();
+    for manifest in &manifests {
+        let config_descriptor = &manifest["config"];
+        let is_image = config_descriptor["mediaType"]
+            .as_str()
+            .is_some_and(|media_type| media_type.ends_with("config.v1+json") || media_type.ends_with("container.image.v1+json"));
+        if !is_image {
+            continue;
+        }
+
+        let config = read_json(&dir.join(hex(config_descriptor)?))?;
+        let platform = format!("{}/{}", config["os"].as_str().unwrap_or("unknown"), config["architecture"].as_str().unwrap_or("unknown"));
+
+        if policy.forbid_root && runs_as_root(&config) {
+            violations.push(format!("{}: runs as root", platform));
+        }
+
+        for label in &policy.required_labels {
+            if config["config"]["Labels"][label].as_str().is_none_or(str::is_empty) {
+                violations.push(format!("{}: missing label {}", platform, label));
+            }
+        }
+
+        let base = manifest["annotations"][BASE_DIGEST_ANNOTATION].as_str().or_else(|| top["annotations"][BASE_DIGEST_ANNOTATION].as_str());
+        let layers: Vec<&str> = manifest["layers"].as_array().into_iter().flatten().filter_map(|layer| layer["digest"].as_str()).collect();
+        for forbidden in &policy.forbidden_base_digests {
+            if base == Some(forbidden.as_str()) || layers.contains(&forbidden.as_str()) {
+                violations.push(format!("{}: built on forbidden base {}", platform, forbidden));
+            }
+        }
+    }
+
+    if !policy.approved_bases.is_empty() {
+        for manifest in &manifests {
+            let layers: Vec<&str> = manifest["layers"].as_array().into_iter().flatten().filter_map(|layer| layer["digest"].as_str()).collect();
+            if layers.is_empty() {
+                continue;
+            }
+
+            // The image's lowest layers must be exactly the layers of one approved base.
+            let approved = policy.approved_bases.iter().find(|base| {
+                !base.layers.is_empty() && base.layers.len() <= layers.len() && base.layers.iter().zip(&layers).all(|(approved, layer)| approved == layer)
+            });
+
+            match (approved, policy.unapproved_base) {
+                (Some(base), _) => log::debug!("Image is built on approved base {}", base.name),
+                (None, PolicyAction::Warn) => log::warn!("Image is not built on an approved base (lowest layer {})", layers[0]),
+                (None, PolicyAction::Reject) => violations.push(format!("not built on an approved base (lowest layer {})", layers[0])),
+            }
+        }
+    }
+
+    Ok(violations)
+}
+
+// BuildKit attaches provenance and SBOMs as `unknown/unknown` manifests of the index that refer
+// to the image they describe. Their layers are in-toto statements, not a base image.
+fn is_attestation(descriptor: &Value) -> bool {
+    descriptor["annotations"]["vnd.docker.reference.type"].as_str() == Some("attestation-manifest")
+        || (descriptor["platform"]["os"].as_str() == Some("unknown") && descriptor["platform"]["architecture"].as_str() == Some("unknown"))
+}
+
+// An empty user means the image default, which is root.
+fn runs_as_root(config: &Value) -> bool {
+    let user = config["config"]["User"].as_str().unwrap_or_default();
+    let user = user.split(':').next().unwrap_or_default();
+
+    user.is_empty() || user == "root" || user == "0"
+}
+
+fn hex(descriptor: &Value) -> Result<&str> {
+    let digest = descriptor["digest"].as_str().context("Descriptor has no digest")?;
+    digest.split_once(':').map(|(_, hex)| hex).context(format!("Malformed digest {}", digest))
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let data = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&data).context(format!("Failed to parse {}", path.display()))
+}
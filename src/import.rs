@@ -0,0 +1,302 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::{ACCEPT, LINK, WWW_AUTHENTICATE};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use crate::converter::{self, ConverterKind};
+use crate::r2configs::{self, R2Configs};
+use crate::smoke::Puller;
+use crate::{metadata, mirror, proxy, run_with_options, PushOptions};
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    pub all_repos: bool,
+    pub repositories: Vec<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Defaults to `.oci-r2-import-<host>.json` in the working directory.
+    pub state_file: Option<PathBuf>,
+    // Also records each tag's labels, creation time and platforms, shown by `list_repositories`
+    // with `with_metadata`.
+    pub with_metadata: bool,
+    pub push: PushOptions,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct ImportState {
+    done: BTreeSet<String>,
+}
+
+// Mirrors the repositories of a registry (`docker://<host>[:port]`) into the bucket through its v2
+// API, one push per tag. Every imported tag is recorded in the state file right away, so an
+// interrupted import picks up where it stopped when run again.
+pub async fn import_registry(source: &str, options: ImportOptions) -> Result<ImportReport> {
+    let host = source.strip_prefix("docker://").unwrap_or(source).trim_end_matches('/');
+    if host.is_empty() || host.contains('/') {
+        bail!("{} is not a registry (expected docker://<host>[:port])", source);
+    }
+    if !options.all_repos && options.repositories.is_empty() {
+        bail!("Select the repositories to import, or import all of them");
+    }
+
+    let state_file = options.state_file.clone().unwrap_or_else(|| PathBuf::from(format!(".oci-r2-import-{}.json", host.replace(':', "_"))));
+    let mut state = load_state(&state_file)?;
+
+    let registry = Registry {
+        client: proxy::http_client()?,
+        base: format!("https://{}", host),
+        username: options.username.clone(),
+        password: options.password.clone(),
+    };
+
+    let repositories = match options.all_repos {
+        true => registry.list("/v2/_catalog?n=100", "repositories").await?,
+        false => options.repositories.clone(),
+    };
+    log::info!("Importing {} repositories from {}", repositories.len(), host);
+
+    let converter = match options.push.converter {
+        ConverterKind::Auto if converter::command_exists("skopeo") => ConverterKind::Skopeo,
+        ConverterKind::Auto => ConverterKind::Crane,
+        kind @ (ConverterKind::Skopeo | ConverterKind::Crane) => kind,
+        kind => bail!("{:?} cannot pull from a registry, use skopeo or crane", kind),
+    };
+
+    // The pulls authenticate with the import's credentials through a temporary docker config.
+    let registry_config = match (&options.username, &options.password) {
+        (Some(username), Some(password)) => Some(registry_config(host, username, password)?),
+        _ => None,
+    };
+    let push_options = PushOptions {
+        registry_config: registry_config.as_ref().map(|dir| dir.path().to_owned()).or(options.push.registry_config.clone()),
+        ..options.push.clone()
+    };
+
+    let env_vars = match options.with_metadata {
+        true => Some(r2configs::parse_r2configs()?),
+        false => None,
+    };
+
+    let mut report = ImportReport::default();
+    for repository in &repositories {
+        let imported = report.imported.len();
+        let mut tags = registry.list(&format!("/v2/{}/tags/list?n=100", repository), "tags").await?;
+        tags.retain(|tag| {
+            let reference = format!("{}:{}", repository, tag);
+            let done = state.done.contains(&reference);
+            if done {
+                report.skipped.push(reference);
+            }
+            !done
+        });
+
+        // skopeo pulls all tags of the repository into one layout, so shared layers are
+        // downloaded once.
+        if converter == ConverterKind::Skopeo {
+            let source = format!("docker://{}/{}", host, repository);
+            let mirrored = mirror::mirror_each(&source, repository.clone(), &tags, push_options.clone(), |tag, _| {
+                let reference = format!("{}:{}", repository, tag);
+                state.done.insert(reference.clone());
+                save_state(&state_file, &state)?;
+                report.imported.push(reference);
+                Ok(())
+            })
+            .await?;
+            report.failed.extend(mirrored.failed.into_iter().map(|(tag, _)| format!("{}:{}", repository, tag)));
+            import_metadata(&registry, env_vars.as_ref(), repository, &report.imported[imported..], &options).await;
+            continue;
+        }
+
+        for tag in tags {
+            let reference = format!("{}:{}", repository, tag);
+
+            let push = PushOptions {
+                converter,
+                source: Some(format!("{}/{}", host, reference)),
+                ..push_options.clone()
+            };
+
+            match run_with_options(repository.clone(), tag.clone(), push).await {
+                Ok(_) => {
+                    state.done.insert(reference.clone());
+                    save_state(&state_file, &state)?;
+                    report.imported.push(reference);
+                }
+                Err(e) => {
+                    log::error!("Failed to import {}: {:#}", reference, e);
+                    report.failed.push(reference);
+                }
+            }
+        }
+
+        import_metadata(&registry, env_vars.as_ref(), repository, &report.imported[imported..], &options).await;
+    }
+
+    log::info!(
+        "Imported {} tags from {} ({} already imported, {} failed)",
+        report.imported.len(),
+        host,
+        report.skipped.len(),
+        report.failed.len()
+    );
+
+    if !report.failed.is_empty() {
+        bail!("Failed to import {} tags: {}, run the import again to retry them", report.failed.len(), report.failed.join(", "));
+    }
+
+    Ok(report)
+}
+
+// Records the metadata of the `imported` references of `repository` when `env_vars` is set.
+// Metadata is a nicety, so failing to read or record it only warns.
+async fn import_metadata(registry: &Registry, env_vars: Option<&R2Configs>, repository: &str, imported: &[String], options: &ImportOptions) {
+    let Some(env_vars) = env_vars else {
+        return;
+    };
+    let mut puller = match Puller::new(&registry.base, repository) {
+        Ok(puller) => puller.with_credentials(options.username.clone(), options.password.clone()),
+        Err(e) => return log::warn!("Failed to read the metadata of {}: {:#}", repository, e),
+    };
+
+    for tag in imported.iter().filter_map(|reference| Some(reference.rsplit_once(':')?.1)) {
+        let recorded = match metadata::fetch(&mut puller, tag).await {
+            Ok(tag_metadata) => metadata::record(env_vars, repository, tag, &tag_metadata).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = recorded {
+            log::warn!("Failed to record the metadata of {}:{}: {:#}", repository, tag, e);
+        }
+    }
+}
+
+fn registry_config(host: &str, username: &str, password: &str) -> Result<TempDir> {
+    let dir = TempDir::new()?;
+    let auth = STANDARD.encode(format!("{}:{}", username, password));
+    let config = json!({ "auths": { host: { "auth": auth } } });
+    fs::write(dir.path().join("config.json"), serde_json::to_vec(&config)?).context("Failed to write the registry credentials")?;
+
+    Ok(dir)
+}
+
+fn load_state(path: &Path) -> Result<ImportState> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).context(format!("Malformed import state {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ImportState::default()),
+        Err(e) => Err(e).context(format!("Failed to read {}", path.display())),
+    }
+}
+
+fn save_state(path: &Path, state: &ImportState) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(&tmp, path).context(format!("Failed to write {}", path.display()))
+}
+
+pub(crate) struct Registry {
+    pub(crate) client: Client,
+    pub(crate) base: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+}
+
+impl Registry {
+    // Collects `field` from every page of a paginated list endpoint, following `Link` headers.
+    async fn list(&self, path: &str, field: &str) -> Result<Vec<String>> {
+        let mut items = Vec::new();
+        let mut next = Some(path.to_owned());
+        let mut token = None;
+
+        while let Some(path) = next.take() {
+            let url = format!("{}{}", self.base, path);
+            let mut response = self.get(&url, token.as_deref(), None).await?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                token = Some(self.authorize(&response).await?);
+                response = self.get(&url, token.as_deref(), None).await?;
+            }
+            if !response.status().is_success() {
+                bail!("Failed to list {}: HTTP {}", url, response.status());
+            }
+
+            next = response
+                .headers()
+                .get(LINK)
+                .and_then(|link| link.to_str().ok())
+                .filter(|link| link.contains("rel=\"next\""))
+                .and_then(|link| link.split_once('<'))
+                .and_then(|(_, rest)| rest.split_once('>'))
+                .map(|(next, _)| next.trim_start_matches(&self.base).to_owned());
+
+            let page: Value = response.json().await.context(format!("Malformed response from {}", url))?;
+            items.extend(page[field].as_array().into_iter().flatten().filter_map(|item| item.as_str().map(str::to_owned)));
+        }
+
+        Ok(items)
+    }
+
+    pub(crate) async fn get(&self, url: &str, token: Option<&str>, accept: Option<&str>) -> Result<reqwest::Response> {
+        let mut request = self.client.get(url);
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+
+        self.authenticate(request, token).send().await.context(format!("Failed to reach {}", url))
+    }
+
+    pub(crate) fn authenticate(&self, request: RequestBuilder, token: Option<&str>) -> RequestBuilder {
+        match (token, &self.username) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, Some(username)) => request.basic_auth(username, self.password.as_deref()),
+            (None, None) => request,
+        }
+    }
+
+    // Exchanges the credentials for a token as described by a `Bearer` challenge.
+    pub(crate) async fn authorize(&self, response: &reqwest::Response) -> Result<String> {
+        let challenge = response.headers().get(WWW_AUTHENTICATE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let Some(params) = challenge.strip_prefix("Bearer ") else {
+            bail!("{} rejected the credentials", self.base);
+        };
+
+        let param = |name: &str| {
+            params.split(',').find_map(|param| {
+                let (key, value) = param.trim().split_once('=')?;
+                (key == name).then(|| value.trim_matches('"').to_owned())
+            })
+        };
+        let realm = param("realm").context(format!("{} sent a challenge without a realm", self.base))?;
+        let query: Vec<(&str, String)> = [("service", param("service")), ("scope", param("scope"))]
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect();
+
+        let mut request = self.client.get(&realm).query(&query);
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_deref());
+        }
+        let response = request.send().await.context(format!("Failed to reach {}", realm))?;
+        if !response.status().is_success() {
+            bail!("{} refused a token: HTTP {}", realm, response.status());
+        }
+
+        let body: Value = response.json().await.context(format!("Malformed token from {}", realm))?;
+        body["token"]
+            .as_str()
+            .or(body["access_token"].as_str())
+            .map(str::to_owned)
+            .context(format!("{} returned no token", realm))
+    }
+}
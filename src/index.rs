@@ -0,0 +1,350 @@
+use anyhow::{bail, Context, Result};
+use rusoto_s3::{CopyObjectRequest, PutObjectRequest, S3Client, S3};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::capabilities::{self, Capability};
+use crate::{config, destination};
+use crate::names;
+use crate::PushOptions;
+use crate::r2configs::{self, R2Configs};
+use crate::v2;
+use crate::v2::{cas, catalog, checksums, chunks};
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreatedIndex {
+    pub image: String,
+    pub tag: String,
+    pub digest: String,
+    pub platforms: Vec<String>,
+}
+
+pub(crate) struct PlatformManifest {
+    pub(crate) descriptor: Value,
+    pub(crate) platform: String,
+}
+
+// Composes an image index tagged `target` (`<image>:<tag>`) from per-platform manifests that were
+// pushed earlier, given as `<image>@sha256:<hex>`. Manifests from other repositories are copied
+// into the target repository together with their blobs.
+pub async fn create_index(target: &str, manifests: &[String], platform_tags: bool) -> Result<CreatedIndex> {
+    let (image, tag) = parse_target(target)?;
+    if manifests.is_empty() {
+        bail!("An index needs at least one manifest");
+    }
+
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    capabilities::require(&client, &env_vars.r2_bucket, "create-index", &[Capability::Read, Capability::Write]).await?;
+
+    let mut children = Vec::new();
+    for reference in manifests {
+        let (source, digest) = reference.split_once('@').context(format!("{} is not a digest reference (expected <image>@sha256:<hex>)", reference))?;
+        children.push(import_manifest(&env_vars, &client, source, digest, image).await?);
+    }
+
+    let docker = children.iter().all(|child| child.descriptor["mediaType"] == DOCKER_MANIFEST);
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": if docker { DOCKER_MANIFEST_LIST } else { OCI_INDEX },
+        "manifests": children.iter().map(|child| child.descriptor.clone()).collect::<Vec<_>>(),
+    });
+
+    let digest = put_index(&env_vars, &client, image, tag, &index).await?;
+    catalog::update_tags(&env_vars, image, tag).await?;
+    if platform_tags {
+        tag_platforms(&env_vars, &client, image, tag, &index).await?;
+    }
+    catalog::update_catalog(&env_vars, image).await?;
+
+    let platforms: Vec<String> = children.into_iter().map(|child| child.platform).collect();
+    log::info!("Created index {}:{} ({}) for {}", image, tag, digest, platforms.join(", "));
+
+    Ok(CreatedIndex {
+        image: image.to_owned(),
+        tag: tag.to_owned(),
+        digest,
+        platforms,
+    })
+}
+
+// Pushes `source` as another platform of the index tagged `target` and adds it to the index. The
+// index is rewritten with a conditional write, so platforms added concurrently are all kept; a
+// platform already in the index is replaced.
+pub async fn add_to_index(target: &str, source: &str, options: PushOptions) -> Result<CreatedIndex> {
+    let (image, tag) = parse_target(target)?;
+    let platform_tags = options.platform_tags;
+
+    // Checked before anything is pushed, so a missing index or a tag naming a single manifest fails
+    // without leaving an orphaned platform behind.
+    let env_vars = r2configs::parse_r2configs()?;
+    let config = config::load_config()?;
+    let prefixed = match destination::resolve(&config)? {
+        Some((_, destination)) => destination::prefixed(destination, image),
+        None => image.to_owned(),
+    };
+    read_index(cas::read_object(&env_vars, &format!("v2/{}/manifests/{}", prefixed, tag)).await?.as_deref(), &prefixed, tag)?;
+
+    // The tag names the index, which is updated below instead of replaced by the pushed manifest.
+    let options = PushOptions {
+        source: Some(source.to_owned()),
+        no_tag: true,
+        ..options
+    };
+    let report = crate::run_with_options(image.to_owned(), tag.to_owned(), options).await?;
+    let (image, tag) = (report.image.as_str(), report.tag.as_str());
+    let pushed = report.digest.as_deref().context(format!("The manifest pushed from {} has no digest", source))?;
+
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    let child = import_manifest(&env_vars, &client, image, pushed, image).await?;
+
+    let tag_key = format!("v2/{}/manifests/{}", image, tag);
+    let mut updated = Vec::new();
+    let mut platforms = Vec::new();
+    cas::update_object(&env_vars, &tag_key, OCI_INDEX, |current| {
+        let mut index = read_index(current, image, tag)?;
+        let manifests = index["manifests"].as_array_mut().context(format!("{}:{} has no manifests", image, tag))?;
+        manifests.retain(|manifest| manifest["platform"] != child.descriptor["platform"]);
+        manifests.push(child.descriptor.clone());
+        platforms = manifests.iter().filter_map(|manifest| platform_name(&manifest["platform"])).collect();
+
+        // A Docker manifest list cannot reference OCI manifests.
+        if child.descriptor["mediaType"] != DOCKER_MANIFEST {
+            index["mediaType"] = json!(OCI_INDEX);
+        }
+
+        updated = serde_json::to_vec(&index)?;
+        Ok(Some(updated.clone()))
+    })
+    .await?;
+
+    let digest = sha256(&updated);
+    let media_type = serde_json::from_slice::<Value>(&updated)?["mediaType"].as_str().unwrap_or(OCI_INDEX).to_owned();
+    put(&client, &env_vars.r2_bucket, &format!("v2/{}/manifests/{}", image, digest), updated.clone(), &media_type).await?;
+    if platform_tags {
+        tag_platforms(&env_vars, &client, image, tag, &serde_json::from_slice(&updated)?).await?;
+    }
+
+    log::info!("Added {} to {}:{} ({})", child.platform, image, tag, digest);
+
+    Ok(CreatedIndex {
+        image: image.to_owned(),
+        tag: tag.to_owned(),
+        digest,
+        platforms,
+    })
+}
+
+fn read_index(current: Option<&[u8]>, image: &str, tag: &str) -> Result<Value> {
+    let current = current.context(format!("{}:{} does not exist, create it with create_index first", image, tag))?;
+    let index: Value = serde_json::from_slice(current).context(format!("{}:{} is not JSON", image, tag))?;
+    let media_type = index["mediaType"].as_str().unwrap_or_default();
+    if media_type != OCI_INDEX && media_type != DOCKER_MANIFEST_LIST {
+        bail!("{}:{} is not an image index", image, tag);
+    }
+
+    Ok(index)
+}
+
+pub(crate) fn parse_target(target: &str) -> Result<(&str, &str)> {
+    match target.rsplit_once(':') {
+        Some((image, tag)) if !image.is_empty() && !tag.is_empty() && !tag.contains('/') => {
+            names::validate_repository_name(image)?;
+            names::validate_tag(tag)?;
+            Ok((image, tag))
+        }
+        _ => bail!("{} is not a tagged reference (expected <image>:<tag>)", target),
+    }
+}
+
+// Writes the index under its tag and by digest.
+pub(crate) async fn put_index(env_vars: &R2Configs, client: &S3Client, image: &str, tag: &str, index: &Value) -> Result<String> {
+    let data = serde_json::to_vec(index)?;
+    let media_type = index["mediaType"].as_str().unwrap_or(OCI_INDEX);
+
+    cas::update_object(env_vars, &format!("v2/{}/manifests/{}", image, tag), media_type, |_| Ok(Some(data.clone()))).await?;
+
+    let digest = sha256(&data);
+    put(client, &env_vars.r2_bucket, &format!("v2/{}/manifests/{}", image, digest), data, media_type).await?;
+
+    Ok(digest)
+}
+
+// Makes `<source>@<digest>` available as `v2/<image>/manifests/<digest>` and returns its index
+// descriptor, with the platform read from its config.
+pub(crate) async fn import_manifest(env_vars: &R2Configs, client: &S3Client, source: &str, digest: &str, image: &str) -> Result<PlatformManifest> {
+    let (manifest_key, data) = read_by_digest(env_vars, client, source, digest).await?.context(format!("{}@{} is not in the bucket", source, digest))?;
+    let manifest: Value = serde_json::from_slice(&data).context(format!("{}@{} is not a manifest", source, digest))?;
+
+    let media_type = manifest["mediaType"].as_str().unwrap_or(OCI_MANIFEST);
+    if media_type != OCI_MANIFEST && media_type != DOCKER_MANIFEST {
+        bail!("{}@{} is a {}, only image manifests can be added to an index", source, digest, media_type);
+    }
+
+    let config_digest = manifest["config"]["digest"].as_str().context(format!("{}@{} has no config", source, digest))?;
+    let (config_key, config_data) = read_by_digest(env_vars, client, source, config_digest).await?.context(format!("Config {} of {}@{} is not in the bucket", config_digest, source, digest))?;
+    let config: Value = serde_json::from_slice(&config_data).context(format!("Config {} is not JSON", config_digest))?;
+
+    let mut platform = json!({
+        "os": config["os"].as_str().context(format!("Config {} has no os", config_digest))?,
+        "architecture": config["architecture"].as_str().context(format!("Config {} has no architecture", config_digest))?,
+    });
+    if let Some(variant) = config["variant"].as_str() {
+        platform["variant"] = json!(variant);
+    }
+    let platform_name = platform_name(&platform).unwrap_or_default();
+
+    if source != image {
+        copy_object(client, &env_vars.r2_bucket, &config_key, &rebase(&config_key, source, image)).await?;
+        for layer in manifest["layers"].as_array().into_iter().flatten() {
+            let layer_digest = layer["digest"].as_str().context("Layer has no digest")?;
+            let layer_key = locate(env_vars, client, source, layer_digest).await?.context(format!("Layer {} of {}@{} is not in the bucket", layer_digest, source, digest))?;
+            copy_layer(client, &env_vars.r2_bucket, &layer_key, source, image).await?;
+        }
+    }
+
+    let target_key = format!("v2/{}/manifests/{}", image, digest);
+    if manifest_key != target_key {
+        put(client, &env_vars.r2_bucket, &target_key, data.clone(), media_type).await?;
+    }
+
+    Ok(PlatformManifest {
+        descriptor: json!({
+            "mediaType": media_type,
+            "digest": digest,
+            "size": data.len(),
+            "platform": platform,
+        }),
+        platform: platform_name,
+    })
+}
+
+// Tags every platform manifest of the index tagged `tag` as `<tag>-<platform>`, e.g. `1.0-amd64`,
+// `1.0-armv7` or `1.0-windows-amd64`, for clients that cannot resolve indexes.
+pub(crate) async fn tag_platforms(env_vars: &R2Configs, client: &S3Client, image: &str, tag: &str, index: &Value) -> Result<Vec<String>> {
+    let mut tags = Vec::new();
+    for manifest in index["manifests"].as_array().into_iter().flatten() {
+        let platform = &manifest["platform"];
+        let (Some(os), Some(architecture)) = (platform["os"].as_str(), platform["architecture"].as_str()) else {
+            continue;
+        };
+        // Attestations are attached to the index as `unknown/unknown` manifests.
+        if os == "unknown" {
+            continue;
+        }
+
+        let mut alias = match os {
+            "linux" => format!("{}-{}", tag, architecture),
+            _ => format!("{}-{}-{}", tag, os, architecture),
+        };
+        alias.extend(platform["variant"].as_str());
+        if let Err(e) = names::validate_tag(&alias) {
+            log::warn!("Not tagging the {}/{} manifest of {}:{}: {:#}", os, architecture, image, tag, e);
+            continue;
+        }
+
+        let digest = manifest["digest"].as_str().context(format!("A manifest of {}:{} has no digest", image, tag))?;
+        let (_, data) = read_by_digest(env_vars, client, image, digest).await?.context(format!("{}@{} is not in the bucket", image, digest))?;
+        let media_type = manifest["mediaType"].as_str().unwrap_or(OCI_MANIFEST);
+
+        cas::update_object(env_vars, &format!("v2/{}/manifests/{}", image, alias), media_type, |_| Ok(Some(data.clone()))).await?;
+        catalog::update_tags(env_vars, image, &alias).await?;
+        tags.push(alias);
+    }
+
+    log::info!("Tagged platforms of {}:{} as {}", image, tag, tags.join(", "));
+
+    Ok(tags)
+}
+
+// `os/architecture[/variant]`, as accepted by `--platform`.
+pub(crate) fn platform_name(platform: &Value) -> Option<String> {
+    let os = platform["os"].as_str()?;
+    let architecture = platform["architecture"].as_str()?;
+
+    Some(match platform["variant"].as_str() {
+        Some(variant) => format!("{}/{}/{}", os, architecture, variant),
+        None => format!("{}/{}", os, architecture),
+    })
+}
+
+// Finds an object of `image` by content digest: manifests stored under their digest directly,
+// anything else through the repository's checksums.
+async fn read_by_digest(env_vars: &R2Configs, client: &S3Client, image: &str, digest: &str) -> Result<Option<(String, Vec<u8>)>> {
+    let key = match locate(env_vars, client, image, digest).await? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+
+    let data = cas::read_object(env_vars, &key).await?.context(format!("{} disappeared", key))?;
+    if sha256(&data) != digest {
+        bail!("{} does not match its digest {}", key, digest);
+    }
+
+    Ok(Some((key, data)))
+}
+
+async fn locate(env_vars: &R2Configs, client: &S3Client, image: &str, digest: &str) -> Result<Option<String>> {
+    for kind in ["manifests", "blobs"] {
+        let key = format!("v2/{}/{}/{}", image, kind, digest);
+        if chunks::exists(client, &env_vars.r2_bucket, &key).await? {
+            return Ok(Some(key));
+        }
+    }
+
+    checksums::locate(env_vars, image, digest.trim_start_matches("sha256:")).await
+}
+
+// Chunked layers are copied as their recipe, the chunks themselves are shared by all repositories.
+// Delta layers depend on bases of their own repository and cannot be copied.
+async fn copy_layer(client: &S3Client, r2_bucket: &str, key: &str, source: &str, image: &str) -> Result<()> {
+    if chunks::exists(client, r2_bucket, key).await? {
+        return copy_object(client, r2_bucket, key, &rebase(key, source, image)).await;
+    }
+
+    // Rebuilt from the blob name, the repository itself may have a component named `blobs`.
+    let recipe = chunks::recipe_key(source, key.rsplit_once('/').map_or(key, |(_, name)| name));
+    if chunks::exists(client, r2_bucket, &recipe).await? {
+        return copy_object(client, r2_bucket, &recipe, &rebase(&recipe, source, image)).await;
+    }
+
+    bail!("{} is stored as a delta and cannot be copied to {}, push it to {} instead", key, image, image)
+}
+
+fn rebase(key: &str, source: &str, image: &str) -> String {
+    key.replacen(&format!("v2/{}/", source), &format!("v2/{}/", image), 1)
+}
+
+async fn copy_object(client: &S3Client, r2_bucket: &str, source_key: &str, key: &str) -> Result<()> {
+    let req = CopyObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        copy_source: format!("{}/{}", r2_bucket, source_key),
+        ..Default::default()
+    };
+    client.copy_object(req).await.context(format!("Failed to copy {} to {}", source_key, key))?;
+
+    Ok(())
+}
+
+async fn put(client: &S3Client, r2_bucket: &str, key: &str, data: Vec<u8>, content_type: &str) -> Result<()> {
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        body: Some(data.into()),
+        content_type: Some(content_type.to_owned()),
+        ..Default::default()
+    };
+    client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+
+    Ok(())
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use futures::stream::{self, StreamExt};
+use rusoto_s3::S3Client;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::budget::{self, OpEstimate};
+use crate::capabilities::{self, Capability};
+use crate::findings::Finding;
+use crate::names;
+use crate::progress::{Progress, ProgressEvent};
+use crate::r2configs::{self, R2Configs};
+use crate::v2;
+use crate::v2::cas;
+use crate::v2::lister::{ListedObject, Lister};
+use crate::v2::verify::{claimed_digest, object_digest};
+use crate::validate::Severity;
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    // Only objects of this repository.
+    pub repository: Option<String>,
+    // Only the manifest behind this tag of `repository` and the objects it references.
+    pub tag: Option<String>,
+    // Objects downloaded at once, 8 when 0.
+    pub concurrency: usize,
+    pub requests_per_second: Option<u32>,
+    pub progress: Progress,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptObject {
+    pub key: String,
+    pub problem: String,
+}
+
+impl fmt::Display for CorruptObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.problem)
+    }
+}
+
+impl From<&CorruptObject> for Finding {
+    fn from(corrupt: &CorruptObject) -> Self {
+        Finding { rule: "digest-mismatch".to_owned(), severity: Severity::Error, location: corrupt.key.clone(), message: Some(corrupt.problem.clone()) }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    pub objects: usize,
+    pub bytes: u64,
+    pub corrupt: Vec<CorruptObject>,
+}
+
+// Downloads the blobs and manifests in the bucket again and checks that each hashes to the digest
+// its key claims. Objects are streamed through the hasher, a few at a time, so memory use does not
+// grow with their size; keys that are not digests (tags) are only checked to be valid JSON.
+pub async fn verify(options: VerifyOptions) -> Result<VerifyReport> {
+    if options.tag.is_some() && options.repository.is_none() {
+        bail!("Verifying a tag needs its repository");
+    }
+
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    capabilities::require(&client, &env_vars.r2_bucket, "verify", &[Capability::Read, Capability::List]).await?;
+
+    let prefix = match &options.repository {
+        Some(repository) => format!("v2/{}/", repository),
+        None => "v2/".to_owned(),
+    };
+    let listed = Lister::new(&client, &env_vars.r2_bucket)
+        .concurrency(8)
+        .requests_per_second(options.requests_per_second)
+        .list(&prefix)
+        .await?;
+    let mut objects: Vec<ListedObject> = listed.into_iter().filter(is_content).collect();
+    if let Some(repository) = &options.repository {
+        objects.retain(|object| names::owns_key(repository, &object.key));
+    }
+    if let (Some(repository), Some(tag)) = (&options.repository, &options.tag) {
+        let keys = referenced_keys(&env_vars, repository, tag, &objects).await?;
+        objects.retain(|object| keys.contains(&object.key));
+    }
+
+    budget::check_estimate("verify", OpEstimate { class_b: objects.len() as u64, ..Default::default() })?;
+    let bytes = objects.iter().map(|object| object.size).sum();
+    options.progress.emit(ProgressEvent::VerifyPlanned { objects: objects.len(), bytes });
+    log::info!("Verifying {} objects ({} bytes) under {}", objects.len(), bytes, prefix);
+
+    let limiter = options.requests_per_second.filter(|rps| *rps > 0).map(|rps| {
+        let mut interval = time::interval(Duration::from_secs(1) / rps);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Mutex::new(interval)
+    });
+    let (env_vars, client, limiter, progress) = (&env_vars, &client, &limiter, &options.progress);
+    let corrupt: Vec<CorruptObject> = stream::iter(&objects)
+        .map(|object| async move {
+            if let Some(limiter) = limiter {
+                limiter.lock().await.tick().await;
+            }
+            let problem = check(env_vars, client, &object.key).await;
+            progress.emit(ProgressEvent::Verified { key: object.key.clone(), bytes: object.size });
+            problem.map(|problem| CorruptObject { key: object.key.clone(), problem })
+        })
+        .buffer_unordered(if options.concurrency == 0 { 8 } else { options.concurrency })
+        .filter_map(|corrupt| async move { corrupt })
+        .collect()
+        .await;
+
+    for corrupt in &corrupt {
+        log::error!("{}", corrupt);
+    }
+    match corrupt.len() {
+        0 => log::info!("Verified {} objects", objects.len()),
+        count => log::warn!("{} of {} objects failed verification", count, objects.len()),
+    }
+
+    Ok(VerifyReport { objects: objects.len(), bytes, corrupt })
+}
+
+// What is wrong with the object at `key`, if anything.
+pub(crate) async fn check(env_vars: &R2Configs, client: &S3Client, key: &str) -> Option<String> {
+    let result = match claimed_digest(key) {
+        Some(claimed) => object_digest(client, &env_vars.r2_bucket, key, claimed).await.map(|(actual, _)| (actual != claimed).then(|| format!("content hashes to {}", actual))),
+        None => read_manifest(env_vars, key).await.map(|_| None),
+    };
+
+    match result {
+        Ok(problem) => problem,
+        Err(e) => Some(format!("{:#}", e)),
+    }
+}
+
+pub(crate) fn is_content(object: &ListedObject) -> bool {
+    matches!(crate::list::split_key(&object.key), Some((_, "manifests" | "blobs", _)))
+}
+
+// The tag's manifest, the child manifests of an index and every blob they reference, as far as
+// they are stored under their digest.
+async fn referenced_keys(env_vars: &R2Configs, repository: &str, tag: &str, objects: &[ListedObject]) -> Result<BTreeSet<String>> {
+    let listed: BTreeSet<&str> = objects.iter().map(|object| object.key.as_str()).collect();
+    let tag_key = format!("v2/{}/manifests/{}", repository, tag);
+    if !listed.contains(tag_key.as_str()) {
+        bail!("{}:{} does not exist", repository, tag);
+    }
+
+    let mut keys = BTreeSet::from([tag_key.clone()]);
+    let mut pending = vec![tag_key];
+    while let Some(key) = pending.pop() {
+        let data = cas::read_object(env_vars, &key).await?.context(format!("{} does not exist", key))?;
+        let Ok(manifest) = serde_json::from_slice::<Value>(&data) else {
+            continue;
+        };
+
+        let descriptors = manifest["manifests"].as_array().into_iter().flatten().chain(manifest["layers"].as_array().into_iter().flatten()).chain([&manifest["config"]]);
+        for digest in descriptors.filter_map(|descriptor| descriptor["digest"].as_str()) {
+            for kind in ["manifests", "blobs"] {
+                let key = format!("v2/{}/{}/{}", repository, kind, digest);
+                if listed.contains(key.as_str()) && keys.insert(key.clone()) && kind == "manifests" {
+                    pending.push(key);
+                }
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+async fn read_manifest(env_vars: &R2Configs, key: &str) -> Result<()> {
+    let data = cas::read_object(env_vars, key).await?.context(format!("{} does not exist", key))?;
+    serde_json::from_slice::<Value>(&data).context("not valid JSON")?;
+
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::{json, Value};
+use tokio::sync::Semaphore;
+
+use crate::batch::BatchJob;
+use crate::run_with_options;
+
+const FINISHED_JOBS_KEPT: usize = 1000;
+// Backoffs double per attempt up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The wait after the `attempt`th failed attempt, counting from 1.
+    pub(crate) fn backoff_after(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.checked_mul(factor).unwrap_or(MAX_BACKOFF).min(MAX_BACKOFF)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JobQueueConfig {
+    pub concurrency: usize,
+    pub retry: RetryPolicy,
+}
+
+impl Default for JobQueueConfig {
+    fn default() -> Self {
+        JobQueueConfig {
+            concurrency: 2,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Retrying,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_active(self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running | JobState::Retrying)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Retrying => "retrying",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JobStatus {
+    pub id: u64,
+    pub image: String,
+    pub tag: String,
+    pub source: Option<String>,
+    pub digest: Option<String>,
+    pub state: JobState,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+impl JobStatus {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "image": self.image,
+            "tag": self.tag,
+            "source": self.source,
+            "digest": self.digest,
+            "state": self.state.as_str(),
+            "attempts": self.attempts,
+            "error": self.error,
+        })
+    }
+
+    fn same_work(&self, job: &BatchJob, digest: Option<&str>) -> bool {
+        self.image == job.image
+            && self.tag == job.tag
+            && self.source == job.options.source
+            && self.digest.as_deref() == digest
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    statuses: BTreeMap<u64, JobStatus>,
+}
+
+#[derive(Clone)]
+pub struct JobQueue {
+    config: JobQueueConfig,
+    jobs: Arc<Mutex<Jobs>>,
+    workers: Arc<Semaphore>,
+}
+
+impl JobQueue {
+    pub fn new(config: JobQueueConfig) -> Self {
+        JobQueue {
+            config,
+            jobs: Arc::new(Mutex::new(Jobs::default())),
+            workers: Arc::new(Semaphore::new(config.concurrency.max(1))),
+        }
+    }
+
+    // Returns the job id and whether the job was collapsed into an identical job that is still
+    // queued or running.
+    pub fn enqueue(&self, job: BatchJob, digest: Option<String>, retry: Option<RetryPolicy>) -> (u64, bool) {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        let duplicate = jobs
+            .statuses
+            .values()
+            .find(|status| status.state.is_active() && status.same_work(&job, digest.as_deref()));
+        if let Some(duplicate) = duplicate {
+            return (duplicate.id, true);
+        }
+
+        jobs.next_id += 1;
+        let id = jobs.next_id;
+        jobs.statuses.insert(id, JobStatus {
+            id,
+            image: job.image.clone(),
+            tag: job.tag.clone(),
+            source: job.options.source.clone(),
+            digest,
+            state: JobState::Queued,
+            attempts: 0,
+            error: None,
+        });
+        prune_finished(&mut jobs);
+
+        let queue = self.clone();
+        let retry = retry.unwrap_or(self.config.retry);
+        tokio::spawn(async move { queue.run(id, job, retry).await });
+
+        (id, false)
+    }
+
+    pub fn jobs(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().statuses.values().cloned().collect()
+    }
+
+    pub fn job(&self, id: u64) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().statuses.get(&id).cloned()
+    }
+
+    async fn run(self, id: u64, job: BatchJob, retry: RetryPolicy) {
+        let _permit = self.workers.acquire().await.expect("job queue semaphore closed");
+        let reference = format!("{}:{}", job.image, job.tag);
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.update(id, |status| {
+                status.state = JobState::Running;
+                status.attempts = attempt;
+            });
+            log::info!("Starting push job {} for {} (attempt {}/{})", id, reference, attempt, retry.max_attempts);
+
+            let result = run_with_options(job.image.clone(), job.tag.clone(), job.options.clone()).await;
+            let e = match result {
+                Ok(_) => {
+                    self.update(id, |status| {
+                        status.state = JobState::Succeeded;
+                        status.error = None;
+                    });
+                    log::info!("Push job {} for {} finished", id, reference);
+                    return;
+                }
+                Err(e) => format!("{:#}", e),
+            };
+
+            if attempt >= retry.max_attempts {
+                log::error!("Push job {} for {} failed: {}", id, reference, e);
+                self.update(id, |status| {
+                    status.state = JobState::Failed;
+                    status.error = Some(e);
+                });
+                return;
+            }
+
+            let backoff = retry.backoff_after(attempt);
+            log::warn!("Push job {} for {} failed, retrying in {:?}: {}", id, reference, backoff, e);
+            self.update(id, |status| {
+                status.state = JobState::Retrying;
+                status.error = Some(e);
+            });
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    fn update<F: FnOnce(&mut JobStatus)>(&self, id: u64, f: F) {
+        if let Some(status) = self.jobs.lock().unwrap().statuses.get_mut(&id) {
+            f(status);
+        }
+    }
+}
+
+fn prune_finished(jobs: &mut Jobs) {
+    let finished: Vec<u64> = jobs
+        .statuses
+        .values()
+        .filter(|status| !status.state.is_active())
+        .map(|status| status.id)
+        .collect();
+
+    for id in finished.iter().take(finished.len().saturating_sub(FINISHED_JOBS_KEPT)) {
+        jobs.statuses.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let retry = RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_secs(5),
+        };
+        assert_eq!(retry.backoff_after(1), Duration::from_secs(5));
+        assert_eq!(retry.backoff_after(2), Duration::from_secs(10));
+        assert_eq!(retry.backoff_after(3), Duration::from_secs(20));
+        assert_eq!(retry.backoff_after(8), MAX_BACKOFF);
+        assert_eq!(retry.backoff_after(u32::MAX), MAX_BACKOFF);
+        assert_eq!(RetryPolicy { max_attempts: 5, backoff: Duration::ZERO }.backoff_after(3), Duration::ZERO);
+    }
+}
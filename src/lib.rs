@@ -1,100 +1,363 @@
 mod r2configs;
 mod v2;
 mod hash_utils;
+mod converter;
+mod progress;
+mod batch;
+mod budget;
+mod hooks;
+mod jobs;
+mod config;
+mod conformance;
+mod control;
+mod notify;
+mod pull_config;
+mod serve;
+mod capabilities;
+mod build_info;
+mod filter;
+mod list;
+mod names;
+mod mirror;
+mod metadata;
+mod lockfile;
+mod stats;
+mod status;
+mod smoke;
+mod prewarm;
+mod replication;
+mod policy;
+mod quota;
+mod usage;
+mod expiry;
+mod delete;
+mod preview;
+mod image_policy;
+mod provenance;
+mod discover;
+mod recompress;
+mod redact;
+mod upstream;
+mod proxy;
+mod pulls;
+mod trace;
+mod diagnostics;
+mod validate;
+mod dotenv;
+mod destination;
+mod annotations;
+mod receipt;
+mod index;
+mod import;
+mod integrity;
+mod backup;
+mod fsck;
+mod findings;
+mod pipeline;
+mod workdir;
+mod plan;
+mod estimate;
+mod canonical;
+mod history;
+#[cfg(feature = "tui")]
+mod tui;
 
-use std::fs;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use anyhow::{bail, Context, Result};
-use tempfile::TempDir;
+use std::time::SystemTime;
+use anyhow::{bail, Result};
 
-pub async fn run(image: String, tag: String) -> Result<()> {
-    let script_dir = Path::new("--").parent().unwrap().to_owned();
-    let tmp_dir = TempDir::new_in(&script_dir)?;
+use pipeline::{Pipeline, PipelineDeps};
+use status::StatusFile;
 
-    check_skopeo("skopeo")?;
+pub use backup::{backup, restore, BackupOptions, BackupSummary};
+pub use batch::{parse_batch_file, run_batch, BatchJob};
+pub use budget::{operations_used, set_operation_budget, OpEstimate};
+pub use build_info::build_info;
+pub use canonical::ManifestEncoding;
+pub use capabilities::{probe_capabilities, Capability};
+pub use config::{
+    load_config, ApprovedBase, AuthConfig, BlobCacheConfig, CacheControl, Config, ConvertRetryConfig, DestinationConfig, ImagePolicyConfig, MessageConfig, NotifierConfig, NotifyOn, PolicyAction, PricingConfig, PullEventsConfig, QuotaConfig,
+    RedactionConfig, ReplicaConfig, RewriteRule, ScrubConfig, ServeSettings, UserConfig,
+};
+pub use conformance::{conformance, ConformanceCheck};
+pub use control::UploadControl;
+pub use converter::{ConverterKind, SourceLocation};
+pub use destination::select_destination;
+pub use delete::delete_tag;
+pub use diagnostics::install_diagnostics_logger;
+pub use discover::{discover, Referrer};
+pub use dotenv::{disable_dotenv, load_dotenv};
+pub use estimate::{estimate, PushEstimate};
+pub use expiry::{parse_expiry, prune_expired, PrunedTag};
+pub use filter::ImageFilter;
+pub use findings::{findings_report, Finding, ReportFormat};
+pub use fsck::{fsck, Drift};
+pub use hooks::{serve_hooks, HookServerConfig};
+pub use image_policy::check_image_policy;
+pub use import::{import_registry, ImportOptions, ImportReport};
+pub use index::{add_to_index, create_index, CreatedIndex};
+pub use integrity::{verify, CorruptObject, VerifyOptions, VerifyReport};
+pub use jobs::{JobQueue, JobQueueConfig, JobState, JobStatus, RetryPolicy};
+pub use list::{list_repositories, ListOptions, RepositorySummary};
+pub use metadata::TagMetadata;
+pub use mirror::{mirror_tags, sync_repository, MirrorReport};
+pub use names::{sanitize_repository_name, validate_repository_name, validate_tag};
+pub use plan::{apply, plan, Operation, PlannedOperation, PushPlan};
+pub use progress::{Phase, Progress, ProgressEvent};
+pub use proxy::{connections_opened, set_proxy};
+pub use pull_config::{configure_pull, GeneratedFile, PullConfigFormat};
+pub use pulls::{import_pull_log, RepositoryPulls};
+pub use receipt::ReceiptKey;
+pub use recompress::{Compression, Recompression};
+pub use replication::{replication_status, ReplicationStatus};
+pub use serve::{serve, ServeConfig};
+pub use smoke::{smoke_test, SmokeTestReport};
+pub use stats::{BlobTiming, LayerRecompression, PushStats, ReusedBlob};
+pub use status::{default_status_file, follow_status, running_pushes, status_dir, PushStatus};
+pub use upstream::{check_upstream, UpstreamCheckOptions, UpstreamStatus};
+pub use usage::{usage_csv, usage_json, usage_report, GroupBy, UsageOptions, UsageRow};
+pub use v2::memory::parse_size;
+pub use v2::multipart::ParallelUpload;
+pub use v2::verify::VerifyMode;
+pub use validate::{validate_config, ConfigIssue, Severity};
+pub use workdir::work_dir;
+#[cfg(feature = "tui")]
+pub use tui::run_batch_tui;
 
-    let env_vars = r2configs::parse_r2configs()?;
+#[derive(Clone, Debug, Default)]
+pub struct PushOptions {
+    pub converter: ConverterKind,
+    pub source: Option<String>,
+    pub progress: Progress,
+    pub verify: VerifyMode,
+    pub max_memory: Option<u64>,
+    pub prewarm: Option<usize>,
+    pub policy: Option<PathBuf>,
+    pub ignore_quota: bool,
+    pub expires: Option<SystemTime>,
+    pub preview: Option<String>,
+    pub provenance: bool,
+    pub signing_key: Option<PathBuf>,
+    pub chunked: bool,
+    pub delta: bool,
+    pub recompress: Option<Recompression>,
+    pub diagnostics: Option<PathBuf>,
+    pub annotations: BTreeMap<String, String>,
+    pub cache_control: CacheControl,
+    pub receipt_key: Option<ReceiptKey>,
+    pub list_existing: bool,
+    pub platform_tags: bool,
+    pub oci_layout: bool,
+    pub check_reproducible: bool,
+    pub manifest_encoding: ManifestEncoding,
+    pub strip_history: bool,
+    pub parallel_upload: Option<ParallelUpload>,
+    // Blobs uploaded at once at most; by default 16 small and 4 large blobs.
+    pub concurrency: Option<usize>,
+    pub status_file: Option<PathBuf>,
+    pub control: Option<UploadControl>,
+    pub control_socket: Option<PathBuf>,
+    pub nice: bool,
+    // Copies blobs that earlier versions stored under their BLAKE3 name instead of uploading them
+    // again, at the cost of hashing every new blob. Meant for the pushes that migrate a bucket.
+    pub adopt_legacy: bool,
+    // Falls back to `[convert_retry]` of the config, then to `RetryPolicy::default()`.
+    pub convert_retry: Option<RetryPolicy>,
+    pub sources: Vec<SourceLocation>,
+    // A directory with a docker `config.json` holding the credentials skopeo and crane pull with,
+    // in place of the user's own.
+    pub registry_config: Option<PathBuf>,
+    pub lockfile: Option<PathBuf>,
+    pub update_lockfile: bool,
+    // Uploads the image by digest only, for content that other manifests reference by digest
+    // (signatures, attestations, referrers). The tag only names the source; the tag's manifest, the
+    // tag list, platform tags, expiry and the OCI layout are left as they are.
+    pub no_tag: bool,
+    // Fails the push unless signed provenance is attached. Set by destinations with
+    // `require_signing`.
+    pub require_signing: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PushReport {
+    pub image: String,
+    pub tag: String,
+    pub digest: Option<String>,
+    pub manifest_url: Option<String>,
+    pub pull_reference: Option<String>,
+    pub expires: Option<SystemTime>,
+    pub request_id: String,
+    pub receipt: Option<String>,
+    pub stats: PushStats,
+}
 
-    let status = convert_oci(&image, &tag, &tmp_dir)?;
-    if !status.success() {
-        bail!("Failed to convert image");
+impl PushReport {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "image": self.image,
+            "tag": self.tag,
+            "digest": self.digest,
+            "manifest_url": self.manifest_url,
+            "pull_reference": self.pull_reference,
+            "expires": self.expires.map(|expires| humantime::format_rfc3339_seconds(expires).to_string()),
+            "request_id": self.request_id,
+            "receipt": self.receipt,
+            "stats": self.stats.to_json(),
+        })
     }
 
-    let (image_manifests_dir, image_blobs_dir) = prepare_dir(&script_dir, &image)?;
+    pub fn to_markdown(&self) -> String {
+        preview::markdown(self)
+    }
+}
 
-    move_files(&tmp_dir, &image_manifests_dir, &image_blobs_dir)?;
+pub async fn run(image: String, tag: String) -> Result<()> {
+    run_with_options(image, tag, PushOptions::default()).await?;
 
-    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    Ok(())
+}
 
-    v2::s3_upload::upload_blobs(&image, &image_blobs_dir, &client, &env_vars.r2_bucket).await?;
+pub async fn push_wasm(module: &Path, image: String, tag: String, options: PushOptions) -> Result<PushReport> {
+    let options = PushOptions {
+        converter: ConverterKind::Wasm,
+        source: Some(module.to_string_lossy().into_owned()),
+        ..options
+    };
 
-    v2::s3_upload::upload_manifests(&image, &image_manifests_dir, &client, &env_vars.r2_bucket).await?;
+    run_with_options(image, tag, options).await
+}
 
-    cleanup(tmp_dir, &script_dir, &image)?;
+pub async fn run_with_options(image: String, tag: String, mut options: PushOptions) -> Result<PushReport> {
+    let config = config::load_config()?;
+    let (image, tag) = resolve_target(image, tag, &mut options, &config)?;
 
-    Ok(())
+    execute(&image, &tag, &options, &config, None).await
 }
 
-fn check_skopeo(cmd: &str) -> Result<()> {
-    if Command::new(cmd).output().is_err() {
-        bail!("{} is not installed", cmd);
+// Applies the preview tag, the configured source chain and the selected destination's defaults and
+// image prefix.
+pub(crate) fn resolve_target(image: String, tag: String, options: &mut PushOptions, config: &Config) -> Result<(String, String)> {
+    if options.sources.is_empty() {
+        options.sources = config.sources.clone();
     }
 
-    Ok(())
+    let tag = match &options.preview {
+        Some(_) if options.no_tag => bail!("A preview push needs a tag, it cannot be combined with no_tag"),
+        Some(preview) => {
+            options.expires.get_or_insert_with(|| SystemTime::now() + preview::DEFAULT_TTL);
+            preview::preview_tag(preview, &tag)
+        }
+        None => tag,
+    };
+
+    let image = match destination::resolve(config)? {
+        Some((name, destination)) => {
+            destination::apply_defaults(&name, destination, options);
+            destination::prefixed(destination, &image)
+        }
+        None => image,
+    };
+    names::validate_repository_name(&image)?;
+    names::validate_tag(&tag)?;
+
+    Ok((image, tag))
 }
 
-fn convert_oci(image: &str, tag: &str, tmp_dir: &TempDir) -> Result<std::process::ExitStatus> {
-    Command::new("skopeo")
-        .arg("copy")
-        .arg("--all")
-        .arg(format!("docker-daemon:{}:{}", image, tag))
-        .arg(format!("dir:{}", tmp_dir.path().display()))
-        .status()
-        .context("Failed to execute skopeo command")
+pub(crate) fn resolve_destination_defaults(options: &mut PushOptions, config: &Config) -> Result<()> {
+    if let Some((name, destination)) = destination::resolve(config)? {
+        destination::apply_defaults(&name, destination, options);
+    }
+
+    Ok(())
 }
 
-fn prepare_dir(script_dir: &Path, image: &str) -> Result<(PathBuf, PathBuf)> {
-    let v2_dir = script_dir.join("v2");
-    fs::create_dir_all(&v2_dir)?;
+// Runs a push (or applies a plan) with a request id, and reports the outcome to diagnostics,
+// notifiers and replicas.
+pub(crate) async fn execute(image: &str, tag: &str, options: &PushOptions, config: &Config, plan: Option<&PushPlan>) -> Result<PushReport> {
+    let request_id = trace::new_request_id();
+    log::info!("Pushing {}:{} (request id {})", image, tag, request_id);
 
-    let image_manifests_dir = v2_dir.join(&image).join("manifests");
-    let image_blobs_dir = v2_dir.join(&image).join("blobs");
-    fs::create_dir_all(&image_manifests_dir)?;
-    fs::create_dir_all(&image_blobs_dir)?;
+    let status_file = match &options.status_file {
+        Some(path) => Some(StatusFile::create(path, &request_id, image, tag)?),
+        None => None,
+    };
+    let progress = match &status_file {
+        Some(file) => file.wrap(options.progress.clone()),
+        None => options.progress.clone(),
+    };
+    let heartbeat = status_file.as_ref().map(StatusFile::heartbeat);
 
-    Ok((image_manifests_dir, image_blobs_dir))
-}
+    let control = match &options.control {
+        None if options.control_socket.is_some() || options.nice => Some(UploadControl::default()),
+        control => control.clone(),
+    };
+    if let Some(control) = control.as_ref().filter(|control| options.nice && control.rate_limit().is_none()) {
+        control.set_rate_limit(Some(control::NICE_RATE_LIMIT));
+    }
+    let socket = match (&options.control_socket, &control) {
+        (Some(path), Some(control)) => Some(control::serve_socket(path, control.clone())?),
+        _ => None,
+    };
+    let options = &PushOptions { progress, control, ..options.clone() };
 
-fn move_files(tmp_dir: &TempDir, image_manifests_dir: &Path, image_blobs_dir: &Path) -> Result<()> {
-    for entry in fs::read_dir(tmp_dir.path())? {
-        let src = entry?.path();
-        let file_name = src.file_name().unwrap().to_string_lossy().into_owned();
+    let mut stats = PushStats::default();
+    let result = trace::scope(request_id.clone(), push(image, tag, options, config, plan, &mut stats))
+        .await
+        .map_err(|e| e.context(format!("Push of {}:{} failed (request id {})", image, tag, request_id)));
 
-        if file_name == "version" {
-            fs::remove_file(src)?;
-            continue;
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.abort();
+    }
+    if let (Some(socket), Some(path)) = (socket, &options.control_socket) {
+        socket.abort();
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(file) = &status_file {
+        file.finish(&result);
+    }
+
+    if let (Err(e), Some(dir)) = (&result, &options.diagnostics) {
+        match diagnostics::write_bundle(dir, &request_id, e, &stats, options) {
+            Ok(path) => log::error!("Wrote diagnostics bundle {}, attach it when reporting this failure", path.display()),
+            Err(bundle_error) => log::warn!("Failed to write the diagnostics bundle: {:#}", bundle_error),
         }
+    }
+    notify::notify_all(&config.notify, image, tag, &result).await;
 
-        let dst_dir = if file_name.ends_with(".manifest.json") {
-            &image_manifests_dir
-        } else {
-            &image_blobs_dir
-        };
+    if result.is_ok() && !config.replication.is_empty() {
+        // The primary push already succeeded, so lagging secondaries only warn and catch up on the
+        // next push of the image.
+        if let Err(e) = trace::scope(request_id.clone(), replicate(image, config)).await {
+            log::warn!("{:#}", e);
+        }
+    }
 
-        let hash = hash_utils::compute_blake3(&src)?;
-        let dst = dst_dir.join(hash);
+    result
+}
 
-        fs::rename(&src, &dst)?;
+async fn push(image: &str, tag: &str, options: &PushOptions, config: &Config, plan: Option<&PushPlan>, stats: &mut PushStats) -> Result<PushReport> {
+    let mut deps = PipelineDeps::from_env()?;
+    if let Some(plan) = plan {
+        deps.work_dir = plan.staging_dir.clone();
+        return Pipeline::new(image, tag, options, config, &deps).apply(plan, stats).await;
     }
 
-    Ok(())
+    // Every push stages in a directory of its own, so concurrent pushes of the same image neither
+    // mix their files nor remove each other's.
+    let staging_dir = tempfile::Builder::new().prefix(".oci-r2-push-").tempdir_in(&deps.work_dir)?;
+    deps.work_dir = staging_dir.path().to_owned();
+    Pipeline::new(image, tag, options, config, &deps).run(stats).await
 }
 
-fn cleanup(tmp_dir: TempDir, script_dir: &Path, image: &str) -> Result<()> {
-    tmp_dir.close()?;
-    let v2_dir = script_dir.join("v2").join(image);
-    fs::remove_dir_all(&v2_dir)?;
+async fn replicate(image: &str, config: &Config) -> Result<()> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
 
-    Ok(())
+    replication::replicate(&env_vars, &client, &config.replication, image).await
+}
+
+pub(crate) fn registry_host(public_url: &str) -> &str {
+    let host = public_url.split_once("://").map_or(public_url, |(_, rest)| rest);
+    host.split('/').next().unwrap_or(host)
 }
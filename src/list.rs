@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+
+use crate::metadata::{self, TagMetadata};
+use crate::names;
+use crate::pulls::{self, RepositoryPulls};
+use crate::r2configs;
+use crate::v2;
+use crate::v2::lister::Lister;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListOptions {
+    pub concurrency: usize,
+    pub requests_per_second: Option<u32>,
+    // Adds the pull counts recorded by serve mode.
+    pub with_pulls: bool,
+    // Adds the tag metadata recorded by `import_registry` with `with_metadata`.
+    pub with_metadata: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        ListOptions {
+            concurrency: 8,
+            requests_per_second: None,
+            with_pulls: false,
+            with_metadata: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepositorySummary {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub objects: u64,
+    pub bytes: u64,
+    pub pulls: Option<RepositoryPulls>,
+    pub metadata: BTreeMap<String, TagMetadata>,
+}
+
+pub async fn list_repositories(options: ListOptions) -> Result<Vec<RepositorySummary>> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+
+    let objects = Lister::new(&client, &env_vars.r2_bucket)
+        .concurrency(options.concurrency)
+        .requests_per_second(options.requests_per_second)
+        .list("v2/")
+        .await?;
+
+    let mut repositories: BTreeMap<String, RepositorySummary> = BTreeMap::new();
+    for object in objects {
+        let (name, kind, reference) = match split_key(&object.key) {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        let repository = repositories.entry(name.to_owned()).or_insert_with(|| RepositorySummary {
+            name: name.to_owned(),
+            ..Default::default()
+        });
+        repository.objects += 1;
+        repository.bytes += object.size;
+        if kind == "manifests" && !reference.contains(':') {
+            repository.tags.push(reference.to_owned());
+        }
+    }
+
+    // Repositories pushed before names were validated are still listed, so they can be deleted.
+    for name in repositories.keys() {
+        if let Err(e) = names::validate_repository_name(name) {
+            log::warn!("Clients cannot pull repository {}: {:#}", name, e);
+        }
+    }
+
+    if options.with_pulls {
+        let mut counts = pulls::read(&env_vars).await?;
+        for repository in repositories.values_mut() {
+            repository.pulls = Some(counts.repositories.remove(&repository.name).unwrap_or_default());
+        }
+    }
+
+    if options.with_metadata {
+        for repository in repositories.values_mut() {
+            repository.metadata = metadata::read(&env_vars, &repository.name).await?;
+        }
+    }
+
+    Ok(repositories.into_values().collect())
+}
+
+// Splits `v2/<name>/<blobs|manifests>/<reference>` where `<name>` may contain slashes.
+pub(crate) fn split_key(key: &str) -> Option<(&str, &str, &str)> {
+    let rest = key.strip_prefix("v2/")?;
+    for kind in ["manifests", "blobs"] {
+        if let Some((name, reference)) = rest.rsplit_once(&format!("/{}/", kind)) {
+            if !name.is_empty() && !reference.is_empty() && !reference.contains('/') {
+                return Some((name, kind, reference));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_key_keeps_nested_names() {
+        assert_eq!(split_key("v2/app/manifests/1.0"), Some(("app", "manifests", "1.0")));
+        assert_eq!(split_key("v2/org/team/app/blobs/sha256:abc"), Some(("org/team/app", "blobs", "sha256:abc")));
+        assert_eq!(split_key("v2/blobs/app/blobs/sha256:abc"), Some(("blobs/app", "blobs", "sha256:abc")));
+        assert_eq!(split_key("v2/app/manifests/"), None);
+        assert_eq!(split_key("v2/app/recipes/sha256:abc"), None);
+        assert_eq!(split_key("app/manifests/1.0"), None);
+    }
+}
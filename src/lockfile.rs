@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::converter;
+
+const HEADER: &str = "# Upstream digests of mirrored tags, written by oci-r2-uploader. Commit this file and review\n# changes to it: a changed digest means the tag moved upstream.\n";
+
+// `images.lock`: the upstream digest each mirrored tag is pinned to, one table per source, e.g.
+//
+//   ["docker://ghcr.io/org/app"]
+//   "1.0" = "sha256:..."
+//
+// Pinned tags are pulled by their pinned digest, so syncing again mirrors the same content even if
+// the tag moved upstream. Tags are only re-resolved when updating the lockfile.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct Lockfile(BTreeMap<String, BTreeMap<String, String>>);
+
+impl Lockfile {
+    // A missing lockfile is an empty one, it is created on the first sync.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(data) => toml::from_str(&data).context(format!("Failed to parse lockfile {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Lockfile::default()),
+            Err(e) => Err(e).context(format!("Failed to read lockfile {}", path.display())),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let data = toml::to_string_pretty(&self.0)?;
+        std::fs::write(path, format!("{}\n{}", HEADER, data)).context(format!("Failed to write lockfile {}", path.display()))
+    }
+
+    pub(crate) fn get(&self, source: &str, tag: &str) -> Option<&str> {
+        self.0.get(source)?.get(tag).map(String::as_str)
+    }
+
+    // Records `digest` for `tag`, logging when it replaces a different one. Returns whether the
+    // lockfile changed.
+    pub(crate) fn set(&mut self, source: &str, tag: &str, digest: &str) -> bool {
+        let previous = self.0.entry(source.to_owned()).or_default().insert(tag.to_owned(), digest.to_owned());
+        match previous {
+            Some(previous) if previous == digest => false,
+            Some(previous) => {
+                log::warn!("{}:{} moved upstream from {} to {}", source, tag, previous, digest);
+                true
+            }
+            None => true,
+        }
+    }
+
+    // The digest to pull `tag` by: the pinned one, or the one the tag resolves to upstream when it is
+    // not pinned yet or `update` is set. Returns whether the lockfile changed with it.
+    pub(crate) fn pin(&mut self, source: &str, tag: &str, update: bool, registry_config: Option<&Path>) -> Result<(String, bool)> {
+        if let Some(pinned) = self.get(source, tag).filter(|_| !update) {
+            return Ok((pinned.to_owned(), false));
+        }
+
+        let digest = converter::upstream_digest(&format!("{}:{}", source, tag), registry_config)?;
+        let changed = self.set(source, tag, &digest);
+        Ok((digest, changed))
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use anyhow::{bail, Result};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use serde_json::json;
+
+use oci_r2_uploader::{ConverterKind, Finding, ListOptions, ParallelUpload, PushOptions, ReportFormat, RetryPolicy, SourceLocation, VerifyMode, VerifyOptions};
+
+// Exit codes besides 0 for success and 2 for usage errors, which clap reports itself.
+const EXIT_FAILURE: u8 = 1;
+// The command ran, but found corrupt objects.
+const EXIT_FINDINGS: u8 = 3;
+
+/// Converts container images and pushes them to a Cloudflare R2 bucket served as an OCI registry.
+///
+/// Credentials are read from CLOUDFLARE_ACCOUNT_ID, R2_BUCKET, R2_ACCESS_KEY_ID and
+/// R2_SECRET_ACCESS_KEY, or from a `.env` file in the working directory.
+#[derive(Parser)]
+#[command(disable_version_flag = true)]
+struct Cli {
+    /// Prints the version.
+    #[arg(long, short = 'V')]
+    version: bool,
+
+    /// With --version, also prints the enabled features, target and linkage.
+    #[arg(long, requires = "version")]
+    build_info: bool,
+
+    /// The bucket to use instead of R2_BUCKET.
+    #[arg(long, global = true)]
+    bucket: Option<String>,
+
+    /// The config file to use instead of OCI_R2_CONFIG or `oci-r2-uploader.toml`.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Where images are staged instead of OCI_R2_WORK_DIR or the working directory.
+    #[arg(long, global = true)]
+    work_dir: Option<PathBuf>,
+
+    /// A destination defined in the config file.
+    #[arg(long, global = true)]
+    destination: Option<String>,
+
+    /// Routes all outbound connections through this proxy.
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+
+    /// Does not load `.env` from the working directory.
+    #[arg(long, global = true)]
+    no_dotenv: bool,
+
+    /// Only logs warnings and errors.
+    #[arg(long, short, global = true)]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Converts an image and uploads it to the bucket.
+    #[command(alias = "push")]
+    Upload(Box<UploadArgs>),
+    /// Checks objects in the bucket against their digests.
+    Verify(VerifyArgs),
+    /// Lists the repositories in the bucket.
+    List(ListArgs),
+    /// Deletes a tag; its layers are left for garbage collection.
+    Delete { image: String, tag: String },
+    /// Deletes the manifests of expired tags.
+    Prune {
+        /// Only lists the tags that would be pruned.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Estimates the bytes, operations and cost of a push without uploading anything.
+    Estimate { image: String, tag: String },
+    /// Shows the pushes running on this host.
+    Status {
+        /// Reports every change until the pushes finish.
+        #[arg(long)]
+        follow: bool,
+    },
+}
+
+#[derive(Args)]
+struct UploadArgs {
+    image: String,
+    tag: String,
+
+    /// auto, skopeo, crane, oci-layout or wasm.
+    #[arg(long)]
+    converter: Option<ConverterKind>,
+
+    /// Where the converter reads the image from, e.g. an OCI layout directory.
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Locations tried in order for the image: docker-daemon, containerd[:<namespace>] or docker://<registry>.
+    #[arg(long = "from")]
+    sources: Vec<SourceLocation>,
+
+    /// Conversion attempts at most before the push fails.
+    #[arg(long, value_parser = parse_attempts)]
+    convert_retries: Option<u32>,
+
+    /// Seconds before the first conversion retry, doubling after each.
+    #[arg(long)]
+    convert_backoff: Option<u64>,
+
+    /// Re-reads uploaded objects to check them: off, all or sample=<percent>.
+    #[arg(long)]
+    verify: Option<VerifyMode>,
+
+    /// Bytes buffered by blob uploads at most, e.g. 512M.
+    #[arg(long, value_parser = oci_r2_uploader::parse_size)]
+    max_memory: Option<u64>,
+
+    /// Uploads large blobs in parallel parts, `<part size>[,<parallelism>]`.
+    #[arg(long)]
+    parallel_upload: Option<ParallelUpload>,
+
+    /// Uploads by digest only, without writing the tag.
+    #[arg(long)]
+    no_tag: bool,
+
+    /// Blobs uploaded at once at most.
+    #[arg(long, value_parser = parse_concurrency)]
+    concurrency: Option<usize>,
+
+    /// When the tag expires: a duration such as 14d, a date or a timestamp.
+    #[arg(long, value_parser = oci_r2_uploader::parse_expiry)]
+    expires: Option<std::time::SystemTime>,
+
+    /// Pushes a preview tag named after this branch or pull request.
+    #[arg(long)]
+    preview: Option<String>,
+
+    /// A manifest annotation, `<key>=<value>`; may be repeated.
+    #[arg(long = "annotation", value_parser = parse_annotation)]
+    annotations: Vec<(String, String)>,
+
+    /// Attaches a provenance attestation.
+    #[arg(long)]
+    provenance: bool,
+
+    /// Signs the image with this cosign key.
+    #[arg(long)]
+    signing_key: Option<PathBuf>,
+
+    /// Stores layers as content-defined chunks.
+    #[arg(long)]
+    chunked: bool,
+
+    /// Uploads zstd deltas against the previous push.
+    #[arg(long)]
+    delta: bool,
+
+    /// Removes the build history from the image config.
+    #[arg(long)]
+    strip_history: bool,
+
+    /// Pushes each platform of an index under its own tag as well.
+    #[arg(long)]
+    platform_tags: bool,
+
+    /// The trust policy to check the image against.
+    #[arg(long)]
+    policy: Option<PathBuf>,
+
+    /// Pushes even when the repository is over its quota.
+    #[arg(long)]
+    ignore_quota: bool,
+
+    /// Throttles the upload to leave bandwidth for other traffic.
+    #[arg(long)]
+    nice: bool,
+
+    /// Copies blobs stored under their BLAKE3 name by earlier versions instead of uploading them.
+    #[arg(long)]
+    adopt_legacy: bool,
+
+    /// Listens for pause, resume, abort and rate commands on this Unix socket.
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Writes the push's progress to this file.
+    #[arg(long)]
+    status_file: Option<PathBuf>,
+
+    /// Pins tags to the digests recorded in this lockfile.
+    #[arg(long)]
+    lockfile: Option<PathBuf>,
+
+    /// Records moved tags in the lockfile instead of keeping the pinned digests.
+    #[arg(long, requires = "lockfile")]
+    update_lockfile: bool,
+
+    /// Writes a diagnostics bundle into this directory when the push fails.
+    #[arg(long)]
+    diagnostics: Option<PathBuf>,
+
+    /// Prints the push report as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Only objects of this repository.
+    #[arg(long)]
+    repository: Option<String>,
+
+    /// Only the objects behind this tag of the repository.
+    #[arg(long, requires = "repository")]
+    tag: Option<String>,
+
+    /// Objects downloaded at once.
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Caps the request rate, e.g. below the bucket's rate limit.
+    #[arg(long)]
+    requests_per_second: Option<u32>,
+
+    /// Prints the corrupt objects as sarif or junit instead of text.
+    #[arg(long)]
+    format: Option<ReportFormat>,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Only repositories whose name starts with this prefix.
+    #[arg(long)]
+    prefix: Option<String>,
+
+    /// Listing requests sent at once.
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Caps the request rate, e.g. below the bucket's rate limit.
+    #[arg(long)]
+    requests_per_second: Option<u32>,
+
+    /// Adds the pull counts recorded by serve mode.
+    #[arg(long)]
+    with_pulls: bool,
+
+    /// Adds the tag metadata recorded by imports.
+    #[arg(long)]
+    with_metadata: bool,
+
+    /// Prints the repositories as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+fn parse_annotation(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => bail!("Invalid annotation {} (expected <key>=<value>)", s),
+    }
+}
+
+fn parse_attempts(s: &str) -> Result<u32> {
+    match s.parse::<u32>() {
+        Ok(attempts) if attempts > 0 => Ok(attempts),
+        _ => bail!("Invalid number of attempts {} (expected a positive number)", s),
+    }
+}
+
+fn parse_concurrency(s: &str) -> Result<usize> {
+    match s.parse::<usize>() {
+        Ok(concurrency) if concurrency > 0 => Ok(concurrency),
+        _ => bail!("Invalid concurrency {} (expected a positive number)", s),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.version {
+        match cli.build_info {
+            true => println!("{}", oci_r2_uploader::build_info()),
+            false => println!("oci-r2-uploader {}", env!("CARGO_PKG_VERSION")),
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let level = if cli.quiet { "warn" } else { "info" };
+    let logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).build();
+    if let Err(e) = oci_r2_uploader::install_diagnostics_logger(Box::new(logger)) {
+        eprintln!("{:#}", e);
+    }
+
+    // Setting variables races with threads reading the environment, so it all happens before the
+    // runtime starts. The library reads them when it needs them, so the flags only override them.
+    if cli.no_dotenv {
+        oci_r2_uploader::disable_dotenv();
+    }
+    oci_r2_uploader::load_dotenv();
+    if let Some(bucket) = &cli.bucket {
+        env::set_var("R2_BUCKET", bucket);
+    }
+    if let Some(config) = &cli.config {
+        env::set_var("OCI_R2_CONFIG", config);
+    }
+    if let Some(work_dir) = &cli.work_dir {
+        env::set_var("OCI_R2_WORK_DIR", work_dir);
+    }
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            log::error!("Failed to start the runtime: {}", e);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    match runtime.block_on(run(cli)) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            log::error!("{:#}", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<u8> {
+    let Some(command) = cli.command else {
+        Cli::command().error(ErrorKind::MissingSubcommand, "a subcommand is required").exit();
+    };
+
+    if let Some(destination) = &cli.destination {
+        oci_r2_uploader::select_destination(destination)?;
+    }
+    if let Some(proxy) = &cli.proxy {
+        oci_r2_uploader::set_proxy(proxy)?;
+    }
+
+    // Resolved up front, so an unusable work directory fails before any conversion.
+    oci_r2_uploader::work_dir()?;
+
+    match command {
+        Command::Upload(args) => upload(*args).await,
+        Command::Verify(args) => verify(args).await,
+        Command::List(args) => list(args).await,
+        Command::Delete { image, tag } => match oci_r2_uploader::delete_tag(&image, &tag).await? {
+            true => Ok(0),
+            false => bail!("Tag {}:{} does not exist", image, tag),
+        },
+        Command::Prune { dry_run } => {
+            let pruned = oci_r2_uploader::prune_expired(dry_run).await?;
+            for tag in pruned {
+                println!("{}:{}", tag.image, tag.tag);
+            }
+            Ok(0)
+        }
+        Command::Estimate { image, tag } => {
+            println!("{}", oci_r2_uploader::estimate(image, tag, PushOptions::default()).await?);
+            Ok(0)
+        }
+        Command::Status { follow: false } => {
+            for (_, status) in oci_r2_uploader::running_pushes()? {
+                println!("{}", status);
+            }
+            Ok(0)
+        }
+        Command::Status { follow: true } => {
+            let mut failed = false;
+            for (path, status) in oci_r2_uploader::running_pushes()? {
+                if status.finished {
+                    println!("{}", status);
+                    continue;
+                }
+                let status = oci_r2_uploader::follow_status(&path, |status| println!("{}", status)).await?;
+                failed |= status.error.is_some();
+            }
+            Ok(if failed { EXIT_FAILURE } else { 0 })
+        }
+    }
+}
+
+async fn upload(args: UploadArgs) -> Result<u8> {
+    let default_retry = RetryPolicy::default();
+    let options = PushOptions {
+        converter: args.converter.unwrap_or_default(),
+        source: args.source,
+        sources: args.sources,
+        convert_retry: (args.convert_retries.is_some() || args.convert_backoff.is_some()).then(|| RetryPolicy {
+            max_attempts: args.convert_retries.unwrap_or(default_retry.max_attempts),
+            backoff: args.convert_backoff.map(Duration::from_secs).unwrap_or(default_retry.backoff),
+        }),
+        verify: args.verify.unwrap_or_default(),
+        max_memory: args.max_memory,
+        parallel_upload: args.parallel_upload,
+        concurrency: args.concurrency,
+        no_tag: args.no_tag,
+        expires: args.expires,
+        preview: args.preview,
+        annotations: args.annotations.into_iter().collect::<BTreeMap<_, _>>(),
+        provenance: args.provenance,
+        signing_key: args.signing_key,
+        chunked: args.chunked,
+        delta: args.delta,
+        strip_history: args.strip_history,
+        platform_tags: args.platform_tags,
+        policy: args.policy,
+        ignore_quota: args.ignore_quota,
+        nice: args.nice,
+        adopt_legacy: args.adopt_legacy,
+        control_socket: args.control_socket,
+        status_file: Some(args.status_file.map_or_else(oci_r2_uploader::default_status_file, Ok)?),
+        lockfile: args.lockfile,
+        update_lockfile: args.update_lockfile,
+        diagnostics: args.diagnostics,
+        ..Default::default()
+    };
+
+    let report = oci_r2_uploader::run_with_options(args.image, args.tag, options).await?;
+    match args.json {
+        true => println!("{}", serde_json::to_string_pretty(&report.to_json())?),
+        false => println!("{}", report.pull_reference.as_deref().or(report.digest.as_deref()).unwrap_or(&report.request_id)),
+    }
+
+    Ok(0)
+}
+
+async fn verify(args: VerifyArgs) -> Result<u8> {
+    let options = VerifyOptions {
+        repository: args.repository,
+        tag: args.tag,
+        concurrency: args.concurrency,
+        requests_per_second: args.requests_per_second,
+        ..Default::default()
+    };
+
+    let report = oci_r2_uploader::verify(options).await?;
+    match args.format {
+        Some(format) => {
+            let findings: Vec<Finding> = report.corrupt.iter().map(Finding::from).collect();
+            println!("{}", oci_r2_uploader::findings_report(&findings, format)?);
+        }
+        None => {
+            for object in &report.corrupt {
+                println!("{}: {}", object.key, object.problem);
+            }
+            log::info!("Verified {} objects ({} bytes), {} corrupt", report.objects, report.bytes, report.corrupt.len());
+        }
+    }
+
+    Ok(if report.corrupt.is_empty() { 0 } else { EXIT_FINDINGS })
+}
+
+async fn list(args: ListArgs) -> Result<u8> {
+    let options = ListOptions {
+        concurrency: args.concurrency,
+        requests_per_second: args.requests_per_second,
+        with_pulls: args.with_pulls,
+        with_metadata: args.with_metadata,
+    };
+
+    let prefix = args.prefix.unwrap_or_default();
+    let repositories: Vec<_> = oci_r2_uploader::list_repositories(options).await?.into_iter().filter(|repository| repository.name.starts_with(&prefix)).collect();
+
+    if args.json {
+        let repositories: Vec<_> = repositories
+            .iter()
+            .map(|repository| {
+                json!({
+                    "name": repository.name,
+                    "tags": repository.tags,
+                    "objects": repository.objects,
+                    "bytes": repository.bytes,
+                    "pulls": repository.pulls,
+                    "metadata": repository.metadata,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&repositories)?);
+        return Ok(0);
+    }
+
+    for repository in repositories {
+        let mut line = format!("{}\t{} tags\t{} objects\t{} bytes", repository.name, repository.tags.len(), repository.objects, repository.bytes);
+        if let Some(pulls) = &repository.pulls {
+            line.push_str(&format!("\t{} pulls", pulls.pulls));
+        }
+        println!("{}", line);
+    }
+
+    Ok(0)
+}
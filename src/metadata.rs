@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::r2configs::R2Configs;
+use crate::smoke::Puller;
+use crate::v2::cas;
+
+// What the source registry's UI shows about a tag, kept next to the tag list as
+// `v2/<name>/_metadata` so listings of the mirror are as informative.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    // `<os>/<architecture>[/<variant>]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct MetadataIndex {
+    tags: BTreeMap<String, TagMetadata>,
+}
+
+pub(crate) fn metadata_key(image: &str) -> String {
+    format!("v2/{}/_metadata", image)
+}
+
+// Reads the metadata of `tag` from the registry: the platforms of an index, and the creation time
+// and labels from the config of its first image (or of the image itself).
+pub(crate) async fn fetch(puller: &mut Puller, tag: &str) -> Result<TagMetadata> {
+    let (digest, manifest) = puller.manifest(tag, None).await?;
+    let mut metadata = TagMetadata { digest: Some(digest), ..Default::default() };
+
+    let image = match manifest["manifests"].as_array() {
+        Some(children) => {
+            let images: Vec<&Value> = children.iter().filter(|child| child["platform"]["os"].as_str().is_some_and(|os| os != "unknown")).collect();
+            metadata.platforms = images.iter().filter_map(|child| platform(&child["platform"])).collect();
+            match images.first().and_then(|child| child["digest"].as_str()) {
+                Some(digest) => puller.manifest(digest, Some(digest)).await?.1,
+                None => return Ok(metadata),
+            }
+        }
+        None => manifest,
+    };
+
+    let config_digest = image["config"]["digest"].as_str().context("Manifest without a config")?;
+    let config = puller.json_blob(config_digest).await?;
+    metadata.created = config["created"].as_str().map(str::to_owned);
+    metadata.labels = config["config"]["Labels"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_owned())))
+        .collect();
+    if metadata.platforms.is_empty() {
+        metadata.platforms.extend(platform(&config));
+    }
+
+    Ok(metadata)
+}
+
+fn platform(platform: &Value) -> Option<String> {
+    let mut name = format!("{}/{}", platform["os"].as_str()?, platform["architecture"].as_str()?);
+    if let Some(variant) = platform["variant"].as_str() {
+        name = format!("{}/{}", name, variant);
+    }
+
+    Some(name)
+}
+
+pub(crate) async fn record(env_vars: &R2Configs, image: &str, tag: &str, metadata: &TagMetadata) -> Result<()> {
+    cas::update_object(env_vars, &metadata_key(image), "application/json", |current| {
+        let mut index = parse(current)?;
+        if index.tags.get(tag) == Some(metadata) {
+            return Ok(None);
+        }
+        index.tags.insert(tag.to_owned(), metadata.clone());
+
+        Ok(Some(serde_json::to_vec(&index)?))
+    })
+    .await
+}
+
+pub(crate) async fn read(env_vars: &R2Configs, image: &str) -> Result<BTreeMap<String, TagMetadata>> {
+    Ok(parse(cas::read_object(env_vars, &metadata_key(image)).await?.as_deref())?.tags)
+}
+
+fn parse(current: Option<&[u8]>) -> Result<MetadataIndex> {
+    match current {
+        Some(data) => serde_json::from_slice(data).context("Malformed metadata index"),
+        None => Ok(MetadataIndex::default()),
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+
+use crate::converter::{self, ConverterKind};
+use crate::hash_utils;
+use crate::lockfile::Lockfile;
+use crate::r2configs::{self, R2Configs};
+use crate::v2::cas;
+use crate::workdir;
+use crate::{run_with_options, PushOptions, PushReport};
+
+// Mirrored tags record where they came from in `v2/<image>/mirrors.json`, so `check_upstream` can
+// tell when upstream moved on. The record stays out of the manifest, which keeps upstream's digest
+// and with it upstream's signatures and digest pins.
+const MIRRORS_FILE: &str = "mirrors.json";
+// Where earlier versions recorded the origin, in the mirrored manifest's annotations.
+pub(crate) const SOURCE_ANNOTATION: &str = "dev.oci-r2.mirror.source";
+pub(crate) const DIGEST_ANNOTATION: &str = "dev.oci-r2.mirror.digest";
+
+#[derive(Clone, Debug, Default)]
+pub struct MirrorReport {
+    pub pushed: Vec<PushReport>,
+    // Tags that failed, with the error.
+    pub failed: Vec<(String, String)>,
+}
+
+// Mirrors several tags of one repository (a skopeo reference without a tag, e.g.
+// `docker://ghcr.io/org/app`) into `image`. All tags are pulled into one shared OCI layout first,
+// so the layers they have in common are downloaded once instead of once per tag, and then pushed
+// from there. A failed tag does not stop the others. With `options.lockfile` set, tags are pulled by
+// the digest pinned in it.
+pub async fn mirror_tags(source: &str, image: String, tags: &[String], options: PushOptions) -> Result<MirrorReport> {
+    mirror_each(source, image, tags, options, |_, _| Ok(())).await
+}
+
+// `mirror_tags`, calling `on_pushed` with each tag right after it is pushed.
+pub(crate) async fn mirror_each<F>(source: &str, image: String, tags: &[String], options: PushOptions, on_pushed: F) -> Result<MirrorReport>
+where
+    F: FnMut(&str, &PushReport) -> Result<()>,
+{
+    if !converter::command_exists("skopeo") {
+        bail!("Mirroring tags needs skopeo");
+    }
+
+    let mut lock = match &options.lockfile {
+        Some(path) => Some(Lockfile::load(path)?),
+        None => None,
+    };
+    let mut lock_changed = false;
+
+    let layout = tempfile::Builder::new().prefix(".oci-r2-mirror-").tempdir_in(workdir::work_dir()?)?;
+    let mut report = MirrorReport::default();
+    let mut staged = Vec::new();
+    for tag in tags {
+        let reference = format!("{}:{}", source, tag);
+        // The tag is pulled by the digest it resolved to, or is pinned to, so the recorded digest is
+        // the one pushed even if the tag moves in between.
+        let resolved = match &mut lock {
+            Some(lock) => lock.pin(source, tag, options.update_lockfile, options.registry_config.as_deref()).map(|(digest, changed)| {
+                lock_changed |= changed;
+                digest
+            }),
+            None => converter::upstream_digest(&reference, options.registry_config.as_deref()),
+        };
+        let pulled = resolved.and_then(|digest| {
+            converter::copy_to_layout(&format!("{}@{}", source, digest), options.policy.as_deref(), options.registry_config.as_deref(), layout.path(), tag)?;
+            Ok(digest)
+        });
+        match pulled {
+            Ok(digest) => staged.push((tag.clone(), digest)),
+            Err(e) => {
+                log::error!("Failed to pull {}: {:#}", reference, e);
+                report.failed.push((tag.clone(), format!("{:#}", e)));
+            }
+        }
+    }
+    log::info!("Pulled {} of {} tags of {} into a shared layout", staged.len(), tags.len(), source);
+    if let (Some(lock), Some(path), true) = (&lock, &options.lockfile, lock_changed) {
+        lock.save(path)?;
+    }
+
+    let push = PushOptions {
+        converter: ConverterKind::OciLayout,
+        source: Some(layout.path().to_string_lossy().into_owned()),
+        ..options
+    };
+    let staged = staged.into_iter().map(|(tag, digest)| (tag, push.clone(), Some(digest))).collect();
+    push_staged(&image, source, staged, &mut report, on_pushed).await?;
+
+    Ok(report)
+}
+
+// Mirrors every tag of a repository (`docker://<registry>/<path>`) into `image` with a single
+// `skopeo sync --all` into a staging directory, instead of one `skopeo copy` per tag, and then pushes
+// each synced tag from there. Tags that moved away from the digest pinned in `options.lockfile` are
+// pulled by the pinned digest instead.
+pub async fn sync_repository(source: &str, image: String, options: PushOptions) -> Result<MirrorReport> {
+    if !converter::command_exists("skopeo") {
+        bail!("Syncing a repository needs skopeo");
+    }
+
+    let mut lock = match &options.lockfile {
+        Some(path) => Some(Lockfile::load(path)?),
+        None => None,
+    };
+    let mut lock_changed = false;
+
+    let staging = tempfile::Builder::new().prefix(".oci-r2-sync-").tempdir_in(workdir::work_dir()?)?;
+    converter::sync_to_dir(source, options.policy.as_deref(), options.registry_config.as_deref(), staging.path())?;
+    let images = converter::synced_images(staging.path())?;
+    log::info!("Synced {} tags of {}", images.len(), source);
+
+    let mut report = MirrorReport::default();
+    let mut staged = Vec::new();
+    // Tags that moved away from their pinned digest, pulled by that digest instead.
+    let mut pinned = Vec::new();
+    for (tag, dir) in images {
+        let push = PushOptions {
+            converter: ConverterKind::Skopeo,
+            source: Some(format!("dir:{}", dir.display())),
+            ..options.clone()
+        };
+        // `dir:` layouts keep the manifest exactly as upstream serves it.
+        let Ok(digest) = hash_utils::compute_sha256(dir.join("manifest.json")) else {
+            staged.push((tag, push, None));
+            continue;
+        };
+
+        match (&mut lock, options.update_lockfile) {
+            (Some(lock), false) => match lock.get(source, &tag) {
+                Some(locked) if locked != digest => {
+                    log::warn!("{}:{} moved upstream to {}, keeping the pinned {}", source, tag, digest, locked);
+                    pinned.push((tag, locked.to_owned()));
+                    continue;
+                }
+                Some(_) => {}
+                None => lock_changed |= lock.set(source, &tag, &digest),
+            },
+            (Some(lock), true) => lock_changed |= lock.set(source, &tag, &digest),
+            (None, _) => {}
+        }
+        staged.push((tag, push, Some(digest)));
+    }
+    if let (Some(lock), Some(path), true) = (&lock, &options.lockfile, lock_changed) {
+        lock.save(path)?;
+    }
+
+    if !pinned.is_empty() {
+        let layout = staging.path().join(".pinned");
+        for (tag, digest) in pinned {
+            match converter::copy_to_layout(&format!("{}@{}", source, digest), options.policy.as_deref(), options.registry_config.as_deref(), &layout, &tag) {
+                Ok(()) => {
+                    let push = PushOptions {
+                        converter: ConverterKind::OciLayout,
+                        source: Some(layout.to_string_lossy().into_owned()),
+                        ..options.clone()
+                    };
+                    staged.push((tag, push, Some(digest)));
+                }
+                Err(e) => {
+                    log::error!("Failed to pull {}@{}: {:#}", source, digest, e);
+                    report.failed.push((tag, format!("{:#}", e)));
+                }
+            }
+        }
+    }
+    push_staged(&image, source, staged, &mut report, |_, _| Ok(())).await?;
+
+    Ok(report)
+}
+
+// Pushes each staged tag and records the upstream digest it was mirrored from, when known.
+async fn push_staged<F>(image: &str, source: &str, staged: Vec<(String, PushOptions, Option<String>)>, report: &mut MirrorReport, mut on_pushed: F) -> Result<()>
+where
+    F: FnMut(&str, &PushReport) -> Result<()>,
+{
+    let env_vars = r2configs::parse_r2configs()?;
+    for (tag, push, digest) in staged {
+        match run_with_options(image.to_owned(), tag.clone(), push).await {
+            Ok(pushed) => {
+                if let Some(digest) = digest {
+                    record_origin(&env_vars, &pushed.image, &pushed.tag, source, &digest).await?;
+                }
+                on_pushed(&tag, &pushed)?;
+                report.pushed.push(pushed);
+            }
+            Err(e) => {
+                log::error!("Failed to push {}:{}: {:#}", image, tag, e);
+                report.failed.push((tag, format!("{:#}", e)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn record_origin(env_vars: &R2Configs, image: &str, tag: &str, source: &str, digest: &str) -> Result<()> {
+    let entry = json!({ "source": source, "digest": digest });
+    cas::update_object(env_vars, &format!("v2/{}/{}", image, MIRRORS_FILE), "application/json", |current| {
+        let mut origins = read_origins(current)?;
+        origins.insert(tag.to_owned(), entry.clone());
+
+        Ok(Some(serde_json::to_vec(&origins)?))
+    })
+    .await
+}
+
+// The mirrored tags of `image`, each with its source and the upstream digest it was mirrored from.
+pub(crate) async fn origins(env_vars: &R2Configs, image: &str) -> Result<Map<String, Value>> {
+    read_origins(cas::read_object(env_vars, &format!("v2/{}/{}", image, MIRRORS_FILE)).await?.as_deref())
+}
+
+fn read_origins(current: Option<&[u8]>) -> Result<Map<String, Value>> {
+    match current {
+        Some(data) => serde_json::from_slice(data).context("Malformed mirror index"),
+        None => Ok(Map::new()),
+    }
+}
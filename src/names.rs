@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+
+// The directories below `v2/<name>/` that hold a repository's own objects. Nested components may not
+// use these names, so `v2/<name>/<entry>/...` always belongs to `<name>` and any other directory
+// below it to a nested repository.
+const RESERVED_COMPONENTS: [&str; 6] = ["blobs", "manifests", "tags", "recipes", "deltas", "referrers"];
+
+// Longer names would push deep keys such as `v2/<name>/blobs/<digest>` towards R2's 1024-byte
+// key limit.
+const MAX_NAME_LENGTH: usize = 255;
+
+// Checks `name` against the distribution-spec repository name grammar:
+//
+//   name      := component ("/" component)*
+//   component := [a-z0-9]+ (separator [a-z0-9]+)*
+//   separator := "." | "_" | "__" | "-"+
+//
+// Names of this grammar only contain characters that are safe in object keys, so a repository's
+// objects are stored under `v2/<name>/` as-is and every key maps back to exactly one name. Push,
+// serve and list rely on this instead of escaping names.
+pub fn validate_repository_name(name: &str) -> Result<()> {
+    if name.len() > MAX_NAME_LENGTH {
+        bail!("Invalid repository name {} (longer than {} characters)", name, MAX_NAME_LENGTH);
+    }
+    if let Some(component) = name.split('/').skip(1).find(|component| RESERVED_COMPONENTS.contains(component)) {
+        bail!("Invalid repository name {} ({} is reserved for the repository's objects and cannot name a nested repository)", name, component);
+    }
+    if !name.split('/').all(is_component) {
+        let suggestion = sanitize_repository_name(name);
+        match suggestion.is_empty() {
+            true => bail!("Invalid repository name {:?} (expected lowercase path components such as team/app)", name),
+            false => bail!("Invalid repository name {:?} (expected lowercase path components, e.g. {})", name, suggestion),
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn is_repository_name(name: &str) -> bool {
+    validate_repository_name(name).is_ok()
+}
+
+// Whether `key` is one of `image`'s own objects rather than one of a repository nested below it,
+// e.g. `v2/org/team/app/...` is not `org/team`'s.
+pub(crate) fn owns_key(image: &str, key: &str) -> bool {
+    let Some(rest) = key.strip_prefix("v2/").and_then(|rest| rest.strip_prefix(image)).and_then(|rest| rest.strip_prefix('/')) else {
+        return false;
+    };
+
+    match rest.split_once('/') {
+        Some((entry, _)) => RESERVED_COMPONENTS.contains(&entry),
+        None => true,
+    }
+}
+
+// The closest valid name: lowercased, with runs of other characters that do not form a separator
+// replaced by `-` and separators trimmed from the ends of components. Empty when nothing of `name`
+// is usable.
+pub fn sanitize_repository_name(name: &str) -> String {
+    let components: Vec<String> = name
+        .to_lowercase()
+        .split('/')
+        .map(|component| {
+            let mut sanitized = String::with_capacity(component.len());
+            let mut separator = String::new();
+            for c in component.chars() {
+                if !c.is_ascii_lowercase() && !c.is_ascii_digit() {
+                    separator.push(c);
+                    continue;
+                }
+                if !separator.is_empty() {
+                    let valid = matches!(separator.as_str(), "." | "_" | "__") || separator.chars().all(|c| c == '-');
+                    sanitized.push_str(if valid { &separator } else { "-" });
+                    separator.clear();
+                }
+                sanitized.push(c);
+            }
+            sanitized.trim_matches(['.', '_', '-']).to_owned()
+        })
+        .filter(|component| !component.is_empty())
+        .collect();
+
+    let mut name = components.join("/");
+    name.truncate(MAX_NAME_LENGTH);
+    name.trim_end_matches(['.', '_', '-', '/']).to_owned()
+}
+
+fn is_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let alphanumeric = |b: &u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    if !bytes.first().is_some_and(alphanumeric) || !bytes.last().is_some_and(alphanumeric) {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if alphanumeric(&bytes[i]) {
+            i += 1;
+            continue;
+        }
+
+        let separator_end = bytes[i..].iter().position(alphanumeric).map_or(bytes.len(), |len| i + len);
+        let separator = &component[i..separator_end];
+        let valid = matches!(separator, "." | "_" | "__") || separator.bytes().all(|b| b == b'-');
+        if !valid {
+            return false;
+        }
+        i = separator_end;
+    }
+
+    true
+}
+
+// Checks `tag` against the distribution-spec tag grammar, `[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}`, and
+// refuses the tags serve could not tell from other objects under `v2/<name>/manifests/`: 64 hex
+// characters, the way manifests were once named by their content hash, and a leading `_`, which
+// marks the bucket's internal objects.
+pub fn validate_tag(tag: &str) -> Result<()> {
+    let valid = (1..=128).contains(&tag.len())
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        bail!("Invalid tag {:?} (expected up to 128 letters, digits, `_`, `.` and `-`, not starting with `.` or `-`)", tag);
+    }
+    if tag.starts_with('_') {
+        bail!("Invalid tag {} (tags starting with `_` are reserved)", tag);
+    }
+    if tag.len() == 64 && tag.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid tag {} (64 hex characters look like a digest, push it by digest instead)", tag);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn is_tag(tag: &str) -> bool {
+    validate_tag(tag).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owns_key_stops_at_nested_repositories() {
+        assert!(owns_key("org/team", "v2/org/team/blobs/sha256:abc"));
+        assert!(owns_key("org/team", "v2/org/team/manifests/1.0"));
+        assert!(owns_key("org/team", "v2/org/team/_checksums.json"));
+        assert!(!owns_key("org/team", "v2/org/team/app/blobs/sha256:abc"));
+        assert!(!owns_key("org/team", "v2/org/teams/blobs/sha256:abc"));
+        assert!(!owns_key("org", "v2/org/team/manifests/1.0"));
+    }
+
+    #[test]
+    fn reserved_components_only_name_objects() {
+        assert!(validate_repository_name("blobs").is_ok());
+        assert!(validate_repository_name("blobs/app").is_ok());
+        assert!(validate_repository_name("org/manifests").is_err());
+        assert!(validate_repository_name("org/app/recipes").is_err());
+        assert!(validate_repository_name("org/blobs-cache").is_ok());
+    }
+
+    #[test]
+    fn validate_tag_follows_the_distribution_grammar() {
+        assert!(validate_tag("latest").is_ok());
+        assert!(validate_tag("v1.2.3-rc_1").is_ok());
+        assert!(validate_tag(&"a".repeat(128)).is_ok());
+        assert!(validate_tag("").is_err());
+        assert!(validate_tag(&"a".repeat(129)).is_err());
+        assert!(validate_tag(".hidden").is_err());
+        assert!(validate_tag("-rc").is_err());
+        assert!(validate_tag("1.0+build").is_err());
+        assert!(validate_tag("_reserved").is_err());
+        assert!(validate_tag(&"ab".repeat(32)).is_err());
+        assert!(validate_tag(&"ab".repeat(31)).is_ok());
+    }
+}
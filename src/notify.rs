@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde_json::json;
+
+use crate::config::{MessageConfig, NotifierConfig, NotifyOn};
+use crate::PushReport;
+
+const DEFAULT_SUCCESS_TEMPLATE: &str = "Pushed {image}:{tag} ({digest}) {manifest_url}";
+const DEFAULT_FAILURE_TEMPLATE: &str = "Failed to push {image}:{tag}: {error}";
+
+pub(crate) async fn notify_all(notifiers: &[NotifierConfig], image: &str, tag: &str, result: &Result<PushReport>) {
+    for notifier in notifiers {
+        if let Err(e) = notify(notifier, image, tag, result).await {
+            log::warn!("Failed to send {} notification: {:#}", notifier_name(notifier), e);
+        }
+    }
+}
+
+async fn notify(notifier: &NotifierConfig, image: &str, tag: &str, result: &Result<PushReport>) -> Result<()> {
+    let message = match notifier {
+        NotifierConfig::Slack { message, .. } | NotifierConfig::Discord { message, .. } | NotifierConfig::Email { message, .. } => message,
+    };
+
+    let text = match render(message, image, tag, result) {
+        Some(text) => text,
+        None => return Ok(()),
+    };
+
+    match notifier {
+        NotifierConfig::Slack { url, .. } => post_json(url, json!({ "text": text })).await,
+        NotifierConfig::Discord { url, .. } => post_json(url, json!({ "content": text })).await,
+        NotifierConfig::Email { smtp_host, smtp_port, username, password, from, to, .. } => {
+            let subject = text.lines().next().unwrap_or_default().to_owned();
+
+            let mut builder = Message::builder().from(from.parse().context(format!("Invalid sender {}", from))?).subject(subject);
+            for recipient in to {
+                builder = builder.to(recipient.parse().context(format!("Invalid recipient {}", recipient))?);
+            }
+            let email = builder.body(text)?;
+
+            let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?;
+            if let Some(port) = smtp_port {
+                transport = transport.port(*port);
+            }
+            if let (Some(username), Some(password)) = (username, password) {
+                transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+
+            transport.build().send(email).await?;
+            Ok(())
+        }
+    }
+}
+
+fn render(message: &MessageConfig, image: &str, tag: &str, result: &Result<PushReport>) -> Option<String> {
+    let outcome = if result.is_ok() { NotifyOn::Success } else { NotifyOn::Failure };
+    if !message.on.is_empty() && !message.on.contains(&outcome) {
+        return None;
+    }
+
+    let text = match result {
+        Ok(report) => message
+            .success_template
+            .as_deref()
+            .unwrap_or(DEFAULT_SUCCESS_TEMPLATE)
+            .replace("{digest}", report.digest.as_deref().unwrap_or("unknown digest"))
+            .replace("{manifest_url}", report.manifest_url.as_deref().unwrap_or_default())
+            .replace("{pull_reference}", report.pull_reference.as_deref().unwrap_or_default())
+            .replace("{error}", ""),
+        Err(e) => message
+            .failure_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FAILURE_TEMPLATE)
+            .replace("{digest}", "")
+            .replace("{manifest_url}", "")
+            .replace("{pull_reference}", "")
+            .replace("{error}", &format!("{:#}", e)),
+    };
+
+    Some(text.replace("{image}", image).replace("{tag}", tag).trim().to_owned())
+}
+
+async fn post_json(url: &str, body: serde_json::Value) -> Result<()> {
+    let response = crate::proxy::http_client()?.post(url).json(&body).send().await?;
+    if !response.status().is_success() {
+        bail!("{} answered {}", url, response.status());
+    }
+
+    Ok(())
+}
+
+fn notifier_name(notifier: &NotifierConfig) -> &'static str {
+    match notifier {
+        NotifierConfig::Slack { .. } => "Slack",
+        NotifierConfig::Discord { .. } => "Discord",
+        NotifierConfig::Email { .. } => "email",
+    }
+}
@@ -0,0 +1,665 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+use anyhow::{bail, Context, Result};
+use rusoto_s3::S3Client;
+use serde_json::Value;
+use tempfile::TempDir;
+
+use crate::canonical::{self, ManifestEncoding};
+use crate::capabilities::{self, Capability};
+use crate::config::Config;
+use crate::jobs::RetryPolicy;
+use crate::plan::{Operation, PlannedOperation, PushPlan};
+use crate::progress::{Phase, ProgressEvent};
+use crate::r2configs::{self, R2Configs};
+use crate::stats::PushStats;
+use crate::v2::bloom::BloomFilter;
+use crate::v2::delta::DeltaPlan;
+use crate::{annotations, converter, expiry, hash_utils, history, image_policy, index, prewarm, provenance, quota, receipt, recompress, redact, trace, v2, workdir};
+use crate::{PushOptions, PushReport};
+
+// What a push needs from the outside world, resolved once by the caller instead of being read from
+// the environment and the working directory along the way.
+pub(crate) struct PipelineDeps {
+    pub(crate) env_vars: R2Configs,
+    pub(crate) client: S3Client,
+    pub(crate) work_dir: PathBuf,
+    // Where interrupted multipart uploads keep their records, shared by every push so a later one
+    // can resume them.
+    pub(crate) resume_dir: PathBuf,
+}
+
+impl PipelineDeps {
+    // The R2 credentials from the environment, staging in the work directory.
+    pub(crate) fn from_env() -> Result<Self> {
+        let env_vars = r2configs::parse_r2configs()?;
+        let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+
+        let work_dir = workdir::work_dir()?;
+
+        Ok(PipelineDeps {
+            env_vars,
+            client,
+            resume_dir: work_dir.join(v2::multipart::RESUME_DIR),
+            work_dir,
+        })
+    }
+}
+
+// The converted image in a temporary directory, as the converter wrote it.
+pub(crate) struct Sourced {
+    tmp_dir: TempDir,
+    expires: Option<SystemTime>,
+    delta_layers: Option<Vec<String>>,
+}
+
+// The image staged for upload: every file named by its digest, manifests and blobs apart.
+pub(crate) struct Staged {
+    tmp_dir: TempDir,
+    pub(crate) manifests_dir: PathBuf,
+    pub(crate) blobs_dir: PathBuf,
+    pub(crate) top_manifest_name: String,
+    pub(crate) digest: Option<String>,
+    expires: Option<SystemTime>,
+    delta_layers: Option<Vec<String>>,
+}
+
+impl Staged {
+    pub(crate) fn dirs(&self) -> [(&str, &Path); 2] {
+        [("blobs", &self.blobs_dir), ("manifests", &self.manifests_dir)]
+    }
+
+    pub(crate) fn top_manifest(&self) -> PathBuf {
+        self.manifests_dir.join(&self.top_manifest_name)
+    }
+}
+
+// What the bucket already holds, to decide which objects to upload and how.
+pub(crate) struct Planned {
+    index: BloomFilter,
+    delta: Option<DeltaPlan>,
+    existing: Option<BTreeSet<String>>,
+    repositories: Vec<String>,
+}
+
+pub(crate) struct Uploaded {
+    keys: Vec<String>,
+}
+
+// A push as a sequence of stages, Source → Stage → Plan → Upload → Finalize, each taking the
+// output of the previous one.
+pub(crate) struct Pipeline<'a> {
+    image: &'a str,
+    tag: &'a str,
+    options: &'a PushOptions,
+    config: &'a Config,
+    deps: &'a PipelineDeps,
+}
+
+impl<'a> Pipeline<'a> {
+    pub(crate) fn new(image: &'a str, tag: &'a str, options: &'a PushOptions, config: &'a Config, deps: &'a PipelineDeps) -> Self {
+        Pipeline { image, tag, options, config, deps }
+    }
+
+    // Source, Stage and Plan, recording the operations an upload would perform instead of
+    // performing them. The staged files are left in the work directory for `apply`.
+    pub(crate) async fn dry_run(&self, stats: &mut PushStats) -> Result<PushPlan> {
+        capabilities::require(&self.deps.client, &self.deps.env_vars.r2_bucket, "plan", &[Capability::Read]).await?;
+
+        let sourced = self.source(stats).await?;
+        let staged = self.stage(sourced, stats)?;
+        let planned = self.plan(&staged).await?;
+        let operations = self.operations(&staged, &planned).await?;
+
+        Ok(PushPlan {
+            image: self.image.to_owned(),
+            tag: self.tag.to_owned(),
+            digest: staged.digest.clone(),
+            created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            staging_dir: self.deps.work_dir.clone(),
+            top_manifest: staged.top_manifest_name.clone(),
+            expires: staged.expires.map(|expires| humantime::format_rfc3339_seconds(expires).to_string()),
+            delta_layers: staged.delta_layers.clone(),
+            operations,
+        })
+    }
+
+    // Upload and Finalize for a plan made by `dry_run`, whose staging directory is the work
+    // directory.
+    pub(crate) async fn apply(&self, plan: &PushPlan, stats: &mut PushStats) -> Result<PushReport> {
+        let push_started = SystemTime::now();
+        capabilities::require(&self.deps.client, &self.deps.env_vars.r2_bucket, "apply", &[Capability::Write]).await?;
+        self.check_signing()?;
+
+        let (manifests_dir, blobs_dir) = prepare_dir(&self.deps.work_dir, self.image)?;
+        let staged = Staged {
+            tmp_dir: TempDir::new_in(&self.deps.work_dir)?,
+            manifests_dir,
+            blobs_dir,
+            top_manifest_name: plan.top_manifest.clone(),
+            digest: plan.digest.clone(),
+            expires: plan.expires.as_deref().map(humantime::parse_rfc3339).transpose().context("Malformed expiry in the plan")?,
+            delta_layers: plan.delta_layers.clone(),
+        };
+
+        for operation in &plan.operations {
+            if let (Some(file), Some(sha256)) = (&operation.file, &operation.sha256) {
+                let path = staged.blobs_dir.parent().unwrap().join(file);
+                if hash_utils::compute_sha256(&path).ok().as_ref() != Some(sha256) {
+                    bail!("Staged file {} no longer matches the plan", path.display());
+                }
+            }
+        }
+
+        let planned = self.plan(&staged).await?;
+        let uploads = |operations: &[PlannedOperation]| -> BTreeSet<(String, Operation)> {
+            operations
+                .iter()
+                .filter(|operation| operation.operation != Operation::Update)
+                .map(|operation| (operation.key.clone(), operation.operation))
+                .collect()
+        };
+        if uploads(&self.operations(&staged, &planned).await?) != uploads(&plan.operations) {
+            bail!("The bucket changed since {}:{} was planned, plan it again", self.image, self.tag);
+        }
+
+        let uploaded = self.upload(&staged, &planned, stats).await?;
+
+        self.finalize(staged, planned, uploaded, push_started, stats).await
+    }
+
+    pub(crate) async fn run(&self, stats: &mut PushStats) -> Result<PushReport> {
+        let push_started = SystemTime::now();
+        capabilities::require(&self.deps.client, &self.deps.env_vars.r2_bucket, "push", &[Capability::Write]).await?;
+        self.check_signing()?;
+
+        let sourced = self.source(stats).await?;
+        let staged = self.stage(sourced, stats)?;
+        if self.options.check_reproducible {
+            self.check_reproducible(&staged).await?;
+        }
+        let planned = self.plan(&staged).await?;
+        let uploaded = self.upload(&staged, &planned, stats).await?;
+
+        self.finalize(staged, planned, uploaded, push_started, stats).await
+    }
+
+    // Converts the image and applies everything that changes its content: recompression, stripped
+    // history, redaction and annotations.
+    pub(crate) async fn source(&self, stats: &mut PushStats) -> Result<Sourced> {
+        let options = self.options;
+        let tmp_dir = TempDir::new_in(&self.deps.work_dir)?;
+
+        let converter = converter::select(options.converter, options.source.as_deref(), options.policy.as_deref(), &options.sources, options.registry_config.as_deref())?;
+
+        options.progress.emit(ProgressEvent::Phase(Phase::Converting));
+        let started = Instant::now();
+        converter::convert_with_retry(converter.into(), self.image, self.tag, options.source.as_deref(), tmp_dir.path(), convert_retry(options, self.config), &options.progress).await?;
+        stats.convert = started.elapsed();
+
+        image_policy::check(&self.config.image_policy, tmp_dir.path())?;
+
+        if let Some(recompression) = options.recompress {
+            let started = Instant::now();
+            // Recompression keeps its threads busy for minutes, away from the runtime's workers.
+            let dir = tmp_dir.path().to_owned();
+            stats.recompressed = tokio::task::spawn_blocking(move || recompress::recompress(&dir, &recompression)).await??;
+            stats.recompress = started.elapsed();
+        }
+
+        if options.strip_history {
+            history::strip_history(tmp_dir.path())?;
+        }
+        redact::redact(&self.config.redact, tmp_dir.path())?;
+
+        let top_manifest = tmp_dir.path().join("manifest.json");
+        if let Some(expires) = options.expires {
+            expiry::annotate(&top_manifest, expires)?;
+        }
+        annotations::add(&top_manifest, &options.annotations)?;
+        if options.manifest_encoding == ManifestEncoding::Canonical {
+            canonical::canonicalize(tmp_dir.path())?;
+        }
+        let expires = fs::read(&top_manifest).ok().and_then(|manifest| expiry::read_annotation(&manifest));
+
+        let delta_layers = match options.delta {
+            true => Some(v2::delta::layer_names(tmp_dir.path())?),
+            false => None,
+        };
+
+        Ok(Sourced { tmp_dir, expires, delta_layers })
+    }
+
+    pub(crate) fn stage(&self, sourced: Sourced, stats: &mut PushStats) -> Result<Staged> {
+        let started = Instant::now();
+        let (manifests_dir, blobs_dir) = prepare_dir(&self.deps.work_dir, self.image)?;
+
+        let top_manifest_name = move_files(&sourced.tmp_dir, &manifests_dir, &blobs_dir)?;
+        let digest = Some(top_manifest_name.clone());
+        stats.hash = started.elapsed();
+
+        Ok(Staged {
+            tmp_dir: sourced.tmp_dir,
+            manifests_dir,
+            blobs_dir,
+            top_manifest_name,
+            digest,
+            expires: sourced.expires,
+            delta_layers: sourced.delta_layers,
+        })
+    }
+
+    // Provenance is only attached in CI, so a push that must be signed fails before uploading
+    // anything rather than landing unsigned.
+    fn check_signing(&self) -> Result<()> {
+        if self.options.require_signing && provenance::detect().is_none() {
+            bail!("{}:{} must be signed, but provenance can only be attached in a supported CI environment", self.image, self.tag);
+        }
+
+        Ok(())
+    }
+
+    // Converts and stages the image a second time in a scratch directory and requires the exact same
+    // files, which are named by their content hash.
+    async fn check_reproducible(&self, staged: &Staged) -> Result<()> {
+        let scratch = TempDir::new_in(&self.deps.work_dir)?;
+        let deps = PipelineDeps {
+            env_vars: self.deps.env_vars.clone(),
+            client: self.deps.client.clone(),
+            work_dir: scratch.path().to_owned(),
+            resume_dir: self.deps.resume_dir.clone(),
+        };
+        let again = Pipeline { deps: &deps, ..*self };
+
+        let mut stats = PushStats::default();
+        let rebuilt = again.stage(again.source(&mut stats).await?, &mut stats)?;
+
+        let names = |staged: &Staged| -> Result<BTreeSet<String>> {
+            let mut names = BTreeSet::new();
+            for (kind, dir) in staged.dirs() {
+                for path in v2::staged_files(dir)? {
+                    names.insert(format!("{}/{}", kind, path.file_name().unwrap().to_string_lossy()));
+                }
+            }
+            Ok(names)
+        };
+        let (first, second) = (names(staged)?, names(&rebuilt)?);
+        if first != second {
+            let differing: Vec<&String> = first.symmetric_difference(&second).collect();
+            bail!(
+                "{}:{} is not reproducible: {} staged files differ between two conversions ({})",
+                self.image,
+                self.tag,
+                differing.len(),
+                differing.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        log::info!("{}:{} is reproducible: two conversions staged the same {} files", self.image, self.tag, first.len());
+
+        Ok(())
+    }
+
+    pub(crate) async fn plan(&self, staged: &Staged) -> Result<Planned> {
+        let (options, client, env_vars) = (self.options, &self.deps.client, &self.deps.env_vars);
+
+        quota::check(&self.config.quota, self.image, &staged.dirs(), client, &env_vars.r2_bucket, options.ignore_quota).await?;
+
+        options.progress.emit(ProgressEvent::Phase(Phase::Uploading));
+        options.progress.emit(v2::s3_upload::plan_upload(&[&staged.blobs_dir, &staged.manifests_dir])?);
+
+        let index = v2::bloom::load(client, env_vars).await?;
+
+        let delta = match &staged.delta_layers {
+            Some(layers) => Some(v2::delta::load_plan(client, &env_vars.r2_bucket, self.image, self.tag, layers.clone()).await?),
+            None => None,
+        };
+
+        let existing = match options.list_existing {
+            true => Some(v2::s3_upload::list_existing(self.image, client, &env_vars.r2_bucket).await?),
+            false => None,
+        };
+
+        // The other repositories a blob may already be stored in. This one's blobs are skipped
+        // rather than copied.
+        let catalog = v2::cas::read_object(env_vars, v2::catalog::CATALOG_KEY).await?;
+        let repositories = v2::catalog::read_list(catalog.as_deref(), "repositories")?.into_iter().filter(|repository| repository != self.image).collect();
+
+        Ok(Planned { index, delta, existing, repositories })
+    }
+
+    // One operation per staged file, in the order of their keys, followed by the index objects the
+    // push updates.
+    async fn operations(&self, staged: &Staged, planned: &Planned) -> Result<Vec<PlannedOperation>> {
+        let (image, options, client, r2_bucket) = (self.image, self.options, &self.deps.client, &self.deps.env_vars.r2_bucket);
+        let settings = v2::s3_upload::UploadSettings {
+            index: &planned.index,
+            budget: None,
+            chunked: options.chunked,
+            delta: planned.delta.as_ref(),
+            cache_control: options.cache_control.blobs.as_deref(),
+            existing: planned.existing.as_ref(),
+            repositories: &planned.repositories,
+            parallel: options.parallel_upload.as_ref(),
+            concurrency: options.concurrency,
+            control: options.control.as_ref(),
+            nice: options.nice,
+            adopt_legacy: options.adopt_legacy,
+            resume_dir: &self.deps.resume_dir,
+            progress: &options.progress,
+        };
+
+        let mut operations = Vec::new();
+        for (kind, dir) in staged.dirs() {
+            for path in v2::staged_files(dir)? {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let size = fs::metadata(&path)?.len();
+
+                let (key, operation) = match kind {
+                    "blobs" => v2::s3_upload::blob_operation(image, &path, size, client, r2_bucket, &settings).await?,
+                    _ => (format!("v2/{}/manifests/{}", image, name), Operation::Upload),
+                };
+                operations.push(PlannedOperation {
+                    key,
+                    operation,
+                    size: Some(size),
+                    file: Some(format!("{}/{}", kind, name)),
+                    sha256: Some(hash_utils::compute_sha256(&path)?),
+                });
+            }
+        }
+        operations.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let mut updates = Vec::new();
+        if !options.no_tag {
+            updates.push(format!("v2/{}/manifests/{}", image, self.tag));
+            updates.push(v2::catalog::tags_key(image));
+        }
+        updates.extend([
+            format!("v2/{}/{}", image, v2::checksums::CHECKSUMS_FILE),
+            v2::catalog::CATALOG_KEY.to_owned(),
+            v2::bloom::BLOOM_KEY.to_owned(),
+        ]);
+        if planned.delta.is_some() {
+            updates.push(v2::delta::plan_key(image));
+        }
+        if options.oci_layout && !options.no_tag {
+            updates.push(format!("oci/{}/index.json", image));
+        }
+        operations.extend(updates.into_iter().map(|key| PlannedOperation { key, operation: Operation::Update, size: None, file: None, sha256: None }));
+
+        Ok(operations)
+    }
+
+    pub(crate) async fn upload(&self, staged: &Staged, planned: &Planned, stats: &mut PushStats) -> Result<Uploaded> {
+        let (options, client, r2_bucket) = (self.options, &self.deps.client, &self.deps.env_vars.r2_bucket);
+
+        let started = Instant::now();
+        let connections = crate::proxy::connections_opened();
+        let budget = options.max_memory.map(v2::memory::MemoryBudget::new);
+        let settings = v2::s3_upload::UploadSettings {
+            index: &planned.index,
+            budget: budget.as_ref(),
+            chunked: options.chunked,
+            delta: planned.delta.as_ref(),
+            cache_control: options.cache_control.blobs.as_deref(),
+            existing: planned.existing.as_ref(),
+            repositories: &planned.repositories,
+            parallel: options.parallel_upload.as_ref(),
+            concurrency: options.concurrency,
+            control: options.control.as_ref(),
+            nice: options.nice,
+            adopt_legacy: options.adopt_legacy,
+            resume_dir: &self.deps.resume_dir,
+            progress: &options.progress,
+        };
+        let uploaded = v2::s3_upload::upload_blobs(self.image, &staged.blobs_dir, client, r2_bucket, &settings).await?;
+
+        let manifests = v2::s3_upload::ManifestSet {
+            dir: &staged.manifests_dir,
+            top_manifest: &staged.top_manifest_name,
+            tag: Some(self.tag).filter(|_| !options.no_tag),
+        };
+        v2::s3_upload::upload_manifests(self.image, &manifests, client, r2_bucket, options.cache_control.manifests.as_deref(), &options.progress).await?;
+        stats.upload = started.elapsed();
+        stats.blobs = uploaded.timings;
+        stats.reused = uploaded.reused;
+        stats.new_connections = crate::proxy::connections_opened() - connections;
+
+        Ok(Uploaded { keys: uploaded.keys })
+    }
+
+    // Everything that describes the uploaded objects: provenance, checksums, tags, the catalog and
+    // the indexes that speed up later pushes.
+    pub(crate) async fn finalize(&self, staged: Staged, planned: Planned, uploaded: Uploaded, push_started: SystemTime, stats: &mut PushStats) -> Result<PushReport> {
+        let (image, tag, options) = (self.image, self.tag, self.options);
+        let (client, env_vars) = (&self.deps.client, &self.deps.env_vars);
+        let top_manifest = staged.top_manifest();
+
+        if options.provenance {
+            let attestation = provenance::attach(image, tag, &top_manifest, options.signing_key.as_deref(), push_started, client, env_vars).await?;
+            if options.require_signing && attestation.is_none() {
+                bail!("{}:{} must be signed, but no attestation was attached", image, tag);
+            }
+        }
+
+        v2::verify::verify_uploads(image, &staged.dirs(), client, &env_vars.r2_bucket, options.verify).await?;
+
+        v2::checksums::update_checksums(image, &staged.dirs(), client, env_vars).await?;
+
+        if options.oci_layout && !options.no_tag {
+            v2::layout::write_layout(image, tag, &staged.dirs(), &top_manifest, client, env_vars).await?;
+        }
+
+        if !options.no_tag {
+            v2::catalog::update_tags(env_vars, image, tag).await?;
+        }
+
+        if options.platform_tags && !options.no_tag {
+            let manifest: Value = serde_json::from_slice(&fs::read(&top_manifest)?)?;
+            if manifest["manifests"].is_array() {
+                index::tag_platforms(env_vars, client, image, tag, &manifest).await?;
+            }
+        }
+
+        v2::catalog::update_catalog(env_vars, image).await?;
+
+        v2::bloom::record(env_vars, &planned.index, &uploaded.keys).await?;
+
+        if let Some(plan) = &planned.delta {
+            v2::delta::save_plan(client, &env_vars.r2_bucket, image, plan).await?;
+        }
+
+        if let Some(expires) = staged.expires.filter(|_| !options.no_tag) {
+            expiry::record(env_vars, image, tag, expires).await?;
+        }
+
+        let public_url = self.config.public_url.as_deref().map(|public_url| public_url.trim_end_matches('/'));
+        // An untagged push can only be pulled by digest.
+        let reference = match options.no_tag {
+            true => format!("@{}", staged.top_manifest_name),
+            false => format!(":{}", tag),
+        };
+        let manifest_url = public_url.map(|public_url| format!("{}/v2/{}/manifests/{}", public_url, image, &reference[1..]));
+        let pull_reference = public_url.map(|public_url| format!("{}/{}{}", crate::registry_host(public_url), image, reference));
+
+        if let Some(blobs) = options.prewarm {
+            match (public_url, &manifest_url) {
+                (Some(public_url), Some(manifest_url)) => {
+                    prewarm::prewarm(public_url, image, manifest_url, &staged.manifests_dir, &staged.blobs_dir, blobs).await?;
+                }
+                _ => log::warn!("Skipping prewarm: public_url is not configured"),
+            }
+        }
+
+        let mut report = PushReport {
+            image: image.to_owned(),
+            tag: tag.to_owned(),
+            digest: staged.digest.clone(),
+            manifest_url,
+            pull_reference,
+            expires: staged.expires,
+            request_id: trace::current().unwrap_or_default(),
+            receipt: None,
+            stats: std::mem::take(stats),
+        };
+
+        if let Some(key) = &options.receipt_key {
+            report.receipt = Some(receipt::attach(&report, key, client, &env_vars.r2_bucket).await?);
+        }
+
+        cleanup(staged.tmp_dir, &self.deps.work_dir, image)?;
+
+        options.progress.emit(ProgressEvent::Phase(Phase::Done));
+
+        report.stats.log_summary();
+
+        if let (Some(pull_reference), Some(manifest_url)) = (&report.pull_reference, &report.manifest_url) {
+            log::info!("Pull with: docker pull {}", pull_reference);
+            log::info!("Manifest URL: {}", manifest_url);
+        }
+
+        Ok(report)
+    }
+}
+
+// The policy of the options, or else the one of the configuration file.
+pub(crate) fn convert_retry(options: &PushOptions, config: &Config) -> RetryPolicy {
+    options.convert_retry.unwrap_or_else(|| config.convert_retry.apply(RetryPolicy::default()))
+}
+
+fn prepare_dir(work_dir: &Path, image: &str) -> Result<(PathBuf, PathBuf)> {
+    let v2_dir = work_dir.join("v2");
+    fs::create_dir_all(&v2_dir)?;
+
+    let image_manifests_dir = v2_dir.join(image).join("manifests");
+    let image_blobs_dir = v2_dir.join(image).join("blobs");
+    fs::create_dir_all(&image_manifests_dir)?;
+    fs::create_dir_all(&image_blobs_dir)?;
+
+    Ok((image_manifests_dir, image_blobs_dir))
+}
+
+// Names every file by its OCI digest, `sha256:<hex>`, which is how clients request it. Converters
+// name blobs and per-platform manifests by the hex of their digest; each file is checked against
+// it and every digest the manifests reference must be present, so a corrupt or incomplete
+// conversion fails here instead of in a client. Returns the top manifest's digest.
+fn move_files(tmp_dir: &TempDir, image_manifests_dir: &Path, image_blobs_dir: &Path) -> Result<String> {
+    let top_digest = hash_utils::compute_sha256(tmp_dir.path().join("manifest.json"))?;
+    let referenced = referenced_digests(tmp_dir.path())?;
+
+    for entry in fs::read_dir(tmp_dir.path())? {
+        let src = entry?.path();
+        let file_name = src.file_name().unwrap().to_string_lossy().into_owned();
+
+        if file_name == "version" {
+            fs::remove_file(src)?;
+            continue;
+        }
+        if file_name == "manifest.json" {
+            fs::rename(&src, image_manifests_dir.join(&top_digest))?;
+            continue;
+        }
+
+        let (hex, dst_dir) = match file_name.strip_suffix(".manifest.json") {
+            Some(hex) => (hex, image_manifests_dir),
+            None => (file_name.as_str(), image_blobs_dir),
+        };
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            log::warn!("Skipping {}, which is not named by a sha256 digest", file_name);
+            continue;
+        }
+
+        let digest = hash_utils::compute_sha256(&src)?;
+        if digest != format!("sha256:{}", hex) {
+            bail!("{} does not match its digest sha256:{} (the file hashes to {})", file_name, hex, digest);
+        }
+        if !referenced.contains(&digest) {
+            log::warn!("Skipping {}, which no manifest of the image references", digest);
+            continue;
+        }
+
+        fs::rename(&src, dst_dir.join(&digest))?;
+    }
+
+    for digest in &referenced {
+        if !image_blobs_dir.join(digest).exists() && !image_manifests_dir.join(digest).exists() {
+            bail!("{} is referenced by the image but missing from the conversion", digest);
+        }
+    }
+
+    Ok(top_digest)
+}
+
+// The digests of the per-platform manifests, configs and layers the top manifest references.
+// Non-distributable layers fetched from their `urls` are not part of the conversion.
+fn referenced_digests(dir: &Path) -> Result<BTreeSet<String>> {
+    let top: Value = serde_json::from_slice(&fs::read(dir.join("manifest.json"))?).context("Malformed manifest")?;
+
+    let mut manifests = Vec::new();
+    let mut referenced = BTreeSet::new();
+    match top["manifests"].as_array() {
+        Some(children) => {
+            for digest in children.iter().filter_map(|child| child["digest"].as_str()) {
+                let hex = digest.strip_prefix("sha256:").context(format!("Unsupported digest {}", digest))?;
+                let path = dir.join(format!("{}.manifest.json", hex));
+                let child = fs::read(&path).context(format!("Manifest {} is missing from the conversion", digest))?;
+                manifests.push(serde_json::from_slice(&child).context(format!("Malformed manifest {}", digest))?);
+                referenced.insert(digest.to_owned());
+            }
+        }
+        None => manifests.push(top),
+    }
+
+    for manifest in &manifests {
+        let descriptors = std::iter::once(&manifest["config"]).chain(manifest["layers"].as_array().into_iter().flatten());
+        for descriptor in descriptors.filter(|descriptor| descriptor["urls"].is_null()) {
+            if let Some(digest) = descriptor["digest"].as_str() {
+                referenced.insert(digest.to_owned());
+            }
+        }
+    }
+
+    Ok(referenced)
+}
+
+fn cleanup(tmp_dir: TempDir, work_dir: &Path, image: &str) -> Result<()> {
+    tmp_dir.close()?;
+    let v2_dir = work_dir.join("v2");
+    fs::remove_dir_all(v2_dir.join(image))?;
+
+    // Nested repositories leave their parents behind, which are removed unless another push
+    // staged a repository below them.
+    for parent in Path::new(image).ancestors().skip(1).filter(|parent| !parent.as_os_str().is_empty()) {
+        if fs::remove_dir(v2_dir.join(parent)).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleanup_removes_empty_parents_of_nested_names() -> Result<()> {
+        let work_dir = TempDir::new()?;
+        let v2_dir = work_dir.path().join("v2");
+        fs::create_dir_all(v2_dir.join("org/team/app/blobs"))?;
+        fs::create_dir_all(v2_dir.join("org/other/blobs"))?;
+
+        cleanup(TempDir::new()?, work_dir.path(), "org/team/app")?;
+        assert!(!v2_dir.join("org/team").exists());
+        assert!(v2_dir.join("org/other/blobs").exists());
+
+        cleanup(TempDir::new()?, work_dir.path(), "org/other")?;
+        assert!(!v2_dir.join("org").exists());
+        assert!(v2_dir.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, Config};
+use crate::names;
+use crate::pipeline::{Pipeline, PipelineDeps};
+use crate::stats::PushStats;
+use crate::workdir;
+use crate::{PushOptions, PushReport};
+
+const STAGING_PREFIX: &str = ".oci-r2-plan-";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Upload,
+    UploadChunked,
+    UploadDelta,
+    Copy,
+    Skip,
+    Update,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedOperation {
+    pub key: String,
+    pub operation: Operation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    // The staged file, for objects that are uploaded from one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+// Everything a push will write, computed against the bucket as it was when planning. The staged
+// files stay in `staging_dir` until the plan is applied.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushPlan {
+    pub image: String,
+    pub tag: String,
+    pub digest: Option<String>,
+    pub created: String,
+    pub staging_dir: PathBuf,
+    pub top_manifest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_layers: Option<Vec<String>>,
+    pub operations: Vec<PlannedOperation>,
+}
+
+impl PushPlan {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).context(format!("Failed to read plan {}", path.display()))?;
+        serde_json::from_slice(&data).context(format!("Malformed plan {}", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_vec_pretty(self)?).context(format!("Failed to write plan {}", path.display()))
+    }
+
+    // A plan is read from a file that may have been edited since planning, so everything it names
+    // must stay inside its own staging directory below the work directory.
+    fn check(&self, work_dir: &Path) -> Result<()> {
+        names::validate_repository_name(&self.image)?;
+        names::validate_tag(&self.tag)?;
+
+        let staging_dir = self.staging_dir.canonicalize().context(format!("The staging directory {} of the plan is gone, plan again", self.staging_dir.display()))?;
+        let work_dir = work_dir.canonicalize().context(format!("Failed to resolve the work directory {}", work_dir.display()))?;
+        let is_plan_dir = staging_dir.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(STAGING_PREFIX));
+        if !is_plan_dir || staging_dir.parent() != Some(work_dir.as_path()) {
+            bail!("The plan stages in {}, not in a {}* directory of {}", self.staging_dir.display(), STAGING_PREFIX, work_dir.display());
+        }
+
+        let is_relative = |file: &str| !file.is_empty() && Path::new(file).components().all(|component| matches!(component, Component::Normal(_)));
+        if !is_relative(&self.top_manifest) || self.top_manifest.contains('/') {
+            bail!("Invalid top manifest {} in the plan", self.top_manifest);
+        }
+        if let Some(file) = self.operations.iter().filter_map(|operation| operation.file.as_deref()).find(|file| !is_relative(file)) {
+            bail!("Invalid staged file {} in the plan", file);
+        }
+
+        Ok(())
+    }
+
+    pub fn upload_bytes(&self) -> u64 {
+        self.operations
+            .iter()
+            .filter(|operation| !matches!(operation.operation, Operation::Copy | Operation::Skip | Operation::Update))
+            .filter_map(|operation| operation.size)
+            .sum()
+    }
+}
+
+// Converts and stages the image and records what pushing it would do, without writing to the
+// bucket.
+pub async fn plan(image: String, tag: String, mut options: PushOptions) -> Result<PushPlan> {
+    let config = config::load_config()?;
+    let (image, tag) = crate::resolve_target(image, tag, &mut options, &config)?;
+
+    let deps = PipelineDeps::from_env()?;
+    let staging_dir = tempfile::Builder::new().prefix(STAGING_PREFIX).tempdir_in(&deps.work_dir)?.keep();
+    let deps = PipelineDeps { work_dir: staging_dir, ..deps };
+
+    let plan = Pipeline::new(&image, &tag, &options, &config, &deps).dry_run(&mut PushStats::default()).await?;
+    log::info!(
+        "Planned {}:{}: {} operations, {} bytes to upload, staged in {}",
+        image,
+        tag,
+        plan.operations.len(),
+        plan.upload_bytes(),
+        plan.staging_dir.display()
+    );
+
+    Ok(plan)
+}
+
+// Pushes a reviewed plan. The staged files must be unchanged and the bucket must still call for the
+// same uploads, otherwise the plan is stale and nothing is written.
+pub async fn apply(plan: &PushPlan, mut options: PushOptions) -> Result<PushReport> {
+    let config: Config = config::load_config()?;
+    crate::resolve_destination_defaults(&mut options, &config)?;
+    plan.check(&workdir::work_dir()?)?;
+
+    let report = crate::execute(&plan.image, &plan.tag, &options, &config, Some(plan)).await?;
+    fs::remove_dir_all(&plan.staging_dir).context(format!("Failed to remove {}", plan.staging_dir.display()))?;
+
+    Ok(report)
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+// The containers-policy.json(5) format, so that one policy file governs both skopeo (which
+// evaluates it itself) and the converters that pull without it.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct TrustPolicy {
+    default: Vec<Requirement>,
+    #[serde(default)]
+    transports: HashMap<String, HashMap<String, Vec<Requirement>>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Requirement {
+    InsecureAcceptAnything,
+    Reject,
+    #[serde(rename_all = "camelCase")]
+    SignedBy {
+        key_type: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    SigstoreSigned {
+        key_path: Option<PathBuf>,
+        key_data: Option<String>,
+        fulcio: Option<Fulcio>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Fulcio {
+    oidc_issuer: String,
+    subject_email: String,
+}
+
+impl TrustPolicy {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path).context(format!("Failed to read policy {}", path.display()))?;
+        serde_json::from_str(&data).context(format!("Failed to parse policy {}", path.display()))
+    }
+
+    pub(crate) fn enforce_registry(&self, reference: &str) -> Result<()> {
+        let reference = normalize(reference);
+        let requirements = self.requirements("docker", &registry_scopes(&reference));
+
+        for requirement in requirements {
+            match requirement {
+                Requirement::InsecureAcceptAnything => {}
+                Requirement::Reject => bail!("Policy rejects {}", reference),
+                Requirement::SignedBy { key_type } => {
+                    bail!("Policy requires a {} signature for {}, which only the skopeo converter can verify", key_type, reference)
+                }
+                Requirement::SigstoreSigned { key_path, key_data, fulcio } => {
+                    verify_sigstore(&reference, key_path.as_deref(), key_data.as_deref(), fulcio.as_ref())?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn enforce_layout(&self, layout_dir: &Path) -> Result<()> {
+        let layout_dir = fs::canonicalize(layout_dir).unwrap_or_else(|_| layout_dir.to_owned());
+        let scopes: Vec<String> = layout_dir.ancestors().map(|dir| dir.to_string_lossy().into_owned()).collect();
+
+        for requirement in self.requirements("oci", &scopes) {
+            match requirement {
+                Requirement::InsecureAcceptAnything => {}
+                Requirement::Reject => bail!("Policy rejects {}", layout_dir.display()),
+                _ => bail!("Policy requires signatures for {}, which cannot be verified for OCI layouts", layout_dir.display()),
+            }
+        }
+
+        Ok(())
+    }
+
+    // The most specific scope wins, then the transport default (""), then the global default.
+    fn requirements(&self, transport: &str, scopes: &[String]) -> &[Requirement] {
+        let scoped = self.transports.get(transport);
+        let matched = scoped.and_then(|scoped| scopes.iter().find_map(|scope| scoped.get(scope)).or_else(|| scoped.get("")));
+
+        matched.unwrap_or(&self.default)
+    }
+}
+
+// Mirrors the docker reference normalization: `app` is `docker.io/library/app`.
+fn normalize(reference: &str) -> String {
+    let reference = reference.strip_prefix("docker://").unwrap_or(reference);
+    match reference.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => reference.to_owned(),
+        Some(_) => format!("docker.io/{}", reference),
+        None => format!("docker.io/library/{}", reference),
+    }
+}
+
+fn registry_scopes(reference: &str) -> Vec<String> {
+    let mut scopes = vec![reference.to_owned()];
+
+    let repository = match reference.split_once('@') {
+        Some((repository, _)) => repository,
+        None => match reference.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => repository,
+            _ => reference,
+        },
+    };
+
+    let mut scope = repository;
+    loop {
+        if scope != reference {
+            scopes.push(scope.to_owned());
+        }
+        match scope.rsplit_once('/') {
+            Some((parent, _)) => scope = parent,
+            None => break,
+        }
+    }
+
+    let mut host = scope.split(':').next().unwrap_or(scope);
+    while let Some((_, parent)) = host.split_once('.') {
+        scopes.push(format!("*.{}", parent));
+        host = parent;
+    }
+
+    scopes
+}
+
+fn verify_sigstore(reference: &str, key_path: Option<&Path>, key_data: Option<&str>, fulcio: Option<&Fulcio>) -> Result<()> {
+    let mut command = Command::new("cosign");
+    crate::proxy::apply(&mut command);
+    command.arg("verify");
+
+    let _key_file;
+    match (key_path, key_data, fulcio) {
+        (Some(key_path), _, _) => {
+            command.arg("--key").arg(key_path);
+        }
+        (None, Some(key_data), _) => {
+            let key = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, key_data).context("Invalid keyData in policy")?;
+            let file = NamedTempFile::new()?;
+            fs::write(file.path(), key)?;
+            command.arg("--key").arg(file.path());
+            _key_file = file;
+        }
+        (None, None, Some(fulcio)) => {
+            command
+                .arg("--certificate-oidc-issuer")
+                .arg(&fulcio.oidc_issuer)
+                .arg("--certificate-identity")
+                .arg(&fulcio.subject_email);
+        }
+        (None, None, None) => bail!("sigstoreSigned requirement without keyPath, keyData or fulcio"),
+    }
+
+    let output = command.arg(reference).output().context("Failed to execute cosign command, which is needed to verify sigstore signatures")?;
+    if !output.status.success() {
+        return Err(anyhow!("cosign {}:\n{}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+            .context(format!("Signature verification failed for {}", reference));
+    }
+
+    log::info!("Verified the signature of {}", reference);
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use crate::PushReport;
+
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+// `pr-1234` and `latest` become `pr-1234-latest`, kept within the 128 characters allowed in tags.
+pub(crate) fn preview_tag(preview: &str, tag: &str) -> String {
+    let preview: String = preview
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') { c } else { '-' })
+        .collect();
+
+    format!("{}-{}", preview.trim_matches(['-', '.']), tag).chars().take(128).collect()
+}
+
+pub(crate) fn markdown(report: &PushReport) -> String {
+    let reference = report.pull_reference.clone().unwrap_or_else(|| format!("{}:{}", report.image, report.tag));
+
+    let mut markdown = format!("#### Preview image `{}:{}`\n\n```bash\ndocker pull {}\n```\n", report.image, report.tag, reference);
+    if let Some(manifest_url) = &report.manifest_url {
+        markdown.push_str(&format!("\nManifest: {}\n", manifest_url));
+    }
+    if let Some(digest) = &report.digest {
+        markdown.push_str(&format!("\nDigest: `{}`\n", digest));
+    }
+    if let Some(expires) = report.expires {
+        markdown.push_str(&format!("\nExpires: {}\n", humantime::format_rfc3339_seconds(expires)));
+    }
+
+    markdown
+}
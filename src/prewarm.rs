@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Result};
+use futures::stream::{self, StreamExt};
+
+const CONCURRENCY: usize = 4;
+pub(crate) const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.docker.distribution.manifest.v2+json";
+
+// Fetches the freshly pushed manifests and the largest blobs through the public domain so the
+// first pull from the nearest edge finds them in the Cloudflare cache. Failures only warn.
+pub(crate) async fn prewarm(public_url: &str, image: &str, manifest_url: &str, manifests_dir: &Path, blobs_dir: &Path, blobs: usize) -> Result<()> {
+    let mut urls = vec![manifest_url.to_owned()];
+    for entry in fs::read_dir(manifests_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        urls.push(format!("{}/v2/{}/manifests/{}", public_url, image, name));
+    }
+
+    let mut sizes = Vec::new();
+    for entry in fs::read_dir(blobs_dir)? {
+        let entry = entry?;
+        sizes.push((entry.metadata()?.len(), entry.file_name().to_string_lossy().into_owned()));
+    }
+    sizes.sort_by(|a, b| b.cmp(a));
+    urls.extend(sizes.into_iter().take(blobs).map(|(_, name)| format!("{}/v2/{}/blobs/{}", public_url, image, name)));
+
+    let client = crate::proxy::http_client()?;
+    let results: Vec<_> = stream::iter(urls.clone())
+        .map(|url| {
+            let client = client.clone();
+            async move { fetch(&client, &url).await }
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut warmed = 0;
+    for result in results {
+        match result {
+            Ok(()) => warmed += 1,
+            Err(e) => log::warn!("Failed to prewarm: {:#}", e),
+        }
+    }
+    log::info!("Prewarmed {} of {} objects", warmed, urls.len());
+
+    Ok(())
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<()> {
+    let mut response = client.get(url).header("Accept", MANIFEST_ACCEPT).send().await?;
+    if !response.status().is_success() {
+        bail!("{} answered {}", url, response.status());
+    }
+
+    let cache_status = response.headers().get("cf-cache-status").and_then(|value| value.to_str().ok()).unwrap_or("unknown").to_owned();
+
+    // The edge only stores a response once it has been fetched completely.
+    while response.chunk().await?.is_some() {}
+
+    log::debug!("Prewarmed {} (cache {})", url, cache_status);
+
+    Ok(())
+}
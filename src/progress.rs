@@ -0,0 +1,43 @@
+use std::fmt;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Converting,
+    Uploading,
+    Done,
+}
+
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    Phase(Phase),
+    // A blob or config the converter finished copying, by digest.
+    Converted { name: String },
+    UploadPlanned { objects: usize, bytes: u64 },
+    Uploaded { name: String, bytes: u64 },
+    VerifyPlanned { objects: usize, bytes: u64 },
+    Verified { key: String, bytes: u64 },
+}
+
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<dyn Fn(ProgressEvent) + Send + Sync>>);
+
+impl Progress {
+    pub fn new<F: Fn(ProgressEvent) + Send + Sync + 'static>(callback: F) -> Self {
+        Progress(Some(Arc::new(callback)))
+    }
+
+    pub(crate) fn emit(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.0 {
+            callback(event);
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Progress(callback)" } else { "Progress(none)" })
+    }
+}
@@ -0,0 +1,208 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::SystemTime;
+use anyhow::{anyhow, Context, Result};
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use crate::r2configs::R2Configs;
+use crate::v2::referrers;
+
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+const BUILD_TYPE: &str = "https://github.com/smileostrich/oci-r2-uploader/push@v1";
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+pub(crate) struct BuildContext {
+    pub(crate) builder_id: String,
+    pub(crate) repository: String,
+    pub(crate) git_ref: Option<String>,
+    pub(crate) commit: Option<String>,
+    pub(crate) workflow: Option<String>,
+    pub(crate) invocation_id: Option<String>,
+}
+
+// GitHub Actions and GitLab CI expose everything the predicate needs in their environment.
+pub(crate) fn detect() -> Option<BuildContext> {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+    if var("GITHUB_ACTIONS").is_some() {
+        let server = var("GITHUB_SERVER_URL").unwrap_or_else(|| "https://github.com".to_owned());
+        let repository = format!("{}/{}", server, var("GITHUB_REPOSITORY")?);
+        let invocation_id = var("GITHUB_RUN_ID").map(|run| format!("{}/actions/runs/{}/attempts/{}", repository, run, var("GITHUB_RUN_ATTEMPT").unwrap_or_else(|| "1".to_owned())));
+
+        return Some(BuildContext {
+            builder_id: format!("{}/actions/runner/{}", server, var("RUNNER_ENVIRONMENT").unwrap_or_else(|| "github-hosted".to_owned())),
+            repository,
+            git_ref: var("GITHUB_REF"),
+            commit: var("GITHUB_SHA"),
+            workflow: var("GITHUB_WORKFLOW_REF"),
+            invocation_id,
+        });
+    }
+
+    if var("GITLAB_CI").is_some() {
+        return Some(BuildContext {
+            builder_id: format!("{}/-/runners/{}", var("CI_SERVER_URL")?, var("CI_RUNNER_ID").unwrap_or_default()),
+            repository: var("CI_PROJECT_URL")?,
+            git_ref: var("CI_COMMIT_REF_NAME"),
+            commit: var("CI_COMMIT_SHA"),
+            workflow: var("CI_CONFIG_PATH"),
+            invocation_id: var("CI_JOB_URL"),
+        });
+    }
+
+    None
+}
+
+fn predicate(context: &BuildContext, image: &str, tag: &str, started: SystemTime) -> Value {
+    let mut dependency = json!({ "uri": format!("git+{}", context.repository) });
+    if let Some(git_ref) = &context.git_ref {
+        dependency["uri"] = json!(format!("git+{}@{}", context.repository, git_ref));
+    }
+    if let Some(commit) = &context.commit {
+        dependency["digest"] = json!({ "gitCommit": commit });
+    }
+
+    json!({
+        "buildDefinition": {
+            "buildType": BUILD_TYPE,
+            "externalParameters": {
+                "repository": context.repository,
+                "ref": context.git_ref,
+                "workflow": context.workflow,
+                "image": format!("{}:{}", image, tag),
+            },
+            "internalParameters": {},
+            "resolvedDependencies": [dependency],
+        },
+        "runDetails": {
+            "builder": { "id": context.builder_id },
+            "metadata": {
+                "invocationId": context.invocation_id,
+                "startedOn": humantime::format_rfc3339_seconds(started).to_string(),
+                "finishedOn": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            },
+        },
+    })
+}
+
+// Signs a SLSA v1 provenance attestation for the pushed manifest with `cosign attest-blob`
+// (keyless unless a key is given) and stores it as an OCI 1.1 referrer of the image.
+pub(crate) async fn attach(image: &str, tag: &str, manifest: &Path, key: Option<&Path>, started: SystemTime, client: &S3Client, env_vars: &R2Configs) -> Result<Option<String>> {
+    let r2_bucket = env_vars.r2_bucket.as_str();
+    let context = match detect() {
+        Some(context) => context,
+        None => {
+            log::warn!("Skipping provenance: no supported CI environment detected");
+            return Ok(None);
+        }
+    };
+
+    let work_dir = TempDir::new()?;
+    let predicate_path = work_dir.path().join("predicate.json");
+    let envelope_path = work_dir.path().join("attestation.json");
+    let bundle_path = work_dir.path().join("bundle.json");
+    fs::write(&predicate_path, serde_json::to_vec(&predicate(&context, image, tag, started))?)?;
+
+    let mut command = Command::new("cosign");
+    crate::proxy::apply(&mut command);
+    command
+        .arg("attest-blob")
+        .arg("--yes")
+        .arg("--predicate")
+        .arg(&predicate_path)
+        .arg("--type")
+        .arg(PREDICATE_TYPE)
+        .arg("--output-attestation")
+        .arg(&envelope_path)
+        .arg("--bundle")
+        .arg(&bundle_path);
+    if let Some(key) = key {
+        command.arg("--key").arg(key);
+    }
+
+    let output = command.arg(manifest).output().context("Failed to execute cosign command")?;
+    if !output.status.success() {
+        return Err(anyhow!("cosign {}:\n{}", output.status, String::from_utf8_lossy(&output.stderr).trim())).context("Failed to sign provenance");
+    }
+
+    let subject_data = fs::read(manifest)?;
+    let subject: Value = serde_json::from_slice(&subject_data)?;
+    let subject_digest = sha256(&subject_data);
+
+    let mut layers = Vec::new();
+    for (path, media_type) in [(&envelope_path, "application/vnd.dsse.envelope.v1+json"), (&bundle_path, "application/vnd.dev.sigstore.bundle+json")] {
+        if let Ok(data) = fs::read(path) {
+            let digest = put_blob(client, r2_bucket, image, data.clone(), media_type).await?;
+            layers.push(json!({
+                "mediaType": media_type,
+                "digest": digest,
+                "size": data.len(),
+                "annotations": { "in-toto.io/predicate-type": PREDICATE_TYPE },
+            }));
+        }
+    }
+
+    let config_digest = put_blob(client, r2_bucket, image, EMPTY_CONFIG.to_vec(), "application/vnd.oci.empty.v1+json").await?;
+    let referrer = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": "application/vnd.in-toto+json",
+        "config": { "mediaType": "application/vnd.oci.empty.v1+json", "digest": config_digest, "size": EMPTY_CONFIG.len() },
+        "layers": layers,
+        "subject": {
+            "mediaType": subject["mediaType"].as_str().unwrap_or("application/vnd.oci.image.manifest.v1+json"),
+            "digest": subject_digest,
+            "size": subject_data.len(),
+        },
+        "annotations": {
+            "org.opencontainers.image.created": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        },
+    });
+
+    let referrer_data = serde_json::to_vec(&referrer)?;
+    let referrer_digest = sha256(&referrer_data);
+    let descriptor = json!({
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": referrer["artifactType"],
+        "digest": referrer_digest,
+        "size": referrer_data.len(),
+        "annotations": referrer["annotations"],
+    });
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: format!("v2/{}/manifests/{}", image, referrer_digest),
+        body: Some(referrer_data.into()),
+        content_type: Some("application/vnd.oci.image.manifest.v1+json".to_owned()),
+        ..Default::default()
+    };
+    client.put_object(req).await.context("Failed to upload the provenance manifest")?;
+
+    referrers::add(env_vars, image, &subject_digest, descriptor).await?;
+
+    log::info!("Attached SLSA provenance {} to {}:{}", referrer_digest, image, tag);
+
+    Ok(Some(referrer_digest))
+}
+
+async fn put_blob(client: &S3Client, r2_bucket: &str, image: &str, data: Vec<u8>, media_type: &str) -> Result<String> {
+    let digest = sha256(&data);
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: format!("v2/{}/blobs/{}", image, digest),
+        body: Some(data.into()),
+        content_type: Some(media_type.to_owned()),
+        ..Default::default()
+    };
+    client.put_object(req).await.context(format!("Failed to upload blob {}", digest))?;
+
+    Ok(digest)
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
@@ -0,0 +1,107 @@
+use std::str::FromStr;
+use anyhow::{bail, Result};
+use serde_json::json;
+
+use crate::config;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PullConfigFormat {
+    ContainerdHosts,
+    ContainerdCri,
+    DockerDaemon,
+}
+
+impl FromStr for PullConfigFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "containerd-hosts" => Ok(PullConfigFormat::ContainerdHosts),
+            "containerd-cri" => Ok(PullConfigFormat::ContainerdCri),
+            "docker" => Ok(PullConfigFormat::DockerDaemon),
+            _ => bail!("Unknown pull config format {} (expected containerd-hosts, containerd-cri or docker)", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedFile {
+    pub path: String,
+    pub contents: String,
+}
+
+pub fn configure_pull(public_url: Option<&str>, format: PullConfigFormat, credential_helper: bool) -> Result<Vec<GeneratedFile>> {
+    let public_url = match public_url {
+        Some(public_url) => public_url.to_owned(),
+        None => match config::load_config()?.public_url {
+            Some(public_url) => public_url,
+            None => bail!("No public URL given and public_url is not set in the config file"),
+        },
+    };
+    let public_url = public_url.trim_end_matches('/');
+    if !public_url.starts_with("https://") && !public_url.starts_with("http://") {
+        bail!("The public URL must start with https:// or http://, got {}", public_url);
+    }
+
+    let host = crate::registry_host(public_url);
+
+    let mut files = vec![match format {
+        PullConfigFormat::ContainerdHosts => GeneratedFile {
+            path: format!("/etc/containerd/certs.d/{}/hosts.toml", host),
+            contents: format!(
+                "server = \"{url}\"\n\n[host.\"{url}\"]\n  capabilities = [\"pull\", \"resolve\"]\n",
+                url = public_url,
+            ),
+        },
+        PullConfigFormat::ContainerdCri => GeneratedFile {
+            path: "/etc/containerd/config.toml".to_owned(),
+            contents: format!(
+                "[plugins.\"io.containerd.grpc.v1.cri\".registry.mirrors.\"{}\"]\n  endpoint = [\"{}\"]\n",
+                host, public_url,
+            ),
+        },
+        PullConfigFormat::DockerDaemon => {
+            let mut daemon = json!({ "registry-mirrors": [public_url] });
+            if public_url.starts_with("http://") {
+                daemon["insecure-registries"] = json!([host]);
+            }
+
+            GeneratedFile {
+                path: "/etc/docker/daemon.json".to_owned(),
+                contents: format!("{:#}\n", daemon),
+            }
+        }
+    }];
+
+    if credential_helper {
+        files.push(GeneratedFile {
+            path: "/usr/local/bin/docker-credential-r2".to_owned(),
+            contents: credential_helper_script(public_url),
+        });
+        files.push(GeneratedFile {
+            path: "~/.docker/config.json".to_owned(),
+            contents: format!("{:#}\n", json!({ "credHelpers": { host: "r2" } })),
+        });
+    }
+
+    Ok(files)
+}
+
+fn credential_helper_script(public_url: &str) -> String {
+    format!(
+        r#"#!/bin/sh
+# Docker credential helper for {url}.
+# Reads the pull token from OCI_R2_PULL_TOKEN; only `get` is supported.
+case "$1" in
+  get)
+    read -r server
+    printf '{{"ServerURL":"%s","Username":"%s","Secret":"%s"}}\n' "$server" "${{OCI_R2_PULL_USER:-token}}" "$OCI_R2_PULL_TOKEN"
+    ;;
+  *)
+    exit 0
+    ;;
+esac
+"#,
+        url = public_url,
+    )
+}
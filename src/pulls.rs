@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::r2configs::{self, R2Configs};
+use crate::v2::cas;
+
+// Pull counts per repository, kept in the bucket so they outlive any one serve process.
+pub(crate) const PULLS_KEY: &str = "v2/_pulls.json";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepositoryPulls {
+    // Manifest pulls by tag. Clients pulling a multi-platform image by tag fetch the index by tag
+    // and the platform manifest by digest, so each pull counts once.
+    pub pulls: u64,
+    #[serde(default)]
+    pub by_digest: u64,
+    #[serde(default)]
+    pub tags: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_pulled: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct PullCounts {
+    #[serde(default)]
+    pub(crate) repositories: BTreeMap<String, RepositoryPulls>,
+    // Bytes already imported from each event log, keyed by the SHA-256 of the log's first line.
+    // Every line starts with the time of its pull, so a rotated log starts a new entry.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) imported: BTreeMap<String, u64>,
+}
+
+impl PullCounts {
+    pub(crate) fn record(&mut self, repository: &str, reference: &str, time: &str) {
+        let counts = self.repositories.entry(repository.to_owned()).or_default();
+        match reference.starts_with("sha256:") {
+            true => counts.by_digest += 1,
+            false => {
+                counts.pulls += 1;
+                *counts.tags.entry(reference.to_owned()).or_default() += 1;
+            }
+        }
+        if counts.last_pulled.as_deref().is_none_or(|last| last < time) {
+            counts.last_pulled = Some(time.to_owned());
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.repositories.is_empty()
+    }
+
+    pub(crate) fn add(&mut self, other: &PullCounts) {
+        for (repository, pulls) in &other.repositories {
+            let counts = self.repositories.entry(repository.clone()).or_default();
+            counts.pulls += pulls.pulls;
+            counts.by_digest += pulls.by_digest;
+            for (tag, count) in &pulls.tags {
+                *counts.tags.entry(tag.clone()).or_default() += count;
+            }
+            if pulls.last_pulled > counts.last_pulled {
+                counts.last_pulled = pulls.last_pulled.clone();
+            }
+        }
+    }
+}
+
+pub(crate) async fn read(env_vars: &R2Configs) -> Result<PullCounts> {
+    match cas::read_object(env_vars, PULLS_KEY).await? {
+        Some(data) => serde_json::from_slice(&data).context(format!("Malformed {}", PULLS_KEY)),
+        None => Ok(PullCounts::default()),
+    }
+}
+
+// Adds `counts` to the totals in the bucket.
+pub(crate) async fn merge(env_vars: &R2Configs, counts: &PullCounts) -> Result<()> {
+    cas::update_object(env_vars, PULLS_KEY, "application/json", |current| {
+        let mut totals: PullCounts = match current {
+            Some(data) => serde_json::from_slice(data).context(format!("Malformed {}", PULLS_KEY))?,
+            None => PullCounts::default(),
+        };
+        totals.add(counts);
+
+        Ok(Some(serde_json::to_vec(&totals)?))
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct LoggedPull {
+    time: String,
+    repository: String,
+    reference: String,
+}
+
+// Adds the pulls recorded in a serve mode event log (`[serve.events] log_file`) to the counts in
+// the bucket, for setups where the counting server cannot write to the bucket. The bucket records
+// how far each log has been imported, so importing a log again, or again after it grew, only adds
+// the pulls logged since. Returns the number of pulls imported.
+pub async fn import_pull_log<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    // A line without its newline is still being written.
+    let complete = data.rfind('\n').map_or(0, |end| end + 1);
+    let log = match data[..complete].lines().next() {
+        Some(first) => format!("{:x}", Sha256::digest(first)),
+        None => {
+            log::info!("Imported 0 pulls from {}", path.display());
+            return Ok(0);
+        }
+    };
+
+    let mut imported = 0;
+    cas::update_object(&r2configs::parse_r2configs()?, PULLS_KEY, "application/json", |current| {
+        let mut totals: PullCounts = match current {
+            Some(data) => serde_json::from_slice(data).context(format!("Malformed {}", PULLS_KEY))?,
+            None => PullCounts::default(),
+        };
+        let start = totals.imported.get(&log).map_or(0, |&offset| (offset as usize).min(complete));
+        let skipped = data[..start].lines().count();
+
+        let mut counts = PullCounts::default();
+        imported = 0;
+        for (number, line) in data[start..complete].lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let line_number = skipped + number + 1;
+            let pull: LoggedPull = serde_json::from_str(line).context(format!("Malformed event on line {} of {}", line_number, path.display()))?;
+            counts.record(&pull.repository, &pull.reference, &pull.time);
+            imported += 1;
+        }
+        if start == complete {
+            return Ok(None);
+        }
+        totals.add(&counts);
+        totals.imported.insert(log.clone(), complete as u64);
+
+        Ok(Some(serde_json::to_vec(&totals)?))
+    })
+    .await?;
+    log::info!("Imported {} pulls from {}", imported, path.display());
+
+    Ok(imported)
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Result};
+use rusoto_s3::S3Client;
+
+use crate::config::QuotaConfig;
+use crate::v2::lister::Lister;
+
+// Estimates the namespace usage after the push from a listing of the namespace plus the staged
+// files, counting overwritten objects only once.
+pub(crate) async fn check(quotas: &[QuotaConfig], image: &str, dirs: &[(&str, &Path)], client: &S3Client, r2_bucket: &str, ignore_quota: bool) -> Result<()> {
+    for quota in quotas.iter().filter(|quota| quota.applies_to(image)) {
+        let prefix = format!("v2/{}/", quota.namespace.trim_end_matches('/'));
+        let existing: HashMap<String, u64> = Lister::new(client, r2_bucket)
+            .concurrency(8)
+            .list(&prefix)
+            .await?
+            .into_iter()
+            .map(|object| (object.key, object.size))
+            .collect();
+
+        let mut bytes: u64 = existing.values().sum();
+        let mut objects = existing.len() as u64;
+        for (kind, dir) in dirs {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let key = format!("v2/{}/{}/{}", image, kind, entry.file_name().to_string_lossy());
+                let size = entry.metadata()?.len();
+                match existing.get(&key) {
+                    Some(old) => bytes = bytes - old + size,
+                    None => {
+                        bytes += size;
+                        objects += 1;
+                    }
+                }
+            }
+        }
+
+        let mut exceeded = Vec::new();
+        if let Some(max_bytes) = quota.max_bytes.filter(|max_bytes| bytes > *max_bytes) {
+            exceeded.push(format!("{} of {} bytes", bytes, max_bytes));
+        }
+        if let Some(max_objects) = quota.max_objects.filter(|max_objects| objects > *max_objects) {
+            exceeded.push(format!("{} of {} objects", objects, max_objects));
+        }
+
+        if exceeded.is_empty() {
+            log::debug!("Quota for {}: {} bytes, {} objects after push", quota.namespace, bytes, objects);
+        } else if ignore_quota {
+            log::warn!("Ignoring the quota for {}: {}", quota.namespace, exceeded.join(", "));
+        } else {
+            bail!("Pushing {} would exceed the quota for {}: {}", image, quota.namespace, exceeded.join(", "));
+        }
+    }
+
+    Ok(())
+}
@@ -1,6 +1,10 @@
 use std::env;
-use anyhow::{Context, Result};
+use anyhow::{bail, Result};
 
+use crate::config;
+use crate::destination;
+
+#[derive(Clone)]
 pub struct R2Configs {
     pub cloudflare_account_id: String,
     pub r2_bucket: String,
@@ -9,15 +13,32 @@ pub struct R2Configs {
 }
 
 pub fn parse_r2configs() -> Result<R2Configs> {
-    let cloudflare_account_id = env::var("CLOUDFLARE_ACCOUNT_ID").context("CLOUDFLARE_ACCOUNT_ID is not set")?;
-    let r2_bucket = env::var("R2_BUCKET").context("R2_BUCKET is not set")?;
-    let r2_access_key_id = env::var("R2_ACCESS_KEY_ID").context("R2_ACCESS_KEY_ID is not set")?;
-    let r2_secret_access_key = env::var("R2_SECRET_ACCESS_KEY").context("R2_SECRET_ACCESS_KEY is not set")?;
+    // A selected destination may replace any of the variables, so they are only required when it
+    // does not.
+    let mut env_vars = R2Configs {
+        cloudflare_account_id: env::var("CLOUDFLARE_ACCOUNT_ID").unwrap_or_default(),
+        r2_bucket: env::var("R2_BUCKET").unwrap_or_default(),
+        r2_access_key_id: env::var("R2_ACCESS_KEY_ID").unwrap_or_default(),
+        r2_secret_access_key: env::var("R2_SECRET_ACCESS_KEY").unwrap_or_default(),
+    };
+
+    if destination::selected().is_some() {
+        let config = config::load_config_file()?;
+        if let Some((_, destination)) = destination::resolve(&config)? {
+            destination::apply(destination, &mut env_vars)?;
+        }
+    }
+
+    for (name, value) in [
+        ("CLOUDFLARE_ACCOUNT_ID", &env_vars.cloudflare_account_id),
+        ("R2_BUCKET", &env_vars.r2_bucket),
+        ("R2_ACCESS_KEY_ID", &env_vars.r2_access_key_id),
+        ("R2_SECRET_ACCESS_KEY", &env_vars.r2_secret_access_key),
+    ] {
+        if value.is_empty() {
+            bail!("{} is not set", name);
+        }
+    }
 
-    Ok(R2Configs {
-        cloudflare_account_id,
-        r2_bucket,
-        r2_access_key_id,
-        r2_secret_access_key,
-    })
+    Ok(env_vars)
 }
@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::SystemTime;
+use anyhow::{anyhow, bail, Context, Result};
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use serde_json::json;
+use tempfile::TempDir;
+
+use crate::provenance;
+use crate::PushReport;
+
+const RECEIPT_TYPE: &str = "https://github.com/smileostrich/oci-r2-uploader/receipt@v1";
+pub(crate) const RECEIPTS_PREFIX: &str = "v2/_receipts/";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReceiptKey {
+    Cosign(PathBuf),
+    Minisign(PathBuf),
+}
+
+impl FromStr for ReceiptKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("cosign", key)) => Ok(ReceiptKey::Cosign(PathBuf::from(key))),
+            Some(("minisign", key)) => Ok(ReceiptKey::Minisign(PathBuf::from(key))),
+            _ => bail!("Invalid receipt key {} (expected cosign:<key> or minisign:<key>)", s),
+        }
+    }
+}
+
+impl ReceiptKey {
+    fn signature_extension(&self) -> &'static str {
+        match self {
+            ReceiptKey::Cosign(_) => "sig",
+            ReceiptKey::Minisign(_) => "minisig",
+        }
+    }
+
+    fn sign(&self, receipt: &std::path::Path, signature: &std::path::Path) -> Result<()> {
+        let (tool, mut command) = match self {
+            ReceiptKey::Cosign(key) => {
+                let mut command = Command::new("cosign");
+                command.arg("sign-blob").arg("--yes").arg("--key").arg(key).arg("--output-signature").arg(signature).arg(receipt);
+                ("cosign", command)
+            }
+            ReceiptKey::Minisign(key) => {
+                let mut command = Command::new("minisign");
+                command.arg("-S").arg("-s").arg(key).arg("-m").arg(receipt).arg("-x").arg(signature);
+                ("minisign", command)
+            }
+        };
+        crate::proxy::apply(&mut command);
+
+        let output = command.output().context(format!("Failed to execute {} command", tool))?;
+        if !output.status.success() {
+            return Err(anyhow!("{} {}:\n{}", tool, output.status, String::from_utf8_lossy(&output.stderr).trim())).context("Failed to sign the push receipt");
+        }
+
+        Ok(())
+    }
+}
+
+// Signs a statement that this uploader pushed `report.digest` as `report.image:report.tag` and
+// stores it, with its signature, under `v2/_receipts/<image>/`. The dedup outcome of the push is
+// recorded in its annotations. Returns the receipt key.
+pub(crate) async fn attach(report: &PushReport, key: &ReceiptKey, client: &S3Client, r2_bucket: &str) -> Result<String> {
+    let digest = report.digest.as_deref().context("The pushed manifest has no digest to sign")?;
+    let ci = provenance::detect().map(|context| {
+        json!({
+            "builder": context.builder_id,
+            "repository": context.repository,
+            "ref": context.git_ref,
+            "commit": context.commit,
+            "invocation": context.invocation_id,
+        })
+    });
+
+    let receipt = json!({
+        "type": RECEIPT_TYPE,
+        "image": report.image,
+        "tag": report.tag,
+        "digest": digest,
+        "bucket": r2_bucket,
+        "request_id": report.request_id,
+        "pushed_at": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "uploader": format!("oci-r2-uploader {}", env!("CARGO_PKG_VERSION")),
+        "ci": ci,
+        "annotations": report.stats.dedup_annotations(),
+    });
+
+    let work_dir = TempDir::new()?;
+    let receipt_path = work_dir.path().join("receipt.json");
+    let signature_path = work_dir.path().join("receipt.sig");
+    fs::write(&receipt_path, serde_json::to_vec_pretty(&receipt)?)?;
+    key.sign(&receipt_path, &signature_path)?;
+
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    let receipt_key = format!("{}{}/{}.json", RECEIPTS_PREFIX, report.image, hex);
+    let signature_key = format!("{}.{}", receipt_key, key.signature_extension());
+    for (object_key, path, content_type) in [(&receipt_key, &receipt_path, "application/json"), (&signature_key, &signature_path, "text/plain")] {
+        let req = PutObjectRequest {
+            bucket: r2_bucket.to_owned(),
+            key: object_key.clone(),
+            body: Some(fs::read(path)?.into()),
+            content_type: Some(content_type.to_owned()),
+            ..Default::default()
+        };
+        client.put_object(req).await.context(format!("Failed to upload {}", object_key))?;
+    }
+
+    log::info!("Stored signed push receipt {}", receipt_key);
+
+    Ok(receipt_key)
+}
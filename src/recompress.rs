@@ -0,0 +1,270 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::canonical;
+use crate::hash_utils;
+use crate::stats::LayerRecompression;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+const DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const OCI_LAYERS: [&str; 3] = [
+    "application/vnd.oci.image.layer.v1.tar",
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+    "application/vnd.oci.image.layer.v1.tar+zstd",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("Unknown compression {} (expected gzip or zstd)", s),
+        }
+    }
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn default_level(self) -> i32 {
+        match self {
+            Compression::Gzip => 6,
+            Compression::Zstd => 3,
+        }
+    }
+
+    fn levels(self) -> (i32, i32) {
+        match self {
+            Compression::Gzip => (0, 9),
+            Compression::Zstd => (1, 22),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recompression {
+    pub compression: Compression,
+    pub level: Option<i32>,
+    pub threads: usize,
+}
+
+impl Default for Recompression {
+    fn default() -> Self {
+        Recompression {
+            compression: Compression::default(),
+            level: None,
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+        }
+    }
+}
+
+impl Recompression {
+    fn level(&self) -> Result<i32> {
+        let level = self.level.unwrap_or_else(|| self.compression.default_level());
+        let (min, max) = self.compression.levels();
+        if !(min..=max).contains(&level) {
+            bail!("{} level must be between {} and {}, got {}", self.compression.name(), min, max, level);
+        }
+
+        Ok(level)
+    }
+}
+
+// Recompresses every layer of the staged image in `dir` and rewrites the manifests (and, for an
+// index, the child manifest digests) to match.
+pub(crate) fn recompress(dir: &Path, settings: &Recompression) -> Result<Vec<LayerRecompression>> {
+    let level = settings.level()?;
+
+    let top_path = dir.join("manifest.json");
+    let mut top: Value = read_json(&top_path)?;
+    let children: Vec<String> = top["manifests"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|child| hex(child).map(str::to_owned))
+        .collect();
+
+    let mut manifests = Vec::new();
+    for child in &children {
+        manifests.push(read_json(&dir.join(format!("{}.manifest.json", child)))?);
+    }
+    if top["manifests"].is_null() {
+        manifests.push(top.clone());
+    }
+
+    let mut layers: Vec<String> = Vec::new();
+    for manifest in &manifests {
+        let docker = manifest["mediaType"].as_str().is_some_and(|media_type| media_type.starts_with("application/vnd.docker."));
+        if docker && settings.compression == Compression::Zstd {
+            bail!("Docker schema 2 manifests cannot reference zstd layers, convert the image to OCI or use gzip");
+        }
+
+        for layer in manifest["layers"].as_array().into_iter().flatten() {
+            let media_type = layer["mediaType"].as_str().unwrap_or_default();
+            if let Some(hex) = hex(layer).filter(|_| is_tar_layer(media_type)) {
+                if !layers.iter().any(|known| known == hex) {
+                    layers.push(hex.to_owned());
+                }
+            }
+        }
+    }
+
+    let results = recompress_layers(dir, &layers, settings.compression, level, settings.threads.max(1))?;
+
+    for manifest in &mut manifests {
+        for layer in manifest["layers"].as_array_mut().into_iter().flatten() {
+            let result = match hex(layer).and_then(|hex| results.iter().find(|result| result.name == hex)) {
+                Some(result) => result,
+                None => continue,
+            };
+
+            let docker = layer["mediaType"].as_str() == Some(DOCKER_LAYER);
+            layer["digest"] = Value::from(format!("sha256:{}", result.digest));
+            layer["size"] = Value::from(result.after);
+            if !docker {
+                layer["mediaType"] = Value::from(format!("application/vnd.oci.image.layer.v1.tar+{}", settings.compression.name()));
+            }
+        }
+    }
+
+    if children.is_empty() {
+        fs::write(&top_path, canonical::to_vec(&manifests[0])?)?;
+    } else {
+        for (child, manifest) in children.iter().zip(&manifests) {
+            let data = canonical::to_vec(manifest)?;
+            fs::remove_file(dir.join(format!("{}.manifest.json", child)))?;
+            let new_path = dir.join("child.manifest.json.tmp");
+            fs::write(&new_path, &data)?;
+            let new_hex = hash_utils::compute_sha256(&new_path)?.trim_start_matches("sha256:").to_owned();
+            fs::rename(&new_path, dir.join(format!("{}.manifest.json", new_hex)))?;
+
+            for descriptor in top["manifests"].as_array_mut().into_iter().flatten() {
+                if hex(descriptor) == Some(child) {
+                    descriptor["digest"] = Value::from(format!("sha256:{}", new_hex));
+                    descriptor["size"] = Value::from(data.len());
+                }
+            }
+        }
+        fs::write(&top_path, canonical::to_vec(&top)?)?;
+    }
+
+    Ok(results.into_iter().map(|result| result.stats).collect())
+}
+
+struct Recompressed {
+    name: String,
+    digest: String,
+    after: u64,
+    stats: LayerRecompression,
+}
+
+fn recompress_layers(dir: &Path, layers: &[String], compression: Compression, level: i32, threads: usize) -> Result<Vec<Recompressed>> {
+    let next = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(layers.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    while let Some(layer) = layers.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        results.push(recompress_layer(dir, layer, compression, level)?);
+                    }
+
+                    Ok::<_, anyhow::Error>(results)
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for worker in workers {
+            results.extend(worker.join().expect("recompression worker panicked")?);
+        }
+
+        Ok(results)
+    })
+}
+
+fn recompress_layer(dir: &Path, name: &str, compression: Compression, level: i32) -> Result<Recompressed> {
+    let started = Instant::now();
+    let src = dir.join(name);
+    let before = fs::metadata(&src)?.len();
+    let tmp = dir.join(format!("{}.recompress", name));
+
+    let mut magic = [0; 4];
+    let read = File::open(&src)?.read(&mut magic)?;
+    let file = BufReader::new(File::open(&src)?);
+    let mut tar: Box<dyn Read> = if read >= 2 && magic[..2] == GZIP_MAGIC {
+        Box::new(flate2::read::MultiGzDecoder::new(file))
+    } else if read == 4 && magic == ZSTD_MAGIC {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    let out = BufWriter::new(File::create(&tmp)?);
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(out, flate2::Compression::new(level as u32));
+            io::copy(&mut tar, &mut encoder).context(format!("Failed to recompress layer {}", name))?;
+            encoder.finish()?;
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(out, level)?;
+            io::copy(&mut tar, &mut encoder).context(format!("Failed to recompress layer {}", name))?;
+            encoder.finish()?;
+        }
+    }
+
+    let digest = hash_utils::compute_sha256(&tmp)?.trim_start_matches("sha256:").to_owned();
+    let after = fs::metadata(&tmp)?.len();
+    fs::remove_file(&src)?;
+    fs::rename(&tmp, dir.join(&digest))?;
+
+    let stats = LayerRecompression {
+        name: format!("sha256:{}", digest),
+        compression: compression.name(),
+        level,
+        before,
+        after,
+        duration: started.elapsed(),
+    };
+    log::info!("Recompressed layer {} with {} level {}: {} -> {} bytes", name, compression.name(), level, before, after);
+
+    Ok(Recompressed { name: name.to_owned(), digest, after, stats })
+}
+
+fn is_tar_layer(media_type: &str) -> bool {
+    media_type == DOCKER_LAYER || OCI_LAYERS.contains(&media_type)
+}
+
+fn hex(descriptor: &Value) -> Option<&str> {
+    descriptor["digest"].as_str()?.split_once(':').map(|(_, hex)| hex)
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let data = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&data).context(format!("Failed to parse {}", path.display()))
+}
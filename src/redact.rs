@@ -0,0 +1,62 @@
+use std::path::Path;
+use anyhow::Result;
+use glob::Pattern;
+use serde_json::{Map, Value};
+
+use crate::canonical;
+use crate::config::RedactionConfig;
+
+// Applies the redaction policy to the labels in the image configs and to the annotations of the
+// manifests staged in `dir`: matching keys are removed, and the configured rewrites are applied to
+// the remaining values. Returns the number of labels and annotations removed or rewritten.
+pub(crate) fn redact(policy: &RedactionConfig, dir: &Path) -> Result<usize> {
+    if policy.is_empty() {
+        return Ok(0);
+    }
+
+    // Patterns are checked by `validate_config`; invalid ones match nothing.
+    let remove: Vec<Pattern> = policy.remove.iter().filter_map(|pattern| Pattern::new(pattern).ok()).collect();
+
+    let mut redacted = 0;
+    canonical::rewrite_configs(dir, |config| {
+        let count = match config["config"]["Labels"].as_object_mut() {
+            Some(labels) => redact_map(labels, &remove, policy),
+            None => 0,
+        };
+        redacted += count;
+        Ok(count > 0)
+    })?;
+    canonical::rewrite_manifests(dir, |manifest| {
+        let count = match manifest["annotations"].as_object_mut() {
+            Some(annotations) => redact_map(annotations, &remove, policy),
+            None => 0,
+        };
+        redacted += count;
+        Ok(count > 0)
+    })?;
+
+    if redacted > 0 {
+        log::info!("Redacted {} labels and annotations", redacted);
+    }
+
+    Ok(redacted)
+}
+
+fn redact_map(map: &mut Map<String, Value>, remove: &[Pattern], policy: &RedactionConfig) -> usize {
+    let before = map.len();
+    map.retain(|key, _| !remove.iter().any(|pattern| pattern.matches(key)));
+    let mut count = before - map.len();
+
+    for value in map.values_mut() {
+        let Some(text) = value.as_str() else {
+            continue;
+        };
+        let rewritten = policy.rewrite.iter().fold(text.to_owned(), |text, rule| text.replace(&rule.find, &rule.replace));
+        if rewritten != text {
+            *value = Value::from(rewritten);
+            count += 1;
+        }
+    }
+
+    count
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, SystemTime};
+use anyhow::{bail, Context, Result};
+use rusoto_s3::{CopyObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, StreamingBody, S3};
+
+use crate::capabilities::{self, Capability};
+use crate::config::{self, ReplicaConfig};
+use crate::names;
+use crate::r2configs::{self, R2Configs};
+use crate::v2;
+use crate::v2::catalog::CATALOG_KEY;
+use crate::v2::chunks::CHUNKS_PREFIX;
+use crate::v2::lister::{ListedObject, Lister};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    pub name: String,
+    pub bucket: String,
+    pub missing_objects: u64,
+    pub missing_bytes: u64,
+    pub lag: Option<Duration>,
+}
+
+struct Replica {
+    name: String,
+    env_vars: R2Configs,
+    client: S3Client,
+    same_account: bool,
+}
+
+// Copies every object of `image` (plus the shared catalog) that is missing or outdated on the
+// secondaries. Within the primary account R2 copies server-side, across accounts the objects are
+// streamed through this process.
+pub(crate) async fn replicate(primary: &R2Configs, client: &S3Client, replicas: &[ReplicaConfig], image: &str) -> Result<()> {
+    let prefix = format!("v2/{}/", image);
+    let mut source = Lister::new(client, &primary.r2_bucket).list(&prefix).await?;
+    source.retain(|object| names::owns_key(image, &object.key));
+    source.extend(Lister::new(client, &primary.r2_bucket).list(CATALOG_KEY).await?);
+    if source.iter().any(|object| object.key.contains("/recipes/")) {
+        source.extend(Lister::new(client, &primary.r2_bucket).concurrency(8).list(CHUNKS_PREFIX).await?);
+    }
+
+    let mut failed = Vec::new();
+    for replica in replicas {
+        let result = async {
+            let replica = connect(primary, replica)?;
+            capabilities::require(&replica.client, &replica.env_vars.r2_bucket, "replicate", &[Capability::Write]).await?;
+
+            let pending = pending(&replica, &source, &prefix).await?;
+            for object in &pending {
+                copy(primary, client, &replica, &object.key).await?;
+            }
+
+            log::info!("Replicated {} objects of {} to {}", pending.len(), image, replica.name);
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            log::warn!("Failed to replicate {} to {}: {:#}", image, replica.name, e);
+            failed.push(replica.name.as_str());
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("Replication failed for {}", failed.join(", "));
+    }
+
+    Ok(())
+}
+
+pub async fn replication_status() -> Result<Vec<ReplicationStatus>> {
+    let config = config::load_config()?;
+    let primary = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&primary)?;
+
+    let source = Lister::new(&client, &primary.r2_bucket).concurrency(8).list("v2/").await?;
+    let now = SystemTime::now();
+
+    let mut statuses = Vec::new();
+    for replica in &config.replication {
+        let replica = connect(&primary, replica)?;
+        let pending = pending(&replica, &source, "v2/").await?;
+
+        let oldest = pending
+            .iter()
+            .filter_map(|object| object.last_modified.as_deref())
+            .filter_map(|last_modified| humantime::parse_rfc3339_weak(last_modified).ok())
+            .min();
+
+        statuses.push(ReplicationStatus {
+            name: replica.name,
+            bucket: replica.env_vars.r2_bucket,
+            missing_objects: pending.len() as u64,
+            missing_bytes: pending.iter().map(|object| object.size).sum(),
+            lag: oldest.map(|oldest| now.duration_since(oldest).unwrap_or_default()),
+        });
+    }
+
+    Ok(statuses)
+}
+
+fn connect(primary: &R2Configs, replica: &ReplicaConfig) -> Result<Replica> {
+    let credential = |name: &Option<String>, default: &str| match name {
+        Some(name) => env::var(name).context(format!("{} is not set", name)),
+        None => Ok(default.to_owned()),
+    };
+
+    let env_vars = R2Configs {
+        cloudflare_account_id: replica.account_id.clone().unwrap_or_else(|| primary.cloudflare_account_id.clone()),
+        r2_bucket: replica.bucket.clone(),
+        r2_access_key_id: credential(&replica.access_key_id_env, &primary.r2_access_key_id)?,
+        r2_secret_access_key: credential(&replica.secret_access_key_env, &primary.r2_secret_access_key)?,
+    };
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+
+    Ok(Replica {
+        name: replica.name.clone(),
+        same_account: env_vars.cloudflare_account_id == primary.cloudflare_account_id,
+        env_vars,
+        client,
+    })
+}
+
+async fn pending<'a>(replica: &Replica, source: &'a [ListedObject], prefix: &str) -> Result<Vec<&'a ListedObject>> {
+    let mut existing: HashMap<String, ListedObject> = Lister::new(&replica.client, &replica.env_vars.r2_bucket)
+        .concurrency(8)
+        .list(prefix)
+        .await?
+        .into_iter()
+        .map(|object| (object.key.clone(), object))
+        .collect();
+    if prefix != "v2/" {
+        let lister = Lister::new(&replica.client, &replica.env_vars.r2_bucket).concurrency(8);
+        existing.extend(lister.list(CATALOG_KEY).await?.into_iter().map(|object| (object.key.clone(), object)));
+        if source.iter().any(|object| object.key.starts_with(CHUNKS_PREFIX)) {
+            existing.extend(lister.list(CHUNKS_PREFIX).await?.into_iter().map(|object| (object.key.clone(), object)));
+        }
+    }
+
+    Ok(source
+        .iter()
+        .filter(|object| match existing.get(&object.key) {
+            Some(copy) => copy.size != object.size || copy.e_tag != object.e_tag,
+            None => true,
+        })
+        .collect())
+}
+
+async fn copy(primary: &R2Configs, client: &S3Client, replica: &Replica, key: &str) -> Result<()> {
+    if replica.same_account {
+        let req = CopyObjectRequest {
+            bucket: replica.env_vars.r2_bucket.clone(),
+            key: key.to_owned(),
+            copy_source: format!("{}/{}", primary.r2_bucket, key),
+            ..Default::default()
+        };
+
+        replica.client.copy_object(req).await.context(format!("Failed to copy {}", key))?;
+        return Ok(());
+    }
+
+    let req = GetObjectRequest {
+        bucket: primary.r2_bucket.clone(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let output = client.get_object(req).await.context(format!("Failed to download {}", key))?;
+    let size = output.content_length.unwrap_or_default() as usize;
+    let body = output.body.context(format!("{} has no body", key))?;
+
+    let req = PutObjectRequest {
+        bucket: replica.env_vars.r2_bucket.clone(),
+        key: key.to_owned(),
+        body: Some(StreamingBody::new_with_size(body, size)),
+        content_type: output.content_type,
+        ..Default::default()
+    };
+    replica.client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::{header, Body, Request};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AuthConfig, UserConfig};
+use crate::hash_utils;
+
+pub(crate) const CATALOG_SCOPE: &str = "registry:catalog:*";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: u64,
+    iat: u64,
+    access: Vec<Access>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Access {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    actions: Vec<String>,
+}
+
+pub(crate) struct TokenIssuer {
+    config: AuthConfig,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+}
+
+impl TokenIssuer {
+    pub(crate) fn new(config: AuthConfig) -> Self {
+        TokenIssuer {
+            encoding_key: EncodingKey::from_secret(config.secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(config.secret.as_bytes()),
+            config,
+        }
+    }
+
+    pub(crate) fn service(&self) -> &str {
+        &self.config.service
+    }
+
+    // `scope` is e.g. `repository:<name>:pull,push` or `registry:catalog:*`.
+    pub(crate) fn challenge(&self, realm: &str, scope: Option<&str>) -> String {
+        match scope {
+            Some(scope) => format!("Bearer realm=\"{}\",service=\"{}\",scope=\"{}\"", realm, self.config.service, scope),
+            None => format!("Bearer realm=\"{}\",service=\"{}\"", realm, self.config.service),
+        }
+    }
+
+    // Issues a token for the `/token` endpoint. Requested actions the caller is not allowed are
+    // dropped rather than rejected, as the distribution token spec asks for.
+    pub(crate) fn issue(&self, req: &Request<Body>, scopes: &[String]) -> Result<Option<(String, u64)>> {
+        let user = match basic_credentials(req) {
+            Some((name, password)) => match self.authenticate(&name, &password) {
+                Some(user) => Some(user),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+
+        // The catalog names every repository, including those the user cannot pull.
+        let catalog = scopes.iter().any(|scope| scope == CATALOG_SCOPE) && user.is_some();
+        let access = scopes
+            .iter()
+            .filter_map(|scope| parse_scope(scope))
+            .filter_map(|(repository, requested)| {
+                let actions: Vec<String> = requested
+                    .into_iter()
+                    .filter(|action| match *action {
+                        "pull" => self.can_pull(user, repository),
+                        "push" => self.can_push(user, repository),
+                        _ => false,
+                    })
+                    .map(str::to_owned)
+                    .collect();
+                (!actions.is_empty()).then(|| Access { kind: "repository".to_owned(), name: repository.to_owned(), actions })
+            })
+            .chain(catalog.then(|| Access { kind: "registry".to_owned(), name: "catalog".to_owned(), actions: vec!["*".to_owned()] }))
+            .collect();
+
+        let now = now();
+        let claims = Claims {
+            iss: self.config.issuer.clone(),
+            sub: user.map_or_else(String::new, |user| user.name.clone()),
+            aud: self.config.service.clone(),
+            exp: now + self.config.token_ttl_secs,
+            iat: now,
+            access,
+        };
+
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key).context("Failed to sign token")?;
+
+        Ok(Some((token, self.config.token_ttl_secs)))
+    }
+
+    // `None` checks only that the token is valid, which is what `GET /v2/` needs. `action` is the
+    // one the token must grant on the repository, `pull` or `push`.
+    pub(crate) fn authorize(&self, req: &Request<Body>, repository: Option<&str>, action: &str) -> bool {
+        if action == "pull" && repository.is_some_and(|repository| self.can_pull(None, repository)) {
+            return true;
+        }
+
+        let claims = match self.claims(req) {
+            Some(claims) => claims,
+            None => return false,
+        };
+
+        match repository {
+            Some(repository) => claims.access.iter().any(|access| {
+                access.kind == "repository" && access.name == repository && access.actions.iter().any(|granted| granted == action)
+            }),
+            None => true,
+        }
+    }
+
+    // Only tokens issued to a user for `registry:catalog:*` may list the repositories.
+    pub(crate) fn authorize_catalog(&self, req: &Request<Body>) -> bool {
+        self.claims(req).is_some_and(|claims| {
+            claims.access.iter().any(|access| access.kind == "registry" && access.name == "catalog" && access.actions.iter().any(|granted| granted == "*"))
+        })
+    }
+
+    // `/metrics` takes the static `metrics_token` a scraper is configured with, not a registry token.
+    pub(crate) fn authorize_metrics(&self, req: &Request<Body>) -> bool {
+        match (&self.config.metrics_token, bearer_token(req)) {
+            (Some(expected), Some(provided)) => hash_utils::constant_time_eq(provided.as_bytes(), expected.as_bytes()),
+            _ => false,
+        }
+    }
+
+    fn claims(&self, req: &Request<Body>) -> Option<Claims> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.service]);
+
+        decode::<Claims>(bearer_token(req)?, &self.decoding_key, &validation).ok().map(|data| data.claims)
+    }
+
+    fn authenticate(&self, name: &str, password: &str) -> Option<&UserConfig> {
+        let user = self.config.users.iter().find(|user| user.name == name)?;
+        hash_utils::constant_time_eq(user.password.as_bytes(), password.as_bytes()).then_some(user)
+    }
+
+    fn can_pull(&self, user: Option<&UserConfig>, repository: &str) -> bool {
+        let patterns = self.config.anonymous.iter().chain(user.into_iter().flat_map(|user| user.repositories.iter()));
+        patterns
+            .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+            .any(|pattern| pattern.matches(repository))
+    }
+
+    // Anonymous clients never push.
+    fn can_push(&self, user: Option<&UserConfig>, repository: &str) -> bool {
+        user.into_iter()
+            .flat_map(|user| user.push.iter())
+            .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+            .any(|pattern| pattern.matches(repository))
+    }
+}
+
+fn parse_scope(scope: &str) -> Option<(&str, Vec<&str>)> {
+    let rest = scope.strip_prefix("repository:")?;
+    let (name, actions) = rest.rsplit_once(':')?;
+    Some((name, actions.split(',').collect()))
+}
+
+fn bearer_token(req: &Request<Body>) -> Option<&str> {
+    req.headers().get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+fn basic_credentials(req: &Request<Body>) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let decoded = STANDARD.decode(value.strip_prefix("Basic ")?).ok()?;
+    let (name, password) = String::from_utf8(decoded).ok()?.split_once(':').map(|(n, p)| (n.to_owned(), p.to_owned()))?;
+
+    Some((name, password))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
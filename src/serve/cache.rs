@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use hyper::{header, Body, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::config::BlobCacheConfig;
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+const READ_BUFFER: usize = 64 * 1024;
+
+// A read-through cache of blobs on local disk, for serve mode running next to a busy cluster. Blobs
+// are written while they are streamed to the first client that asks for them and kept until the
+// cache outgrows its size, least recently used first, or until they are older than the TTL. Blobs
+// are cached per repository: a client allowed to pull one repository must not get another's blob
+// from the cache just by knowing its digest.
+pub(crate) struct BlobCache {
+    dir: PathBuf,
+    max_size: u64,
+    ttl: Option<Duration>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    size: u64,
+    stored: SystemTime,
+    used: Instant,
+}
+
+impl BlobCache {
+    // Picks up the blobs cached by an earlier run.
+    pub(crate) fn open(config: &BlobCacheConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir).context(format!("Failed to create {}", config.dir.display()))?;
+
+        let mut entries = HashMap::new();
+        for entry in fs::read_dir(&config.dir).context(format!("Failed to read {}", config.dir.display()))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Blobs that were still being written when the server stopped, and blobs cached by
+            // digest alone by earlier versions.
+            if name.starts_with(".tmp") || name.starts_with("sha256-") {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let stored = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+            entries.insert(name, Entry { size: metadata.len(), stored, used: Instant::now() });
+        }
+
+        let cache = BlobCache {
+            dir: config.dir.clone(),
+            max_size: config.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            ttl: config.ttl_secs.map(Duration::from_secs),
+            entries: Mutex::new(entries),
+        };
+        cache.evict();
+        log::info!("Caching blobs in {} ({} cached)", config.dir.display(), cache.entries.lock().unwrap().len());
+
+        Ok(cache)
+    }
+
+    // Serves `digest` of `repository` from disk, None when it is not cached.
+    pub(crate) async fn serve(&self, repository: &str, digest: &str, head: bool, range: Option<&str>) -> Option<Response<Body>> {
+        let name = file_name(repository, digest)?;
+        let size = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.get_mut(&name)?;
+            if self.ttl.is_some_and(|ttl| entry.stored.elapsed().is_ok_and(|age| age > ttl)) {
+                entries.remove(&name);
+                let _ = fs::remove_file(self.dir.join(&name));
+                return None;
+            }
+            entry.used = Instant::now();
+            entry.size
+        };
+
+        let builder = Response::builder()
+            .header("Docker-Distribution-API-Version", "registry/2.0")
+            .header("Docker-Content-Digest", digest)
+            .header(header::ETAG, super::etag(digest))
+            .header(header::CONTENT_TYPE, "application/octet-stream");
+        let Some((builder, range)) = super::ranged(builder, range, size) else {
+            return Some(super::range_not_satisfiable(size));
+        };
+        if head {
+            return Some(builder.body(Body::empty()).unwrap());
+        }
+
+        // The blob may have been evicted in the meantime.
+        let mut file = tokio::fs::File::open(self.dir.join(&name)).await.ok()?;
+        file.seek(SeekFrom::Start(range.start)).await.ok()?;
+        let reader = Some(file.take(range.end - range.start));
+        let body = stream::unfold(reader, |reader| async move {
+            let mut reader = reader?;
+            let mut buffer = vec![0; READ_BUFFER];
+            match reader.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(Bytes::from(buffer)), Some(reader)))
+                }
+                Err(e) => Some((Err::<Bytes, io::Error>(e), None)),
+            }
+        });
+
+        Some(builder.body(Body::wrap_stream(body)).unwrap())
+    }
+
+    // Copies a complete blob response into the cache as it is streamed to the client. The copy is
+    // only kept when the whole blob arrived and matches its digest.
+    pub(crate) fn fill(self: &Arc<Self>, repository: &str, digest: &str, response: Response<Body>) -> Response<Body> {
+        let size = response.headers().get(header::CONTENT_LENGTH).and_then(|length| length.to_str().ok()).and_then(|length| length.parse::<u64>().ok());
+        let (Some(name), Some(size)) = (file_name(repository, digest), size) else {
+            return response;
+        };
+        if response.status() != StatusCode::OK || size > self.max_size {
+            return response;
+        }
+        let file = match NamedTempFile::new_in(&self.dir) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("Failed to cache {}: {}", digest, e);
+                return response;
+            }
+        };
+
+        let fill = Fill {
+            cache: self.clone(),
+            name,
+            digest: digest.to_owned(),
+            file,
+            hasher: Sha256::new(),
+        };
+        let (parts, body) = response.into_parts();
+        let body = stream::unfold((body, Some(fill)), |(mut body, mut fill)| async move {
+            match body.next().await {
+                Some(Ok(data)) => {
+                    if let Some(cache_fill) = fill.as_mut() {
+                        if let Err(e) = cache_fill.write(&data) {
+                            log::warn!("Failed to cache {}: {}", cache_fill.digest, e);
+                            fill = None;
+                        }
+                    }
+                    Some((Ok(data), (body, fill)))
+                }
+                // Dropping the fill removes the partial copy.
+                Some(Err(e)) => Some((Err(e), (body, None))),
+                None => {
+                    if let Some(fill) = fill {
+                        fill.finish();
+                    }
+                    None
+                }
+            }
+        });
+
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    fn insert(&self, name: String, size: u64) {
+        let entry = Entry { size, stored: SystemTime::now(), used: Instant::now() };
+        self.entries.lock().unwrap().insert(name, entry);
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: u64 = entries.values().map(|entry| entry.size).sum();
+        while total > self.max_size {
+            let Some(name) = entries.iter().min_by_key(|(_, entry)| entry.used).map(|(name, _)| name.clone()) else {
+                break;
+            };
+            if let Some(entry) = entries.remove(&name) {
+                total -= entry.size;
+            }
+            if let Err(e) = fs::remove_file(self.dir.join(&name)) {
+                log::warn!("Failed to evict {} from the blob cache: {}", name, e);
+            }
+        }
+    }
+}
+
+struct Fill {
+    cache: Arc<BlobCache>,
+    name: String,
+    digest: String,
+    file: NamedTempFile,
+    hasher: Sha256,
+}
+
+impl Fill {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.file.write_all(data)
+    }
+
+    fn finish(self) {
+        let actual = format!("sha256:{:x}", self.hasher.finalize());
+        if actual != self.digest {
+            log::warn!("Not caching {}: the bucket returned content with digest {}", self.digest, actual);
+            return;
+        }
+
+        let path = self.cache.dir.join(&self.name);
+        match self.file.persist(&path) {
+            Ok(file) => {
+                let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+                self.cache.insert(self.name, size);
+            }
+            Err(e) => log::warn!("Failed to cache {}: {}", self.digest, e.error),
+        }
+    }
+}
+
+// Only well-formed digests are cached, which keeps request paths out of file names. The repository
+// is hashed, as names may contain `/`.
+fn file_name(repository: &str, digest: &str) -> Option<String> {
+    super::is_digest(digest).then(|| format!("{}-{}", &blake3::hash(repository.as_bytes()).to_hex()[..16], digest.replace(':', "-")))
+}
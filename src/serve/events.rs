@@ -0,0 +1,184 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use anyhow::{Context, Result};
+use hyper::{Body, Request};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::config::PullEventsConfig;
+use crate::proxy;
+use crate::pulls::{self, PullCounts};
+use crate::r2configs::R2Configs;
+
+const QUEUE: usize = 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PullEvent {
+    pub(crate) time: String,
+    pub(crate) repository: String,
+    pub(crate) reference: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) digest: Option<String>,
+    pub(crate) client_ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) user_agent: Option<String>,
+}
+
+impl PullEvent {
+    pub(crate) fn new(req: &Request<Body>, remote: SocketAddr, trusted: &[ProxyNetwork], repository: &str, reference: &str, digest: Option<String>) -> Self {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+        let client_ip = client_ip(remote.ip(), trusted, header("cf-connecting-ip"), header("x-forwarded-for")).to_string();
+
+        PullEvent {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            repository: repository.to_owned(),
+            reference: reference.to_owned(),
+            digest,
+            client_ip,
+            user_agent: header("user-agent").map(str::to_owned),
+        }
+    }
+}
+
+// Behind Cloudflare or a reverse proxy the peer is the proxy, which reports the client. Anyone can
+// send those headers, so they only count when the peer is a trusted proxy. Each proxy appends its
+// peer to `X-Forwarded-For`, so the client is the last address not added by a trusted proxy.
+fn client_ip(peer: IpAddr, trusted: &[ProxyNetwork], connecting_ip: Option<&str>, forwarded_for: Option<&str>) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|network| network.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+    if let Some(ip) = connecting_ip.and_then(|ip| ip.trim().parse().ok()) {
+        return ip;
+    }
+
+    let mut client = peer;
+    for hop in forwarded_for.into_iter().flat_map(|forwarded| forwarded.rsplit(',')) {
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+// An address or CIDR network from `serve.trusted_proxies`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ProxyNetwork {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl ProxyNetwork {
+    pub(crate) fn parse(network: &str) -> Result<Self> {
+        let (addr, prefix) = match network.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr.trim().parse().context(format!("{} is not an IP address or CIDR network", network))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|&prefix| prefix <= bits).context(format!("{} has an invalid prefix length", network))?,
+            None => bits,
+        };
+
+        Ok(ProxyNetwork { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // A server listening on [::] sees IPv4 peers as IPv4-mapped IPv6 addresses.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Delivers pull events from a background task so slow webhooks never hold up a pull. Events are
+// dropped with a warning when delivery falls too far behind.
+pub(crate) struct PullEvents {
+    sender: mpsc::Sender<PullEvent>,
+}
+
+impl PullEvents {
+    pub(crate) fn start(config: &PullEventsConfig) -> Result<Self> {
+        let mut log_file = match &config.log_file {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path).context(format!("Failed to open {}", path.display()))?),
+            None => None,
+        };
+        let webhook = config.webhook.clone();
+        let client = proxy::http_client()?;
+
+        let (sender, mut receiver) = mpsc::channel::<PullEvent>(QUEUE);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if let Some(file) = &mut log_file {
+                    if let Err(e) = write_line(file, &event) {
+                        log::warn!("Failed to log a pull event: {}", e);
+                    }
+                }
+                if let Some(url) = &webhook {
+                    match client.post(url).json(&event).send().await {
+                        Ok(response) if !response.status().is_success() => log::warn!("Pull webhook {} answered {}", url, response.status()),
+                        Ok(_) => {}
+                        Err(e) => log::warn!("Failed to reach pull webhook {}: {}", url, e),
+                    }
+                }
+            }
+        });
+
+        Ok(PullEvents { sender })
+    }
+
+    pub(crate) fn emit(&self, event: PullEvent) {
+        if self.sender.try_send(event).is_err() {
+            log::warn!("Dropped a pull event, delivery is falling behind");
+        }
+    }
+}
+
+fn write_line(file: &mut File, event: &PullEvent) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+
+    Ok(())
+}
+
+// Adds the pulls counted since the last flush to the totals in the bucket once a minute. Counts
+// that fail to be written are kept for the next attempt; `flush_pending` writes the rest on
+// shutdown.
+pub(crate) fn flush_pull_counts(env_vars: R2Configs, counts: Arc<Mutex<PullCounts>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            flush_pending(&env_vars, &counts).await;
+        }
+    });
+}
+
+pub(crate) async fn flush_pending(env_vars: &R2Configs, counts: &Mutex<PullCounts>) {
+    let pending = mem::take(&mut *counts.lock().unwrap());
+    if pending.is_empty() {
+        return;
+    }
+    if let Err(e) = pulls::merge(env_vars, &pending).await {
+        log::warn!("Failed to record pull counts: {:#}", e);
+        counts.lock().unwrap().add(&pending);
+    }
+}
@@ -0,0 +1,739 @@
+mod auth;
+mod cache;
+mod events;
+mod push;
+mod range;
+mod scrub;
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use anyhow::{bail, Context, Result};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::http::response::Builder;
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, HeadObjectRequest, S3Client, S3};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::capabilities::{self, Capability};
+use crate::config::{self, Config};
+use crate::names;
+use crate::pulls::PullCounts;
+use crate::r2configs::{self, R2Configs};
+use crate::v2;
+use crate::v2::{catalog, chunks, delta, referrers};
+use crate::v2::lister::Lister;
+use auth::TokenIssuer;
+use cache::BlobCache;
+use events::{PullEvent, PullEvents};
+pub(crate) use events::ProxyNetwork;
+use push::Uploads;
+use range::RangeRequest;
+use scrub::ScrubStats;
+
+// Reconstituted delta blobs are sent in pieces of this size.
+const DELTA_CHUNK: usize = 256 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct ServeConfig {
+    pub addr: SocketAddr,
+}
+
+struct ServeState {
+    client: S3Client,
+    bucket: String,
+    config: Config,
+    issuer: Option<TokenIssuer>,
+    cache: Option<Arc<BlobCache>>,
+    env_vars: R2Configs,
+    uploads: Uploads,
+    events: Option<PullEvents>,
+    pulls: Option<Arc<Mutex<PullCounts>>>,
+    scrub: Option<Arc<ScrubStats>>,
+    trusted_proxies: Vec<ProxyNetwork>,
+}
+
+enum Route<'a> {
+    Base,
+    Token,
+    Metrics,
+    Catalog,
+    Manifest { name: &'a str, reference: &'a str },
+    Blob { name: &'a str, digest: &'a str },
+    Tags { name: &'a str },
+    Referrers { name: &'a str, digest: &'a str },
+    Upload { name: &'a str },
+    UploadSession { name: &'a str, id: &'a str },
+}
+
+impl<'a> Route<'a> {
+    fn repository(&self) -> Option<&'a str> {
+        match *self {
+            Route::Manifest { name, .. } | Route::Blob { name, .. } | Route::Tags { name } | Route::Referrers { name, .. } => Some(name),
+            Route::Upload { name } | Route::UploadSession { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+pub async fn serve(serve_config: ServeConfig) -> Result<()> {
+    let config = config::load_config()?;
+    if config.serve.push && config.serve.auth.is_none() {
+        bail!("serve.push needs [serve.auth], otherwise anyone could push and overwrite tags");
+    }
+    let trusted_proxies = config.serve.trusted_proxies.iter().map(|network| ProxyNetwork::parse(network)).collect::<Result<Vec<_>>>().context("Invalid serve.trusted_proxies")?;
+    let env_vars = r2configs::parse_r2configs()?;
+
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    let required = match config.serve.push || config.serve.count_pulls {
+        true => &[Capability::Read, Capability::List, Capability::Write][..],
+        false => &[Capability::Read, Capability::List][..],
+    };
+    capabilities::require(&client, &env_vars.r2_bucket, "serve", required).await?;
+
+    let scrub = config.serve.scrub.clone().map(|scrub| scrub::start(env_vars.clone(), client.clone(), scrub)).transpose()?;
+    let state = Arc::new(ServeState {
+        client,
+        bucket: env_vars.r2_bucket.clone(),
+        issuer: config.serve.auth.clone().map(TokenIssuer::new),
+        cache: config.serve.cache.as_ref().map(BlobCache::open).transpose()?.map(Arc::new),
+        uploads: Uploads::new()?,
+        events: config.serve.events.as_ref().map(PullEvents::start).transpose()?,
+        pulls: config.serve.count_pulls.then(Default::default),
+        scrub,
+        trusted_proxies,
+        env_vars,
+        config,
+    });
+
+    if let Some(pulls) = &state.pulls {
+        events::flush_pull_counts(state.env_vars.clone(), pulls.clone());
+    }
+    let pending = state.pulls.clone().map(|pulls| (state.env_vars.clone(), pulls));
+
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let remote = conn.remote_addr();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone(), remote))) }
+    });
+
+    let server = Server::try_bind(&serve_config.addr)
+        .context(format!("Failed to bind {}", serve_config.addr))?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown_signal());
+    log::info!("Serving registry from bucket on {}", serve_config.addr);
+
+    let result = server.await.context("Registry server failed");
+    // The pulls counted since the last flush would otherwise be lost.
+    if let Some((env_vars, pulls)) = pending {
+        events::flush_pending(&env_vars, &pulls).await;
+    }
+
+    result
+}
+
+// Resolves on Ctrl-C, or on SIGTERM as sent by `docker stop` and Kubernetes.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                log::warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        let ctrl_c = std::pin::pin!(tokio::signal::ctrl_c());
+        let terminate = std::pin::pin!(terminate.recv());
+        futures::future::select(ctrl_c, terminate).await;
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    log::info!("Shutting down, finishing requests in flight");
+}
+
+async fn handle(req: Request<Body>, state: Arc<ServeState>, remote: SocketAddr) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_owned();
+    let route = match parse_route(&path) {
+        Some(route) => route,
+        None => return Ok(error(StatusCode::NOT_FOUND, "NAME_UNKNOWN", "unknown path")),
+    };
+
+    if let Some(name) = route.repository().filter(|name| !names::is_repository_name(name)) {
+        return Ok(error(StatusCode::BAD_REQUEST, "NAME_INVALID", &format!("invalid repository name {}", name)));
+    }
+    // Digests and references end up in bucket keys, so they must not reach other repositories.
+    match route {
+        Route::Blob { digest, .. } | Route::Referrers { digest, .. } if !is_digest(digest) => {
+            return Ok(error(StatusCode::BAD_REQUEST, "DIGEST_INVALID", &format!("invalid digest {}", digest)));
+        }
+        Route::Manifest { reference, .. } if !names::is_tag(reference) && !is_digest(reference) => {
+            return Ok(error(StatusCode::BAD_REQUEST, "MANIFEST_INVALID", &format!("invalid reference {}", reference)));
+        }
+        _ => {}
+    }
+
+    let allowed = match route {
+        Route::Upload { .. } => req.method() == Method::POST,
+        Route::UploadSession { .. } => matches!(*req.method(), Method::GET | Method::PATCH | Method::PUT | Method::DELETE),
+        Route::Manifest { .. } => matches!(*req.method(), Method::GET | Method::HEAD | Method::PUT),
+        _ => matches!(*req.method(), Method::GET | Method::HEAD),
+    };
+    let write = matches!(route, Route::Upload { .. } | Route::UploadSession { .. }) || req.method() == Method::PUT;
+    if write && !state.config.serve.push {
+        return Ok(error(StatusCode::METHOD_NOT_ALLOWED, "UNSUPPORTED", "the registry is read-only"));
+    }
+    if !allowed {
+        return Ok(error(StatusCode::METHOD_NOT_ALLOWED, "UNSUPPORTED", "method not allowed here"));
+    }
+
+    if let Some(issuer) = &state.issuer {
+        if let Route::Token = route {
+            return Ok(token(&req, issuer, &state.config));
+        }
+
+        let repository = route.repository();
+        let (action, actions) = match write {
+            true => ("push", "pull,push"),
+            false => ("pull", "pull"),
+        };
+        let (authorized, scope) = match route {
+            Route::Metrics => (issuer.authorize_metrics(&req), None),
+            Route::Catalog => (issuer.authorize_catalog(&req), Some(auth::CATALOG_SCOPE.to_owned())),
+            _ => (issuer.authorize(&req, repository, action), repository.map(|repository| format!("repository:{}:{}", repository, actions))),
+        };
+        if !authorized {
+            let challenge = match route {
+                Route::Metrics => "Bearer realm=\"metrics\"".to_owned(),
+                _ => issuer.challenge(&format!("{}/token", base_url(&req, &state.config)), scope.as_deref()),
+            };
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, challenge)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(error_body("UNAUTHORIZED", "authentication required")))
+                .unwrap());
+        }
+    }
+
+    let head = req.method() == Method::HEAD;
+    let range = req.headers().get(header::RANGE).and_then(|range| range.to_str().ok());
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+    let response = match route {
+        Route::Base => Response::builder()
+            .header("Docker-Distribution-API-Version", "registry/2.0")
+            .body(Body::from("{}"))
+            .unwrap(),
+        Route::Token => error(StatusCode::NOT_FOUND, "UNSUPPORTED", "token authentication is not configured"),
+        Route::Metrics => {
+            let mut metrics = format!(
+                "# HELP oci_r2_connections_opened_total Connections opened to R2.\n# TYPE oci_r2_connections_opened_total counter\noci_r2_connections_opened_total {}\n",
+                crate::proxy::connections_opened()
+            );
+            if let Some(scrub) = &state.scrub {
+                metrics.push_str(&scrub.metrics());
+            }
+            Response::builder().header(header::CONTENT_TYPE, "text/plain; version=0.0.4").body(Body::from(metrics)).unwrap()
+        }
+        Route::Catalog => get_object(&state, catalog::CATALOG_KEY, head, None, None, "NAME_UNKNOWN").await,
+        Route::Manifest { name, reference } if req.method() == Method::PUT => push::put_manifest(&state, req, name, reference).await,
+        Route::Manifest { name, reference } => {
+            let response = manifest(&state, name, reference, head, if_none_match).await;
+            if !head && (response.status() == StatusCode::OK || response.status() == StatusCode::NOT_MODIFIED) {
+                record_pull(&state, &req, remote, name, reference, &response);
+            }
+            response
+        }
+        // Content addressed by digest never changes, so a client holding it needs nothing more.
+        Route::Blob { digest, .. } if etag_matches(if_none_match, digest) => not_modified(digest),
+        Route::Blob { name, digest } => blob(&state, name, digest, head, range).await,
+        Route::Tags { name } => tags(&state, name, head).await,
+        Route::Referrers { name, digest } => referrers(&state, name, digest, req.uri().query()).await,
+        Route::Upload { name } => push::start_upload(&state, req, name).await,
+        Route::UploadSession { name, id } => push::upload_session(&state, req, name, id).await,
+    };
+
+    Ok(response)
+}
+
+fn parse_route(path: &str) -> Option<Route<'_>> {
+    if path == "/token" {
+        return Some(Route::Token);
+    }
+    if path == "/metrics" {
+        return Some(Route::Metrics);
+    }
+
+    let rest = path.strip_prefix("/v2")?;
+    if rest.is_empty() || rest == "/" {
+        return Some(Route::Base);
+    }
+
+    let rest = rest.strip_prefix('/')?;
+    if rest == "_catalog" {
+        return Some(Route::Catalog);
+    }
+    if let Some(name) = rest.strip_suffix("/tags/list") {
+        return Some(Route::Tags { name });
+    }
+    if let Some((name, digest)) = rest.rsplit_once("/referrers/") {
+        return Some(Route::Referrers { name, digest });
+    }
+    if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+        return Some(Route::Manifest { name, reference });
+    }
+    if let Some(name) = rest.strip_suffix("/blobs/uploads/").or_else(|| rest.strip_suffix("/blobs/uploads")) {
+        return Some(Route::Upload { name });
+    }
+    if let Some((name, id)) = rest.rsplit_once("/blobs/uploads/") {
+        return Some(Route::UploadSession { name, id });
+    }
+    if let Some((name, digest)) = rest.rsplit_once("/blobs/") {
+        return Some(Route::Blob { name, digest });
+    }
+
+    None
+}
+
+fn token(req: &Request<Body>, issuer: &TokenIssuer, config: &Config) -> Response<Body> {
+    let query = req.uri().query().unwrap_or_default();
+    let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+
+    if let Some((_, service)) = params.iter().find(|(key, _)| key == "service") {
+        if service != issuer.service() {
+            return error(StatusCode::BAD_REQUEST, "DENIED", "unknown service");
+        }
+    }
+
+    let scopes: Vec<String> = params
+        .iter()
+        .filter(|(key, _)| key == "scope")
+        .flat_map(|(_, value)| value.split(' ').map(|scope| scope.to_owned()))
+        .collect();
+
+    match issuer.issue(req, &scopes) {
+        Ok(Some((token, expires_in))) => json_response(
+            StatusCode::OK,
+            json!({ "token": token, "access_token": token, "expires_in": expires_in }),
+        ),
+        Ok(None) => {
+            let realm = format!("{}/token", base_url(req, config));
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, format!("Basic realm=\"{}\"", realm))
+                .body(Body::from(error_body("UNAUTHORIZED", "invalid username or password")))
+                .unwrap()
+        }
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", &format!("{:#}", e)),
+    }
+}
+
+// `range` is passed on to the bucket, which answers with the partial content and its range.
+async fn get_object(state: &ServeState, key: &str, head: bool, digest: Option<&str>, range: Option<&str>, unknown_code: &str) -> Response<Body> {
+    let mut builder = Response::builder().header("Docker-Distribution-API-Version", "registry/2.0");
+    if let Some(digest) = digest {
+        builder = builder.header("Docker-Content-Digest", digest).header(header::ETAG, etag(digest));
+    }
+
+    if head {
+        let req = HeadObjectRequest {
+            bucket: state.bucket.clone(),
+            key: key.to_owned(),
+            ..Default::default()
+        };
+
+        return match state.client.head_object(req).await {
+            Ok(object) => {
+                if let Some(content_type) = object.content_type {
+                    builder = builder.header(header::CONTENT_TYPE, content_type);
+                }
+                if let Some(content_length) = object.content_length {
+                    builder = builder.header(header::CONTENT_LENGTH, content_length);
+                }
+                builder.body(Body::empty()).unwrap()
+            }
+            Err(e) if v2::is_not_found(&e) => error(StatusCode::NOT_FOUND, unknown_code, key),
+            Err(e) => upstream_error(key, e),
+        };
+    }
+
+    let req = GetObjectRequest {
+        bucket: state.bucket.clone(),
+        key: key.to_owned(),
+        range: range.map(str::to_owned),
+        ..Default::default()
+    };
+
+    match state.client.get_object(req).await {
+        Ok(object) => {
+            if let Some(content_range) = object.content_range {
+                builder = builder.status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, content_range);
+            }
+            if let Some(content_type) = object.content_type {
+                builder = builder.header(header::CONTENT_TYPE, content_type);
+            }
+            if let Some(content_length) = object.content_length {
+                builder = builder.header(header::CONTENT_LENGTH, content_length);
+            }
+            match object.body {
+                Some(body) => builder.body(Body::wrap_stream(body)).unwrap(),
+                None => builder.body(Body::empty()).unwrap(),
+            }
+        }
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => error(StatusCode::NOT_FOUND, unknown_code, key),
+        Err(e) if v2::is_not_found(&e) => error(StatusCode::NOT_FOUND, unknown_code, key),
+        Err(RusotoError::Unknown(response)) if response.status.as_u16() == 416 => error(StatusCode::RANGE_NOT_SATISFIABLE, "RANGE_INVALID", key),
+        Err(e) => upstream_error(key, e),
+    }
+}
+
+fn record_pull(state: &ServeState, req: &Request<Body>, remote: SocketAddr, name: &str, reference: &str, response: &Response<Body>) {
+    if let Some(pulls) = &state.pulls {
+        let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        pulls.lock().unwrap().record(name, reference, &now);
+    }
+    if let Some(events) = &state.events {
+        let digest = response.headers().get("Docker-Content-Digest").and_then(|digest| digest.to_str().ok()).map(str::to_owned);
+        events.emit(PullEvent::new(req, remote, &state.trusted_proxies, name, reference, digest));
+    }
+}
+
+async fn manifest(state: &ServeState, name: &str, reference: &str, head: bool, if_none_match: Option<&str>) -> Response<Body> {
+    let key = format!("v2/{}/manifests/{}", name, reference);
+    if reference.starts_with("sha256:") {
+        if etag_matches(if_none_match, reference) {
+            return not_modified(reference);
+        }
+        return get_object(state, &key, head, Some(reference), None, "MANIFEST_UNKNOWN").await;
+    }
+
+    // A tag can move, so its digest is computed from the manifest it points at right now. This lets
+    // clients polling a tag revalidate it with `If-None-Match` and skip unchanged manifests.
+    let req = GetObjectRequest {
+        bucket: state.bucket.clone(),
+        key: key.clone(),
+        ..Default::default()
+    };
+    let object = match state.client.get_object(req).await {
+        Ok(object) => object,
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return error(StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN", &key),
+        Err(e) if v2::is_not_found(&e) => return error(StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN", &key),
+        Err(e) => return upstream_error(&key, e),
+    };
+
+    let mut data = Vec::new();
+    if let Some(body) = object.body {
+        if let Err(e) = body.into_async_read().read_to_end(&mut data).await {
+            log::error!("Failed to read {} from the bucket: {}", key, e);
+            return error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to read from the bucket");
+        }
+    }
+
+    let digest = format!("sha256:{:x}", Sha256::digest(&data));
+    if etag_matches(if_none_match, &digest) {
+        return not_modified(&digest);
+    }
+
+    let mut builder = Response::builder()
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .header("Docker-Content-Digest", &digest)
+        .header(header::ETAG, etag(&digest))
+        .header(header::CONTENT_LENGTH, data.len());
+    if let Some(content_type) = object.content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+    if head {
+        return builder.body(Body::empty()).unwrap();
+    }
+
+    builder.body(Body::from(data)).unwrap()
+}
+
+async fn blob(state: &ServeState, name: &str, digest: &str, head: bool, range: Option<&str>) -> Response<Body> {
+    let Some(cache) = &state.cache else {
+        return stored_blob(state, name, digest, head, range).await;
+    };
+    if let Some(response) = cache.serve(name, digest, head, range).await {
+        return response;
+    }
+
+    let response = stored_blob(state, name, digest, head, range).await;
+    match head {
+        true => response,
+        false => cache.fill(name, digest, response),
+    }
+}
+
+// Blob GETs honour a single byte range, so clients can resume interrupted layer downloads.
+async fn stored_blob(state: &ServeState, name: &str, digest: &str, head: bool, range: Option<&str>) -> Response<Body> {
+    let key = format!("v2/{}/blobs/{}", name, digest);
+    let mut response = get_object(state, &key, head, Some(digest), range::forwardable(range), "BLOB_UNKNOWN").await;
+    if response.status() != StatusCode::NOT_FOUND {
+        if response.status().is_success() {
+            response.headers_mut().insert(header::ACCEPT_RANGES, header::HeaderValue::from_static("bytes"));
+        }
+        return response;
+    }
+
+    // Chunked blobs are reassembled from their recipe.
+    let recipe = match chunks::read_recipe(&state.client, &state.bucket, name, digest).await {
+        Ok(Some(recipe)) => recipe,
+        Ok(None) => return delta_blob(state, name, digest, head, range, response).await,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to read from the bucket");
+        }
+    };
+
+    let builder = Response::builder()
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .header("Docker-Content-Digest", digest)
+        .header(header::ETAG, etag(digest))
+        .header(header::CONTENT_TYPE, "application/octet-stream");
+    let (builder, range) = match ranged(builder, range, recipe.size) {
+        Some(ranged) => ranged,
+        None => return range_not_satisfiable(recipe.size),
+    };
+    if head {
+        return builder.body(Body::empty()).unwrap();
+    }
+
+    builder.body(Body::wrap_stream(chunks::reassemble(state.client.clone(), state.bucket.clone(), recipe, range))).unwrap()
+}
+
+// Delta-stored blobs are reconstituted from their base chain into a temporary file, which is
+// streamed and removed once the response is sent.
+async fn delta_blob(state: &ServeState, name: &str, digest: &str, head: bool, range: Option<&str>, not_found: Response<Body>) -> Response<Body> {
+    let file = match delta::read_blob(&state.client, &state.bucket, name, digest).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return not_found,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to read from the bucket");
+        }
+    };
+
+    let size = match file.as_file().metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            log::error!("Failed to read the reconstituted {}: {}", digest, e);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", "failed to reconstitute the blob");
+        }
+    };
+    let builder = Response::builder()
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .header("Docker-Content-Digest", digest)
+        .header(header::ETAG, etag(digest))
+        .header(header::CONTENT_TYPE, "application/octet-stream");
+    let (builder, range) = match ranged(builder, range, size) {
+        Some(ranged) => ranged,
+        None => return range_not_satisfiable(size),
+    };
+    if head {
+        return builder.body(Body::empty()).unwrap();
+    }
+
+    let reader = match open_range(&file, &range).await {
+        Ok(reader) => reader,
+        Err(e) => {
+            log::error!("Failed to read the reconstituted {}: {}", digest, e);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN", "failed to reconstitute the blob");
+        }
+    };
+    // The stream owns the file, which is removed when the response is done with it.
+    let chunks = futures::stream::try_unfold((file, reader), |(file, mut reader)| async move {
+        let mut chunk = bytes::BytesMut::with_capacity(DELTA_CHUNK);
+        let read = reader.read_buf(&mut chunk).await?;
+
+        Ok::<_, std::io::Error>(if read == 0 { None } else { Some((chunk.freeze(), (file, reader))) })
+    });
+    builder.body(Body::wrap_stream(chunks)).unwrap()
+}
+
+async fn open_range(file: &tempfile::NamedTempFile, range: &Range<u64>) -> std::io::Result<tokio::io::Take<tokio::fs::File>> {
+    let mut reader = tokio::fs::File::from_std(file.reopen()?);
+    reader.seek(std::io::SeekFrom::Start(range.start)).await?;
+
+    Ok(reader.take(range.end - range.start))
+}
+
+// Adds the length, and the status and range of a partial response, for a blob of `size` bytes.
+// None when the requested range lies outside the blob.
+fn ranged(builder: Builder, range: Option<&str>, size: u64) -> Option<(Builder, Range<u64>)> {
+    let builder = builder.header(header::ACCEPT_RANGES, "bytes");
+    match range::resolve(range, size) {
+        RangeRequest::Full => Some((builder.header(header::CONTENT_LENGTH, size), 0..size)),
+        RangeRequest::Partial(range) => Some((
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, range::content_range(&range, size))
+                .header(header::CONTENT_LENGTH, range.end - range.start),
+            range,
+        )),
+        RangeRequest::Unsatisfiable => None,
+    }
+}
+
+fn is_digest(digest: &str) -> bool {
+    digest.strip_prefix("sha256:").is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
+fn etag(digest: &str) -> String {
+    format!("\"{}\"", digest)
+}
+
+// `If-None-Match` holds a list of entity tags, possibly weak. `*` is not honoured since digests are
+// answered without looking at the bucket.
+fn etag_matches(if_none_match: Option<&str>, digest: &str) -> bool {
+    if_none_match.is_some_and(|value| {
+        value.split(',').map(|tag| tag.trim().trim_start_matches("W/").trim_matches('"')).any(|tag| tag == digest)
+    })
+}
+
+fn not_modified(digest: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .header("Docker-Content-Digest", digest)
+        .header(header::ETAG, etag(digest))
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn range_not_satisfiable(size: u64) -> Response<Body> {
+    let mut response = error(StatusCode::RANGE_NOT_SATISFIABLE, "RANGE_INVALID", "the requested range is not satisfiable");
+    response.headers_mut().insert(header::CONTENT_RANGE, header::HeaderValue::from_str(&format!("bytes */{}", size)).unwrap());
+    response
+}
+
+async fn tags(state: &ServeState, name: &str, head: bool) -> Response<Body> {
+    let response = get_object(state, &catalog::tags_key(name), head, None, None, "NAME_UNKNOWN").await;
+    if response.status() != StatusCode::NOT_FOUND {
+        return response;
+    }
+
+    // Repositories pushed before tag lists were maintained only have their manifests.
+    let prefix = format!("v2/{}/manifests/", name);
+    let objects = match Lister::new(&state.client, &state.bucket).list(&prefix).await {
+        Ok(objects) => objects,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to read from the bucket");
+        }
+    };
+
+    let tags: Vec<&str> = objects
+        .iter()
+        .filter_map(|object| object.key.strip_prefix(&prefix))
+        .filter(|tag| !tag.is_empty() && !tag.contains(':'))
+        .collect();
+
+    if tags.is_empty() {
+        return error(StatusCode::NOT_FOUND, "NAME_UNKNOWN", name);
+    }
+
+    json_response(StatusCode::OK, json!({ "name": name, "tags": tags }))
+}
+
+async fn referrers(state: &ServeState, name: &str, digest: &str, query: Option<&str>) -> Response<Body> {
+    let key = referrers::referrers_key(name, digest);
+    let req = GetObjectRequest {
+        bucket: state.bucket.clone(),
+        key: key.clone(),
+        ..Default::default()
+    };
+
+    // Subjects without referrers get an empty index rather than a 404.
+    let mut index = match state.client.get_object(req).await {
+        Ok(object) => {
+            let mut data = Vec::new();
+            if let Some(body) = object.body {
+                if let Err(e) = body.into_async_read().read_to_end(&mut data).await {
+                    log::error!("Failed to read {} from the bucket: {}", key, e);
+                    return error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to read from the bucket");
+                }
+            }
+            match serde_json::from_slice(&data) {
+                Ok(index) => index,
+                Err(e) => {
+                    log::error!("Malformed referrers index {}: {}", key, e);
+                    referrers::empty_index()
+                }
+            }
+        }
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => referrers::empty_index(),
+        Err(e) if v2::is_not_found(&e) => referrers::empty_index(),
+        Err(e) => return upstream_error(&key, e),
+    };
+
+    let artifact_type = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "artifactType")
+        .map(|(_, value)| value.into_owned());
+
+    let mut builder = Response::builder().header(header::CONTENT_TYPE, referrers::INDEX_MEDIA_TYPE);
+    if let Some(artifact_type) = &artifact_type {
+        if let Some(manifests) = index["manifests"].as_array_mut() {
+            manifests.retain(|manifest| manifest["artifactType"].as_str() == Some(artifact_type));
+        }
+        builder = builder.header("OCI-Filters-Applied", "artifactType");
+    }
+
+    builder.body(Body::from(index.to_string())).unwrap()
+}
+
+fn base_url(req: &Request<Body>, config: &Config) -> String {
+    if let Some(public_url) = &config.public_url {
+        return public_url.trim_end_matches('/').to_owned();
+    }
+
+    let host = req.headers().get(header::HOST).and_then(|host| host.to_str().ok()).unwrap_or("localhost");
+    format!("http://{}", host)
+}
+
+fn upstream_error<E: std::error::Error + 'static>(key: &str, e: RusotoError<E>) -> Response<Body> {
+    log::error!("Failed to read {} from the bucket: {}", key, e);
+    error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to read from the bucket")
+}
+
+fn error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(error_body(code, message)))
+        .unwrap()
+}
+
+fn error_body(code: &str, message: &str) -> String {
+    json!({ "errors": [{ "code": code, "message": message }] }).to_string()
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn etag_matches_any_listed_tag() {
+        let digest = "sha256:abc";
+        assert!(etag_matches(Some("\"sha256:abc\""), digest));
+        assert!(etag_matches(Some("W/\"sha256:abc\""), digest));
+        assert!(etag_matches(Some("\"sha256:def\", \"sha256:abc\""), digest));
+        assert!(!etag_matches(Some("\"sha256:def\""), digest));
+        assert!(!etag_matches(Some("*"), digest));
+        assert!(!etag_matches(None, digest));
+    }
+}
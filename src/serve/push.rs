@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use rusoto_s3::{CopyObjectRequest, PutObjectRequest, S3};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+
+use super::{error, is_digest, ServeState};
+use crate::hash_utils;
+use crate::names;
+use crate::v2;
+use crate::v2::multipart::{self, ParallelUpload, PartsTarget};
+use crate::v2::{catalog, chunks, memory, referrers};
+
+const MAX_MANIFEST_SIZE: usize = 4 * 1024 * 1024;
+// Larger blobs are refused, so a client cannot fill the server's disk with one upload.
+const MAX_BLOB_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+const MAX_SESSIONS: usize = 256;
+// Upload sessions nobody wrote to for this long are dropped.
+const SESSION_IDLE: Duration = Duration::from_secs(60 * 60);
+const STREAM_CHUNK: u64 = 8 * 1024 * 1024;
+const DEFAULT_MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+// Blob upload sessions. Chunks are appended to a file on local disk and the blob is only sent to
+// the bucket once the client completes the upload and the digest checks out. Sessions do not
+// survive a restart of the server; clients then start the upload over.
+pub(super) struct Uploads {
+    dir: TempDir,
+    sessions: Mutex<HashMap<String, Session>>,
+    // Sessions that exist, including the ones a request has taken out.
+    open: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+struct Session {
+    name: String,
+    size: u64,
+    touched: Instant,
+}
+
+impl Uploads {
+    pub(super) fn new() -> Result<Self> {
+        Ok(Uploads {
+            dir: tempfile::tempdir().context("Failed to create the upload directory")?,
+            sessions: Mutex::new(HashMap::new()),
+            open: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.path().join(id)
+    }
+
+    // A new session, or `None` when too many are open.
+    fn create(&self, name: &str) -> Result<Option<String>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|id, session| {
+            let idle = session.touched.elapsed() > SESSION_IDLE;
+            if idle {
+                let _ = std::fs::remove_file(self.dir.path().join(id));
+                self.open.fetch_sub(1, Ordering::SeqCst);
+            }
+            !idle
+        });
+        if self.open.load(Ordering::SeqCst) >= MAX_SESSIONS {
+            return Ok(None);
+        }
+
+        let seed = format!("{}-{}-{:?}", std::process::id(), self.next_id.fetch_add(1, Ordering::SeqCst), SystemTime::now());
+        let id = blake3::hash(seed.as_bytes()).to_hex()[..32].to_owned();
+        std::fs::File::create(self.path(&id)).context("Failed to create an upload session")?;
+        sessions.insert(id.clone(), Session { name: name.to_owned(), size: 0, touched: Instant::now() });
+        self.open.fetch_add(1, Ordering::SeqCst);
+
+        Ok(Some(id))
+    }
+
+    // Takes the session out while a request works on it, so concurrent requests for the same
+    // session are refused rather than interleaved.
+    fn take(&self, name: &str, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(session) if session.name == name => sessions.remove(id),
+            _ => None,
+        }
+    }
+
+    fn put_back(&self, id: &str, mut session: Session) {
+        session.touched = Instant::now();
+        self.sessions.lock().unwrap().insert(id.to_owned(), session);
+    }
+
+    // Ends a session that was taken out.
+    fn discard(&self, id: &str) {
+        let _ = std::fs::remove_file(self.path(id));
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// `POST /v2/<name>/blobs/uploads/`: mounts a blob from another repository, uploads it in one
+// request (`?digest=`) or opens a session for a chunked upload.
+pub(super) async fn start_upload(state: &ServeState, req: Request<Body>, name: &str) -> Response<Body> {
+    let params: Vec<(String, String)> = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).into_owned().collect();
+    let param = |key: &str| params.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str());
+
+    if let (Some(digest), Some(from)) = (param("mount"), param("from")) {
+        if !is_digest(digest) {
+            return error(StatusCode::BAD_REQUEST, "DIGEST_INVALID", digest);
+        }
+        // Pull patterns match across `/`, so a name such as `public/../private` must not get
+        // as far as the pull check.
+        if !names::is_repository_name(from) {
+            return error(StatusCode::BAD_REQUEST, "NAME_INVALID", &format!("invalid repository name {}", from));
+        }
+        // Mounting must not leak blobs of repositories the client cannot pull.
+        let readable = state.issuer.as_ref().is_none_or(|issuer| issuer.authorize(&req, Some(from), "pull"));
+        if readable && from != name {
+            match mount(state, name, digest, from).await {
+                Ok(true) => return created(&format!("/v2/{}/blobs/{}", name, digest), digest),
+                // The client falls back to uploading the blob in the session opened below.
+                Ok(false) => {}
+                Err(e) => return internal_error(e),
+            }
+        }
+    }
+
+    let id = match state.uploads.create(name) {
+        Ok(Some(id)) => id,
+        Ok(None) => return error(StatusCode::TOO_MANY_REQUESTS, "TOOMANYREQUESTS", "too many uploads in progress, retry later"),
+        Err(e) => return internal_error(e),
+    };
+    match param("digest").map(str::to_owned) {
+        Some(digest) => {
+            let session = state.uploads.take(name, &id).expect("the session was just created");
+            complete(state, req.into_body(), name, &id, session, &digest).await
+        }
+        None => accepted(name, &id, 0, StatusCode::ACCEPTED),
+    }
+}
+
+// `/v2/<name>/blobs/uploads/<id>`: `PATCH` appends a chunk, `PUT ?digest=` appends the last one
+// and completes the upload, `GET` reports the progress and `DELETE` cancels it.
+pub(super) async fn upload_session(state: &ServeState, req: Request<Body>, name: &str, id: &str) -> Response<Body> {
+    let Some(mut session) = state.uploads.take(name, id) else {
+        return error(StatusCode::NOT_FOUND, "BLOB_UPLOAD_UNKNOWN", id);
+    };
+
+    match *req.method() {
+        Method::GET => {
+            let size = session.size;
+            state.uploads.put_back(id, session);
+            accepted(name, id, size, StatusCode::NO_CONTENT)
+        }
+        Method::DELETE => {
+            state.uploads.discard(id);
+            Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+        }
+        Method::PATCH => {
+            // Chunks must arrive in order; a client resuming at the wrong offset is told where the
+            // upload stands.
+            let start = req.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).and_then(|range| range.split_once('-')).map(|(start, _)| start.trim().parse::<u64>());
+            if start.is_some_and(|start| start.ok() != Some(session.size)) {
+                let size = session.size;
+                state.uploads.put_back(id, session);
+                return accepted(name, id, size, StatusCode::RANGE_NOT_SATISFIABLE);
+            }
+
+            match append(&state.uploads.path(id), req.into_body(), MAX_BLOB_SIZE - session.size).await {
+                Ok(None) => {
+                    state.uploads.discard(id);
+                    too_large()
+                }
+                Ok(Some(written)) => {
+                    session.size += written;
+                    let size = session.size;
+                    state.uploads.put_back(id, session);
+                    accepted(name, id, size, StatusCode::ACCEPTED)
+                }
+                Err(e) => {
+                    state.uploads.discard(id);
+                    internal_error(e)
+                }
+            }
+        }
+        Method::PUT => {
+            let digest = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).find(|(key, _)| key == "digest").map(|(_, value)| value.into_owned());
+            match digest {
+                Some(digest) => complete(state, req.into_body(), name, id, session, &digest).await,
+                None => {
+                    state.uploads.put_back(id, session);
+                    error(StatusCode::BAD_REQUEST, "DIGEST_INVALID", "the digest parameter is missing")
+                }
+            }
+        }
+        _ => {
+            state.uploads.put_back(id, session);
+            error(StatusCode::METHOD_NOT_ALLOWED, "UNSUPPORTED", "method not allowed here")
+        }
+    }
+}
+
+// Appends the last chunk, checks the whole blob against `digest` and stores it in the bucket.
+async fn complete(state: &ServeState, body: Body, name: &str, id: &str, mut session: Session, digest: &str) -> Response<Body> {
+    let path = state.uploads.path(id);
+    let result = async {
+        match append(&path, body, MAX_BLOB_SIZE - session.size).await? {
+            Some(written) => session.size += written,
+            None => return Ok(Completed::TooLarge),
+        }
+        if !is_digest(digest) {
+            return Ok(Completed::Mismatch);
+        }
+        // Hashing a multi-GB blob would stall the runtime's worker.
+        let hashed = path.clone();
+        if tokio::task::spawn_blocking(move || hash_utils::compute_sha256(&hashed)).await?? != digest {
+            return Ok(Completed::Mismatch);
+        }
+        store_blob(state, name, digest, &path, session.size).await?;
+        Ok(Completed::Stored)
+    }
+    .await;
+    state.uploads.discard(id);
+
+    match result {
+        Ok(Completed::Stored) => {
+            log::info!("Received blob {} for {} ({} bytes)", digest, name, session.size);
+            created(&format!("/v2/{}/blobs/{}", name, digest), digest)
+        }
+        Ok(Completed::Mismatch) => error(StatusCode::BAD_REQUEST, "DIGEST_INVALID", &format!("the uploaded content does not match {}", digest)),
+        Ok(Completed::TooLarge) => too_large(),
+        Err(e) => internal_error(e),
+    }
+}
+
+enum Completed {
+    Stored,
+    Mismatch,
+    TooLarge,
+}
+
+fn too_large() -> Response<Body> {
+    error(StatusCode::PAYLOAD_TOO_LARGE, "SIZE_INVALID", &format!("blobs are limited to {} bytes", MAX_BLOB_SIZE))
+}
+
+// Appends the request body to the session's file, or returns `None` once it grows past `room`.
+async fn append(path: &Path, mut body: Body, room: u64) -> Result<Option<u64>> {
+    let mut file = tokio::fs::OpenOptions::new().append(true).open(path).await.context(format!("Failed to open {}", path.display()))?;
+    let mut written = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Failed to receive the upload")?;
+        written += chunk.len() as u64;
+        if written > room {
+            return Ok(None);
+        }
+        file.write_all(&chunk).await.context(format!("Failed to write {}", path.display()))?;
+    }
+    file.flush().await?;
+
+    Ok(Some(written))
+}
+
+async fn store_blob(state: &ServeState, name: &str, digest: &str, path: &Path, size: u64) -> Result<()> {
+    let key = format!("v2/{}/blobs/{}", name, digest);
+    let parallel = ParallelUpload::default();
+    if parallel.applies_to(size) {
+        let target = PartsTarget { client: &state.client, r2_bucket: &state.bucket, key: &key, cache_control: None, resume_dir: None };
+        return multipart::upload_parts(&target, path, size, &parallel, None, None).await;
+    }
+
+    let req = PutObjectRequest {
+        bucket: state.bucket.clone(),
+        key: key.clone(),
+        body: Some(memory::stream_file(path, size, STREAM_CHUNK).await?),
+        content_length: Some(size as i64),
+        content_type: Some("application/octet-stream".to_owned()),
+        ..Default::default()
+    };
+    state.client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+
+    Ok(())
+}
+
+// `PUT /v2/<name>/manifests/<reference>`. The manifest is stored under its digest and, when pushed
+// by tag, under the tag, once every blob and manifest it references is in the repository.
+pub(super) async fn put_manifest(state: &ServeState, req: Request<Body>, name: &str, reference: &str) -> Response<Body> {
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_owned);
+
+    let mut body = req.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(e) => return error(StatusCode::BAD_REQUEST, "MANIFEST_INVALID", &e.to_string()),
+        }
+        if data.len() > MAX_MANIFEST_SIZE {
+            return error(StatusCode::PAYLOAD_TOO_LARGE, "MANIFEST_INVALID", "the manifest is larger than 4 MiB");
+        }
+    }
+
+    let digest = format!("sha256:{:x}", Sha256::digest(&data));
+    let tag = match reference.starts_with("sha256:") {
+        true if reference != digest => return error(StatusCode::BAD_REQUEST, "DIGEST_INVALID", &format!("the manifest has digest {}", digest)),
+        true => None,
+        false if !names::is_tag(reference) => return error(StatusCode::BAD_REQUEST, "TAG_INVALID", reference),
+        false => Some(reference),
+    };
+    let manifest: Value = match serde_json::from_slice(&data) {
+        Ok(manifest) => manifest,
+        Err(e) => return error(StatusCode::BAD_REQUEST, "MANIFEST_INVALID", &e.to_string()),
+    };
+    // The subject names the referrers list the manifest is added to.
+    if let Some(subject) = manifest["subject"]["digest"].as_str().filter(|subject| !is_digest(subject)) {
+        return error(StatusCode::BAD_REQUEST, "MANIFEST_INVALID", &format!("invalid subject digest {}", subject));
+    }
+
+    match missing_reference(state, name, &manifest).await {
+        Ok(Some(missing)) => return error(StatusCode::BAD_REQUEST, "MANIFEST_BLOB_UNKNOWN", &missing),
+        Ok(None) => {}
+        Err(e) => return internal_error(e),
+    }
+
+    let content_type = content_type.or_else(|| manifest["mediaType"].as_str().map(str::to_owned)).unwrap_or_else(|| DEFAULT_MANIFEST_TYPE.to_owned());
+    match store_manifest(state, name, &digest, tag, &manifest, data, &content_type).await {
+        Ok(()) => {
+            log::info!("Received manifest {} for {}:{}", digest, name, tag.unwrap_or(&digest));
+            created(&format!("/v2/{}/manifests/{}", name, digest), &digest)
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+// The first referenced blob or child manifest the repository does not have.
+async fn missing_reference(state: &ServeState, name: &str, manifest: &Value) -> Result<Option<String>> {
+    let blobs = manifest["config"].as_object().into_iter().chain(manifest["layers"].as_array().into_iter().flatten().filter_map(Value::as_object));
+    for descriptor in blobs {
+        let digest = descriptor.get("digest").and_then(Value::as_str).unwrap_or_default();
+        let stored = is_digest(digest)
+            && (chunks::exists(&state.client, &state.bucket, &format!("v2/{}/blobs/{}", name, digest)).await?
+                || chunks::exists(&state.client, &state.bucket, &chunks::recipe_key(name, digest)).await?);
+        if !stored {
+            return Ok(Some(digest.to_owned()));
+        }
+    }
+
+    for child in manifest["manifests"].as_array().into_iter().flatten() {
+        let digest = child["digest"].as_str().unwrap_or_default();
+        if !is_digest(digest) || !chunks::exists(&state.client, &state.bucket, &format!("v2/{}/manifests/{}", name, digest)).await? {
+            return Ok(Some(digest.to_owned()));
+        }
+    }
+
+    Ok(None)
+}
+
+async fn store_manifest(state: &ServeState, name: &str, digest: &str, tag: Option<&str>, manifest: &Value, data: Vec<u8>, content_type: &str) -> Result<()> {
+    let size = data.len();
+    for reference in std::iter::once(digest).chain(tag) {
+        let key = format!("v2/{}/manifests/{}", name, reference);
+        let req = PutObjectRequest {
+            bucket: state.bucket.clone(),
+            key: key.clone(),
+            body: Some(data.clone().into()),
+            content_type: Some(content_type.to_owned()),
+            metadata: Some(HashMap::from([("docker-content-digest".to_owned(), digest.to_owned())])),
+            ..Default::default()
+        };
+        state.client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+    }
+
+    catalog::update_catalog(&state.env_vars, name).await?;
+    if let Some(tag) = tag {
+        catalog::update_tags(&state.env_vars, name, tag).await?;
+    }
+
+    if let Some(subject) = manifest["subject"]["digest"].as_str() {
+        let descriptor = json!({
+            "mediaType": content_type,
+            "artifactType": manifest["artifactType"].as_str().or(manifest["config"]["mediaType"].as_str()),
+            "digest": digest,
+            "size": size,
+            "annotations": manifest["annotations"],
+        });
+        referrers::add(&state.env_vars, name, subject, descriptor).await?;
+    }
+
+    Ok(())
+}
+
+// Copies `digest` from `from`, as a plain blob or as the recipe of a chunked one. False when
+// `from` does not have it.
+async fn mount(state: &ServeState, name: &str, digest: &str, from: &str) -> Result<bool> {
+    let keys = [
+        (format!("v2/{}/blobs/{}", from, digest), format!("v2/{}/blobs/{}", name, digest)),
+        (chunks::recipe_key(from, digest), chunks::recipe_key(name, digest)),
+    ];
+
+    for (_, target) in &keys {
+        if chunks::exists(&state.client, &state.bucket, target).await? {
+            return Ok(true);
+        }
+    }
+
+    for (source, target) in keys {
+        let req = CopyObjectRequest {
+            bucket: state.bucket.clone(),
+            key: target.clone(),
+            copy_source: format!("{}/{}", state.bucket, source),
+            ..Default::default()
+        };
+        match state.client.copy_object(req).await {
+            Ok(_) => {
+                log::info!("Mounted {} from {} into {}", digest, from, name);
+                return Ok(true);
+            }
+            Err(e) if v2::is_not_found(&e) => continue,
+            Err(e) => bail!("Failed to copy {} to {}: {}", source, target, e),
+        }
+    }
+
+    Ok(false)
+}
+
+fn accepted(name: &str, id: &str, size: u64, status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .header("Docker-Upload-UUID", id)
+        .header(header::LOCATION, format!("/v2/{}/blobs/uploads/{}", name, id))
+        .header(header::RANGE, format!("0-{}", size.saturating_sub(1)))
+        .header(header::CONTENT_LENGTH, 0)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn created(location: &str, digest: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .header("Docker-Content-Digest", digest)
+        .header(header::LOCATION, location)
+        .header(header::CONTENT_LENGTH, 0)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn internal_error(e: anyhow::Error) -> Response<Body> {
+    log::error!("{:#}", e);
+    error(StatusCode::BAD_GATEWAY, "UNKNOWN", "failed to write to the bucket")
+}
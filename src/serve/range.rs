@@ -0,0 +1,84 @@
+use std::ops::Range;
+
+pub(crate) enum RangeRequest {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+// Resolves a `Range` header against an object of `size` bytes. Only a single `bytes=` range is
+// honoured, which is what clients resuming a layer download send; anything else gets the whole
+// object, as HTTP allows.
+pub(crate) fn resolve(header: Option<&str>, size: u64) -> RangeRequest {
+    let Some((start, end)) = header.and_then(parse) else {
+        return RangeRequest::Full;
+    };
+
+    let range = match (start, end) {
+        (Some(start), end) => start..end.map_or(size, |end| end.saturating_add(1).min(size)),
+        // `bytes=-<n>` asks for the last n bytes.
+        (None, Some(suffix)) => size.saturating_sub(suffix)..size,
+        (None, None) => return RangeRequest::Full,
+    };
+
+    match range.start < range.end {
+        true => RangeRequest::Partial(range),
+        false => RangeRequest::Unsatisfiable,
+    }
+}
+
+// The header to forward to the bucket for objects whose size is not known up front.
+pub(crate) fn forwardable(header: Option<&str>) -> Option<&str> {
+    header.filter(|header| parse(header).is_some_and(|bounds| bounds != (None, None)))
+}
+
+pub(crate) fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+fn parse(header: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let bound = |value: &str| match value.trim() {
+        "" => Some(None),
+        value => value.parse().ok().map(Some),
+    };
+    let (start, end) = (bound(start)?, bound(end)?);
+    if let (Some(start), Some(end)) = (start, end) {
+        if end < start {
+            return None;
+        }
+    }
+
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_clamps_single_ranges_to_the_object() {
+        assert!(matches!(resolve(Some("bytes=0-99"), 1000), RangeRequest::Partial(range) if range == (0..100)));
+        assert!(matches!(resolve(Some("bytes=900-"), 1000), RangeRequest::Partial(range) if range == (900..1000)));
+        assert!(matches!(resolve(Some("bytes=900-5000"), 1000), RangeRequest::Partial(range) if range == (900..1000)));
+        assert!(matches!(resolve(Some("bytes=-100"), 1000), RangeRequest::Partial(range) if range == (900..1000)));
+        assert!(matches!(resolve(Some("bytes=-5000"), 1000), RangeRequest::Partial(range) if range == (0..1000)));
+        assert!(matches!(resolve(Some("bytes=1000-"), 1000), RangeRequest::Unsatisfiable));
+        assert!(matches!(resolve(Some("bytes=-0"), 1000), RangeRequest::Unsatisfiable));
+    }
+
+    #[test]
+    fn resolve_serves_everything_for_unsupported_headers() {
+        assert!(matches!(resolve(None, 1000), RangeRequest::Full));
+        assert!(matches!(resolve(Some("bytes=0-1,5-6"), 1000), RangeRequest::Full));
+        assert!(matches!(resolve(Some("bytes=-"), 1000), RangeRequest::Full));
+        assert!(matches!(resolve(Some("items=0-1"), 1000), RangeRequest::Full));
+        assert!(matches!(resolve(Some("bytes=a-b"), 1000), RangeRequest::Full));
+        assert!(matches!(resolve(Some("bytes=5-1"), 1000), RangeRequest::Full));
+    }
+}
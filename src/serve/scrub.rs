@@ -0,0 +1,97 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use rusoto_s3::S3Client;
+use serde_json::json;
+
+use crate::config::ScrubConfig;
+use crate::integrity;
+use crate::proxy;
+use crate::r2configs::R2Configs;
+use crate::v2::lister::Lister;
+
+// Counters exposed on `/metrics`.
+#[derive(Default)]
+pub(crate) struct ScrubStats {
+    rounds: AtomicU64,
+    objects: AtomicU64,
+    corrupt: AtomicU64,
+}
+
+impl ScrubStats {
+    pub(crate) fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        for (name, help, value) in [
+            ("oci_r2_scrub_rounds_total", "Scrub rounds completed.", &self.rounds),
+            ("oci_r2_scrub_objects_total", "Objects verified by the scrubber.", &self.objects),
+            ("oci_r2_scrub_corrupt_total", "Objects that did not match their digest.", &self.corrupt),
+        ] {
+            let _ = write!(metrics, "# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, value.load(Ordering::Relaxed));
+        }
+
+        metrics
+    }
+}
+
+// Verifies `config.sample` objects every `config.interval_secs`, walking the bucket listing from a
+// random starting point so restarts do not scrub the same objects first. The listing is refreshed
+// once every object in it has been verified.
+pub(crate) fn start(env_vars: R2Configs, client: S3Client, config: ScrubConfig) -> Result<Arc<ScrubStats>> {
+    let stats = Arc::new(ScrubStats::default());
+    let http = proxy::http_client()?;
+
+    let scrub_stats = stats.clone();
+    tokio::spawn(async move {
+        let stats = scrub_stats;
+        let mut keys: Vec<String> = Vec::new();
+        let mut position = 0;
+        loop {
+            tokio::time::sleep(Duration::from_secs(config.interval_secs)).await;
+
+            if position >= keys.len() {
+                match Lister::new(&client, &env_vars.r2_bucket).concurrency(8).list("v2/").await {
+                    Ok(objects) => keys = objects.into_iter().filter(integrity::is_content).map(|object| object.key).collect(),
+                    Err(e) => {
+                        log::warn!("Failed to list the bucket for scrubbing: {:#}", e);
+                        continue;
+                    }
+                }
+                let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or_default() as usize;
+                let start = seed % keys.len().max(1);
+                keys.rotate_left(start);
+                position = 0;
+            }
+
+            let end = (position + config.sample).min(keys.len());
+            for key in &keys[position..end] {
+                stats.objects.fetch_add(1, Ordering::Relaxed);
+                let Some(problem) = integrity::check(&env_vars, &client, key).await else {
+                    continue;
+                };
+
+                stats.corrupt.fetch_add(1, Ordering::Relaxed);
+                log::error!("Scrub found {} corrupt: {}", key, problem);
+                if let Some(url) = &config.webhook {
+                    let alert = json!({
+                        "time": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                        "bucket": env_vars.r2_bucket,
+                        "key": key,
+                        "problem": problem,
+                    });
+                    match http.post(url).json(&alert).send().await {
+                        Ok(response) if !response.status().is_success() => log::warn!("Scrub webhook {} answered {}", url, response.status()),
+                        Ok(_) => {}
+                        Err(e) => log::warn!("Failed to reach scrub webhook {}: {}", url, e),
+                    }
+                }
+            }
+            log::info!("Scrubbed {} of {} objects", end, keys.len());
+            position = end;
+            stats.rounds.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    Ok(stats)
+}
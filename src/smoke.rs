@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderName, ACCEPT};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::batch::split_reference;
+use crate::import::Registry;
+use crate::prewarm::MANIFEST_ACCEPT;
+use crate::proxy;
+
+#[derive(Clone, Debug, Default)]
+pub struct SmokeTestReport {
+    pub digest: String,
+    pub manifests: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+// Pulls `<image>:<tag>` through the public registry endpoint the way a client would: the manifest
+// by tag, every child manifest of an index, then every config and layer blob, checking each body
+// against its digest. Confirms the whole push, CDN and serving path after deployment changes.
+pub async fn smoke_test(reference: &str, registry_url: &str) -> Result<SmokeTestReport> {
+    let (image, tag) = split_reference(reference).context(format!("Invalid reference {} (expected <image>:<tag>)", reference))?;
+    let mut puller = Puller::new(registry_url, &image)?;
+    let started = Instant::now();
+
+    let (digest, top) = puller.manifest(&tag, None).await?;
+    let mut report = SmokeTestReport { digest, manifests: 1, ..Default::default() };
+
+    let mut manifests = Vec::new();
+    match top["manifests"].as_array() {
+        Some(children) => {
+            for child in children {
+                let digest = child["digest"].as_str().context("Index entry without a digest")?;
+                manifests.push(puller.manifest(digest, Some(digest)).await?.1);
+                report.manifests += 1;
+            }
+        }
+        None => manifests.push(top),
+    }
+
+    for manifest in &manifests {
+        let descriptors = manifest["config"].as_object().into_iter().chain(manifest["layers"].as_array().into_iter().flatten().filter_map(Value::as_object));
+        for descriptor in descriptors {
+            let digest = descriptor.get("digest").and_then(Value::as_str).context("Descriptor without a digest")?;
+            let size = descriptor.get("size").and_then(Value::as_u64);
+            report.bytes += puller.blob(digest, size).await?;
+            report.blobs += 1;
+        }
+    }
+
+    report.elapsed = started.elapsed();
+    log::info!(
+        "Smoke test of {}@{} passed: {} manifests and {} blobs ({} bytes) in {:.1}s",
+        reference,
+        report.digest,
+        report.manifests,
+        report.blobs,
+        report.bytes,
+        report.elapsed.as_secs_f64()
+    );
+
+    Ok(report)
+}
+
+// A client of one repository of the registry, anonymous unless given credentials.
+pub(crate) struct Puller {
+    registry: Registry,
+    image: String,
+    token: Option<String>,
+}
+
+impl Puller {
+    pub(crate) fn new(registry_url: &str, image: &str) -> Result<Self> {
+        Ok(Puller {
+            registry: Registry {
+                client: proxy::http_client()?,
+                base: registry_url.trim_end_matches('/').to_owned(),
+                username: None,
+                password: None,
+            },
+            image: image.to_owned(),
+            token: None,
+        })
+    }
+
+    pub(crate) fn with_credentials(mut self, username: Option<String>, password: Option<String>) -> Self {
+        self.registry.username = username;
+        self.registry.password = password;
+        self
+    }
+
+    // `<registry>/v2/<image>/<path>`
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("{}/v2/{}/{}", self.registry.base, self.image, path)
+    }
+
+    pub(crate) fn base_url(&self) -> String {
+        format!("{}/v2/", self.registry.base)
+    }
+
+    // Fetches a manifest by tag or digest. The digest is checked against `expected` or, for a tag,
+    // against the `Docker-Content-Digest` header when the registry sends one.
+    pub(crate) async fn manifest(&mut self, reference: &str, expected: Option<&str>) -> Result<(String, Value)> {
+        let url = self.url(&format!("manifests/{}", reference));
+        let response = self.get(&url, Some(MANIFEST_ACCEPT)).await?;
+        let header = response.headers().get("docker-content-digest").and_then(|value| value.to_str().ok()).map(str::to_owned);
+
+        let body = response.bytes().await.context(format!("Failed to download {}", url))?;
+        let digest = format!("sha256:{:x}", Sha256::digest(&body));
+        if let Some(expected) = expected.or(header.as_deref()) {
+            if digest != expected {
+                bail!("Manifest {} has digest {}, expected {}", url, digest, expected);
+            }
+        }
+
+        let manifest = serde_json::from_slice(&body).context(format!("Malformed manifest {}", url))?;
+        Ok((digest, manifest))
+    }
+
+    // Streams a blob, following redirects to presigned URLs, and returns its size.
+    pub(crate) async fn blob(&mut self, digest: &str, size: Option<u64>) -> Result<u64> {
+        let url = self.url(&format!("blobs/{}", digest));
+        let mut response = self.get(&url, None).await?;
+
+        let mut hasher = Sha256::new();
+        let mut length = 0;
+        while let Some(chunk) = response.chunk().await.context(format!("Failed to download {}", url))? {
+            hasher.update(&chunk);
+            length += chunk.len() as u64;
+        }
+
+        let actual = format!("sha256:{:x}", hasher.finalize());
+        if actual != digest {
+            bail!("Blob {} has digest {}", url, actual);
+        }
+        if let Some(size) = size.filter(|size| *size != length) {
+            bail!("Blob {} is {} bytes, the manifest says {}", url, length, size);
+        }
+
+        Ok(length)
+    }
+
+    // Downloads a small JSON blob such as an image config, checked against its digest.
+    pub(crate) async fn json_blob(&mut self, digest: &str) -> Result<Value> {
+        let url = self.url(&format!("blobs/{}", digest));
+        let body = self.get(&url, None).await?.bytes().await.context(format!("Failed to download {}", url))?;
+        let actual = format!("sha256:{:x}", Sha256::digest(&body));
+        if actual != digest {
+            bail!("Blob {} has digest {}", url, actual);
+        }
+
+        serde_json::from_slice(&body).context(format!("Malformed JSON in {}", url))
+    }
+
+    async fn get(&mut self, url: &str, accept: Option<&str>) -> Result<reqwest::Response> {
+        let headers: Vec<_> = accept.map(|accept| (ACCEPT, accept)).into_iter().collect();
+        let response = self.send(Method::GET, url, &headers).await?;
+        if !response.status().is_success() {
+            bail!("{} answered {}", url, response.status());
+        }
+
+        Ok(response)
+    }
+
+    // Answers a `Bearer` challenge with an anonymous token once and reuses it for later requests.
+    // The response is returned whatever its status.
+    pub(crate) async fn send(&mut self, method: Method, url: &str, headers: &[(HeaderName, &str)]) -> Result<reqwest::Response> {
+        let request = |token: Option<&str>| {
+            let request = headers.iter().fold(self.registry.client.request(method.clone(), url), |request, (name, value)| request.header(name, *value));
+            self.registry.authenticate(request, token).send()
+        };
+
+        let mut response = request(self.token.as_deref()).await.context(format!("Failed to reach {}", url))?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let token = self.registry.authorize(&response).await?;
+            response = request(Some(&token)).await.context(format!("Failed to reach {}", url))?;
+            self.token = Some(token);
+        }
+
+        Ok(response)
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use serde_json::json;
+
+const LATENCY_BUCKETS: [Duration; 4] = [
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+#[derive(Clone, Debug)]
+pub struct BlobTiming {
+    pub name: String,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+// A blob that was not uploaded because the bucket already had it.
+#[derive(Clone, Debug)]
+pub struct ReusedBlob {
+    pub name: String,
+    pub bytes: u64,
+    // The repository it was copied from, `None` when this repository already had it.
+    pub source: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LayerRecompression {
+    pub name: String,
+    pub compression: &'static str,
+    pub level: i32,
+    pub before: u64,
+    pub after: u64,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PushStats {
+    pub convert: Duration,
+    pub recompress: Duration,
+    pub hash: Duration,
+    pub upload: Duration,
+    pub blobs: Vec<BlobTiming>,
+    pub reused: Vec<ReusedBlob>,
+    pub recompressed: Vec<LayerRecompression>,
+    // Connections the whole process opened while this push uploaded. The pool is shared, so this
+    // includes the connections of any pushes running alongside.
+    pub new_connections: u64,
+}
+
+impl PushStats {
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.blobs.iter().map(|blob| blob.bytes).sum()
+    }
+
+    pub fn reused_bytes(&self) -> u64 {
+        self.reused.iter().map(|blob| blob.bytes).sum()
+    }
+
+    // Bytes copied server-side from other repositories.
+    pub fn copied_bytes(&self) -> u64 {
+        self.reused.iter().filter(|blob| blob.source.is_some()).map(|blob| blob.bytes).sum()
+    }
+
+    // The other repositories this push shares blobs with.
+    pub fn shared_with(&self) -> BTreeSet<&str> {
+        self.reused.iter().filter_map(|blob| blob.source.as_deref()).collect()
+    }
+
+    // The share of the image's bytes that did not have to be uploaded.
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        let total = self.uploaded_bytes() + self.reused_bytes();
+        (total > 0).then(|| self.reused_bytes() as f64 / total as f64)
+    }
+
+    // The dedup outcome as annotations for the push receipt.
+    pub(crate) fn dedup_annotations(&self) -> BTreeMap<String, String> {
+        let shared_with: Vec<&str> = self.shared_with().into_iter().collect();
+        BTreeMap::from([
+            ("dev.oci-r2.dedup.uploaded-bytes".to_owned(), self.uploaded_bytes().to_string()),
+            ("dev.oci-r2.dedup.reused-bytes".to_owned(), self.reused_bytes().to_string()),
+            ("dev.oci-r2.dedup.copied-bytes".to_owned(), self.copied_bytes().to_string()),
+            ("dev.oci-r2.dedup.shared-with".to_owned(), shared_with.join(",")),
+        ])
+    }
+
+    // Bytes per second over the whole upload phase, so that it reflects the concurrency actually
+    // achieved rather than the speed of individual requests.
+    pub fn throughput(&self) -> f64 {
+        if self.upload.is_zero() {
+            return 0.0;
+        }
+
+        self.uploaded_bytes() as f64 / self.upload.as_secs_f64()
+    }
+
+    pub fn latency_percentile(&self, percentile: u8) -> Option<Duration> {
+        let mut durations: Vec<Duration> = self.blobs.iter().map(|blob| blob.duration).collect();
+        if durations.is_empty() {
+            return None;
+        }
+
+        durations.sort();
+        let rank = (durations.len() * usize::from(percentile.min(100))).div_ceil(100).max(1);
+        Some(durations[rank - 1])
+    }
+
+    pub fn latency_histogram(&self) -> Vec<(Option<Duration>, usize)> {
+        let mut histogram: Vec<(Option<Duration>, usize)> = LATENCY_BUCKETS.iter().map(|bound| (Some(*bound), 0)).collect();
+        histogram.push((None, 0));
+
+        for blob in &self.blobs {
+            let bucket = LATENCY_BUCKETS.iter().position(|bound| blob.duration < *bound).unwrap_or(LATENCY_BUCKETS.len());
+            histogram[bucket].1 += 1;
+        }
+
+        histogram
+    }
+
+    // Uncompressed-input bytes per second of recompression CPU time, summed over all workers.
+    pub fn recompression_throughput(&self) -> f64 {
+        let busy: f64 = self.recompressed.iter().map(|layer| layer.duration.as_secs_f64()).sum();
+        if busy == 0.0 {
+            return 0.0;
+        }
+
+        self.recompressed.iter().map(|layer| layer.before).sum::<u64>() as f64 / busy
+    }
+
+    pub fn recompression_ratio(&self) -> Option<f64> {
+        let before: u64 = self.recompressed.iter().map(|layer| layer.before).sum();
+        let after: u64 = self.recompressed.iter().map(|layer| layer.after).sum();
+
+        (before > 0).then(|| after as f64 / before as f64)
+    }
+
+    pub(crate) fn log_summary(&self) {
+        log::info!(
+            "Timing: convert {:.1}s, recompress {:.1}s, hash {:.1}s, upload {:.1}s",
+            self.convert.as_secs_f64(),
+            self.recompress.as_secs_f64(),
+            self.hash.as_secs_f64(),
+            self.upload.as_secs_f64()
+        );
+
+        if let (Some(layer), Some(ratio)) = (self.recompressed.first(), self.recompression_ratio()) {
+            log::info!(
+                "Recompressed {} layers with {} level {} to {:.1}% of their size at {:.1} MiB/s per thread",
+                self.recompressed.len(),
+                layer.compression,
+                layer.level,
+                ratio * 100.0,
+                self.recompression_throughput() / (1024.0 * 1024.0)
+            );
+        }
+
+        if let (Some(p50), Some(p95)) = (self.latency_percentile(50), self.latency_percentile(95)) {
+            log::info!(
+                "Uploaded {} blobs ({} bytes) at {:.1} MiB/s, blob latency p50 {:.2}s p95 {:.2}s",
+                self.blobs.len(),
+                self.uploaded_bytes(),
+                self.throughput() / (1024.0 * 1024.0),
+                p50.as_secs_f64(),
+                p95.as_secs_f64()
+            );
+            log::info!("The process opened {} new connections to R2 during the upload (shared with concurrent pushes)", self.new_connections);
+        }
+
+        if let Some(ratio) = self.dedup_ratio().filter(|_| !self.reused.is_empty()) {
+            log::info!(
+                "Reused {} bytes already in the bucket ({:.1}% of the image), {} of them copied from {}",
+                self.reused_bytes(),
+                ratio * 100.0,
+                self.copied_bytes(),
+                match self.shared_with().is_empty() {
+                    true => "no other repository".to_owned(),
+                    false => self.shared_with().into_iter().collect::<Vec<_>>().join(", "),
+                }
+            );
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let millis = |duration: Option<Duration>| duration.map(|duration| duration.as_millis() as u64);
+
+        json!({
+            "convert_ms": self.convert.as_millis() as u64,
+            "recompress_ms": self.recompress.as_millis() as u64,
+            "hash_ms": self.hash.as_millis() as u64,
+            "upload_ms": self.upload.as_millis() as u64,
+            "uploaded_blobs": self.blobs.len(),
+            "uploaded_bytes": self.uploaded_bytes(),
+            "new_connections": self.new_connections,
+            "reused_bytes": self.reused_bytes(),
+            "copied_bytes": self.copied_bytes(),
+            "dedup_ratio": self.dedup_ratio(),
+            "shared_with": self.shared_with(),
+            "throughput_bytes_per_sec": self.throughput().round() as u64,
+            "blob_latency_p50_ms": millis(self.latency_percentile(50)),
+            "blob_latency_p95_ms": millis(self.latency_percentile(95)),
+            "blob_latency_histogram": self
+                .latency_histogram()
+                .into_iter()
+                .map(|(bound, count)| json!({ "lt_ms": millis(bound), "count": count }))
+                .collect::<Vec<_>>(),
+            "recompression_ratio": self.recompression_ratio(),
+            "recompression_throughput_bytes_per_sec": self.recompression_throughput().round() as u64,
+            "recompressed_layers": self
+                .recompressed
+                .iter()
+                .map(|layer| {
+                    json!({
+                        "digest": layer.name,
+                        "compression": layer.compression,
+                        "level": layer.level,
+                        "before_bytes": layer.before,
+                        "after_bytes": layer.after,
+                        "duration_ms": layer.duration.as_millis() as u64,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+}
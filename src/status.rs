@@ -0,0 +1,293 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::progress::{Phase, Progress, ProgressEvent};
+use crate::workdir;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_WRITE_INTERVAL: Duration = Duration::from_secs(1);
+// A running push whose file has not been refreshed for this long is presumed dead.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+// The state of a push as written to its status file. `updated` is refreshed at least every few
+// seconds while the push runs, so a supervisor can tell a stalled or killed push from a slow one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushStatus {
+    pub pid: u32,
+    pub request_id: String,
+    pub image: String,
+    pub tag: String,
+    pub phase: Phase,
+    pub started: String,
+    pub updated: String,
+    pub objects_done: usize,
+    pub objects_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    // Blobs and configs the converter copied so far.
+    #[serde(default)]
+    pub objects_converted: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+    #[serde(default)]
+    pub finished: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PushStatus {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).context(format!("Failed to read status file {}", path.display()))?;
+        serde_json::from_slice(&data).context(format!("Malformed status file {}", path.display()))
+    }
+
+    // The push stopped refreshing its file without finishing: it was killed or is hung.
+    pub fn is_stale(&self) -> bool {
+        !self.finished && self.age().is_some_and(|age| age > STALE_AFTER)
+    }
+
+    fn age(&self) -> Option<Duration> {
+        humantime::parse_rfc3339_weak(&self.updated).ok().and_then(|updated| SystemTime::now().duration_since(updated).ok())
+    }
+}
+
+impl fmt::Display for PushStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} (pid {}) {:?}", self.image, self.tag, self.pid, self.phase)?;
+        if self.phase == Phase::Converting && self.objects_converted > 0 {
+            write!(f, ", {} objects converted", self.objects_converted)?;
+        }
+        if self.bytes_total > 0 {
+            let percent = self.bytes_done as f64 * 100.0 / self.bytes_total as f64;
+            write!(f, ", {}/{} objects, {}/{} bytes ({:.1}%)", self.objects_done, self.objects_total, self.bytes_done, self.bytes_total, percent)?;
+        }
+        if let Some(eta) = self.eta_secs {
+            write!(f, ", ETA {}", humantime::format_duration(Duration::from_secs(eta)))?;
+        }
+
+        match (&self.error, self.finished) {
+            (Some(error), _) => write!(f, ", failed: {}", error),
+            (None, true) => f.write_str(", finished"),
+            (None, false) if self.is_stale() => write!(f, ", no update since {}", self.updated),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+// The status files of the pushes on this host that write to `status_dir()`, oldest first.
+pub fn running_pushes() -> Result<Vec<(PathBuf, PushStatus)>> {
+    let dir = status_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut pushes = Vec::new();
+    for path in crate::v2::staged_files(&dir)? {
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        // Files can vanish or be replaced between listing and reading them.
+        let Ok(status) = PushStatus::load(&path) else {
+            continue;
+        };
+        // Every push writes a file by default, finished ones are kept only long enough to be seen.
+        if status.finished && status.age().is_some_and(|age| age > STALE_AFTER) {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        pushes.push((path, status));
+    }
+    pushes.sort_by(|(_, a), (_, b)| a.started.cmp(&b.started));
+
+    Ok(pushes)
+}
+
+// Attaches to the push writing `path` and calls `on_update` every time its status changes, until
+// the push finishes. Fails when the push stops refreshing the file.
+pub async fn follow_status<F: FnMut(&PushStatus)>(path: &Path, mut on_update: F) -> Result<PushStatus> {
+    let mut last: Option<PushStatus> = None;
+    loop {
+        let status = PushStatus::load(path)?;
+        if last.as_ref() != Some(&status) {
+            on_update(&status);
+        }
+        if status.finished {
+            return Ok(status);
+        }
+        if status.is_stale() {
+            bail!("The push of {}:{} (pid {}) stopped updating {} at {}", status.image, status.tag, status.pid, path.display(), status.updated);
+        }
+
+        last = Some(status);
+        tokio::time::sleep(MIN_WRITE_INTERVAL).await;
+    }
+}
+
+// Where pushes write their status when asked to without a path: one file per process. Below the
+// temporary directory, which is below the work directory on systems without one.
+pub fn status_dir() -> Result<PathBuf> {
+    workdir::work_dir()?;
+    Ok(tempfile::env::temp_dir().join("oci-r2-uploader"))
+}
+
+pub fn default_status_file() -> Result<PathBuf> {
+    Ok(status_dir()?.join(format!("{}.json", process::id())))
+}
+
+#[derive(Clone)]
+pub(crate) struct StatusFile {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    // Held from taking a snapshot until it is renamed into place, so the heartbeat and progress
+    // events never share the temporary file or replace a newer snapshot with an older one.
+    writer: Arc<Mutex<()>>,
+}
+
+struct State {
+    status: PushStatus,
+    upload_started: Option<Instant>,
+    written: Option<Instant>,
+}
+
+impl StatusFile {
+    pub(crate) fn create(path: &Path, request_id: &str, image: &str, tag: &str) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+
+        let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        let status = PushStatus {
+            pid: process::id(),
+            request_id: request_id.to_owned(),
+            image: image.to_owned(),
+            tag: tag.to_owned(),
+            phase: Phase::Converting,
+            started: now.clone(),
+            updated: now,
+            objects_done: 0,
+            objects_total: 0,
+            bytes_done: 0,
+            bytes_total: 0,
+            objects_converted: 0,
+            eta_secs: None,
+            finished: false,
+            error: None,
+        };
+
+        let file = StatusFile {
+            path: path.to_owned(),
+            state: Arc::new(Mutex::new(State { status, upload_started: None, written: None })),
+            writer: Arc::new(Mutex::new(())),
+        };
+        file.write(true);
+
+        Ok(file)
+    }
+
+    // A progress callback that records every event before passing it on to `progress`.
+    pub(crate) fn wrap(&self, progress: Progress) -> Progress {
+        let file = self.clone();
+        Progress::new(move |event| {
+            file.record(&event);
+            progress.emit(event);
+        })
+    }
+
+    fn record(&self, event: &ProgressEvent) {
+        let force = {
+            let mut state = self.state.lock().unwrap();
+            match event {
+                ProgressEvent::Phase(phase) => {
+                    state.status.phase = *phase;
+                    true
+                }
+                ProgressEvent::Converted { .. } => {
+                    state.status.objects_converted += 1;
+                    false
+                }
+                ProgressEvent::UploadPlanned { objects, bytes } => {
+                    state.status.objects_total = *objects;
+                    state.status.bytes_total = *bytes;
+                    state.upload_started = Some(Instant::now());
+                    true
+                }
+                ProgressEvent::Uploaded { bytes, .. } => {
+                    state.status.objects_done += 1;
+                    state.status.bytes_done += bytes;
+                    false
+                }
+                ProgressEvent::VerifyPlanned { .. } | ProgressEvent::Verified { .. } => false,
+            }
+        };
+
+        self.write(force);
+    }
+
+    // Rewrites the file from a background task until the returned handle is aborted.
+    pub(crate) fn heartbeat(&self) -> tokio::task::JoinHandle<()> {
+        let file = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                file.write(true);
+            }
+        })
+    }
+
+    pub(crate) fn finish<T>(&self, result: &Result<T>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.status.finished = true;
+            state.status.eta_secs = None;
+            state.status.error = result.as_ref().err().map(|e| format!("{:#}", e));
+        }
+
+        self.write(true);
+    }
+
+    // Progress events are frequent, so unforced writes are limited to one per second. The file is
+    // replaced atomically so readers never see a partial write.
+    fn write(&self, force: bool) {
+        let _writer = self.writer.lock().unwrap();
+        let data = {
+            let mut state = self.state.lock().unwrap();
+            if !force && state.written.is_some_and(|written| written.elapsed() < MIN_WRITE_INTERVAL) {
+                return;
+            }
+            state.written = Some(Instant::now());
+
+            let eta = eta(&state.status, state.upload_started);
+            state.status.eta_secs = eta.filter(|_| !state.status.finished);
+            state.status.updated = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            serde_json::to_vec_pretty(&state.status)
+        };
+
+        let tmp = self.path.with_extension("json.tmp");
+        let written = data.map_err(anyhow::Error::from).and_then(|data| {
+            fs::write(&tmp, data)?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(())
+        });
+        if let Err(e) = written {
+            log::warn!("Failed to write status file {}: {:#}", self.path.display(), e);
+        }
+    }
+}
+
+// Extrapolated from the average upload rate so far.
+fn eta(status: &PushStatus, upload_started: Option<Instant>) -> Option<u64> {
+    let elapsed = upload_started?.elapsed().as_secs_f64();
+    if status.bytes_done == 0 || elapsed == 0.0 {
+        return None;
+    }
+
+    let remaining = status.bytes_total.saturating_sub(status.bytes_done) as f64;
+    Some((remaining / (status.bytes_done as f64 / elapsed)).round() as u64)
+}
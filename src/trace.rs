@@ -0,0 +1,75 @@
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture, HttpClient};
+use rusoto_core::signature::SignedRequest;
+
+use crate::proxy::Connector;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-oci-r2-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// A short random-looking id per push, unique across processes and concurrent pushes of one process.
+pub(crate) fn new_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&nanos.to_le_bytes());
+    hasher.update(&process::id().to_le_bytes());
+    hasher.update(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+
+    hasher.finalize().to_hex()[..16].to_owned()
+}
+
+pub(crate) async fn scope<F: std::future::Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+pub(crate) fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+// Tags every R2 request made inside a push with the push's request id, and logs failed requests
+// with it so they can be matched against the bucket's logs.
+pub(crate) struct TracingDispatcher(pub(crate) HttpClient<Connector>);
+
+impl DispatchSignedRequest for TracingDispatcher {
+    fn dispatch(&self, mut request: SignedRequest, timeout: Option<Duration>) -> DispatchSignedRequestFuture {
+        let request_id = match current() {
+            Some(request_id) => request_id,
+            None => return self.0.dispatch(request, timeout),
+        };
+
+        request.add_header(REQUEST_ID_HEADER, &request_id);
+        let (method, path) = (request.method.clone(), request.path.clone());
+        let response = self.0.dispatch(request, timeout);
+
+        Box::pin(async move {
+            let response = response.await;
+            match &response {
+                Ok(response) if is_failure(response.status.as_u16()) => {
+                    let line = format!("{} {} returned HTTP {} (request id {})", method, path, response.status.as_u16(), request_id);
+                    log::warn!("{}", line);
+                    crate::diagnostics::record_failed_request(line);
+                }
+                Err(e) => {
+                    let line = format!("{} {} failed: {} (request id {})", method, path, e, request_id);
+                    log::warn!("{}", line);
+                    crate::diagnostics::record_failed_request(line);
+                }
+                Ok(_) => {}
+            }
+
+            response
+        })
+    }
+}
+
+// Missing objects and lost conditional writes are part of normal operation.
+fn is_failure(status: u16) -> bool {
+    status >= 400 && !matches!(status, 404 | 409 | 412)
+}
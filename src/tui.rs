@@ -0,0 +1,308 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use tokio::task::AbortHandle;
+
+use crate::batch::BatchJob;
+use crate::progress::{Phase, Progress, ProgressEvent};
+use crate::run_with_options;
+
+const TICK: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Queued,
+    Paused,
+    Converting,
+    Uploading,
+    Done,
+    Failed,
+    Skipped,
+}
+
+struct JobRow {
+    job: BatchJob,
+    status: Status,
+    bytes: u64,
+    total: u64,
+    converted: usize,
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    error: Option<String>,
+    abort: Option<AbortHandle>,
+}
+
+struct State {
+    rows: Vec<JobRow>,
+    selected: usize,
+}
+
+pub async fn run_batch_tui(jobs: Vec<BatchJob>) -> Result<()> {
+    let rows = jobs
+        .into_iter()
+        .map(|job| JobRow {
+            job,
+            status: Status::Queued,
+            bytes: 0,
+            total: 0,
+            converted: 0,
+            started: None,
+            finished: None,
+            error: None,
+            abort: None,
+        })
+        .collect();
+
+    let state = Arc::new(Mutex::new(State { rows, selected: 0 }));
+    let quit = Arc::new(AtomicBool::new(false));
+
+    let ui = {
+        let state = state.clone();
+        let quit = quit.clone();
+        tokio::task::spawn_blocking(move || run_ui(state, quit))
+    };
+
+    while !quit.load(Ordering::Relaxed) {
+        let next = {
+            let state = state.lock().unwrap();
+            state.rows.iter().position(|row| row.status == Status::Queued)
+        };
+
+        match next {
+            Some(index) => run_job(&state, index).await,
+            None => tokio::time::sleep(TICK).await,
+        }
+    }
+
+    ui.await??;
+
+    let state = state.lock().unwrap();
+    let failed = state.rows.iter().filter(|row| row.status == Status::Failed).count();
+    if failed > 0 {
+        bail!("Failed to push {} of {} images", failed, state.rows.len());
+    }
+
+    Ok(())
+}
+
+async fn run_job(state: &Arc<Mutex<State>>, index: usize) {
+    let job = {
+        let mut state = state.lock().unwrap();
+        let row = &mut state.rows[index];
+        row.status = Status::Converting;
+        row.bytes = 0;
+        row.total = 0;
+        row.converted = 0;
+        row.started = Some(Instant::now());
+        row.finished = None;
+        row.error = None;
+        row.job.clone()
+    };
+
+    let mut options = job.options;
+    options.progress = {
+        let state = state.clone();
+        Progress::new(move |event| {
+            let mut state = state.lock().unwrap();
+            let row = &mut state.rows[index];
+            match event {
+                ProgressEvent::Phase(Phase::Converting) => row.status = Status::Converting,
+                ProgressEvent::Phase(Phase::Uploading) => row.status = Status::Uploading,
+                ProgressEvent::Phase(Phase::Done) => {}
+                ProgressEvent::Converted { .. } => row.converted += 1,
+                ProgressEvent::UploadPlanned { bytes, .. } => row.total = bytes,
+                ProgressEvent::Uploaded { bytes, .. } => row.bytes += bytes,
+                ProgressEvent::VerifyPlanned { .. } | ProgressEvent::Verified { .. } => {}
+            }
+        })
+    };
+
+    let task = tokio::spawn(run_with_options(job.image, job.tag, options));
+    state.lock().unwrap().rows[index].abort = Some(task.abort_handle());
+
+    let result = task.await;
+
+    let mut state = state.lock().unwrap();
+    let row = &mut state.rows[index];
+    row.abort = None;
+    row.finished = Some(Instant::now());
+    if row.status == Status::Skipped {
+        return;
+    }
+
+    match result {
+        Ok(Ok(_)) => row.status = Status::Done,
+        Ok(Err(e)) => {
+            row.status = Status::Failed;
+            row.error = Some(format!("{:#}", e));
+        }
+        Err(e) => {
+            row.status = Status::Failed;
+            row.error = Some(e.to_string());
+        }
+    }
+}
+
+fn run_ui(state: Arc<Mutex<State>>, quit: Arc<AtomicBool>) -> Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+
+    let result = ui_loop(&state, &quit);
+
+    quit.store(true, Ordering::Relaxed);
+    for row in &state.lock().unwrap().rows {
+        if let Some(abort) = &row.abort {
+            abort.abort();
+        }
+    }
+
+    disable_raw_mode()?;
+    execute!(io::stdout(), LeaveAlternateScreen)?;
+
+    result
+}
+
+fn ui_loop(state: &Arc<Mutex<State>>, quit: &AtomicBool) -> Result<()> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    while !quit.load(Ordering::Relaxed) {
+        terminal.draw(|frame| draw(frame, &state.lock().unwrap()))?;
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+
+        let mut state = state.lock().unwrap();
+        let selected = state.selected;
+        let last = state.rows.len().saturating_sub(1);
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Up | KeyCode::Char('k') => state.selected = selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => state.selected = (selected + 1).min(last),
+            KeyCode::Char('p') => {
+                if let Some(row) = state.rows.get_mut(selected) {
+                    match row.status {
+                        Status::Queued => row.status = Status::Paused,
+                        Status::Paused => row.status = Status::Queued,
+                        _ => {}
+                    }
+                }
+            }
+            KeyCode::Char('s') => {
+                if let Some(row) = state.rows.get_mut(selected) {
+                    if matches!(row.status, Status::Queued | Status::Paused | Status::Converting | Status::Uploading) {
+                        row.status = Status::Skipped;
+                        if let Some(abort) = &row.abort {
+                            abort.abort();
+                        }
+                    }
+                }
+            }
+            KeyCode::Char('r') => {
+                if let Some(row) = state.rows.get_mut(selected) {
+                    if matches!(row.status, Status::Failed | Status::Skipped) && row.abort.is_none() {
+                        row.status = Status::Queued;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn draw(frame: &mut Frame, state: &State) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)])
+        .split(frame.size());
+
+    let header = Row::new(["Image", "Phase", "Progress", "Speed", "Error"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let rows = state.rows.iter().map(|row| {
+        Row::new(vec![
+            Cell::from(format!("{}:{}", row.job.image, row.job.tag)),
+            Cell::from(format!("{:?}", row.status)),
+            Cell::from(format_progress(row)),
+            Cell::from(format_speed(row)),
+            Cell::from(row.error.clone().unwrap_or_default()),
+        ])
+    });
+
+    let widths = [
+        Constraint::Percentage(30),
+        Constraint::Length(12),
+        Constraint::Length(24),
+        Constraint::Length(12),
+        Constraint::Percentage(40),
+    ];
+
+    let done = state.rows.iter().filter(|row| row.status == Status::Done).count();
+    let table = Table::new(rows, widths)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(format!(" oci-r2-uploader ({}/{}) ", done, state.rows.len())))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    let mut table_state = TableState::default();
+    table_state.select(Some(state.selected));
+    frame.render_stateful_widget(table, chunks[0], &mut table_state);
+
+    let help = Paragraph::new(" ↑/↓ select  p pause/resume  s skip  r retry  q quit");
+    frame.render_widget(help, chunks[1]);
+}
+
+fn format_progress(row: &JobRow) -> String {
+    if row.status == Status::Converting && row.converted > 0 {
+        return format!("{} blobs converted", row.converted);
+    }
+    if row.total == 0 {
+        return String::new();
+    }
+
+    let percent = row.bytes as f64 / row.total as f64 * 100.0;
+    format!("{} / {} ({:.0}%)", format_bytes(row.bytes), format_bytes(row.total), percent)
+}
+
+fn format_speed(row: &JobRow) -> String {
+    let elapsed = match row.started {
+        Some(started) => row.finished.unwrap_or_else(Instant::now).duration_since(started).as_secs_f64(),
+        None => return String::new(),
+    };
+
+    if elapsed <= 0.0 || row.bytes == 0 {
+        return String::new();
+    }
+
+    format!("{}/s", format_bytes((row.bytes as f64 / elapsed) as u64))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
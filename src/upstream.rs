@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::converter;
+use crate::filter::ImageFilter;
+use crate::mirror::{self, DIGEST_ANNOTATION, SOURCE_ANNOTATION};
+use crate::r2configs;
+use crate::v2::catalog::{self, CATALOG_KEY};
+use crate::v2::cas;
+use crate::PushOptions;
+
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamCheckOptions {
+    pub filter: ImageFilter,
+    // Mirrors the stale tags again with `push`.
+    pub resync: bool,
+    pub push: PushOptions,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    pub image: String,
+    pub tag: String,
+    pub source: String,
+    pub mirrored_digest: String,
+    // None when upstream could not be resolved, see `error`.
+    pub upstream_digest: Option<String>,
+    pub error: Option<String>,
+}
+
+impl UpstreamStatus {
+    pub fn is_stale(&self) -> bool {
+        self.upstream_digest.as_ref().is_some_and(|digest| *digest != self.mirrored_digest)
+    }
+}
+
+impl fmt::Display for UpstreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} from {}:{}", self.image, self.tag, self.source, self.tag)?;
+        match (&self.upstream_digest, &self.error) {
+            (Some(upstream), _) if self.is_stale() => write!(f, " is stale: mirrored {}, upstream {}", self.mirrored_digest, upstream),
+            (Some(_), _) => write!(f, " is up to date ({})", self.mirrored_digest),
+            (None, error) => write!(f, " could not be checked: {}", error.as_deref().unwrap_or("unknown error")),
+        }
+    }
+}
+
+// Compares every mirrored tag in the bucket (pushed by `mirror_tags`, `sync_repository` or
+// `import_registry`) with the digest its upstream tag resolves to now, and optionally mirrors the
+// stale ones again. Meant to run as a scheduled job.
+pub async fn check_upstream(options: UpstreamCheckOptions) -> Result<Vec<UpstreamStatus>> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let current = cas::read_object(&env_vars, CATALOG_KEY).await?;
+    let repositories = catalog::read_list(current.as_deref(), "repositories")?;
+
+    let mut statuses = Vec::new();
+    for image in repositories.into_iter().filter(|image| options.filter.matches(image)) {
+        let origins = mirror::origins(&env_vars, &image).await?;
+        let current = cas::read_object(&env_vars, &catalog::tags_key(&image)).await?;
+        for tag in catalog::read_list(current.as_deref(), "tags")? {
+            let origin = match origins.get(&tag) {
+                Some(origin) => (origin["source"].as_str().map(str::to_owned), origin["digest"].as_str().map(str::to_owned)),
+                // Tags mirrored by earlier versions carry their origin as annotations.
+                None => {
+                    let key = format!("v2/{}/manifests/{}", image, tag);
+                    let Some(data) = cas::read_object(&env_vars, &key).await? else {
+                        continue;
+                    };
+                    let manifest: Value = serde_json::from_slice(&data).context(format!("Malformed manifest {}", key))?;
+                    let annotation = |name: &str| manifest["annotations"][name].as_str().map(str::to_owned);
+                    (annotation(SOURCE_ANNOTATION), annotation(DIGEST_ANNOTATION))
+                }
+            };
+            let (Some(source), Some(mirrored_digest)) = origin else {
+                continue;
+            };
+
+            let upstream = converter::upstream_digest(&format!("{}:{}", source, tag), None);
+            statuses.push(UpstreamStatus {
+                image: image.clone(),
+                tag,
+                source,
+                mirrored_digest,
+                error: upstream.as_ref().err().map(|e| format!("{:#}", e)),
+                upstream_digest: upstream.ok(),
+            });
+        }
+    }
+
+    let stale: Vec<&UpstreamStatus> = statuses.iter().filter(|status| status.is_stale()).collect();
+    log::info!("{} of {} mirrored tags are stale", stale.len(), statuses.len());
+
+    if options.resync && !stale.is_empty() {
+        let mut groups: BTreeMap<(&str, &str), Vec<String>> = BTreeMap::new();
+        for status in stale {
+            groups.entry((&status.image, &status.source)).or_default().push(status.tag.clone());
+        }
+        for ((image, source), tags) in groups {
+            let report = mirror::mirror_tags(source, image.to_owned(), &tags, options.push.clone()).await?;
+            for (tag, error) in &report.failed {
+                log::error!("Failed to re-sync {}:{}: {}", image, tag, error);
+            }
+            log::info!("Re-synced {} of {} stale tags of {}", report.pushed.len(), tags.len(), image);
+        }
+    }
+
+    Ok(statuses)
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use anyhow::{bail, Result};
+use serde_json::json;
+
+use crate::config;
+use crate::list::split_key;
+use crate::r2configs;
+use crate::v2;
+use crate::v2::lister::Lister;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GroupBy {
+    #[default]
+    Namespace,
+    Repository,
+}
+
+impl FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "namespace" => Ok(GroupBy::Namespace),
+            "repo" | "repository" => Ok(GroupBy::Repository),
+            _ => bail!("Unknown grouping {} (expected namespace or repo)", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsageOptions {
+    pub group_by: GroupBy,
+    pub since: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsageRow {
+    pub group: String,
+    pub repositories: u64,
+    pub objects: u64,
+    pub bytes: u64,
+    pub uploaded_objects: u64,
+    pub uploaded_bytes: u64,
+    // In US dollars, at the `[pricing]` from the config.
+    pub monthly_storage_cost: f64,
+}
+
+// Upload activity is derived from the objects' last-modified times, so blobs that were pushed
+// again unchanged within the window count as recent uploads too.
+pub async fn usage_report(options: UsageOptions) -> Result<Vec<UsageRow>> {
+    let env_vars = r2configs::parse_r2configs()?;
+    let client = v2::s3_upload::prepare_s3_client(&env_vars)?;
+    let pricing = config::load_config()?.pricing;
+
+    let objects = Lister::new(&client, &env_vars.r2_bucket).concurrency(8).list("v2/").await?;
+    let cutoff = options.since.and_then(|since| SystemTime::now().checked_sub(since));
+
+    let mut rows: BTreeMap<String, (UsageRow, BTreeSet<String>)> = BTreeMap::new();
+    for object in objects {
+        let name = match split_key(&object.key) {
+            Some((name, _, _)) => name,
+            None => continue,
+        };
+
+        let group = match options.group_by {
+            GroupBy::Namespace => name.split('/').next().unwrap_or(name),
+            GroupBy::Repository => name,
+        };
+
+        let (row, repositories) = rows.entry(group.to_owned()).or_insert_with(|| {
+            let row = UsageRow {
+                group: group.to_owned(),
+                ..Default::default()
+            };
+            (row, BTreeSet::new())
+        });
+        repositories.insert(name.to_owned());
+        row.objects += 1;
+        row.bytes += object.size;
+
+        let modified = object.last_modified.as_deref().and_then(|modified| humantime::parse_rfc3339_weak(modified).ok());
+        if cutoff.is_none() || modified.zip(cutoff).is_some_and(|(modified, cutoff)| modified >= cutoff) {
+            row.uploaded_objects += 1;
+            row.uploaded_bytes += object.size;
+        }
+    }
+
+    Ok(rows
+        .into_values()
+        .map(|(mut row, repositories)| {
+            row.repositories = repositories.len() as u64;
+            row.monthly_storage_cost = pricing.storage_cost(row.bytes);
+            row
+        })
+        .collect())
+}
+
+pub fn usage_csv(rows: &[UsageRow]) -> String {
+    let mut csv = String::from("group,repositories,objects,bytes,uploaded_objects,uploaded_bytes,monthly_storage_cost\n");
+    for row in rows {
+        let group = if row.group.contains([',', '"']) { format!("\"{}\"", row.group.replace('"', "\"\"")) } else { row.group.clone() };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{:.4}\n",
+            group, row.repositories, row.objects, row.bytes, row.uploaded_objects, row.uploaded_bytes, row.monthly_storage_cost
+        ));
+    }
+
+    csv
+}
+
+pub fn usage_json(rows: &[UsageRow]) -> serde_json::Value {
+    rows.iter()
+        .map(|row| {
+            json!({
+                "group": row.group,
+                "repositories": row.repositories,
+                "objects": row.objects,
+                "bytes": row.bytes,
+                "uploaded_objects": row.uploaded_objects,
+                "uploaded_bytes": row.uploaded_bytes,
+                "monthly_storage_cost": row.monthly_storage_cost,
+            })
+        })
+        .collect()
+}
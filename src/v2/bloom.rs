@@ -0,0 +1,182 @@
+use anyhow::{bail, Context, Result};
+use rusoto_s3::S3Client;
+
+use super::cas;
+use super::chunks::CHUNKS_PREFIX;
+use super::lister::Lister;
+use crate::r2configs::R2Configs;
+
+pub(crate) const BLOOM_KEY: &str = "v2/_blobs.bloom";
+
+const MAGIC: &[u8; 4] = b"BLM1";
+const HASHES: u32 = 7;
+// ~1% false positives at capacity with 7 hash functions.
+const BITS_PER_ENTRY: u64 = 10;
+const MIN_CAPACITY: u64 = 4096;
+
+// Probabilistic index of the blob keys present in the bucket. A negative answer is definite, so
+// only keys the filter claims to contain need a HEAD request before deciding to skip the upload.
+#[derive(Clone)]
+pub(crate) struct BloomFilter {
+    hashes: u32,
+    capacity: u64,
+    count: u64,
+    words: Vec<u64>,
+}
+
+impl BloomFilter {
+    pub(crate) fn with_capacity(capacity: u64) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let words = (capacity * BITS_PER_ENTRY).div_ceil(64) as usize;
+
+        BloomFilter {
+            hashes: HASHES,
+            capacity,
+            count: 0,
+            words: vec![0; words],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        let mut added = false;
+        for position in positions(key, self.hashes, self.words.len()) {
+            let (word, bit) = (position / 64, position % 64);
+            if self.words[word] & (1 << bit) == 0 {
+                self.words[word] |= 1 << bit;
+                added = true;
+            }
+        }
+
+        if added {
+            self.count += 1;
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        positions(key, self.hashes, self.words.len()).all(|position| self.words[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    pub(crate) fn is_saturated(&self) -> bool {
+        self.count > self.capacity
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(24 + self.words.len() * 8);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.hashes.to_le_bytes());
+        data.extend_from_slice(&self.capacity.to_le_bytes());
+        data.extend_from_slice(&self.count.to_le_bytes());
+        for word in &self.words {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+
+        data
+    }
+
+    pub(crate) fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 24 || &data[..4] != MAGIC || !(data.len() - 24).is_multiple_of(8) {
+            bail!("Malformed blob index");
+        }
+
+        let hashes = u32::from_le_bytes(data[4..8].try_into()?);
+        let capacity = u64::from_le_bytes(data[8..16].try_into()?);
+        let count = u64::from_le_bytes(data[16..24].try_into()?);
+        let words: Vec<u64> = data[24..].chunks_exact(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect();
+        if hashes == 0 || words.is_empty() {
+            bail!("Malformed blob index");
+        }
+
+        Ok(BloomFilter { hashes, capacity, count, words })
+    }
+}
+
+fn positions(key: &str, hashes: u32, words: usize) -> impl Iterator<Item = usize> {
+    let hash = blake3::hash(key.as_bytes());
+    let bytes = hash.as_bytes();
+    let h1 = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let h2 = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) | 1;
+    let bits = words as u64 * 64;
+
+    (0..u64::from(hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+}
+
+// Downloads the index once per push. A missing, unreadable or overfull index is rebuilt from a
+// bucket listing and written back by `record`.
+pub(crate) async fn load(client: &S3Client, env_vars: &R2Configs) -> Result<BloomFilter> {
+    if let Some(data) = cas::read_object(env_vars, BLOOM_KEY).await? {
+        match BloomFilter::from_bytes(&data) {
+            Ok(filter) if !filter.is_saturated() => return Ok(filter),
+            Ok(_) => log::info!("Blob index is over capacity, rebuilding"),
+            Err(e) => log::warn!("{:#}, rebuilding", e),
+        }
+    }
+
+    let keys: Vec<String> = Lister::new(client, &env_vars.r2_bucket)
+        .list_keys("v2/")
+        .await?
+        .into_iter()
+        .filter(|key| key.contains("/blobs/") || key.contains("/recipes/") || key.contains("/deltas/") || key.starts_with(CHUNKS_PREFIX))
+        .collect();
+
+    let mut filter = BloomFilter::with_capacity(keys.len() as u64 * 2);
+    for key in &keys {
+        filter.insert(key);
+    }
+    log::info!("Built blob index with {} entries", keys.len());
+
+    Ok(filter)
+}
+
+pub(crate) async fn record(env_vars: &R2Configs, filter: &BloomFilter, keys: &[String]) -> Result<()> {
+    cas::update_object(env_vars, BLOOM_KEY, "application/octet-stream", |current| {
+        let (mut updated, stale) = match current.map(BloomFilter::from_bytes) {
+            Some(Ok(current)) if !current.is_saturated() => (current, false),
+            _ => (filter.clone(), true),
+        };
+
+        if !stale && keys.iter().all(|key| updated.contains(key)) {
+            return Ok(None);
+        }
+
+        for key in keys {
+            updated.insert(key);
+        }
+
+        Ok(Some(updated.to_bytes()))
+    })
+    .await
+    .context("Failed to update the blob index")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_keys_survive_serialization() {
+        let mut filter = BloomFilter::with_capacity(10);
+        for i in 0..100 {
+            filter.insert(&format!("v2/blobs/sha256:{:064x}", i));
+        }
+
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert!((0..100).all(|i| filter.contains(&format!("v2/blobs/sha256:{:064x}", i))));
+        assert!(!filter.contains("v2/blobs/sha256:missing"));
+        assert!(!filter.is_saturated());
+    }
+
+    #[test]
+    fn overfull_and_malformed_filters_are_detected() {
+        let mut filter = BloomFilter::with_capacity(0);
+        for i in 0..2 * MIN_CAPACITY {
+            filter.insert(&i.to_string());
+        }
+        assert!(filter.is_saturated());
+
+        assert!(BloomFilter::from_bytes(b"BLM1").is_err());
+        assert!(BloomFilter::from_bytes(&[0; 32]).is_err());
+        let mut data = BloomFilter::with_capacity(0).to_bytes();
+        data.push(0);
+        assert!(BloomFilter::from_bytes(&data).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context, Result};
+use rusoto_core::signature::SignedRequest;
+
+use crate::budget::{self, OpClass};
+use crate::r2configs::R2Configs;
+
+const MAX_ATTEMPTS: u32 = 8;
+const BASE_BACKOFF: Duration = Duration::from_millis(100);
+
+// Read-modify-write of a single object guarded by ETag preconditions: the write only succeeds when
+// the object is unchanged since it was read (`If-Match`) or still absent (`If-None-Match: *`),
+// otherwise the update is re-run against the fresh copy. `update` returns `None` to leave the
+// object untouched.
+pub(crate) async fn update_object<F>(env_vars: &R2Configs, key: &str, content_type: &str, mut update: F) -> Result<()>
+where
+    F: FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>>,
+{
+    let client = super::s3_upload::prepare_core_client(env_vars)?;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let (current, etag) = get(&client, env_vars, key).await?;
+
+        let data = match update(current.as_deref())? {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+
+        let mut request = SignedRequest::new("PUT", "s3", &super::s3_upload::prepare_region(env_vars), &object_path(env_vars, key));
+        request.add_header("Content-Type", content_type);
+        match &etag {
+            Some(etag) => request.add_header("If-Match", etag),
+            None => request.add_header("If-None-Match", "*"),
+        }
+        request.set_payload(Some(data));
+
+        budget::charge(OpClass::A)?;
+        let response = client
+            .sign_and_dispatch(request)
+            .await
+            .map_err(|e| anyhow!("{:?}", e))
+            .context(format!("Failed to upload {}", key))?;
+
+        match response.status.as_u16() {
+            200..=299 => return Ok(()),
+            409 | 412 => {
+                let backoff = BASE_BACKOFF * 2u32.pow(attempt.min(6)) + jitter();
+                match crate::trace::current() {
+                    Some(request_id) => log::debug!("{} changed concurrently, retrying in {:?} (attempt {}, request id {})", key, backoff, attempt, request_id),
+                    None => log::debug!("{} changed concurrently, retrying in {:?} (attempt {})", key, backoff, attempt),
+                }
+                tokio::time::sleep(backoff).await;
+            }
+            status => bail!("Failed to upload {}: HTTP {}", key, status),
+        }
+    }
+
+    bail!("Gave up updating {} after {} conflicting writes", key, MAX_ATTEMPTS);
+}
+
+pub(crate) async fn read_object(env_vars: &R2Configs, key: &str) -> Result<Option<Vec<u8>>> {
+    let client = super::s3_upload::prepare_core_client(env_vars)?;
+    let (data, _) = get(&client, env_vars, key).await?;
+
+    Ok(data)
+}
+
+async fn get(client: &rusoto_core::Client, env_vars: &R2Configs, key: &str) -> Result<(Option<Vec<u8>>, Option<String>)> {
+    let request = SignedRequest::new("GET", "s3", &super::s3_upload::prepare_region(env_vars), &object_path(env_vars, key));
+
+    budget::charge(OpClass::B)?;
+    let mut response = client
+        .sign_and_dispatch(request)
+        .await
+        .map_err(|e| anyhow!("{:?}", e))
+        .context(format!("Failed to download {}", key))?;
+
+    match response.status.as_u16() {
+        404 => Ok((None, None)),
+        200 => {
+            let etag = response.headers.get("etag").cloned();
+            let response = response.buffer().await.map_err(|e| anyhow!("{}", e))?;
+            Ok((Some(response.body.to_vec()), etag))
+        }
+        status => bail!("Failed to download {}: HTTP {}", key, status),
+    }
+}
+
+fn object_path(env_vars: &R2Configs, key: &str) -> String {
+    format!("/{}/{}", env_vars.r2_bucket, key)
+}
+
+fn jitter() -> Duration {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or_default();
+    Duration::from_millis(u64::from(nanos % 100))
+}
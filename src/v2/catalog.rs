@@ -0,0 +1,64 @@
+use std::collections::BTreeSet;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use super::cas;
+use crate::r2configs::R2Configs;
+
+pub(crate) const CATALOG_KEY: &str = "v2/_catalog";
+
+// `v2/_catalog` and `v2/<name>/tags/list` mirror the registry API responses so that the bucket can
+// answer catalog and tag list requests when served statically.
+pub(crate) async fn update_catalog(env_vars: &R2Configs, image: &str) -> Result<()> {
+    cas::update_object(env_vars, CATALOG_KEY, "application/json", |current| {
+        let mut repositories = read_list(current, "repositories")?;
+        if !repositories.insert(image.to_owned()) {
+            return Ok(None);
+        }
+
+        Ok(Some(json!({ "repositories": repositories }).to_string().into_bytes()))
+    })
+    .await
+}
+
+pub(crate) async fn update_tags(env_vars: &R2Configs, image: &str, tag: &str) -> Result<()> {
+    cas::update_object(env_vars, &tags_key(image), "application/json", |current| {
+        let mut tags = read_list(current, "tags")?;
+        if !tags.insert(tag.to_owned()) {
+            return Ok(None);
+        }
+
+        Ok(Some(json!({ "name": image, "tags": tags }).to_string().into_bytes()))
+    })
+    .await
+}
+
+pub(crate) async fn remove_tag(env_vars: &R2Configs, image: &str, tag: &str) -> Result<()> {
+    cas::update_object(env_vars, &tags_key(image), "application/json", |current| {
+        let mut tags = read_list(current, "tags")?;
+        if !tags.remove(tag) {
+            return Ok(None);
+        }
+
+        Ok(Some(json!({ "name": image, "tags": tags }).to_string().into_bytes()))
+    })
+    .await
+}
+
+pub(crate) fn tags_key(image: &str) -> String {
+    format!("v2/{}/tags/list", image)
+}
+
+pub(crate) fn read_list(current: Option<&[u8]>, field: &str) -> Result<BTreeSet<String>> {
+    let current: Value = match current {
+        Some(data) => serde_json::from_slice(data).context(format!("Malformed {} index", field))?,
+        None => return Ok(BTreeSet::new()),
+    };
+
+    Ok(current[field]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str().map(|value| value.to_owned()))
+        .collect())
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use rusoto_s3::S3Client;
+
+use super::cas;
+use super::lister::Lister;
+use crate::hash_utils;
+use crate::names;
+use crate::r2configs::R2Configs;
+
+pub(crate) const CHECKSUMS_FILE: &str = "checksums.txt";
+
+// Refreshes `v2/<image>/checksums.txt`, a `sha256sum -c` compatible listing of every object of the
+// repository relative to `v2/<image>/`. Entries for the objects just uploaded are (re)computed
+// from the local files, entries of objects that are gone from the bucket are dropped.
+pub(crate) async fn update_checksums(image: &str, dirs: &[(&str, &Path)], client: &S3Client, env_vars: &R2Configs) -> Result<()> {
+    let prefix = format!("v2/{}/", image);
+    let key = format!("{}{}", prefix, CHECKSUMS_FILE);
+
+    let mut uploaded = BTreeMap::new();
+    for (kind, dir) in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let digest = hash_utils::compute_sha256(&path)?;
+            uploaded.insert(format!("{}/{}", kind, name), digest.trim_start_matches("sha256:").to_owned());
+        }
+    }
+
+    let mut existing = Lister::new(client, &env_vars.r2_bucket).list_keys(&prefix).await?;
+    existing.retain(|key| names::owns_key(image, key));
+
+    let mut count = 0;
+    cas::update_object(env_vars, &key, "text/plain; charset=utf-8", |current| {
+        let mut checksums = match current {
+            Some(data) => parse(&String::from_utf8_lossy(data)),
+            None => BTreeMap::new(),
+        };
+
+        checksums.extend(uploaded.clone());
+        // Chunked blobs only exist as their recipe, delta blobs as their note.
+        checksums.retain(|path, _| {
+            existing.contains(&format!("{}{}", prefix, path))
+                || existing.contains(&format!("{}{}", prefix, path.replacen("blobs/", "recipes/", 1)))
+                || existing.contains(&format!("{}{}.json", prefix, path.replacen("blobs/", "deltas/", 1)))
+        });
+        count = checksums.len();
+
+        Ok(Some(render(&checksums).into_bytes()))
+    })
+    .await
+    .context(format!("Failed to update {}", key))?;
+
+    log::info!("Updated {} ({} objects)", key, count);
+
+    Ok(())
+}
+
+fn parse(data: &str) -> BTreeMap<String, String> {
+    data.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(digest, path)| (path.to_owned(), digest.to_owned()))
+        .collect()
+}
+
+fn render(checksums: &BTreeMap<String, String>) -> String {
+    checksums.iter().map(|(path, digest)| format!("{}  {}\n", digest, path)).collect()
+}
+
+// The key of the object of `image` whose SHA-256 is `hex`, according to its checksums.
+pub(crate) async fn locate(env_vars: &R2Configs, image: &str, hex: &str) -> Result<Option<String>> {
+    let key = format!("v2/{}/{}", image, CHECKSUMS_FILE);
+    let checksums = match cas::read_object(env_vars, &key).await? {
+        Some(data) => parse(&String::from_utf8_lossy(&data)),
+        None => return Ok(None),
+    };
+
+    Ok(checksums.into_iter().find(|(_, digest)| digest == hex).map(|(path, _)| format!("v2/{}/{}", image, path)))
+}
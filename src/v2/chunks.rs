@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use fastcdc::v2020::StreamCDC;
+use futures::stream::{self, Stream, StreamExt};
+use rusoto_s3::{GetObjectRequest, HeadObjectRequest, PutObjectRequest, S3Client, S3};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use super::bloom::BloomFilter;
+use crate::control::UploadControl;
+
+pub(crate) const CHUNKS_PREFIX: &str = "v2/_chunks/";
+pub(crate) const MIN_CHUNKED_SIZE: u64 = 1024 * 1024;
+
+const MIN_CHUNK: u32 = 16 * 1024;
+const AVG_CHUNK: u32 = 64 * 1024;
+const MAX_CHUNK: u32 = 256 * 1024;
+
+// A chunked blob is stored as its content-defined chunks under `v2/_chunks/<blake3>`, shared by
+// every repository, plus a recipe at `v2/<image>/recipes/<blob>` listing the chunks in order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Recipe {
+    pub(crate) size: u64,
+    pub(crate) chunks: Vec<RecipeChunk>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RecipeChunk {
+    pub(crate) hash: String,
+    pub(crate) size: u64,
+}
+
+pub(crate) struct ChunkedUpload {
+    pub(crate) chunk_keys: Vec<String>,
+    pub(crate) new_chunks: usize,
+    pub(crate) new_bytes: u64,
+}
+
+pub(crate) fn recipe_key(image: &str, blob_name: &str) -> String {
+    format!("v2/{}/recipes/{}", image, blob_name)
+}
+
+pub(crate) async fn upload_chunked(image: &str, blob: &Path, client: &S3Client, r2_bucket: &str, index: &BloomFilter, control: Option<&UploadControl>) -> Result<ChunkedUpload> {
+    let blob_name = blob.file_name().unwrap().to_string_lossy().into_owned();
+    let file = File::open(blob).context(format!("Failed to open {}", blob.display()))?;
+
+    let mut recipe = Recipe { size: 0, chunks: Vec::new() };
+    let mut upload = ChunkedUpload { chunk_keys: Vec::new(), new_chunks: 0, new_bytes: 0 };
+    for chunk in StreamCDC::new(file, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+        let chunk = chunk.map_err(io::Error::from).context(format!("Failed to chunk {}", blob.display()))?;
+        let hash = blake3::hash(&chunk.data).to_hex().to_string();
+        let key = format!("{}{}", CHUNKS_PREFIX, hash);
+
+        recipe.size += chunk.length as u64;
+        recipe.chunks.push(RecipeChunk { hash, size: chunk.length as u64 });
+
+        if upload.chunk_keys.contains(&key) || (index.contains(&key) && exists(client, r2_bucket, &key).await?) {
+            upload.chunk_keys.push(key);
+            continue;
+        }
+
+        // Chunks are small enough to be admitted whole.
+        if let Some(control) = control {
+            control.admit(chunk.length as u64).await?;
+        }
+        let req = PutObjectRequest {
+            bucket: r2_bucket.to_owned(),
+            key: key.clone(),
+            body: Some(chunk.data.into()),
+            content_type: Some("application/octet-stream".to_owned()),
+            ..Default::default()
+        };
+        client.put_object(req).await.context(format!("Failed to upload chunk {}", key))?;
+
+        upload.new_chunks += 1;
+        upload.new_bytes += chunk.length as u64;
+        upload.chunk_keys.push(key);
+    }
+
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: recipe_key(image, &blob_name),
+        body: Some(serde_json::to_vec(&recipe)?.into()),
+        content_type: Some("application/json".to_owned()),
+        ..Default::default()
+    };
+    client.put_object(req).await.context(format!("Failed to upload the recipe of {}", blob_name))?;
+
+    Ok(upload)
+}
+
+pub(crate) async fn read_recipe(client: &S3Client, r2_bucket: &str, image: &str, blob_name: &str) -> Result<Option<Recipe>> {
+    let key = recipe_key(image, blob_name);
+    let req = GetObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.clone(),
+        ..Default::default()
+    };
+
+    let output = match client.get_object(req).await {
+        Ok(output) => output,
+        Err(e) if super::is_not_found(&e) => return Ok(None),
+        Err(rusoto_core::RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to download {}", key)),
+    };
+
+    let mut data = Vec::new();
+    if let Some(body) = output.body {
+        body.into_async_read().read_to_end(&mut data).await?;
+    }
+
+    Ok(Some(serde_json::from_slice(&data).context(format!("Malformed recipe {}", key))?))
+}
+
+// Reassembles a chunked blob by fetching its chunks in order.
+// Streams the bytes of `range` of a chunked blob. Only the chunks overlapping the range are fetched.
+pub(crate) fn reassemble(client: S3Client, r2_bucket: String, recipe: Recipe, range: Range<u64>) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+    let mut offset = 0;
+    let mut pieces = Vec::new();
+    for chunk in recipe.chunks {
+        let (chunk_start, chunk_end) = (offset, offset + chunk.size);
+        offset = chunk_end;
+        if chunk_end > range.start && chunk_start < range.end {
+            let trim = (range.start.saturating_sub(chunk_start) as usize, (range.end.min(chunk_end) - chunk_start) as usize);
+            pieces.push((chunk, trim));
+        }
+    }
+
+    stream::iter(pieces).then(move |(chunk, (from, to))| {
+        let client = client.clone();
+        let req = GetObjectRequest {
+            bucket: r2_bucket.clone(),
+            key: format!("{}{}", CHUNKS_PREFIX, chunk.hash),
+            ..Default::default()
+        };
+
+        async move {
+            let output = client.get_object(req).await.map_err(io::Error::other)?;
+            let mut data = Vec::with_capacity(chunk.size as usize);
+            if let Some(body) = output.body {
+                body.into_async_read().read_to_end(&mut data).await?;
+            }
+
+            let to = to.min(data.len());
+            Ok(Bytes::from(data).slice(from.min(to)..to))
+        }
+    })
+}
+
+pub(crate) async fn exists(client: &S3Client, r2_bucket: &str, key: &str) -> Result<bool> {
+    let req = HeadObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+
+    match client.head_object(req).await {
+        Ok(_) => Ok(true),
+        Err(e) if super::is_not_found(&e) => Ok(false),
+        Err(e) => Err(e).context(format!("Failed to check {}", key)),
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, StreamingBody, S3};
+use rusoto_core::RusotoError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::memory;
+use crate::control::UploadControl;
+
+
+const LEVEL: i32 = 19;
+const MAX_CHAIN: usize = 8;
+
+// Layers of consecutive tags are often near-identical. In delta mode a layer that replaces the
+// layer at the same position of the previous push is stored as a zstd patch against it
+// (`--patch-from`), under `v2/<image>/deltas/<blob>`, with a note naming the base.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct DeltaPlan {
+    pub(crate) tag: String,
+    pub(crate) layers: Vec<String>,
+    #[serde(skip)]
+    pub(crate) previous: Option<Box<DeltaPlan>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DeltaNote {
+    base: String,
+    size: u64,
+    chain: usize,
+}
+
+pub(crate) fn note_key(image: &str, blob_name: &str) -> String {
+    format!("v2/{}/deltas/{}.json", image, blob_name)
+}
+
+pub(crate) fn patch_key(image: &str, blob_name: &str) -> String {
+    format!("v2/{}/deltas/{}", image, blob_name)
+}
+
+pub(crate) fn plan_key(image: &str) -> String {
+    format!("v2/{}/deltas/base.json", image)
+}
+
+// Names the staged layers in manifest order, following every platform of an index.
+pub(crate) fn layer_names(tmp_dir: &Path) -> Result<Vec<String>> {
+    let top: Value = serde_json::from_slice(&fs::read(tmp_dir.join("manifest.json"))?)?;
+    let manifests = match top["manifests"].as_array() {
+        Some(children) => children
+            .iter()
+            .filter_map(|child| child["digest"].as_str()?.split_once(':').map(|(_, hex)| hex.to_owned()))
+            .map(|hex| Ok(serde_json::from_slice(&fs::read(tmp_dir.join(format!("{}.manifest.json", hex)))?)?))
+            .collect::<Result<Vec<Value>>>()?,
+        None => vec![top],
+    };
+
+    let mut names = Vec::new();
+    for manifest in &manifests {
+        for layer in manifest["layers"].as_array().into_iter().flatten() {
+            if let Some(digest) = layer["digest"].as_str() {
+                names.push(digest.to_owned());
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+pub(crate) async fn load_plan(client: &S3Client, r2_bucket: &str, image: &str, tag: &str, layers: Vec<String>) -> Result<DeltaPlan> {
+    let previous = match get(client, r2_bucket, &plan_key(image)).await? {
+        Some(data) => serde_json::from_slice(&data).ok().map(Box::new),
+        None => None,
+    };
+
+    Ok(DeltaPlan { tag: tag.to_owned(), layers, previous })
+}
+
+pub(crate) async fn save_plan(client: &S3Client, r2_bucket: &str, image: &str, plan: &DeltaPlan) -> Result<()> {
+    put(client, r2_bucket, &plan_key(image), serde_json::to_vec(plan)?.into(), "application/json").await
+}
+
+impl DeltaPlan {
+    pub(crate) fn base_for(&self, blob_name: &str) -> Option<&str> {
+        let position = self.layers.iter().position(|layer| layer == blob_name)?;
+        let base = self.previous.as_ref()?.layers.get(position)?;
+
+        (base != blob_name).then_some(base.as_str())
+    }
+}
+
+// Stores `blob` as a patch against `base` when that saves at least half of the size. Returns the
+// patch size, or `None` when the caller should upload the full blob.
+pub(crate) async fn upload_delta(client: &S3Client, r2_bucket: &str, image: &str, blob: &Path, base_name: &str, control: Option<&UploadControl>) -> Result<Option<u64>> {
+    let blob_name = blob.file_name().unwrap().to_string_lossy().into_owned();
+
+    let base_chain = match get(client, r2_bucket, &note_key(image, base_name)).await? {
+        Some(data) => serde_json::from_slice::<DeltaNote>(&data)?.chain,
+        None => 0,
+    };
+    if base_chain >= MAX_CHAIN {
+        return Ok(None);
+    }
+
+    let base = match read_blob(client, r2_bucket, image, base_name).await? {
+        Some(base) => base,
+        None => return Ok(None),
+    };
+    let target = blob.to_owned();
+    let (patch, target_size) = tokio::task::spawn_blocking(move || encode(base.path(), &target)).await??;
+    let patch_size = patch.as_file().metadata()?.len();
+
+    if patch_size * 2 > target_size {
+        return Ok(None);
+    }
+
+    let note = DeltaNote {
+        base: base_name.to_owned(),
+        size: target_size,
+        chain: base_chain + 1,
+    };
+    let patch = match control {
+        Some(control) => memory::throttled_file(patch.path(), patch_size, control).await?,
+        None => memory::read_file(patch.path(), patch_size)?,
+    };
+    put(client, r2_bucket, &patch_key(image, &blob_name), patch, "application/zstd").await?;
+    put(client, r2_bucket, &note_key(image, &blob_name), serde_json::to_vec(&note)?.into(), "application/json").await?;
+
+    Ok(Some(patch_size))
+}
+
+// Encodes `target` as a patch against `base` into a temporary file. Returns it with the size of
+// `target`.
+fn encode(base: &Path, target: &Path) -> Result<(NamedTempFile, u64)> {
+    let base = memory::map_file(base)?;
+    let target = memory::map_file(target)?;
+
+    let patch = NamedTempFile::new()?;
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(BufWriter::new(patch.reopen()?), LEVEL, &base)?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log(base.len().max(target.len())))?;
+    encoder.write_all(&target)?;
+    encoder.finish()?.flush()?;
+
+    Ok((patch, target.len() as u64))
+}
+
+// Reads a blob into a temporary file, reconstituting it from its delta chain when it was stored as
+// a patch. The patches are applied one at a time from the root of the chain on, each to the file
+// the previous one produced, so only the base being patched is mapped at any time.
+pub(crate) async fn read_blob(client: &S3Client, r2_bucket: &str, image: &str, blob_name: &str) -> Result<Option<NamedTempFile>> {
+    let mut chain = Vec::new();
+    let mut name = blob_name.to_owned();
+    let mut file = loop {
+        if let Some(file) = download(client, r2_bucket, &format!("v2/{}/blobs/{}", image, name)).await? {
+            break file;
+        }
+
+        let note: DeltaNote = match get(client, r2_bucket, &note_key(image, &name)).await? {
+            Some(data) => serde_json::from_slice(&data).context(format!("Malformed delta note for {}", name))?,
+            None if chain.is_empty() => return Ok(None),
+            None => bail!("Delta base {} of {} is missing", name, blob_name),
+        };
+        if chain.len() > MAX_CHAIN {
+            bail!("The delta chain of {} is longer than {} patches", blob_name, MAX_CHAIN);
+        }
+        let base = note.base.clone();
+        chain.push((name, note));
+        name = base;
+    };
+
+    for (name, note) in chain.into_iter().rev() {
+        let patch = download(client, r2_bucket, &patch_key(image, &name)).await?.context(format!("Delta of {} is missing", name))?;
+        file = tokio::task::spawn_blocking(move || decode(file.path(), patch.path(), note.size)).await??;
+    }
+
+    Ok(Some(file))
+}
+
+fn decode(base: &Path, patch: &Path, size: u64) -> Result<NamedTempFile> {
+    let base = memory::map_file(base)?;
+    let patch = BufReader::new(fs::File::open(patch)?);
+
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(patch, &base)?;
+    decoder.window_log_max(window_log(base.len().max(size as usize)))?;
+    let data = NamedTempFile::new()?;
+    let mut writer = BufWriter::new(data.reopen()?);
+    io::copy(&mut decoder, &mut writer)?;
+    writer.flush()?;
+
+    Ok(data)
+}
+
+// Streams the object at `key` into a temporary file.
+async fn download(client: &S3Client, r2_bucket: &str, key: &str) -> Result<Option<NamedTempFile>> {
+    let req = GetObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+
+    let output = match client.get_object(req).await {
+        Ok(output) => output,
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(e) if super::is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to download {}", key)),
+    };
+
+    let file = NamedTempFile::new()?;
+    if let Some(body) = output.body {
+        let mut writer = tokio::fs::File::from_std(file.reopen()?);
+        tokio::io::copy(&mut body.into_async_read(), &mut writer).await.context(format!("Failed to download {}", key))?;
+        writer.flush().await?;
+    }
+
+    Ok(Some(file))
+}
+
+fn window_log(size: usize) -> u32 {
+    (usize::BITS - size.max(1024).leading_zeros()).min(31)
+}
+
+async fn get(client: &S3Client, r2_bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    let req = GetObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+
+    let output = match client.get_object(req).await {
+        Ok(output) => output,
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(e) if super::is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to download {}", key)),
+    };
+
+    let mut data = Vec::new();
+    if let Some(body) = output.body {
+        body.into_async_read().read_to_end(&mut data).await?;
+    }
+
+    Ok(Some(data))
+}
+
+async fn put(client: &S3Client, r2_bucket: &str, key: &str, body: StreamingBody, content_type: &str) -> Result<()> {
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        body: Some(body),
+        content_type: Some(content_type.to_owned()),
+        ..Default::default()
+    };
+
+    client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use rusoto_s3::{CopyObjectRequest, PutObjectRequest, S3Client, S3};
+use serde_json::{json, Value};
+
+use super::{cas, chunks, memory};
+use crate::hash_utils;
+use crate::r2configs::R2Configs;
+
+const LAYOUT_PREFIX: &str = "oci/";
+const STREAM_CHUNK: u64 = 8 * 1024 * 1024;
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+// Mirrors the staged objects of a push into an OCI image layout at `oci/<image>/`: `oci-layout`,
+// `index.json` and `blobs/sha256/<hex>`. Objects already uploaded as plain blobs are copied
+// server-side, chunked and delta blobs are uploaded again from the staged files.
+pub(crate) async fn write_layout(image: &str, tag: &str, dirs: &[(&str, &Path)], top_manifest: &Path, client: &S3Client, env_vars: &R2Configs) -> Result<()> {
+    let prefix = format!("{}{}/", LAYOUT_PREFIX, image);
+    let r2_bucket = env_vars.r2_bucket.as_str();
+
+    let mut written = 0;
+    for (kind, dir) in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let digest = hash_utils::compute_sha256(&path)?;
+            let key = format!("{}blobs/sha256/{}", prefix, digest.trim_start_matches("sha256:"));
+            if chunks::exists(client, r2_bucket, &key).await? {
+                continue;
+            }
+
+            let source = format!("v2/{}/{}/{}", image, kind, name);
+            if chunks::exists(client, r2_bucket, &source).await? {
+                let req = CopyObjectRequest {
+                    bucket: r2_bucket.to_owned(),
+                    key: key.clone(),
+                    copy_source: format!("{}/{}", r2_bucket, source),
+                    ..Default::default()
+                };
+                client.copy_object(req).await.context(format!("Failed to copy {} to {}", source, key))?;
+            } else {
+                let size = fs::metadata(&path)?.len();
+                let req = PutObjectRequest {
+                    bucket: r2_bucket.to_owned(),
+                    key: key.clone(),
+                    body: Some(memory::stream_file(&path, size, STREAM_CHUNK).await?),
+                    content_type: Some("application/octet-stream".to_owned()),
+                    ..Default::default()
+                };
+                client.put_object(req).await.context(format!("Failed to upload {}", key))?;
+            }
+            written += 1;
+        }
+    }
+
+    cas::update_object(env_vars, &format!("{}oci-layout", prefix), "application/json", |current| match current {
+        Some(_) => Ok(None),
+        None => Ok(Some(serde_json::to_vec(&json!({ "imageLayoutVersion": "1.0.0" }))?)),
+    })
+    .await?;
+
+    let manifest = fs::read(top_manifest)?;
+    let manifest_json: Value = serde_json::from_slice(&manifest)?;
+    let descriptor = json!({
+        "mediaType": manifest_json["mediaType"].as_str().unwrap_or("application/vnd.oci.image.manifest.v1+json"),
+        "digest": hash_utils::compute_sha256(top_manifest)?,
+        "size": manifest.len(),
+        "annotations": { REF_NAME: tag },
+    });
+
+    let index_key = format!("{}index.json", prefix);
+    cas::update_object(env_vars, &index_key, "application/vnd.oci.image.index.v1+json", |current| {
+        let mut index: Value = match current {
+            Some(data) => serde_json::from_slice(data).context(format!("{} is not JSON", index_key))?,
+            None => json!({ "schemaVersion": 2, "mediaType": "application/vnd.oci.image.index.v1+json", "manifests": [] }),
+        };
+
+        let manifests = index["manifests"].as_array_mut().context(format!("{} has no manifests", index_key))?;
+        manifests.retain(|manifest| manifest["annotations"][REF_NAME] != tag);
+        manifests.push(descriptor.clone());
+
+        Ok(Some(serde_json::to_vec_pretty(&index)?))
+    })
+    .await?;
+
+    log::info!("Updated the OCI layout {} ({} new objects)", prefix, written);
+
+    Ok(())
+}
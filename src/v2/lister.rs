@@ -0,0 +1,120 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+use tokio::sync::Mutex;
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+use crate::budget::{self, OpClass};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListedObject {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<String>,
+    pub e_tag: Option<String>,
+}
+
+struct Page {
+    objects: Vec<ListedObject>,
+    prefixes: Vec<String>,
+}
+
+pub(crate) struct Lister<'a> {
+    client: &'a S3Client,
+    bucket: &'a str,
+    concurrency: usize,
+    limiter: Option<Mutex<Interval>>,
+}
+
+impl<'a> Lister<'a> {
+    pub(crate) fn new(client: &'a S3Client, bucket: &'a str) -> Self {
+        Lister {
+            client,
+            bucket,
+            concurrency: 1,
+            limiter: None,
+        }
+    }
+
+    pub(crate) fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub(crate) fn requests_per_second(mut self, requests_per_second: Option<u32>) -> Self {
+        self.limiter = requests_per_second.filter(|rps| *rps > 0).map(|rps| {
+            let mut interval = time::interval(Duration::from_secs(1) / rps);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Mutex::new(interval)
+        });
+        self
+    }
+
+    pub(crate) async fn list(&self, prefix: &str) -> Result<Vec<ListedObject>> {
+        if self.concurrency == 1 {
+            return Ok(self.list_pages(prefix, None).await?.objects);
+        }
+
+        // Shard by the next path segment below `prefix` and list the shards concurrently.
+        let top = self.list_pages(prefix, Some("/")).await?;
+        let shards: Vec<Vec<ListedObject>> = stream::iter(top.prefixes)
+            .map(|shard| async move { self.list_pages(&shard, None).await.map(|page| page.objects) })
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        let mut objects = top.objects;
+        objects.extend(shards.into_iter().flatten());
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(objects)
+    }
+
+    pub(crate) async fn list_keys(&self, prefix: &str) -> Result<BTreeSet<String>> {
+        Ok(self.list(prefix).await?.into_iter().map(|object| object.key).collect())
+    }
+
+    async fn list_pages(&self, prefix: &str, delimiter: Option<&str>) -> Result<Page> {
+        let mut page = Page {
+            objects: Vec::new(),
+            prefixes: Vec::new(),
+        };
+        let mut continuation_token = None;
+
+        loop {
+            if let Some(limiter) = &self.limiter {
+                limiter.lock().await.tick().await;
+            }
+
+            let req = ListObjectsV2Request {
+                bucket: self.bucket.to_owned(),
+                prefix: Some(prefix.to_owned()),
+                delimiter: delimiter.map(|delimiter| delimiter.to_owned()),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+
+            budget::charge(OpClass::A)?;
+            let output = self.client.list_objects_v2(req).await.context(format!("Failed to list {}", prefix))?;
+
+            page.objects.extend(output.contents.unwrap_or_default().into_iter().filter_map(|object| {
+                Some(ListedObject {
+                    key: object.key?,
+                    size: object.size.unwrap_or_default().max(0) as u64,
+                    last_modified: object.last_modified,
+                    e_tag: object.e_tag,
+                })
+            }));
+            page.prefixes.extend(output.common_prefixes.unwrap_or_default().into_iter().filter_map(|p| p.prefix));
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(page)
+    }
+}
@@ -0,0 +1,139 @@
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use rusoto_s3::StreamingBody;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::control::{UploadControl, THROTTLE_CHUNK};
+
+// Permits are accounted in KiB so that budgets beyond the semaphore's permit limit still work.
+const UNIT: u64 = 1024;
+const STREAM_CHUNK: u64 = 8 * 1024 * 1024;
+#[cfg(unix)]
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+pub(crate) struct MemoryBudget {
+    limit: u64,
+    semaphore: Arc<Semaphore>,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: u64) -> Self {
+        let limit = limit.max(UNIT);
+
+        MemoryBudget {
+            limit,
+            semaphore: Arc::new(Semaphore::new(units(limit) as usize)),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    // Blocks until `bytes` (capped at the whole budget) can be buffered; the reservation is
+    // released when the permit is dropped.
+    pub(crate) async fn reserve(&self, bytes: u64) -> Result<OwnedSemaphorePermit> {
+        self.semaphore
+            .clone()
+            .acquire_many_owned(units(bytes.min(self.limit)))
+            .await
+            .context("Memory budget closed")
+    }
+
+    pub(crate) fn stream_chunk(&self) -> u64 {
+        STREAM_CHUNK.min(self.limit)
+    }
+}
+
+fn units(bytes: u64) -> u32 {
+    bytes.div_ceil(UNIT).clamp(1, u64::from(u32::MAX >> 3)) as u32
+}
+
+// Blobs larger than the budget are read from disk in chunks instead of being buffered whole.
+pub(crate) async fn stream_file(path: &Path, size: u64, chunk: u64) -> Result<StreamingBody> {
+    Ok(StreamingBody::new_with_size(file_chunks(path, chunk).await?, size as usize))
+}
+
+// `path` read in chunks and sent at the rate `control` admits them.
+pub(crate) async fn throttled_file(path: &Path, size: u64, control: &UploadControl) -> Result<StreamingBody> {
+    let chunks = file_chunks(path, THROTTLE_CHUNK as u64).await?;
+    Ok(StreamingBody::new_with_size(control.throttle(chunks), size as usize))
+}
+
+// `data` sent at the rate `control` admits it.
+pub(crate) fn throttled_bytes(data: Vec<u8>, control: &UploadControl) -> StreamingBody {
+    let size = data.len();
+    let data = Bytes::from(data);
+    let chunks = futures::stream::iter((0..size).step_by(THROTTLE_CHUNK).map(move |start| Ok(data.slice(start..(start + THROTTLE_CHUNK).min(size)))));
+    StreamingBody::new_with_size(control.throttle(chunks), size)
+}
+
+async fn file_chunks(path: &Path, chunk: u64) -> Result<impl Stream<Item = io::Result<Bytes>> + Send + 'static> {
+    let file = tokio::fs::File::open(path).await.context(format!("Failed to open {}", path.display()))?;
+
+    Ok(futures::stream::try_unfold(file, move |mut file| async move {
+        let mut buffer = BytesMut::with_capacity(chunk as usize);
+        let bytes = file.read_buf(&mut buffer).await?;
+
+        Ok::<_, io::Error>(if bytes == 0 { None } else { Some((buffer.freeze(), file)) })
+    }))
+}
+
+// Large blobs are handed to the HTTP client as a single memory-mapped chunk, so the data is read
+// from the page cache by the TLS writer instead of being copied into a heap buffer first.
+#[cfg(unix)]
+pub(crate) fn read_file(path: &Path, size: u64) -> Result<StreamingBody> {
+    if size < MMAP_THRESHOLD {
+        return Ok(std::fs::read(path)?.into());
+    }
+
+    let file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    // SAFETY: staged blobs live in our own temporary directory and are not modified while mapped.
+    let map = unsafe { memmap2::Mmap::map(&file) }.context(format!("Failed to map {}", path.display()))?;
+    let chunk = Bytes::from_owner(map);
+
+    Ok(StreamingBody::new_with_size(futures::stream::once(async move { Ok(chunk) }), size as usize))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read_file(path: &Path, _size: u64) -> Result<StreamingBody> {
+    Ok(std::fs::read(path)?.into())
+}
+
+// The whole file as one slice, for zstd's reference prefixes, from the page cache where possible.
+#[cfg(unix)]
+pub(crate) fn map_file(path: &Path) -> Result<impl Deref<Target = [u8]>> {
+    let file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    // SAFETY: only our own temporary files and staged blobs are mapped, which nothing modifies.
+    unsafe { memmap2::Mmap::map(&file) }.context(format!("Failed to map {}", path.display()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn map_file(path: &Path) -> Result<impl Deref<Target = [u8]>> {
+    std::fs::read(path).context(format!("Failed to read {}", path.display()))
+}
+
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+
+    let number: u64 = number.parse().context(format!("Invalid size {}", s))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => bail!("Invalid size {} (expected a number with an optional K, M, G or T suffix)", s),
+    };
+
+    number.checked_mul(multiplier).context(format!("Size {} is too large", s))
+}
@@ -1 +1,31 @@
+pub mod bloom;
+pub mod cas;
+pub mod catalog;
+pub mod checksums;
+pub mod chunks;
+pub mod delta;
+pub mod layout;
+pub mod lister;
+pub mod memory;
+pub mod multipart;
+pub mod referrers;
 pub mod s3_upload;
+pub mod verify;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use rusoto_core::RusotoError;
+
+// The files of a staging directory in name order, so that every run over the same image visits
+// them in the same order regardless of the filesystem.
+pub(crate) fn staged_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+
+    Ok(files)
+}
+
+pub(crate) fn is_not_found<E>(e: &RusotoError<E>) -> bool {
+    matches!(e, RusotoError::Unknown(response) if response.status.as_u16() == 404)
+}
@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use futures::{stream, StreamExt, TryStreamExt};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart, CreateMultipartUploadRequest, ListPartsRequest, S3Client, UploadPartRequest, S3,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::memory::{self, parse_size, MemoryBudget};
+use crate::control::UploadControl;
+
+// Every part but the last must be at least 5 MiB, and an upload has at most 10,000 parts.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+// R2 aborts uploads left unfinished for a week; older records are not worth resuming.
+const MAX_RECORD_AGE: Duration = Duration::from_secs(6 * 24 * 60 * 60);
+
+// Uploads a single large blob as ranges of the file sent in parallel within one multipart upload,
+// for fat pipes where one request stream cannot saturate the link. Blobs no larger than one part
+// are uploaded with a single request as usual.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParallelUpload {
+    pub part_size: u64,
+    pub parallelism: usize,
+}
+
+impl Default for ParallelUpload {
+    fn default() -> Self {
+        ParallelUpload {
+            part_size: 64 * 1024 * 1024,
+            parallelism: 8,
+        }
+    }
+}
+
+// `<part size>[,<parallelism>]`, e.g. "64M,8".
+impl FromStr for ParallelUpload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (part_size, parallelism) = match s.split_once(',') {
+            Some((part_size, parallelism)) => (part_size, Some(parallelism)),
+            None => (s, None),
+        };
+
+        let part_size = parse_size(part_size)?;
+        if part_size < MIN_PART_SIZE {
+            bail!("Part size {} is below the 5 MiB minimum", part_size);
+        }
+
+        let parallelism = match parallelism {
+            Some(parallelism) => match parallelism.trim().parse::<usize>() {
+                Ok(parallelism) if parallelism > 0 => parallelism,
+                _ => bail!("Invalid part parallelism {} (expected a positive number)", parallelism),
+            },
+            None => ParallelUpload::default().parallelism,
+        };
+
+        Ok(ParallelUpload { part_size, parallelism })
+    }
+}
+
+impl ParallelUpload {
+    pub(crate) fn applies_to(&self, size: u64) -> bool {
+        size > self.part_size.max(MIN_PART_SIZE)
+    }
+
+    // Parts grow past the configured size when the blob would need more than the maximum number.
+    fn part_size_for(&self, size: u64) -> u64 {
+        self.part_size.max(MIN_PART_SIZE).max(size.div_ceil(MAX_PARTS))
+    }
+}
+
+pub(crate) struct PartsTarget<'a> {
+    pub(crate) client: &'a S3Client,
+    pub(crate) r2_bucket: &'a str,
+    pub(crate) key: &'a str,
+    pub(crate) cache_control: Option<&'a str>,
+    // Where the parts uploaded so far are recorded, so an interrupted upload continues from them on
+    // the next run. Without it a failed upload is aborted.
+    pub(crate) resume_dir: Option<&'a Path>,
+}
+
+// The working directory's record of unfinished multipart uploads.
+pub(crate) const RESUME_DIR: &str = ".oci-r2-parts";
+
+// The parts of one unfinished multipart upload. Keys name their content, so a record for the same
+// key, size and part size describes the same bytes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct PartsRecord {
+    key: String,
+    size: u64,
+    part_size: u64,
+    upload_id: String,
+    // Part number to ETag.
+    parts: BTreeMap<i64, String>,
+    // When the upload was started, in seconds since the epoch. Missing in older records.
+    #[serde(default)]
+    created: u64,
+}
+
+impl PartsRecord {
+    fn path(dir: &Path, r2_bucket: &str, key: &str) -> PathBuf {
+        let hash = blake3::hash(format!("{}/{}", r2_bucket, key).as_bytes()).to_hex();
+        dir.join(format!("{}.json", &hash[..32]))
+    }
+
+    fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path).context(format!("Failed to write {}", path.display()))
+    }
+}
+
+pub(crate) async fn upload_parts(target: &PartsTarget<'_>, path: &Path, size: u64, settings: &ParallelUpload, budget: Option<&MemoryBudget>, control: Option<&UploadControl>) -> Result<()> {
+    let (client, r2_bucket, key) = (target.client, target.r2_bucket, target.key);
+    let part_size = settings.part_size_for(size);
+    let record_path = target.resume_dir.map(|dir| PartsRecord::path(dir, r2_bucket, key));
+
+    let resumed = match &record_path {
+        Some(record_path) => resume(target, record_path, size, part_size).await,
+        None => None,
+    };
+    let record = match resumed {
+        Some(record) => {
+            log::info!("Resuming the multipart upload of {} with {} parts already uploaded", key, record.parts.len());
+            record
+        }
+        None => {
+            let req = CreateMultipartUploadRequest {
+                bucket: r2_bucket.to_owned(),
+                key: key.to_owned(),
+                cache_control: target.cache_control.map(str::to_owned),
+                content_type: Some("application/octet-stream".to_owned()),
+                ..Default::default()
+            };
+            let created = client.create_multipart_upload(req).await.context(format!("Failed to start the multipart upload of {}", key))?;
+            let upload_id = created.upload_id.context(format!("No upload ID for {}", key))?;
+            let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            PartsRecord { key: key.to_owned(), size, part_size, upload_id, parts: BTreeMap::new(), created }
+        }
+    };
+    if let Some(record_path) = &record_path {
+        record.save(record_path)?;
+    }
+
+    let upload_id = record.upload_id.clone();
+    let record = Mutex::new(record);
+    let parts = size.div_ceil(part_size);
+    let pending: Vec<u64> = (0..parts).filter(|part| !record.lock().unwrap().parts.contains_key(&(*part as i64 + 1))).collect();
+    let uploaded = stream::iter(pending)
+        .map(|part| {
+            let (upload_id, record, record_path) = (&upload_id, &record, &record_path);
+            async move {
+                let offset = part * part_size;
+                let length = part_size.min(size - offset);
+                if let Some(control) = control {
+                    control.admit(0).await?;
+                }
+                let _reservation = match budget {
+                    Some(budget) => Some(budget.reserve(length).await?),
+                    None => None,
+                };
+                let body = match control {
+                    Some(control) => memory::throttled_bytes(read_range(path, offset, length).await?, control),
+                    None => read_range(path, offset, length).await?.into(),
+                };
+
+                let req = UploadPartRequest {
+                    bucket: r2_bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id: upload_id.clone(),
+                    part_number: part as i64 + 1,
+                    content_length: Some(length as i64),
+                    body: Some(body),
+                    ..Default::default()
+                };
+                let output = client.upload_part(req).await.context(format!("Failed to upload part {} of {}", part + 1, key))?;
+
+                let e_tag = output.e_tag.context(format!("No ETag for part {} of {}", part + 1, key))?;
+                let mut record = record.lock().unwrap();
+                record.parts.insert(part as i64 + 1, e_tag);
+                if let Some(record_path) = record_path {
+                    record.save(record_path)?;
+                }
+
+                Ok::<_, anyhow::Error>(())
+            }
+        })
+        .buffered(settings.parallelism.max(1))
+        .try_collect::<Vec<_>>()
+        .await;
+
+    if let Err(e) = uploaded {
+        match &record_path {
+            Some(_) => log::info!("Keeping the multipart upload of {} to resume it on the next run", key),
+            None => abort(target, &upload_id).await,
+        }
+        return Err(e);
+    }
+
+    let parts = record
+        .into_inner()
+        .unwrap()
+        .parts
+        .into_iter()
+        .map(|(part_number, e_tag)| CompletedPart { e_tag: Some(e_tag), part_number: Some(part_number) })
+        .collect();
+    let req = CompleteMultipartUploadRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        upload_id,
+        multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+    };
+    client.complete_multipart_upload(req).await.context(format!("Failed to complete the multipart upload of {}", key))?;
+
+    if let Some(record_path) = &record_path {
+        let _ = fs::remove_file(record_path);
+    }
+
+    Ok(())
+}
+
+// The recorded upload of the same blob, if it is still open. Only parts the upload still lists
+// with the recorded ETag are kept. A record that cannot be resumed is discarded along with its
+// upload, whose parts would be billed until R2 expires them.
+async fn resume(target: &PartsTarget<'_>, record_path: &Path, size: u64, part_size: u64) -> Option<PartsRecord> {
+    let record = PartsRecord::load(record_path)?;
+    let age = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().saturating_sub(Duration::from_secs(record.created));
+    let resumed = match record.key == target.key && record.size == size && record.part_size == part_size && age < MAX_RECORD_AGE {
+        true => list_parts(target, record.clone()).await,
+        false => None,
+    };
+    if resumed.is_none() {
+        log::debug!("Discarding the multipart upload record of {}", target.key);
+        abort(target, &record.upload_id).await;
+        let _ = fs::remove_file(record_path);
+    }
+
+    resumed
+}
+
+async fn list_parts(target: &PartsTarget<'_>, mut record: PartsRecord) -> Option<PartsRecord> {
+    let mut listed = BTreeMap::new();
+    let mut marker = None;
+    loop {
+        let req = ListPartsRequest {
+            bucket: target.r2_bucket.to_owned(),
+            key: target.key.to_owned(),
+            upload_id: record.upload_id.clone(),
+            part_number_marker: marker,
+            ..Default::default()
+        };
+        let output = match target.client.list_parts(req).await {
+            Ok(output) => output,
+            Err(e) => {
+                log::debug!("Not resuming the multipart upload of {}: {}", target.key, e);
+                return None;
+            }
+        };
+        listed.extend(output.parts.unwrap_or_default().into_iter().filter_map(|part| Some((part.part_number?, part.e_tag?))));
+
+        match output.next_part_number_marker {
+            Some(next) if output.is_truncated == Some(true) => marker = Some(next),
+            _ => break,
+        }
+    }
+
+    record.parts.retain(|part_number, e_tag| listed.get(part_number) == Some(e_tag));
+    Some(record)
+}
+
+// Abandoned parts are billed as storage until the upload is aborted.
+async fn abort(target: &PartsTarget<'_>, upload_id: &str) {
+    let req = AbortMultipartUploadRequest {
+        bucket: target.r2_bucket.to_owned(),
+        key: target.key.to_owned(),
+        upload_id: upload_id.to_owned(),
+    };
+    if let Err(e) = target.client.abort_multipart_upload(req).await {
+        log::warn!("Failed to abort the multipart upload of {}: {}", target.key, e);
+    }
+}
+
+async fn read_range(path: &Path, offset: u64, length: u64) -> Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await.context(format!("Failed to open {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut buffer = vec![0; length as usize];
+    file.read_exact(&mut buffer).await.context(format!("Failed to read {}", path.display()))?;
+
+    Ok(buffer)
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use rusoto_s3::{DeleteObjectRequest, S3Client, S3};
+use serde_json::{json, Value};
+
+use super::cas;
+use crate::budget::{self, OpClass};
+use crate::r2configs::R2Configs;
+
+pub(crate) const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+// `v2/<name>/referrers/<digest>` holds the OCI 1.1 referrers response for a subject manifest, so
+// that static serving can answer referrers queries without computing them.
+pub(crate) fn referrers_key(image: &str, subject: &str) -> String {
+    format!("v2/{}/referrers/{}", image, subject)
+}
+
+pub(crate) fn empty_index() -> Value {
+    json!({ "schemaVersion": 2, "mediaType": INDEX_MEDIA_TYPE, "manifests": [] })
+}
+
+pub(crate) async fn add(env_vars: &R2Configs, image: &str, subject: &str, descriptor: Value) -> Result<()> {
+    cas::update_object(env_vars, &referrers_key(image, subject), INDEX_MEDIA_TYPE, |current| {
+        let mut index = match current {
+            Some(data) => serde_json::from_slice(data).context("Malformed referrers index")?,
+            None => empty_index(),
+        };
+
+        let manifests = index["manifests"].as_array_mut().context("Malformed referrers index")?;
+        if manifests.iter().any(|manifest| manifest["digest"] == descriptor["digest"]) {
+            return Ok(None);
+        }
+        manifests.push(descriptor.clone());
+
+        Ok(Some(serde_json::to_vec(&index)?))
+    })
+    .await
+}
+
+pub(crate) async fn delete_index(client: &S3Client, r2_bucket: &str, image: &str, subject: &str) -> Result<()> {
+    let key = referrers_key(image, subject);
+    let req = DeleteObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.clone(),
+        ..Default::default()
+    };
+
+    budget::charge(OpClass::Delete)?;
+    client.delete_object(req).await.context(format!("Failed to delete {}", key))?;
+
+    Ok(())
+}
@@ -1,78 +1,608 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 
 use rusoto_core::Region;
-use rusoto_s3::{PutObjectRequest, S3Client, S3};
-use std::path::{Path};
-use anyhow::{Context, Result};
+use rusoto_s3::{CopyObjectRequest, HeadObjectRequest, PutObjectRequest, S3Client, S3};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
 use serde_json::Value;
+use tokio::sync::Semaphore;
 
+use super::bloom::BloomFilter;
+use super::chunks;
+use super::delta::{self, DeltaPlan};
+use super::lister::Lister;
+use super::memory::{self, MemoryBudget};
+use super::multipart::{self, ParallelUpload, PartsTarget};
+use crate::control::{UploadControl, THROTTLE_CHUNK};
+use crate::plan::Operation;
+use crate::progress::{Progress, ProgressEvent};
+use crate::stats::{BlobTiming, ReusedBlob};
 use crate::r2configs::R2Configs;
 
-pub(crate) async fn upload_blobs(image: &str, image_blobs_dir: &Path, client: &S3Client, r2_bucket: &str) -> Result<()> {
-    for entry in fs::read_dir(&image_blobs_dir)? {
-        let entry = entry?;
-        let blob = entry.path();
-        let blob_name = blob.file_name().unwrap().to_str().unwrap();
+const SMALL_BLOB_SIZE: u64 = 1024 * 1024;
+const SMALL_CONCURRENCY: usize = 16;
+const LARGE_CONCURRENCY: usize = 4;
+// In nice mode blobs are uploaded one at a time with a pause after each.
+const NICE_PAUSE: Duration = Duration::from_secs(1);
 
-        let key = format!("v2/{}/blobs/{}", image, blob_name);
-        let blob_data = fs::read(blob.clone())?;
+#[derive(Default)]
+pub(crate) struct UploadedBlobs {
+    pub(crate) keys: Vec<String>,
+    pub(crate) timings: Vec<BlobTiming>,
+    pub(crate) reused: Vec<ReusedBlob>,
+}
+
+pub(crate) fn plan_upload(dirs: &[&Path]) -> Result<ProgressEvent> {
+    let mut objects = 0;
+    let mut bytes = 0;
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            objects += 1;
+            bytes += entry?.metadata()?.len();
+        }
+    }
+
+    Ok(ProgressEvent::UploadPlanned { objects, bytes })
+}
+
+pub(crate) struct UploadSettings<'a> {
+    pub(crate) index: &'a BloomFilter,
+    pub(crate) budget: Option<&'a MemoryBudget>,
+    pub(crate) chunked: bool,
+    pub(crate) delta: Option<&'a DeltaPlan>,
+    pub(crate) cache_control: Option<&'a str>,
+    pub(crate) existing: Option<&'a BTreeSet<String>>,
+    // Other repositories of the bucket, whose copy of a blob is copied server-side instead of
+    // uploading it again.
+    pub(crate) repositories: &'a [String],
+    pub(crate) parallel: Option<&'a ParallelUpload>,
+    // Blobs uploaded at once across both pools; by default each pool's own limit.
+    pub(crate) concurrency: Option<usize>,
+    pub(crate) control: Option<&'a UploadControl>,
+    pub(crate) nice: bool,
+    // Looks for blobs stored under their BLAKE3 name by earlier versions, see `adopt_legacy`.
+    pub(crate) adopt_legacy: bool,
+    // Where multipart uploads record their parts, see `multipart::resume`.
+    pub(crate) resume_dir: &'a Path,
+    pub(crate) progress: &'a Progress,
+}
+
+pub(crate) async fn upload_blobs(image: &str, image_blobs_dir: &Path, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<UploadedBlobs> {
+    let mut small = Vec::new();
+    let mut large = Vec::new();
+    for blob in super::staged_files(image_blobs_dir)? {
+        let blob_size = fs::metadata(&blob)?.len();
+        match blob_size < SMALL_BLOB_SIZE {
+            true => small.push((blob, blob_size)),
+            false => large.push((blob, blob_size)),
+        }
+    }
+
+    // Configs and small layers get their own pool so they are not queued behind multi-GB layers.
+    // Both pools share the overall limit. Each upload is a task of its own, so hashing and
+    // compressing blobs spreads over the runtime's worker threads.
+    let limit = settings.concurrency.unwrap_or(SMALL_CONCURRENCY + LARGE_CONCURRENCY).max(1);
+    let permits = Arc::new(Semaphore::new(limit));
+    let shared = Arc::new(SharedSettings::from(settings));
+    let pool = |blobs: Vec<(PathBuf, u64)>, concurrency: usize| {
+        let pool_permits = Arc::new(Semaphore::new(concurrency.min(limit)));
+        blobs
+            .into_iter()
+            .map(|(blob, blob_size)| {
+                let (image, client, r2_bucket) = (image.to_owned(), client.clone(), r2_bucket.to_owned());
+                let (pool_permits, permits, shared) = (pool_permits.clone(), permits.clone(), shared.clone());
+                tokio::spawn(async move {
+                    let _pool_permit = pool_permits.acquire_owned().await?;
+                    let _permit = permits.acquire_owned().await?;
+                    upload_blob(&image, &blob, blob_size, &client, &r2_bucket, &shared.settings()).await.context(format!("Failed to upload blob {}", blob.file_name().unwrap_or_default().to_string_lossy()))
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    let total = small.len() + large.len();
+    let outcomes = match settings.nice {
+        true => {
+            let mut outcomes = Vec::new();
+            for (blob, blob_size) in small.into_iter().chain(large) {
+                outcomes.push(upload_blob(image, &blob, blob_size, client, r2_bucket, settings).await.context(format!("Failed to upload blob {}", blob.file_name().unwrap_or_default().to_string_lossy())));
+                tokio::time::sleep(NICE_PAUSE).await;
+            }
+            outcomes
+        }
+        false => {
+            let mut tasks = pool(small, SMALL_CONCURRENCY);
+            tasks.extend(pool(large, LARGE_CONCURRENCY));
+            let mut outcomes = Vec::new();
+            for task in tasks {
+                outcomes.push(task.await.context("A blob upload task panicked").and_then(|outcome| outcome));
+            }
+            outcomes
+        }
+    };
+
+    // A failed blob does not cancel the others mid-flight: every upload runs to completion and the
+    // failures are reported together, so a retry only has the failed blobs left to send.
+    let mut uploaded = UploadedBlobs::default();
+    let mut failures = Vec::new();
+    for outcome in outcomes {
+        match outcome {
+            Ok(outcome) => {
+                uploaded.keys.extend(outcome.keys);
+                uploaded.timings.extend(outcome.timing);
+                uploaded.reused.extend(outcome.reused);
+            }
+            Err(e) => failures.push(e),
+        }
+    }
+    if !failures.is_empty() {
+        for failure in &failures {
+            log::error!("{:#}", failure);
+        }
+        bail!("{} of {} blobs failed to upload, first: {:#}", failures.len(), total, failures[0]);
+    }
+
+    Ok(uploaded)
+}
+
+// `UploadSettings` owned by the spawned uploads of `upload_blobs`. The budget, the control and the
+// progress callback are shared handles, so every upload still draws on the same ones.
+struct SharedSettings {
+    index: BloomFilter,
+    budget: Option<MemoryBudget>,
+    chunked: bool,
+    delta: Option<DeltaPlan>,
+    cache_control: Option<String>,
+    existing: Option<BTreeSet<String>>,
+    repositories: Vec<String>,
+    parallel: Option<ParallelUpload>,
+    concurrency: Option<usize>,
+    control: Option<UploadControl>,
+    nice: bool,
+    adopt_legacy: bool,
+    resume_dir: PathBuf,
+    progress: Progress,
+}
+
+impl SharedSettings {
+    fn from(settings: &UploadSettings<'_>) -> Self {
+        SharedSettings {
+            index: settings.index.clone(),
+            budget: settings.budget.cloned(),
+            chunked: settings.chunked,
+            delta: settings.delta.cloned(),
+            cache_control: settings.cache_control.map(str::to_owned),
+            existing: settings.existing.cloned(),
+            repositories: settings.repositories.to_vec(),
+            parallel: settings.parallel.copied(),
+            concurrency: settings.concurrency,
+            control: settings.control.cloned(),
+            nice: settings.nice,
+            adopt_legacy: settings.adopt_legacy,
+            resume_dir: settings.resume_dir.to_owned(),
+            progress: settings.progress.clone(),
+        }
+    }
+
+    fn settings(&self) -> UploadSettings<'_> {
+        UploadSettings {
+            index: &self.index,
+            budget: self.budget.as_ref(),
+            chunked: self.chunked,
+            delta: self.delta.as_ref(),
+            cache_control: self.cache_control.as_deref(),
+            existing: self.existing.as_ref(),
+            repositories: &self.repositories,
+            parallel: self.parallel.as_ref(),
+            concurrency: self.concurrency,
+            control: self.control.as_ref(),
+            nice: self.nice,
+            adopt_legacy: self.adopt_legacy,
+            resume_dir: &self.resume_dir,
+            progress: &self.progress,
+        }
+    }
+}
+
+#[derive(Default)]
+struct BlobOutcome {
+    keys: Vec<String>,
+    timing: Option<BlobTiming>,
+    reused: Option<ReusedBlob>,
+}
+
+async fn upload_blob(image: &str, blob: &Path, blob_size: u64, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<BlobOutcome> {
+    let (index, budget, progress) = (settings.index, settings.budget, settings.progress);
+    let blob_name = blob.file_name().unwrap().to_str().unwrap();
+    let mut outcome = BlobOutcome::default();
+    let chunked = settings.chunked && blob_size >= chunks::MIN_CHUNKED_SIZE;
+
+    let key = blob_key(image, blob_name, chunked);
+    outcome.keys.push(key.clone());
 
-        let req = PutObjectRequest {
+    if is_uploaded(&key, client, r2_bucket, settings).await? {
+        log::info!("Blob {} already exists, skipping", blob_name);
+        outcome.reused = Some(ReusedBlob { name: blob_name.to_owned(), bytes: blob_size, source: None });
+        progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+        return Ok(outcome);
+    }
+
+    if let Some(note) = adopt_legacy(image, blob, chunked, client, r2_bucket, settings).await? {
+        log::info!("Blob {} already exists under its BLAKE3 name, copied it", blob_name);
+        outcome.keys.extend(note);
+        outcome.reused = Some(ReusedBlob { name: blob_name.to_owned(), bytes: blob_size, source: None });
+        progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+        return Ok(outcome);
+    }
+
+    if let Some(control) = settings.control {
+        control.admit(0).await?;
+    }
+
+    if let Some(plan) = settings.delta {
+        let note_key = delta::note_key(image, blob_name);
+        if is_uploaded(&note_key, client, r2_bucket, settings).await? {
+            log::info!("Blob {} already exists as a delta, skipping", blob_name);
+            outcome.reused = Some(ReusedBlob { name: blob_name.to_owned(), bytes: blob_size, source: None });
+            outcome.keys.push(note_key);
+            progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+            return Ok(outcome);
+        }
+
+        if let Some(base) = plan.base_for(blob_name).filter(|_| !chunked) {
+            let started = Instant::now();
+            if let Some(patch_size) = delta::upload_delta(client, r2_bucket, image, blob, base, settings.control).await? {
+                outcome.timing = Some(BlobTiming { name: blob_name.to_owned(), bytes: blob_size, duration: started.elapsed() });
+                log::info!("Uploaded blob {} as a {} byte delta against {}", blob_name, patch_size, base);
+                outcome.keys.push(note_key);
+                progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+                return Ok(outcome);
+            }
+        }
+    }
+
+    if chunked {
+        let started = Instant::now();
+        let upload = chunks::upload_chunked(image, blob, client, r2_bucket, index, settings.control).await?;
+        outcome.timing = Some(BlobTiming { name: blob_name.to_owned(), bytes: blob_size, duration: started.elapsed() });
+        log::info!(
+            "Uploaded blob {} as {} chunks ({} new, {} bytes)",
+            blob_name,
+            upload.chunk_keys.len(),
+            upload.new_chunks,
+            upload.new_bytes
+        );
+        outcome.keys.extend(upload.chunk_keys);
+        progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+        return Ok(outcome);
+    }
+
+    if let Some(source) = find_copy(blob_name, client, r2_bucket, settings).await? {
+        let req = CopyObjectRequest {
             bucket: r2_bucket.to_owned(),
             key: key.clone(),
-            body: Some(blob_data.into()),
+            copy_source: format!("{}/{}", r2_bucket, source),
+            // The source's metadata comes from another repository's push.
+            metadata_directive: Some("REPLACE".to_owned()),
+            cache_control: settings.cache_control.map(str::to_owned),
             content_type: Some("application/octet-stream".to_owned()),
             ..Default::default()
         };
+        client.copy_object(req).await.context(format!("Failed to copy {} to {}", source, key))?;
+        log::info!("Copied blob {} from {}", blob_name, source);
+        let repository = source.strip_prefix("v2/").and_then(|key| key.rsplit_once("/blobs/")).map(|(repository, _)| repository.to_owned());
+        outcome.reused = Some(ReusedBlob { name: blob_name.to_owned(), bytes: blob_size, source: repository });
+        progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+        return Ok(outcome);
+    }
 
-        client.put_object(req).await.context(format!("Failed to upload blob {}", blob_name))?;
-        log::info!("Uploaded blob {}", blob_name);
+    if let Some(parallel) = settings.parallel.filter(|parallel| !settings.nice && parallel.applies_to(blob_size)) {
+        let target = PartsTarget { client, r2_bucket, key: &key, cache_control: settings.cache_control, resume_dir: Some(settings.resume_dir) };
+        let started = Instant::now();
+        multipart::upload_parts(&target, blob, blob_size, parallel, budget, settings.control).await.context(format!("Failed to upload blob {}", blob_name))?;
+        outcome.timing = Some(BlobTiming { name: blob_name.to_owned(), bytes: blob_size, duration: started.elapsed() });
+        log::info!("Uploaded blob {} in parts", blob_name);
+        progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+        return Ok(outcome);
     }
 
-    Ok(())
+    let (body, _reservation) = match (settings.control, budget) {
+        // Controlled bodies are streamed so that pauses and rate limits apply while they are sent.
+        (Some(control), budget) => {
+            let reservation = match budget {
+                Some(budget) => Some(budget.reserve(THROTTLE_CHUNK as u64).await?),
+                None => None,
+            };
+            (memory::throttled_file(blob, blob_size, control).await?, reservation)
+        }
+        (None, None) => (memory::read_file(blob, blob_size)?, None),
+        (None, Some(budget)) if blob_size <= budget.limit() => {
+            let reservation = budget.reserve(blob_size).await?;
+            (memory::read_file(blob, blob_size)?, Some(reservation))
+        }
+        (None, Some(budget)) => {
+            let reservation = budget.reserve(budget.stream_chunk()).await?;
+            (memory::stream_file(blob, blob_size, budget.stream_chunk()).await?, Some(reservation))
+        }
+    };
+
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.clone(),
+        body: Some(body),
+        cache_control: settings.cache_control.map(str::to_owned),
+        content_type: Some("application/octet-stream".to_owned()),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    client.put_object(req).await.context(format!("Failed to upload blob {}", blob_name))?;
+    outcome.timing = Some(BlobTiming { name: blob_name.to_owned(), bytes: blob_size, duration: started.elapsed() });
+    log::info!("Uploaded blob {}", blob_name);
+    progress.emit(ProgressEvent::Uploaded { name: blob_name.to_owned(), bytes: blob_size });
+
+    Ok(outcome)
 }
 
-pub(crate) async fn upload_manifests(image: &str, image_manifests_dir: &Path, client: &S3Client, r2_bucket: &str) -> Result<()> {
-    for entry in fs::read_dir(&image_manifests_dir)? {
-        let entry = entry?;
-        let manifest = entry.path();
-        let manifest_name = manifest.file_name().unwrap().to_str().unwrap();
+fn blob_key(image: &str, blob_name: &str, chunked: bool) -> String {
+    match chunked {
+        true => chunks::recipe_key(image, blob_name),
+        false => format!("v2/{}/blobs/{}", image, blob_name),
+    }
+}
+
+// What `upload_blob` will do with a blob, decided the same way without uploading anything. A delta
+// upload falls back to a full upload when the patch turns out too large.
+pub(crate) async fn blob_operation(image: &str, blob: &Path, blob_size: u64, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<(String, Operation)> {
+    let blob_name = &blob.file_name().unwrap().to_string_lossy();
+    let chunked = settings.chunked && blob_size >= chunks::MIN_CHUNKED_SIZE;
+    let key = blob_key(image, blob_name, chunked);
+    if is_uploaded(&key, client, r2_bucket, settings).await? {
+        return Ok((key, Operation::Skip));
+    }
+    if settings.adopt_legacy && find_legacy(image, blob, chunked, client, r2_bucket, settings).await?.is_some() {
+        return Ok((key, Operation::Copy));
+    }
+
+    if let Some(plan) = settings.delta {
+        let note_key = delta::note_key(image, blob_name);
+        if is_uploaded(&note_key, client, r2_bucket, settings).await? {
+            return Ok((note_key, Operation::Skip));
+        }
+        if !chunked && plan.base_for(blob_name).is_some() {
+            return Ok((key, Operation::UploadDelta));
+        }
+    }
+
+    match chunked {
+        true => Ok((key, Operation::UploadChunked)),
+        false if find_copy(blob_name, client, r2_bucket, settings).await?.is_some() => Ok((key, Operation::Copy)),
+        false => Ok((key, Operation::Upload)),
+    }
+}
 
-        let manifest_data = fs::read_to_string(&manifest)?;
-        let manifest_json: Value = serde_json::from_str(&manifest_data)?;
-        let content_type = manifest_json["mediaType"].as_str().unwrap().to_owned();
+// A key of the same blob in another repository. Chunked blobs share their chunks already, so only
+// whole blobs are looked up, and only the keys the bloom filter reports as present cost a HEAD.
+async fn find_copy(blob_name: &str, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<Option<String>> {
+    for repository in settings.repositories {
+        let key = format!("v2/{}/blobs/{}", repository, blob_name);
+        if settings.index.contains(&key) && blob_exists(client, r2_bucket, &key).await? {
+            return Ok(Some(key));
+        }
+    }
 
-        let key = format!("v2/{}/manifests/{}", image, manifest_name);
+    Ok(None)
+}
 
-        let req = PutObjectRequest {
+// Pushes before objects were named by their digest stored blobs, recipes and deltas under the BLAKE3
+// hex of the blob. With `adopt_legacy` such objects are copied to the digest name instead of
+// uploading the blob again, and stay where they are for the delta chains that name them as their
+// base. Looking for them costs a BLAKE3 hash of every new blob, which is why it is opt-in for the
+// pushes that migrate a bucket. Returns the delta note when the blob was stored as a delta.
+async fn adopt_legacy(image: &str, blob: &Path, chunked: bool, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<Option<Option<String>>> {
+    if !settings.adopt_legacy {
+        return Ok(None);
+    }
+    let copies = match find_legacy(image, blob, chunked, client, r2_bucket, settings).await? {
+        Some(copies) => copies,
+        None => return Ok(None),
+    };
+
+    for copy in &copies {
+        let req = CopyObjectRequest {
             bucket: r2_bucket.to_owned(),
-            key: key.clone(),
-            body: Some(manifest_data.into_bytes().into()),
-            content_type: Some(content_type),
+            key: copy.to.clone(),
+            copy_source: format!("{}/{}", r2_bucket, copy.from),
+            // Give the copy the metadata an upload under the digest name would have had.
+            metadata_directive: Some("REPLACE".to_owned()),
+            cache_control: copy.cache_control.then(|| settings.cache_control.map(str::to_owned)).flatten(),
+            content_type: Some(copy.content_type.to_owned()),
             ..Default::default()
         };
+        client.copy_object(req).await.context(format!("Failed to copy {} to {}", copy.from, copy.to))?;
+    }
+    let blob_name = blob.file_name().unwrap().to_string_lossy();
+    Ok(Some((copies.len() > 1).then(|| delta::note_key(image, &blob_name))))
+}
+
+// A legacy object and the digest-named key it is copied to.
+struct LegacyCopy {
+    from: String,
+    to: String,
+    content_type: &'static str,
+    // Blobs carry the configured cache control, recipes and deltas none.
+    cache_control: bool,
+}
+
+// The objects stored for `blob` under its BLAKE3 name: the blob or its recipe, or else a delta
+// patch with its note.
+async fn find_legacy(image: &str, blob: &Path, chunked: bool, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<Option<Vec<LegacyCopy>>> {
+    let blob_name = blob.file_name().unwrap().to_string_lossy().into_owned();
+    let legacy = {
+        let blob = blob.to_owned();
+        tokio::task::spawn_blocking(move || legacy_name(&blob)).await??
+    };
+    let copy = |from: String, to: String, content_type, cache_control| LegacyCopy { from, to, content_type, cache_control };
+
+    let mut candidates = vec![match chunked {
+        true => vec![copy(chunks::recipe_key(image, &legacy), chunks::recipe_key(image, &blob_name), "application/json", false)],
+        false => vec![copy(format!("v2/{}/blobs/{}", image, legacy), format!("v2/{}/blobs/{}", image, blob_name), "application/octet-stream", true)],
+    }];
+    if !chunked {
+        candidates.push(vec![
+            copy(delta::patch_key(image, &legacy), delta::patch_key(image, &blob_name), "application/zstd", false),
+            copy(delta::note_key(image, &legacy), delta::note_key(image, &blob_name), "application/json", false),
+        ]);
+    }
+
+    for copies in candidates {
+        let mut found = true;
+        for copy in &copies {
+            found = found && is_uploaded(&copy.from, client, r2_bucket, settings).await?;
+        }
+        if found {
+            return Ok(Some(copies));
+        }
+    }
+
+    Ok(None)
+}
+
+fn legacy_name(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+// With a listing of the image's objects the check is free, otherwise only the keys the bloom filter
+// reports as present cost a HEAD request.
+async fn is_uploaded(key: &str, client: &S3Client, r2_bucket: &str, settings: &UploadSettings<'_>) -> Result<bool> {
+    match settings.existing {
+        Some(existing) => Ok(existing.contains(key)),
+        None => Ok(settings.index.contains(key) && blob_exists(client, r2_bucket, key).await?),
+    }
+}
+
+// Lists the objects of `image` that an upload may skip, once and paginated, in place of a HEAD
+// request per blob.
+pub(crate) async fn list_existing(image: &str, client: &S3Client, r2_bucket: &str) -> Result<BTreeSet<String>> {
+    let lister = Lister::new(client, r2_bucket);
+    let mut existing = BTreeSet::new();
+    for kind in ["blobs", "recipes", "deltas"] {
+        existing.extend(lister.list_keys(&format!("v2/{}/{}/", image, kind)).await?);
+    }
+    log::info!("Found {} existing objects of {}", existing.len(), image);
+
+    Ok(existing)
+}
+
+async fn blob_exists(client: &S3Client, r2_bucket: &str, key: &str) -> Result<bool> {
+    let req = HeadObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+
+    match client.head_object(req).await {
+        Ok(_) => Ok(true),
+        Err(e) if super::is_not_found(&e) => Ok(false),
+        Err(e) => Err(e).context(format!("Failed to check {}", key)),
+    }
+}
+
+// The staged manifests of a push, every one named by its digest, and the tag the top manifest is
+// published under.
+pub(crate) struct ManifestSet<'a> {
+    pub(crate) dir: &'a Path,
+    pub(crate) top_manifest: &'a str,
+    pub(crate) tag: Option<&'a str>,
+}
+
+// Uploads every manifest under `v2/<image>/manifests/<digest>` and then the top manifest under its
+// tag as well, so `GET /v2/<image>/manifests/<tag>` and `.../<digest>` both resolve. Per-platform
+// manifests go first and the tag last, so a client following the tag never meets a missing manifest.
+pub(crate) async fn upload_manifests(image: &str, manifests: &ManifestSet<'_>, client: &S3Client, r2_bucket: &str, cache_control: Option<&str>, progress: &Progress) -> Result<()> {
+    let mut staged = super::staged_files(manifests.dir)?;
+    staged.sort_by_key(|manifest| manifest.file_name().is_some_and(|name| name == manifests.top_manifest));
 
-        client.put_object(req).await.context(format!("Failed to upload manifest {}", manifest_name))?;
+    for manifest in &staged {
+        let manifest_name = manifest.file_name().unwrap().to_str().unwrap();
+        let manifest_size = put_manifest(client, r2_bucket, &format!("v2/{}/manifests/{}", image, manifest_name), manifest, manifest_name, cache_control).await?;
         log::info!("Uploaded manifest {}", manifest_name);
+        progress.emit(ProgressEvent::Uploaded { name: manifest_name.to_owned(), bytes: manifest_size });
+    }
+
+    if let Some(tag) = manifests.tag {
+        let top_manifest = manifests.dir.join(manifests.top_manifest);
+        put_manifest(client, r2_bucket, &format!("v2/{}/manifests/{}", image, tag), &top_manifest, manifests.top_manifest, cache_control).await?;
+        log::info!("Tagged manifest {} as {}:{}", manifests.top_manifest, image, tag);
     }
 
     Ok(())
 }
 
-pub(crate) fn prepare_s3_client(env_vars: &R2Configs) -> Result<S3Client> {
+// The digest is kept as object metadata, so a static front end can answer with the
+// `Docker-Content-Digest` header for tags too.
+async fn put_manifest(client: &S3Client, r2_bucket: &str, key: &str, path: &Path, digest: &str, cache_control: Option<&str>) -> Result<u64> {
+    let manifest_data = fs::read(path)?;
+    let manifest_json: Value = serde_json::from_slice(&manifest_data).context(format!("Manifest {} is not JSON", digest))?;
+    let content_type = manifest_json["mediaType"].as_str().context(format!("Manifest {} has no mediaType", digest))?.to_owned();
+    let manifest_size = manifest_data.len() as u64;
+
+    let req = PutObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        body: Some(manifest_data.into()),
+        cache_control: cache_control.map(str::to_owned),
+        content_type: Some(content_type),
+        metadata: Some(HashMap::from([("docker-content-digest".to_owned(), digest.to_owned())])),
+        ..Default::default()
+    };
+    client.put_object(req).await.context(format!("Failed to upload manifest {}", key))?;
+
+    Ok(manifest_size)
+}
+
+pub(crate) fn prepare_region(env_vars: &R2Configs) -> Region {
     let s3_endpoint = format!("https://{}.r2.cloudflarestorage.com", env_vars.cloudflare_account_id);
 
-    let region = Region::Custom {
+    Region::Custom {
         name: "auto".to_owned(),
         endpoint: s3_endpoint,
-    };
+    }
+}
 
-    Ok(S3Client::new_with(
-        rusoto_core::HttpClient::new().expect("failed to create request dispatcher"),
+// One client per set of credentials for the whole process. Clients share their connection pool
+// with their clones, so blob uploads, CAS updates and concurrent pushes reuse warm connections
+// instead of paying a DNS lookup and TLS handshake per client.
+pub(crate) fn prepare_core_client(env_vars: &R2Configs) -> Result<rusoto_core::Client> {
+    static CLIENTS: Mutex<BTreeMap<(String, String, String), rusoto_core::Client>> = Mutex::new(BTreeMap::new());
+
+    let credentials = (
+        env_vars.cloudflare_account_id.clone(),
+        env_vars.r2_access_key_id.clone(),
+        env_vars.r2_secret_access_key.clone(),
+    );
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(&credentials) {
+        return Ok(client.clone());
+    }
+
+    let client = rusoto_core::Client::new_with(
         rusoto_core::credential::StaticProvider::new_minimal(
             env_vars.r2_access_key_id.clone(),
             env_vars.r2_secret_access_key.clone(),
         ),
-        region,
-    ))
+        crate::trace::TracingDispatcher(rusoto_core::HttpClient::from_connector(crate::proxy::connector()?)),
+    );
+    clients.insert(credentials, client.clone());
+
+    Ok(client)
+}
+
+pub(crate) fn prepare_s3_client(env_vars: &R2Configs) -> Result<S3Client> {
+    Ok(S3Client::new_with_client(prepare_core_client(env_vars)?, prepare_region(env_vars)))
 }
@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use super::chunks;
+use super::delta;
+use crate::budget::{self, OpClass};
+use crate::hash_utils;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    #[default]
+    Off,
+    All,
+    Sample(u8),
+}
+
+impl FromStr for VerifyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(VerifyMode::Off),
+            "all" => Ok(VerifyMode::All),
+            _ => {
+                let percent = s
+                    .strip_prefix("sample=")
+                    .map(|percent| percent.trim_end_matches('%'))
+                    .and_then(|percent| percent.parse::<u8>().ok())
+                    .filter(|percent| (1..=100).contains(percent));
+
+                match percent {
+                    Some(percent) => Ok(VerifyMode::Sample(percent)),
+                    None => bail!("Unknown verify mode {} (expected off, all or sample=N%)", s),
+                }
+            }
+        }
+    }
+}
+
+// Downloads the selected objects again and compares their SHA-256 with the staged files. Samples
+// are drawn afresh on every run so repeated pushes eventually cover every object.
+pub(crate) async fn verify_uploads(image: &str, dirs: &[(&str, &Path)], client: &S3Client, r2_bucket: &str, mode: VerifyMode) -> Result<()> {
+    if mode == VerifyMode::Off {
+        return Ok(());
+    }
+
+    let mut objects = Vec::new();
+    for (kind, dir) in dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            objects.push((format!("v2/{}/{}/{}", image, kind, name), path));
+        }
+    }
+
+    if let VerifyMode::Sample(percent) = mode {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default().to_le_bytes();
+        objects.sort_by_cached_key(|(key, _)| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&seed);
+            hasher.update(key.as_bytes());
+            *hasher.finalize().as_bytes()
+        });
+        objects.truncate((objects.len() * usize::from(percent)).div_ceil(100));
+    }
+
+    let mut mismatches = Vec::new();
+    for (key, path) in &objects {
+        let expected = hash_utils::compute_sha256(path)?;
+        let (actual, _) = remote_sha256(client, r2_bucket, key).await?;
+        if actual != expected {
+            log::warn!("{} does not match the uploaded file: expected {}, got {}", key, expected, actual);
+            mismatches.push(key.as_str());
+        }
+    }
+
+    if !mismatches.is_empty() {
+        bail!("Verification failed for {} of {} objects: {}", mismatches.len(), objects.len(), mismatches.join(", "));
+    }
+
+    log::info!("Verified {} uploaded objects", objects.len());
+
+    Ok(())
+}
+
+// The digest an object's name claims: `sha256:<hex>` for objects addressed by digest, or the bare
+// BLAKE3 hex that pushes named objects after before they used digests. Tags claim nothing.
+pub(crate) fn claimed_digest(key: &str) -> Option<&str> {
+    let name = key.rsplit('/').next()?;
+    let hex = name.strip_prefix("sha256:").unwrap_or(name);
+    (hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit())).then_some(name)
+}
+
+// Streams the object at `key` through the hash `claimed` is in and returns the actual digest in the
+// same form, without holding the object in memory. Also returns the bytes read.
+pub(crate) async fn object_digest(client: &S3Client, r2_bucket: &str, key: &str, claimed: &str) -> Result<(String, u64)> {
+    if claimed.starts_with("sha256:") {
+        return remote_sha256(client, r2_bucket, key).await;
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    let size = read_body(client, r2_bucket, key, |data| {
+        hasher.update(data);
+    })
+    .await?
+    .context(format!("{} does not exist", key))?;
+
+    Ok((hasher.finalize().to_hex().to_string(), size))
+}
+
+async fn remote_sha256(client: &S3Client, r2_bucket: &str, key: &str) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    match read_body(client, r2_bucket, key, |data| hasher.update(data)).await? {
+        Some(size) => Ok((format!("sha256:{:x}", hasher.finalize()), size)),
+        None => chunked_sha256(client, r2_bucket, key).await,
+    }
+}
+
+// Feeds the object to `update` piece by piece, None when it does not exist.
+async fn read_body<F: FnMut(&[u8])>(client: &S3Client, r2_bucket: &str, key: &str, mut update: F) -> Result<Option<u64>> {
+    let req = GetObjectRequest {
+        bucket: r2_bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+
+    budget::charge(OpClass::B)?;
+    let output = match client.get_object(req).await {
+        Ok(output) => output,
+        Err(e) if super::is_not_found(&e) || matches!(e, RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to download {}", key)),
+    };
+    let mut body = output.body.context(format!("{} has no body", key))?.into_async_read();
+
+    let mut size = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let bytes = body.read(&mut buffer).await.context(format!("Failed to download {}", key))?;
+        if bytes == 0 {
+            break;
+        }
+
+        update(&buffer[..bytes]);
+        size += bytes as u64;
+    }
+
+    Ok(Some(size))
+}
+
+async fn chunked_sha256(client: &S3Client, r2_bucket: &str, key: &str) -> Result<(String, u64)> {
+    let (image, blob_name) = key.strip_prefix("v2/").and_then(|rest| rest.rsplit_once("/blobs/")).context(format!("{} does not exist", key))?;
+    let recipe = match chunks::read_recipe(client, r2_bucket, image, blob_name).await? {
+        Some(recipe) => recipe,
+        None => {
+            let file = delta::read_blob(client, r2_bucket, image, blob_name).await?.context(format!("{} does not exist", key))?;
+            let size = file.as_file().metadata()?.len();
+            let digest = tokio::task::spawn_blocking(move || crate::hash_utils::compute_sha256(file.path())).await??;
+            return Ok((digest, size));
+        }
+    };
+
+    let mut hasher = Sha256::new();
+    let size = recipe.size;
+    let mut chunks = Box::pin(chunks::reassemble(client.clone(), r2_bucket.to_owned(), recipe, 0..size));
+    while let Some(chunk) = chunks.next().await {
+        hasher.update(&chunk.context(format!("Failed to download a chunk of {}", key))?);
+    }
+
+    Ok((format!("sha256:{:x}", hasher.finalize()), size))
+}
@@ -0,0 +1,415 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use anyhow::Result;
+
+use crate::config::{self, Config, NotifierConfig};
+use crate::destination;
+use crate::serve::ProxyNetwork;
+
+const BUCKET_FIX: &str = "bucket names are 3-63 lowercase letters, digits and hyphens, starting and ending with a letter or digit";
+const DIGEST_FIX: &str = "use the form sha256:<64 hex characters>";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    pub location: String,
+    pub message: String,
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.location, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  fix: {}", fix)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Issues {
+    file: String,
+    issues: Vec<ConfigIssue>,
+}
+
+impl Issues {
+    fn push(&mut self, severity: Severity, key: &str, message: String, fix: Option<&str>) {
+        self.issues.push(ConfigIssue {
+            severity,
+            location: format!("{}: {}", self.file, key),
+            message,
+            fix: fix.map(str::to_owned),
+        });
+    }
+
+    fn error(&mut self, key: &str, message: String, fix: &str) {
+        self.push(Severity::Error, key, message, Some(fix));
+    }
+
+    fn warning(&mut self, key: &str, message: String, fix: &str) {
+        self.push(Severity::Warning, key, message, Some(fix));
+    }
+}
+
+// Checks the config file and the environment it relies on, reporting every problem at once
+// instead of the first one a push happens to run into.
+pub fn validate_config() -> Result<Vec<ConfigIssue>> {
+    let path = config::config_path();
+    let file = path.as_ref().map_or_else(|| "<environment>".to_owned(), |path| path.display().to_string());
+    let mut issues = Issues { file, issues: Vec::new() };
+
+    let config = match &path {
+        Some(path) => {
+            let data = match config::read_config_file(path) {
+                Ok(data) => data,
+                Err(e) => {
+                    issues.issues.push(ConfigIssue {
+                        severity: Severity::Error,
+                        location: path.display().to_string(),
+                        message: format!("{:#}", e),
+                        fix: Some("set the variable, e.g. in .env, or give a default with ${VAR:-default}".to_owned()),
+                    });
+                    return Ok(issues.issues);
+                }
+            };
+            match toml::from_str::<Config>(&data) {
+                Ok(config) => config,
+                Err(e) => {
+                    let location = match e.span() {
+                        Some(span) => {
+                            let line = data[..span.start].matches('\n').count() + 1;
+                            let column = span.start - data[..span.start].rfind('\n').map_or(0, |newline| newline + 1) + 1;
+                            format!("{}:{}:{}", path.display(), line, column)
+                        }
+                        None => path.display().to_string(),
+                    };
+                    issues.issues.push(ConfigIssue {
+                        severity: Severity::Error,
+                        location,
+                        message: e.message().to_owned(),
+                        fix: Some("check the key against the Configuration file section of the README".to_owned()),
+                    });
+                    return Ok(issues.issues);
+                }
+            }
+        }
+        None => Config::default(),
+    };
+
+    check_environment(&config, &mut issues);
+    check_destinations(&config, &mut issues);
+    check_public_url(&config, &mut issues);
+    check_filter(&config, &mut issues);
+    check_serve(&config, &mut issues);
+    check_replication(&config, &mut issues);
+    check_quota(&config, &mut issues);
+    check_notify(&config, &mut issues);
+    check_image_policy(&config, &mut issues);
+    check_redact(&config, &mut issues);
+    check_convert_retry(&config, &mut issues);
+
+    Ok(issues.issues)
+}
+
+fn check_environment(config: &Config, issues: &mut Issues) {
+    let destination = destination::selected().and_then(|name| config.dest.get(&name));
+    let replaced = |name: &str| {
+        destination.is_some_and(|destination| match name {
+            "CLOUDFLARE_ACCOUNT_ID" => destination.account_id.is_some(),
+            "R2_BUCKET" => destination.bucket.is_some(),
+            "R2_ACCESS_KEY_ID" => destination.access_key_id_env.is_some(),
+            _ => destination.secret_access_key_env.is_some(),
+        })
+    };
+
+    for name in ["CLOUDFLARE_ACCOUNT_ID", "R2_BUCKET", "R2_ACCESS_KEY_ID", "R2_SECRET_ACCESS_KEY"] {
+        if env::var_os(name).is_none() && !replaced(name) {
+            issues.push(Severity::Error, name, format!("{} is not set", name), Some(&format!("export {}=...", name)));
+        }
+    }
+
+    if let Ok(bucket) = env::var("R2_BUCKET") {
+        if let Some(problem) = bucket_problem(&bucket) {
+            issues.error("R2_BUCKET", format!("{} is not a valid bucket name: {}", bucket, problem), BUCKET_FIX);
+        }
+    }
+}
+
+fn check_destinations(config: &Config, issues: &mut Issues) {
+    if let Some(name) = destination::selected().filter(|name| !config.dest.contains_key(name)) {
+        issues.error("dest", format!("the selected destination {} is not defined", name), &format!("add a [dest.{}] table or select another destination", name));
+    }
+
+    for (name, destination) in &config.dest {
+        let key = |field: &str| format!("dest.{}.{}", name, field);
+
+        if let Some(bucket) = &destination.bucket {
+            if let Some(problem) = bucket_problem(bucket) {
+                issues.error(&key("bucket"), format!("{} is not a valid bucket name: {}", bucket, problem), BUCKET_FIX);
+            }
+        }
+        if destination.access_key_id_env.is_some() != destination.secret_access_key_env.is_some() {
+            issues.error(&key("access_key_id_env"), "only one of access_key_id_env and secret_access_key_env is set".to_owned(), "set both, or neither to use R2_ACCESS_KEY_ID and R2_SECRET_ACCESS_KEY");
+        }
+        for (field, env_name) in [("access_key_id_env", &destination.access_key_id_env), ("secret_access_key_env", &destination.secret_access_key_env)] {
+            if let Some(env_name) = env_name.as_ref().filter(|env_name| env::var_os(env_name).is_none()) {
+                issues.warning(&key(field), format!("{} is not set", env_name), &format!("export {}=... before pushing to {}", env_name, name));
+            }
+        }
+        if let Some(public_url) = &destination.public_url {
+            check_url(&key("public_url"), public_url, issues);
+        }
+        if let Some(policy) = destination.policy.as_ref().filter(|policy| !policy.is_file()) {
+            issues.error(&key("policy"), format!("{} does not exist", policy.display()), "point it at a containers-policy.json file");
+        }
+    }
+}
+
+fn check_public_url(config: &Config, issues: &mut Issues) {
+    if let Some(public_url) = &config.public_url {
+        check_url("public_url", public_url, issues);
+    }
+}
+
+fn check_url(key: &str, url: &str, issues: &mut Issues) {
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        issues.error(key, format!("{} is not an http(s) URL", url), "use the full URL, e.g. https://registry.example.com");
+    }
+}
+
+fn check_filter(config: &Config, issues: &mut Issues) {
+    let (include, exclude) = config.filter.patterns();
+    for pattern in include {
+        if exclude.iter().any(|excluded| excluded.as_str() == "*" || excluded == pattern) {
+            issues.error(
+                "filter.include",
+                format!("{} is also excluded, so it never matches", pattern),
+                "remove it from filter.include or filter.exclude",
+            );
+        }
+    }
+}
+
+fn check_serve(config: &Config, issues: &mut Issues) {
+    if let Some(cache) = &config.serve.cache {
+        if cache.max_size == Some(0) {
+            issues.error("serve.cache.max_size", "the cache can hold nothing".to_owned(), "use a size such as \"20G\" or remove [serve.cache]");
+        }
+        if cache.ttl_secs == Some(0) {
+            issues.error("serve.cache.ttl_secs", "cached blobs would expire immediately".to_owned(), "use a positive number of seconds or leave it out");
+        }
+    }
+
+    if let Some(events) = &config.serve.events {
+        if events.webhook.is_none() && events.log_file.is_none() {
+            issues.warning("serve.events", "pull events are enabled without a webhook or log_file".to_owned(), "set serve.events.webhook or serve.events.log_file");
+        }
+    }
+
+    for (i, network) in config.serve.trusted_proxies.iter().enumerate() {
+        if let Err(e) = ProxyNetwork::parse(network) {
+            issues.error(&format!("serve.trusted_proxies[{}]", i), format!("{:#}", e), "use an address such as \"10.0.0.1\" or a network such as \"10.0.0.0/8\"");
+        }
+    }
+
+    if let Some(scrub) = &config.serve.scrub {
+        if scrub.interval_secs == 0 {
+            issues.error("serve.scrub.interval_secs", "scrubbing would never pause".to_owned(), "use a positive number of seconds, e.g. 3600");
+        }
+        if scrub.sample == 0 {
+            issues.error("serve.scrub.sample", "no objects would be verified".to_owned(), "use a positive number of objects, e.g. 100");
+        }
+    }
+
+    let auth = match &config.serve.auth {
+        Some(auth) => auth,
+        None => {
+            if config.serve.push {
+                issues.error("serve.push", "anyone could push and overwrite tags without [serve.auth]".to_owned(), "configure [serve.auth] with push access for the users that push");
+            }
+            return;
+        }
+    };
+
+    if auth.secret.len() < 32 {
+        issues.warning("serve.auth.secret", "the token signing secret is shorter than 32 bytes".to_owned(), "generate one with `openssl rand -hex 32`");
+    }
+    if auth.token_ttl_secs == 0 {
+        issues.error("serve.auth.token_ttl_secs", "tokens would expire immediately".to_owned(), "use a positive number of seconds, e.g. 300");
+    }
+
+    let mut names = HashSet::new();
+    for (i, user) in auth.users.iter().enumerate() {
+        if !names.insert(&user.name) {
+            issues.error(&format!("serve.auth.users[{}].name", i), format!("user {} is defined more than once", user.name), "merge the entries");
+        }
+        check_patterns(&format!("serve.auth.users[{}].repositories", i), &user.repositories, issues);
+        check_patterns(&format!("serve.auth.users[{}].push", i), &user.push, issues);
+        if !user.push.is_empty() && !config.serve.push {
+            issues.warning(&format!("serve.auth.users[{}].push", i), "serve is read-only, so push access has no effect".to_owned(), "set serve.push = true");
+        }
+    }
+    check_patterns("serve.auth.anonymous", &auth.anonymous, issues);
+}
+
+fn check_replication(config: &Config, issues: &mut Issues) {
+    let primary = env::var("R2_BUCKET").ok();
+    let mut names = HashSet::new();
+
+    for (i, replica) in config.replication.iter().enumerate() {
+        let key = |field: &str| format!("replication[{}].{}", i, field);
+
+        if !names.insert(&replica.name) {
+            issues.error(&key("name"), format!("replica {} is defined more than once", replica.name), "give every replica a unique name");
+        }
+        if let Some(problem) = bucket_problem(&replica.bucket) {
+            issues.error(&key("bucket"), format!("{} is not a valid bucket name: {}", replica.bucket, problem), BUCKET_FIX);
+        }
+        if replica.account_id.is_none() && primary.as_deref() == Some(replica.bucket.as_str()) {
+            issues.error(&key("bucket"), format!("{} is the primary bucket", replica.bucket), "replicate to a different bucket or account");
+        }
+
+        match (&replica.access_key_id_env, &replica.secret_access_key_env) {
+            (Some(_), None) | (None, Some(_)) => issues.error(
+                &key("access_key_id_env"),
+                "only one of access_key_id_env and secret_access_key_env is set".to_owned(),
+                "set both, or neither to reuse the primary credentials",
+            ),
+            (None, None) if replica.account_id.is_some() => issues.warning(
+                &key("account_id"),
+                "the replica is in another account but reuses the primary credentials".to_owned(),
+                "set access_key_id_env and secret_access_key_env",
+            ),
+            _ => {}
+        }
+        for (field, name) in [("access_key_id_env", &replica.access_key_id_env), ("secret_access_key_env", &replica.secret_access_key_env)] {
+            if let Some(name) = name.as_ref().filter(|name| env::var_os(name).is_none()) {
+                issues.error(&key(field), format!("{} is not set", name), &format!("export {}=...", name));
+            }
+        }
+    }
+}
+
+fn check_quota(config: &Config, issues: &mut Issues) {
+    let mut namespaces = HashSet::new();
+
+    for (i, quota) in config.quota.iter().enumerate() {
+        let key = |field: &str| format!("quota[{}].{}", i, field);
+
+        if quota.namespace.trim_end_matches('/').is_empty() {
+            issues.error(&key("namespace"), "the namespace is empty".to_owned(), "name the image namespace, e.g. \"team-a\"");
+        }
+        if !namespaces.insert(quota.namespace.trim_end_matches('/')) {
+            issues.error(&key("namespace"), format!("{} has more than one quota", quota.namespace), "merge the entries");
+        }
+        if quota.max_bytes.is_none() && quota.max_objects.is_none() {
+            issues.warning(&key("namespace"), "the quota sets no limit".to_owned(), "set max_bytes or max_objects, or remove the entry");
+        }
+    }
+}
+
+fn check_notify(config: &Config, issues: &mut Issues) {
+    for (i, notifier) in config.notify.iter().enumerate() {
+        let key = |field: &str| format!("notify[{}].{}", i, field);
+
+        match notifier {
+            NotifierConfig::Slack { url, .. } | NotifierConfig::Discord { url, .. } => {
+                if !url.starts_with("https://") {
+                    issues.error(&key("url"), "the webhook URL is not an https URL".to_owned(), "copy the full webhook URL");
+                }
+            }
+            NotifierConfig::Email { to, username, password, .. } => {
+                if to.is_empty() {
+                    issues.error(&key("to"), "the email notifier has no recipients".to_owned(), "add at least one address to `to`");
+                }
+                if username.is_some() != password.is_some() {
+                    issues.error(&key("username"), "only one of username and password is set".to_owned(), "set both, or neither for unauthenticated SMTP");
+                }
+            }
+        }
+    }
+}
+
+fn check_image_policy(config: &Config, issues: &mut Issues) {
+    let policy = &config.image_policy;
+
+    for (i, digest) in policy.forbidden_base_digests.iter().enumerate() {
+        if !is_digest(digest) {
+            issues.error(&format!("image_policy.forbidden_base_digests[{}]", i), format!("{} is not a sha256 digest", digest), DIGEST_FIX);
+        }
+    }
+
+    for (i, base) in policy.approved_bases.iter().enumerate() {
+        if base.layers.is_empty() {
+            issues.error(&format!("image_policy.approved_bases[{}].layers", i), format!("{} lists no layers", base.name), "list the layer digests of the base image");
+        }
+        for (j, digest) in base.layers.iter().enumerate() {
+            if !is_digest(digest) {
+                issues.error(&format!("image_policy.approved_bases[{}].layers[{}]", i, j), format!("{} is not a sha256 digest", digest), DIGEST_FIX);
+            }
+            if policy.forbidden_base_digests.contains(digest) {
+                issues.error(
+                    &format!("image_policy.approved_bases[{}].layers[{}]", i, j),
+                    format!("{} is both approved and forbidden", digest),
+                    "remove it from approved_bases or forbidden_base_digests",
+                );
+            }
+        }
+    }
+}
+
+fn check_redact(config: &Config, issues: &mut Issues) {
+    check_patterns("redact.remove", &config.redact.remove, issues);
+
+    for (i, rule) in config.redact.rewrite.iter().enumerate() {
+        if rule.find.is_empty() {
+            issues.error(&format!("redact.rewrite[{}].find", i), "the text to find is empty".to_owned(), "set find to the text to replace");
+        }
+    }
+}
+
+fn check_patterns(key: &str, patterns: &[String], issues: &mut Issues) {
+    for (i, pattern) in patterns.iter().enumerate() {
+        if let Err(e) = glob::Pattern::new(pattern) {
+            issues.error(&format!("{}[{}]", key, i), format!("{} is not a valid pattern: {}", pattern, e), "fix the glob syntax, e.g. \"team-a/*\"");
+        }
+    }
+}
+
+fn check_convert_retry(config: &Config, issues: &mut Issues) {
+    if config.convert_retry.max_attempts == Some(0) {
+        issues.error("convert_retry.max_attempts", "0 attempts would never convert an image".to_owned(), "set it to 1 to disable retries");
+    }
+}
+
+fn bucket_problem(bucket: &str) -> Option<&'static str> {
+    if !(3..=63).contains(&bucket.len()) {
+        return Some("it must be 3 to 63 characters long");
+    }
+    if !bucket.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-') {
+        return Some("it may only contain lowercase letters, digits and hyphens");
+    }
+    if bucket.starts_with('-') || bucket.ends_with('-') {
+        return Some("it must start and end with a letter or digit");
+    }
+
+    None
+}
+
+fn is_digest(digest: &str) -> bool {
+    digest
+        .strip_prefix("sha256:")
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)))
+}
@@ -0,0 +1,31 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+
+// Below the work directory when the system has no temporary directory.
+const TMP_DIR: &str = ".oci-r2-tmp";
+
+// Where pushes stage images and keep the records of unfinished uploads, as an absolute path so
+// nothing later depends on the process's working directory. `OCI_R2_WORK_DIR` points it elsewhere,
+// e.g. at a writable volume when the binary runs from a read-only root or a `FROM scratch` image.
+// Such images have no `/tmp` either; temporary files then go below the work directory.
+pub fn work_dir() -> Result<PathBuf> {
+    let dir = match env::var_os("OCI_R2_WORK_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            fs::create_dir_all(&dir).context(format!("Failed to create the work directory {}", dir.display()))?;
+            dir.canonicalize().context(format!("Failed to resolve the work directory {}", dir.display()))?
+        }
+        None => env::current_dir().context("The working directory is not accessible, set OCI_R2_WORK_DIR")?,
+    };
+
+    if !env::temp_dir().is_dir() {
+        let tmp_dir = dir.join(TMP_DIR);
+        fs::create_dir_all(&tmp_dir).context(format!("Failed to create {}", tmp_dir.display()))?;
+        // Only fails when an earlier call already chose a directory.
+        let _ = tempfile::env::override_temp_dir(&tmp_dir);
+    }
+
+    Ok(dir)
+}